@@ -0,0 +1,93 @@
+/* Clock: the system time or a manually advanced time for the timing logic */
+
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> time::Instant;
+
+    /// Wait for `duration` (a manual clock advances the time instead)
+    fn sleep(&self, duration: time::Duration);
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Clock advanced only by advance() / sleep(). Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: time::Instant,
+    now: Arc<Mutex<time::Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        let start = time::Instant::now();
+        Self {
+            start,
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, duration: time::Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Time advanced since the clock was created
+    pub fn get_elapsed(&self) -> time::Duration {
+        *self.now.lock().unwrap() - self.start
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> time::Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Shared system clock
+pub fn get_system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        let start = clock.now();
+        assert_eq!(shared.now(), start);
+
+        clock.advance(time::Duration::from_millis(250));
+        shared.sleep(time::Duration::from_secs(10));
+        assert_eq!(shared.now() - start, time::Duration::from_millis(10_250));
+        assert_eq!(clock.get_elapsed(), time::Duration::from_millis(10_250));
+
+        let system = get_system_clock();
+        let now = system.now();
+        assert!(system.now() >= now);
+    }
+}
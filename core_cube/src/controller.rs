@@ -0,0 +1,326 @@
+/* Host-side closed-loop go-to-pose controller */
+
+use log::{debug, info};
+use std::time;
+
+use crate::id_info::{get_id_info, IdInfo};
+use crate::kinematics::{clamp_wheel_speeds, velocity_to_wheel_speeds, Pose};
+use crate::motor::MotorControl;
+use crate::units::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ControlMode {
+    /// Stop when the cube arrives at the target
+    Once,
+    /// Keep tracking the target (it may change every tick). Never times out.
+    MovingTarget,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GoToPoseConfig {
+    pub mode: ControlMode,
+    /// Linear speed per distance to the target [mm/s per mm]
+    pub distance_gain: f32,
+    /// Angular speed per heading error while moving [deg/s per deg]
+    pub heading_gain: f32,
+    /// Angular speed per angle error at the target [deg/s per deg]
+    pub angle_gain: f32,
+    pub max_mm_per_sec: f32,
+    pub max_deg_per_sec: f32,
+    /// Arrival tolerance [Position ID units]
+    pub position_tolerance: f32,
+    /// Arrival tolerance of the final angle [deg]
+    pub angle_tolerance: f32,
+    /// Drive backward when the target is behind the cube
+    pub allow_backward: bool,
+    pub timeout: time::Duration,
+    /// Stop when no Position ID is received for this duration
+    pub pose_timeout: time::Duration,
+    /// Duration of each motor command (the cube stops by itself if the host stops)
+    pub command_duration: time::Duration,
+    pub mm_per_unit: f32,
+}
+
+impl Default for GoToPoseConfig {
+    fn default() -> Self {
+        Self {
+            mode: ControlMode::Once,
+            distance_gain: 2.0,
+            heading_gain: 4.0,
+            angle_gain: 3.0,
+            max_mm_per_sec: 150.0,
+            max_deg_per_sec: 360.0,
+            position_tolerance: 8.0,
+            angle_tolerance: 10.0,
+            allow_backward: true,
+            timeout: time::Duration::from_secs(10),
+            pose_timeout: time::Duration::from_millis(500),
+            command_duration: time::Duration::from_millis(300),
+            mm_per_unit: MM_PER_UNIT,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ControllerStatus {
+    Moving(MotorControl),
+    Arrived,
+    Timeout,
+    PoseLost,
+}
+
+impl ControllerStatus {
+    /// Motor command for the status (stop unless moving)
+    pub fn get_motor_control(&self) -> MotorControl {
+        match self {
+            ControllerStatus::Moving(control) => *control,
+            _ => MotorControl::stop(),
+        }
+    }
+}
+
+/// Target position and the final angle (None: any angle)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Target {
+    pub x: f32,
+    pub y: f32,
+    pub angle: Option<Angle>,
+}
+
+impl Target {
+    pub fn new(x: f32, y: f32, angle: Option<f32>) -> Self {
+        Self {
+            x,
+            y,
+            angle: angle.map(Angle::from_degrees),
+        }
+    }
+}
+
+pub struct GoToPoseController {
+    config: GoToPoseConfig,
+    target: Target,
+    start_time: Option<time::Instant>,
+    pose: Option<(Pose, time::Instant)>,
+}
+
+fn clamp_abs(value: f32, max: f32) -> f32 {
+    value.clamp(-max, max)
+}
+
+impl GoToPoseController {
+    pub fn new(config: GoToPoseConfig, target: Target) -> Self {
+        Self {
+            config,
+            target,
+            start_time: None,
+            pose: None,
+        }
+    }
+
+    pub fn get_target(&self) -> Target {
+        self.target
+    }
+
+    /// Change the target. The timeout is not restarted.
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
+    /// Restart the timeout
+    pub fn restart(&mut self) {
+        self.start_time = None;
+    }
+
+    pub fn get_pose(&self) -> Option<Pose> {
+        self.pose.map(|(pose, _)| pose)
+    }
+
+    pub fn update_pose(&mut self, pose: Pose, now: time::Instant) {
+        self.pose = Some((pose, now));
+    }
+
+    /// Feed an ID information notification
+    pub fn update_id_info(&mut self, data: &[u8], now: time::Instant) {
+        if let IdInfo::PositionId(position) = get_id_info(data) {
+            self.update_pose(Pose::from(&position), now);
+        }
+    }
+
+    /// Calculate the next motor command
+    pub fn update(&mut self, now: time::Instant) -> ControllerStatus {
+        let start_time = *self.start_time.get_or_insert(now);
+        if self.config.mode == ControlMode::Once
+            && now.duration_since(start_time) > self.config.timeout
+        {
+            info!("go to pose: timeout");
+            return ControllerStatus::Timeout;
+        }
+
+        let pose = match self.pose {
+            Some((pose, time)) if now.duration_since(time) <= self.config.pose_timeout => pose,
+            _ => {
+                debug!("go to pose: no Position ID");
+                return ControllerStatus::PoseLost;
+            }
+        };
+
+        let goal = Pose {
+            x: self.target.x,
+            y: self.target.y,
+            angle: pose.angle,
+        };
+        let distance = pose.distance(&goal);
+        let (mm_per_sec, deg_per_sec) = if distance > self.config.position_tolerance {
+            // move toward the target
+            let mut heading_error = pose.angle.difference(pose.direction_to(&goal));
+            let mut direction = 1.0;
+            if self.config.allow_backward && heading_error.abs() > 90.0 {
+                // heading error of the rear side
+                heading_error =
+                    Angle::default().difference(Angle::from_degrees(heading_error + 180.0));
+                direction = -1.0;
+            }
+            let mm_per_sec = clamp_abs(
+                self.config.distance_gain * distance * self.config.mm_per_unit,
+                self.config.max_mm_per_sec,
+            ) * heading_error.to_radians().cos().max(0.0);
+            (
+                direction * mm_per_sec,
+                self.config.heading_gain * heading_error,
+            )
+        } else {
+            // turn to the final angle
+            let angle_error = match self.target.angle {
+                Some(angle) => pose.angle.difference(angle),
+                None => 0.0,
+            };
+            if angle_error.abs() <= self.config.angle_tolerance {
+                if self.config.mode == ControlMode::Once {
+                    info!("go to pose: arrived {:?}", pose);
+                    return ControllerStatus::Arrived;
+                }
+                return ControllerStatus::Moving(
+                    MotorControl::stop().with_duration(self.config.command_duration),
+                );
+            }
+            (0.0, self.config.angle_gain * angle_error)
+        };
+
+        let deg_per_sec = clamp_abs(deg_per_sec, self.config.max_deg_per_sec);
+        let (left, right) = velocity_to_wheel_speeds(mm_per_sec, deg_per_sec);
+        let (left, right) = clamp_wheel_speeds(left, right);
+
+        // raise tiny commands out of the dead zone keeping the curvature
+        let min_mm_per_sec = speed_to_mm_per_sec(MOTOR_SPEED_MIN);
+        let fastest = left.abs().max(right.abs());
+        let (left, right) = if fastest > 0.0 && fastest < min_mm_per_sec {
+            let ratio = min_mm_per_sec / fastest;
+            (left * ratio, right * ratio)
+        } else {
+            (left, right)
+        };
+        let control =
+            MotorControl::from_mm_per_sec(left, right).with_duration(self.config.command_duration);
+        debug!(
+            "go to pose: pose {:?} distance {:.1} -> {:?}",
+            pose, distance, control
+        );
+        ControllerStatus::Moving(control)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: time::Duration = time::Duration::from_millis(50);
+
+    // simple simulated cube: applies the wheel speeds of the last command
+    fn run(
+        controller: &mut GoToPoseController,
+        mut pose: Pose,
+        ticks: usize,
+    ) -> (Pose, ControllerStatus) {
+        let mut now = time::Instant::now();
+        let mut status = ControllerStatus::PoseLost;
+        for _ in 0..ticks {
+            controller.update_pose(pose, now);
+            status = controller.update(now);
+            let (left, right) = status.get_motor_control().get_mm_per_sec();
+            pose = pose.advance(left, right, TICK, MM_PER_UNIT);
+            now += TICK;
+            if status != ControllerStatus::PoseLost
+                && !matches!(status, ControllerStatus::Moving(_))
+            {
+                break;
+            }
+        }
+        (pose, status)
+    }
+
+    #[test]
+    fn arrive_at_target() {
+        let config = GoToPoseConfig::default();
+        let target = Target::new(300.0, 200.0, Some(180.0));
+        let mut controller = GoToPoseController::new(config, target);
+        let (pose, status) = run(&mut controller, Pose::new(100.0, 100.0, 0.0), 400);
+        assert_eq!(status, ControllerStatus::Arrived);
+        assert!(pose.distance(&Pose::new(300.0, 200.0, 0.0)) < config.position_tolerance + 2.0);
+        assert!(
+            pose.angle.difference(Angle::from_degrees(180.0)).abs() < config.angle_tolerance + 5.0
+        );
+    }
+
+    #[test]
+    fn target_behind_the_cube() {
+        let mut controller =
+            GoToPoseController::new(GoToPoseConfig::default(), Target::new(100.0, 250.0, None));
+        let (pose, status) = run(&mut controller, Pose::new(250.0, 250.0, 0.0), 400);
+        assert_eq!(status, ControllerStatus::Arrived);
+        assert!(pose.distance(&Pose::new(100.0, 250.0, 0.0)) < 10.0);
+    }
+
+    #[test]
+    fn timeout_and_pose_lost() {
+        let config = GoToPoseConfig {
+            timeout: time::Duration::from_millis(100),
+            ..GoToPoseConfig::default()
+        };
+        let mut controller = GoToPoseController::new(config, Target::new(400.0, 400.0, None));
+        let now = time::Instant::now();
+        assert_eq!(controller.update(now), ControllerStatus::PoseLost);
+        controller.update_pose(Pose::new(100.0, 100.0, 0.0), now);
+        assert!(matches!(
+            controller.update(now),
+            ControllerStatus::Moving(_)
+        ));
+        assert_eq!(
+            controller.update(now + time::Duration::from_millis(200)),
+            ControllerStatus::Timeout
+        );
+    }
+
+    #[test]
+    fn moving_target() {
+        let config = GoToPoseConfig {
+            mode: ControlMode::MovingTarget,
+            ..GoToPoseConfig::default()
+        };
+        let mut controller = GoToPoseController::new(config, Target::new(200.0, 200.0, None));
+        let mut pose = Pose::new(100.0, 100.0, 0.0);
+        let mut now = time::Instant::now();
+        for i in 0..600 {
+            // the target moves along the x axis
+            let x = 200.0 + (i as f32 * 0.1).min(40.0);
+            controller.set_target(Target::new(x, 200.0, None));
+            controller.update_pose(pose, now);
+            let status = controller.update(now);
+            assert!(matches!(status, ControllerStatus::Moving(_)));
+            let (left, right) = status.get_motor_control().get_mm_per_sec();
+            pose = pose.advance(left, right, TICK, MM_PER_UNIT);
+            now += TICK;
+        }
+        assert!(pose.distance(&Pose::new(240.0, 200.0, 0.0)) < 10.0);
+    }
+}
@@ -0,0 +1,200 @@
+/* Rotation dial: yaw rotation of the cube to relative steps */
+
+use serde::Deserialize;
+use std::time;
+
+use crate::motor::MotorControl;
+use crate::units::Angle;
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DialConfig {
+    /// Rotation of a step [deg]
+    pub detent: f32,
+    /// Rotation ignored from the rest position [deg]
+    pub dead_zone: f32,
+    /// Step multiplier added per 360 deg/s over `acceleration_speed` (0: no acceleration)
+    pub acceleration: f32,
+    /// Rotation speed where the acceleration starts [deg/s]
+    pub acceleration_speed: f32,
+    /// Time without steps until the dial rests (the yaw drift is absorbed)
+    #[serde(deserialize_with = "deserialize_millis")]
+    pub rest_time: time::Duration,
+    /// Motor pulse at each step
+    pub feedback: bool,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        Self {
+            detent: 15.0,
+            dead_zone: 5.0,
+            acceleration: 0.0,
+            acceleration_speed: 180.0,
+            rest_time: time::Duration::from_millis(1000),
+            feedback: false,
+        }
+    }
+}
+
+fn deserialize_millis<'de, D>(deserializer: D) -> std::result::Result<time::Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(time::Duration::from_millis)
+}
+
+impl DialConfig {
+    pub fn check(&self) -> std::result::Result<(), String> {
+        if !(self.detent > 0.0 && self.detent <= 180.0) {
+            return Err(format!("detent {} must be in (0, 180]", self.detent));
+        }
+        if !(self.dead_zone >= 0.0 && self.dead_zone < 180.0) {
+            return Err(format!("dead_zone {} must be in [0, 180)", self.dead_zone));
+        }
+        if !(self.acceleration >= 0.0 && self.acceleration_speed > 0.0) {
+            return Err("acceleration must be 0 or more".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub struct Dial {
+    config: DialConfig,
+    /// Yaw of the last step (None: no yaw yet)
+    origin: Option<f32>,
+    resting: bool,
+    last_step: Option<time::Instant>,
+    last_yaw: Option<(f32, time::Instant)>,
+}
+
+impl Dial {
+    pub fn new(config: DialConfig) -> Self {
+        Self {
+            config,
+            origin: None,
+            resting: true,
+            last_step: None,
+            last_yaw: None,
+        }
+    }
+
+    pub fn get_config(&self) -> &DialConfig {
+        &self.config
+    }
+
+    /// Use the next yaw as the rest position
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// Feed a yaw [deg] and get the steps (positive: yaw increasing)
+    pub fn update(&mut self, yaw: f32, now: time::Instant) -> i32 {
+        let speed = match self.last_yaw {
+            Some((last, time)) if now > time => {
+                Angle::from_degrees(last)
+                    .difference(Angle::from_degrees(yaw))
+                    .abs()
+                    / now.duration_since(time).as_secs_f32()
+            }
+            _ => 0.0,
+        };
+        self.last_yaw = Some((yaw, now));
+
+        let origin = match self.origin {
+            Some(x) => x,
+            None => {
+                self.origin = Some(yaw);
+                return 0;
+            }
+        };
+        let rest_time = self.config.rest_time;
+        if matches!(self.last_step, Some(x) if now.saturating_duration_since(x) >= rest_time) {
+            // rest at the current detent
+            self.resting = true;
+        }
+
+        let diff = Angle::from_degrees(origin).difference(Angle::from_degrees(yaw));
+        let dead_zone = if self.resting {
+            self.config.dead_zone
+        } else {
+            0.0
+        };
+        if diff.abs() < self.config.detent + dead_zone {
+            if self.resting && diff.abs() < dead_zone {
+                // absorb the drift of the yaw
+                self.origin = Some(yaw);
+            }
+            return 0;
+        }
+
+        let steps = (diff / self.config.detent).trunc();
+        self.origin = Some((origin + steps * self.config.detent) % 360.0);
+        self.resting = false;
+        self.last_step = Some(now);
+
+        let over_speed = speed - self.config.acceleration_speed;
+        let factor = if self.config.acceleration > 0.0 && over_speed > 0.0 {
+            1.0 + self.config.acceleration * over_speed / 360.0
+        } else {
+            1.0
+        };
+        (steps * factor).round() as i32
+    }
+}
+
+/// Short motor pulse for the step feedback (forward / backward by the direction)
+pub fn get_dial_pulse_bytes(steps: i32) -> Vec<u8> {
+    let speed = if steps < 0 { -20 } else { 20 };
+    MotorControl::new(speed, speed)
+        .with_duration(time::Duration::from_millis(20))
+        .get_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dial_steps() {
+        let start = time::Instant::now();
+        let at = |ms: u64| start + time::Duration::from_millis(ms);
+        let mut dial = Dial::new(DialConfig::default());
+
+        assert_eq!(dial.update(350.0, at(0)), 0);
+        // drift in the dead zone
+        assert_eq!(dial.update(353.0, at(1000)), 0);
+        assert_eq!(dial.update(356.0, at(2000)), 0);
+        // detent + dead zone from the rest position (across 0 deg)
+        assert_eq!(dial.update(14.0, at(3000)), 0);
+        assert_eq!(dial.update(17.0, at(4000)), 1);
+        // the next step needs only the detent
+        assert_eq!(dial.update(32.0, at(4100)), 1);
+        assert_eq!(dial.update(10.0, at(4200)), -1);
+        assert_eq!(dial.update(330.0, at(4600)), -2);
+
+        let config = DialConfig {
+            acceleration: 1.0,
+            ..DialConfig::default()
+        };
+        let mut dial = Dial::new(config);
+        dial.update(0.0, at(0));
+        // 60 deg in 100ms: 600 deg/s (4 steps x 2.17)
+        assert_eq!(dial.update(60.0, at(100)), 9);
+        assert_eq!(dial.update(75.0, at(1000)), 1);
+        assert!(DialConfig {
+            detent: 0.0,
+            ..DialConfig::default()
+        }
+        .check()
+        .is_err());
+    }
+
+    #[test]
+    fn dial_pulse() {
+        assert_eq!(
+            get_dial_pulse_bytes(-2),
+            vec![0x02, 0x01, 0x02, 20, 0x02, 0x02, 20, 2]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+/* Button gesture recognizer: clicks, long press and hold from timestamped button events */
+
+use std::time;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ButtonGesture {
+    Click,
+    DoubleClick,
+    TripleClick,
+    /// The button is held for long_press_time (notified while the button is held)
+    LongPress,
+    /// Repeated every repeat_interval after the long press (count from 1)
+    HoldRepeat(u32),
+    /// The button is released after the long press (with the time it was held)
+    HoldRelease(time::Duration),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GestureConfig {
+    /// Maximum time from a release to the next press of a multiple click
+    pub multi_click_time: time::Duration,
+    pub long_press_time: time::Duration,
+    /// Interval of HoldRepeat (None: no repeat)
+    pub repeat_interval: Option<time::Duration>,
+    /// Clicks notified without waiting for the next press (1 - 3)
+    pub max_clicks: u32,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            multi_click_time: time::Duration::from_millis(400),
+            long_press_time: time::Duration::from_millis(1500),
+            repeat_interval: None,
+            max_clicks: 3,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum GestureState {
+    Idle,
+    /// Pressed after `clicks` clicks
+    Pressed {
+        since: time::Instant,
+        clicks: u32,
+    },
+    /// Released after `clicks` clicks
+    Released {
+        since: time::Instant,
+        clicks: u32,
+    },
+    Holding {
+        since: time::Instant,
+        repeats: u32,
+        next_repeat: Option<time::Instant>,
+    },
+}
+
+fn get_click_gesture(clicks: u32) -> Option<ButtonGesture> {
+    match clicks {
+        0 => None,
+        1 => Some(ButtonGesture::Click),
+        2 => Some(ButtonGesture::DoubleClick),
+        _ => Some(ButtonGesture::TripleClick),
+    }
+}
+
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    state: GestureState,
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        Self {
+            config: GestureConfig {
+                max_clicks: config.max_clicks.clamp(1, 3),
+                ..config
+            },
+            state: GestureState::Idle,
+        }
+    }
+
+    pub fn get_config(&self) -> &GestureConfig {
+        &self.config
+    }
+
+    pub fn reset(&mut self) {
+        self.state = GestureState::Idle;
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        matches!(
+            self.state,
+            GestureState::Pressed { .. } | GestureState::Holding { .. }
+        )
+    }
+
+    /// Gestures detected by the time (call periodically to detect the long press,
+    /// the hold repeat and the end of the clicks)
+    pub fn poll(&mut self, now: time::Instant) -> Vec<ButtonGesture> {
+        let mut gestures: Vec<ButtonGesture> = Vec::new();
+        match self.state {
+            GestureState::Pressed { since, clicks }
+                if now.saturating_duration_since(since) >= self.config.long_press_time =>
+            {
+                // clicks before the long press
+                gestures.extend(get_click_gesture(clicks));
+                gestures.push(ButtonGesture::LongPress);
+                let next_repeat = self
+                    .config
+                    .repeat_interval
+                    .map(|x| since + self.config.long_press_time + x);
+                self.state = GestureState::Holding {
+                    since,
+                    repeats: 0,
+                    next_repeat,
+                };
+                gestures.extend(self.poll(now));
+            }
+            GestureState::Released { since, clicks }
+                if now.saturating_duration_since(since) > self.config.multi_click_time =>
+            {
+                gestures.extend(get_click_gesture(clicks));
+                self.state = GestureState::Idle;
+            }
+            GestureState::Holding {
+                since,
+                mut repeats,
+                next_repeat: Some(mut next),
+            } => {
+                let interval = self.config.repeat_interval.unwrap_or_default();
+                while now >= next && interval > time::Duration::ZERO {
+                    repeats += 1;
+                    gestures.push(ButtonGesture::HoldRepeat(repeats));
+                    next += interval;
+                }
+                self.state = GestureState::Holding {
+                    since,
+                    repeats,
+                    next_repeat: Some(next),
+                };
+            }
+            _ => (),
+        }
+        gestures
+    }
+
+    /// Feed a button event. Repeated presses / releases are ignored.
+    pub fn update(&mut self, pressed: bool, time: time::Instant) -> Vec<ButtonGesture> {
+        let mut gestures = self.poll(time);
+        match (self.state, pressed) {
+            (GestureState::Idle, true) => {
+                self.state = GestureState::Pressed {
+                    since: time,
+                    clicks: 0,
+                }
+            }
+            (GestureState::Released { clicks, .. }, true) => {
+                self.state = GestureState::Pressed {
+                    since: time,
+                    clicks,
+                }
+            }
+            (GestureState::Pressed { clicks, .. }, false) => {
+                let clicks = clicks + 1;
+                if clicks >= self.config.max_clicks {
+                    gestures.extend(get_click_gesture(clicks));
+                    self.state = GestureState::Idle;
+                } else {
+                    self.state = GestureState::Released {
+                        since: time,
+                        clicks,
+                    };
+                }
+            }
+            (GestureState::Holding { since, .. }, false) => {
+                gestures.push(ButtonGesture::HoldRelease(
+                    time.saturating_duration_since(since),
+                ));
+                self.state = GestureState::Idle;
+            }
+            _ => (),
+        }
+        gestures
+    }
+
+    /// Feed a button information notification
+    pub fn update_data(&mut self, data: &[u8], time: time::Instant) -> Vec<ButtonGesture> {
+        match data {
+            [0x01, 0x80, ..] => self.update(true, time),
+            [0x01, 0x00, ..] => self.update(false, time),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Timeline {
+        start: time::Instant,
+        recognizer: GestureRecognizer,
+    }
+
+    impl Timeline {
+        fn new(config: GestureConfig) -> Self {
+            Self {
+                start: time::Instant::now(),
+                recognizer: GestureRecognizer::new(config),
+            }
+        }
+
+        fn at(&self, ms: u64) -> time::Instant {
+            self.start + time::Duration::from_millis(ms)
+        }
+
+        // feed (ms, pressed) events and poll until `end_ms`
+        fn run(&mut self, events: &[(u64, bool)], end_ms: u64) -> Vec<(u64, ButtonGesture)> {
+            let mut gestures: Vec<(u64, ButtonGesture)> = Vec::new();
+            let mut events = events.iter().peekable();
+            for ms in (0..=end_ms).step_by(10) {
+                while let Some((time, pressed)) = events.peek() {
+                    if *time > ms {
+                        break;
+                    }
+                    let now = self.at(*time);
+                    let detected = self.recognizer.update(*pressed, now);
+                    gestures.extend(detected.into_iter().map(|x| (*time, x)));
+                    events.next();
+                }
+                let detected = self.recognizer.poll(self.at(ms));
+                gestures.extend(detected.into_iter().map(|x| (ms, x)));
+            }
+            gestures
+        }
+    }
+
+    #[test]
+    fn clicks() {
+        let mut timeline = Timeline::new(GestureConfig::default());
+        assert_eq!(
+            timeline.run(&[(0, true), (100, false)], 1000),
+            vec![(510, ButtonGesture::Click)]
+        );
+
+        // fast double click
+        let mut timeline = Timeline::new(GestureConfig::default());
+        assert_eq!(
+            timeline.run(&[(0, true), (40, false), (80, true), (120, false)], 1000),
+            vec![(530, ButtonGesture::DoubleClick)]
+        );
+
+        // triple click is notified at the last release
+        let mut timeline = Timeline::new(GestureConfig::default());
+        assert_eq!(
+            timeline.run(
+                &[
+                    (0, true),
+                    (50, false),
+                    (300, true),
+                    (350, false),
+                    (600, true),
+                    (650, false)
+                ],
+                1500
+            ),
+            vec![(650, ButtonGesture::TripleClick)]
+        );
+
+        // too slow to be a double click
+        let mut timeline = Timeline::new(GestureConfig::default());
+        assert_eq!(
+            timeline.run(&[(0, true), (50, false), (500, true), (550, false)], 1500),
+            vec![(460, ButtonGesture::Click), (960, ButtonGesture::Click)]
+        );
+
+        // double click without waiting
+        let mut timeline = Timeline::new(GestureConfig {
+            max_clicks: 2,
+            ..GestureConfig::default()
+        });
+        assert_eq!(
+            timeline.run(&[(0, true), (50, false), (100, true), (150, false)], 1000),
+            vec![(150, ButtonGesture::DoubleClick)]
+        );
+    }
+
+    #[test]
+    fn long_press_and_hold() {
+        let mut timeline = Timeline::new(GestureConfig::default());
+        assert_eq!(
+            timeline.run(&[(0, true), (2000, false)], 3000),
+            vec![
+                (1500, ButtonGesture::LongPress),
+                (
+                    2000,
+                    ButtonGesture::HoldRelease(time::Duration::from_millis(2000))
+                )
+            ]
+        );
+
+        let mut timeline = Timeline::new(GestureConfig {
+            repeat_interval: Some(time::Duration::from_millis(200)),
+            ..GestureConfig::default()
+        });
+        assert_eq!(
+            timeline.run(&[(0, true), (2050, false)], 3000),
+            vec![
+                (1500, ButtonGesture::LongPress),
+                (1700, ButtonGesture::HoldRepeat(1)),
+                (1900, ButtonGesture::HoldRepeat(2)),
+                (
+                    2050,
+                    ButtonGesture::HoldRelease(time::Duration::from_millis(2050))
+                )
+            ]
+        );
+
+        // click then long press
+        let mut timeline = Timeline::new(GestureConfig::default());
+        assert_eq!(
+            timeline.run(&[(0, true), (50, false), (200, true), (1800, false)], 2000),
+            vec![
+                (1700, ButtonGesture::Click),
+                (1700, ButtonGesture::LongPress),
+                (
+                    1800,
+                    ButtonGesture::HoldRelease(time::Duration::from_millis(1600))
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn events_without_polling() {
+        let start = time::Instant::now();
+        let ms = |x: u64| start + time::Duration::from_millis(x);
+        let mut recognizer = GestureRecognizer::new(GestureConfig::default());
+        // the first release (pressed before the start) and repeated events are ignored
+        assert!(recognizer.update_data(&[0x01, 0x00], ms(0)).is_empty());
+        assert!(recognizer.update_data(&[0x01, 0x80], ms(100)).is_empty());
+        assert!(recognizer.update_data(&[0x01, 0x80], ms(120)).is_empty());
+        assert!(recognizer.is_pressed());
+        assert!(recognizer.update_data(&[0x01, 0x00], ms(200)).is_empty());
+        // the timeouts are detected by the next event
+        assert_eq!(
+            recognizer.update_data(&[0x01, 0x80], ms(1000)),
+            vec![ButtonGesture::Click]
+        );
+        assert_eq!(
+            recognizer.update_data(&[0x01, 0x00], ms(3000)),
+            vec![
+                ButtonGesture::LongPress,
+                ButtonGesture::HoldRelease(time::Duration::from_millis(2000))
+            ]
+        );
+        assert!(recognizer.update_data(&[0x02], ms(3100)).is_empty());
+        assert!(recognizer.poll(ms(5000)).is_empty());
+    }
+}
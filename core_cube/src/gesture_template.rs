@@ -0,0 +1,527 @@
+/* Gesture templates: record cube motions by example and match them with DTW */
+
+use serde::{Deserialize, Serialize};
+use std::time;
+
+use crate::sensor::PostureAngle;
+use crate::units::Angle;
+
+/// A sample of a motion: roll, pitch, yaw from the start [deg] and the shake level (scaled)
+pub type MotionFeature = [f32; 4];
+
+// a shake level counts as this angle [deg]
+const SHAKE_SCALE: f32 = 10.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TemplateConfig {
+    /// Angle change between two samples where the cube is moving [deg]
+    pub move_threshold: f32,
+    /// Time without moving at the end of a gesture
+    pub rest_time: time::Duration,
+    pub min_time: time::Duration,
+    pub max_time: time::Duration,
+    /// Confidence of a match (0.0 - 1.0)
+    pub min_confidence: f32,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        Self {
+            move_threshold: 2.0,
+            rest_time: time::Duration::from_millis(300),
+            min_time: time::Duration::from_millis(200),
+            max_time: time::Duration::from_secs(5),
+            min_confidence: 0.5,
+        }
+    }
+}
+
+/// Split the posture angle stream into gestures (moves between rests)
+pub struct MotionSegmenter {
+    config: TemplateConfig,
+    last: Option<PostureAngle>,
+    /// (time, angle, shake) from the start of the move
+    samples: Vec<(time::Instant, PostureAngle, u8)>,
+    last_move: Option<time::Instant>,
+}
+
+impl MotionSegmenter {
+    pub fn new(config: TemplateConfig) -> Self {
+        Self {
+            config,
+            last: None,
+            samples: Vec::new(),
+            last_move: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.last_move.is_some()
+    }
+
+    /// Feed a posture angle with the shake level and get the features of an ended gesture
+    pub fn update(
+        &mut self,
+        angle: &PostureAngle,
+        shake: u8,
+        time: time::Instant,
+    ) -> Option<Vec<MotionFeature>> {
+        let last = self.last.replace(*angle);
+        let change = last.map_or(0.0, |x| {
+            [
+                (x.roll, angle.roll),
+                (x.pitch, angle.pitch),
+                (x.yaw, angle.yaw),
+            ]
+            .iter()
+            .map(|(from, to)| {
+                Angle::from_degrees(*from as f32).difference(Angle::from_degrees(*to as f32))
+            })
+            .fold(0.0f32, |a, b| a.max(b.abs()))
+        });
+        let moving = change >= self.config.move_threshold || shake > 0;
+
+        if self.last_move.is_none() {
+            if !moving {
+                return None;
+            }
+            // the gesture starts at the last rest
+            if let Some(last) = last {
+                self.samples.push((time, last, 0));
+            }
+        }
+        if moving {
+            self.samples.push((time, *angle, shake));
+            self.last_move = Some(time);
+        }
+        self.poll(time)
+    }
+
+    /// Get the features of a gesture ended by the rest time without samples
+    /// (the posture angle is notified only on changes: a resting cube sends nothing)
+    pub fn poll(&mut self, now: time::Instant) -> Option<Vec<MotionFeature>> {
+        let last_move = self.last_move?;
+        let since = self.samples[0].0;
+        if now.saturating_duration_since(since) > self.config.max_time {
+            // too long to be a gesture
+            self.samples.clear();
+            self.last_move = None;
+            return None;
+        }
+        if now.saturating_duration_since(last_move) < self.config.rest_time {
+            return None;
+        }
+        let samples = std::mem::take(&mut self.samples);
+        self.last_move = None;
+        if last_move.saturating_duration_since(since) < self.config.min_time {
+            return None;
+        }
+        Some(get_features(&samples))
+    }
+}
+
+fn get_features(samples: &[(time::Instant, PostureAngle, u8)]) -> Vec<MotionFeature> {
+    let (_, origin, _) = samples[0];
+    samples
+        .iter()
+        .map(|(_, angle, shake)| {
+            let diff = |from: i16, to: i16| {
+                let x = Angle::from_degrees(from as f32).difference(Angle::from_degrees(to as f32));
+                (x * 10.0).round() / 10.0
+            };
+            [
+                diff(origin.roll, angle.roll),
+                diff(origin.pitch, angle.pitch),
+                diff(origin.yaw, angle.yaw),
+                *shake as f32 * SHAKE_SCALE,
+            ]
+        })
+        .collect()
+}
+
+/// Dynamic time warping distance normalized by the lengths (average distance of a sample [deg])
+pub fn dtw_distance(a: &[MotionFeature], b: &[MotionFeature]) -> f32 {
+    if a.is_empty() || b.is_empty() {
+        return f32::INFINITY;
+    }
+    let cost = |x: &MotionFeature, y: &MotionFeature| {
+        x.iter()
+            .zip(y.iter())
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f32>()
+            .sqrt()
+    };
+    // two rows of the cost matrix
+    let mut last = vec![f32::INFINITY; b.len() + 1];
+    let mut row = vec![f32::INFINITY; b.len() + 1];
+    last[0] = 0.0;
+    for x in a {
+        row[0] = f32::INFINITY;
+        for (j, y) in b.iter().enumerate() {
+            row[j + 1] = cost(x, y) + last[j].min(last[j + 1]).min(row[j]);
+        }
+        std::mem::swap(&mut last, &mut row);
+    }
+    last[b.len()] * 2.0 / (a.len() + b.len()) as f32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GestureTemplate {
+    pub name: String,
+    /// DTW distance where the confidence is 0
+    pub tolerance: f32,
+    pub examples: Vec<Vec<MotionFeature>>,
+}
+
+impl GestureTemplate {
+    /// Template from the examples (the tolerance is from the differences of the examples)
+    pub fn new(name: &str, examples: Vec<Vec<MotionFeature>>) -> Self {
+        let mut spread: f32 = 0.0;
+        for (i, a) in examples.iter().enumerate() {
+            for b in &examples[(i + 1)..] {
+                spread = spread.max(dtw_distance(a, b));
+            }
+        }
+        Self {
+            name: name.to_string(),
+            tolerance: (spread * 2.0).max(10.0),
+            examples,
+        }
+    }
+
+    /// Confidence (0.0 - 1.0) of the gesture with the nearest example
+    pub fn get_confidence(&self, gesture: &[MotionFeature]) -> f32 {
+        let distance = self
+            .examples
+            .iter()
+            .map(|x| dtw_distance(x, gesture))
+            .fold(f32::INFINITY, f32::min);
+        (1.0 - distance / self.tolerance).max(0.0)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateFile {
+    #[serde(default)]
+    template: Vec<GestureTemplate>,
+}
+
+/// Load templates from TOML text
+pub fn load_templates(text: &str) -> std::result::Result<Vec<GestureTemplate>, String> {
+    let file: TemplateFile = toml::from_str(text).map_err(|e| e.to_string())?;
+    for (i, template) in file.template.iter().enumerate() {
+        if template.examples.iter().any(|x| x.is_empty()) || template.examples.is_empty() {
+            return Err(format!("template[{}]: no examples", i));
+        }
+        if !(template.tolerance > 0.0 && template.tolerance.is_finite()) {
+            return Err(format!("template[{}].tolerance: must be more than 0", i));
+        }
+    }
+    Ok(file.template)
+}
+
+pub fn save_templates(templates: &[GestureTemplate]) -> std::result::Result<String, String> {
+    let file = TemplateFile {
+        template: templates.to_vec(),
+    };
+    toml::to_string(&file).map_err(|e| e.to_string())
+}
+
+/// Add the template (replaces the template of the same name)
+pub fn add_template(templates: &mut Vec<GestureTemplate>, template: GestureTemplate) {
+    match templates.iter_mut().find(|x| x.name == template.name) {
+        Some(x) => *x = template,
+        None => templates.push(template),
+    }
+}
+
+/// Record the examples of a template (each gesture between rests is an example)
+pub struct TemplateRecorder {
+    name: String,
+    count: usize,
+    segmenter: MotionSegmenter,
+    examples: Vec<Vec<MotionFeature>>,
+}
+
+impl TemplateRecorder {
+    pub fn new(name: &str, count: usize, config: TemplateConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            count: count.max(1),
+            segmenter: MotionSegmenter::new(config),
+            examples: Vec::new(),
+        }
+    }
+
+    /// (recorded examples, required examples)
+    pub fn get_count(&self) -> (usize, usize) {
+        (self.examples.len(), self.count)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.examples.len() >= self.count
+    }
+
+    /// Feed a posture angle with the shake level (true: an example is recorded)
+    pub fn update(&mut self, angle: &PostureAngle, shake: u8, time: time::Instant) -> bool {
+        if self.is_done() {
+            return false;
+        }
+        match self.segmenter.update(angle, shake, time) {
+            Some(example) => {
+                self.examples.push(example);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// End the gesture after the rest time without samples (true: an example is recorded)
+    pub fn poll(&mut self, now: time::Instant) -> bool {
+        if self.is_done() {
+            return false;
+        }
+        match self.segmenter.poll(now) {
+            Some(example) => {
+                self.examples.push(example);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The template of the examples (None: not done)
+    pub fn get_template(&self) -> Option<GestureTemplate> {
+        if !self.is_done() {
+            return None;
+        }
+        Some(GestureTemplate::new(&self.name, self.examples.clone()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateMatch {
+    pub name: String,
+    pub confidence: f32,
+}
+
+/// Match the gestures in the posture angle stream with the templates
+pub struct TemplateRecognizer {
+    config: TemplateConfig,
+    segmenter: MotionSegmenter,
+    templates: Vec<GestureTemplate>,
+}
+
+impl TemplateRecognizer {
+    pub fn new(config: TemplateConfig, templates: Vec<GestureTemplate>) -> Self {
+        Self {
+            config,
+            segmenter: MotionSegmenter::new(config),
+            templates,
+        }
+    }
+
+    pub fn get_templates(&self) -> &[GestureTemplate] {
+        &self.templates
+    }
+
+    /// The best template of the gesture (None: no template over min_confidence)
+    pub fn find_template(&self, gesture: &[MotionFeature]) -> Option<TemplateMatch> {
+        self.templates
+            .iter()
+            .map(|x| TemplateMatch {
+                name: x.name.clone(),
+                confidence: x.get_confidence(gesture),
+            })
+            .filter(|x| x.confidence >= self.config.min_confidence)
+            .max_by(|a, b| a.confidence.partial_cmp(&b.confidence).unwrap())
+    }
+
+    /// Feed a posture angle with the shake level and get the matched template of an ended gesture
+    pub fn update(
+        &mut self,
+        angle: &PostureAngle,
+        shake: u8,
+        time: time::Instant,
+    ) -> Option<TemplateMatch> {
+        let gesture = self.segmenter.update(angle, shake, time)?;
+        self.find_template(&gesture)
+    }
+
+    /// End the gesture after the rest time without samples and get the matched template
+    pub fn poll(&mut self, now: time::Instant) -> Option<TemplateMatch> {
+        let gesture = self.segmenter.poll(now)?;
+        self.find_template(&gesture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // posture angles every 50ms: rest, the moves, rest
+    fn feed(moves: &[(i16, i16, i16)]) -> Vec<Vec<MotionFeature>> {
+        let start = time::Instant::now();
+        let mut segmenter = MotionSegmenter::new(TemplateConfig::default());
+        let mut angles = vec![(0, 0, 0); 4];
+        angles.extend_from_slice(moves);
+        angles.extend(vec![*moves.last().unwrap(); 10]);
+        let mut gestures: Vec<Vec<MotionFeature>> = Vec::new();
+        for (i, (roll, pitch, yaw)) in angles.iter().enumerate() {
+            let angle = PostureAngle {
+                roll: *roll,
+                pitch: *pitch,
+                yaw: *yaw,
+            };
+            let time = start + time::Duration::from_millis(i as u64 * 50);
+            gestures.extend(segmenter.update(&angle, 0, time));
+        }
+        gestures
+    }
+
+    fn tilt(step: i16) -> Vec<(i16, i16, i16)> {
+        (1..=6).map(|i| (i * step, 0, 0)).collect()
+    }
+
+    fn turn(step: i16) -> Vec<(i16, i16, i16)> {
+        (1..=8).map(|i| (0, 0, i * step)).collect()
+    }
+
+    #[test]
+    fn segments() {
+        let gestures = feed(&tilt(5));
+        assert_eq!(gestures.len(), 1);
+        assert_eq!(gestures[0].len(), 7);
+        assert_eq!(gestures[0][0], [0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(gestures[0][6], [30.0, 0.0, 0.0, 0.0]);
+        // too short
+        assert!(feed(&[(10, 0, 0)]).is_empty());
+
+        // yaw across 180 deg
+        let gestures = feed(&[
+            (0, 0, 170),
+            (0, 0, -170),
+            (0, 0, -150),
+            (0, 0, -130),
+            (0, 0, -110),
+        ]);
+        assert_eq!(gestures[0].last().unwrap()[2], -110.0);
+    }
+
+    #[test]
+    fn ends_without_samples() {
+        // no posture angle notifications while the cube rests
+        let start = time::Instant::now();
+        let at = |ms: u64| start + time::Duration::from_millis(ms);
+        let angle = |yaw: i16| PostureAngle {
+            roll: 0,
+            pitch: 0,
+            yaw,
+        };
+        let mut segmenter = MotionSegmenter::new(TemplateConfig::default());
+        assert_eq!(segmenter.poll(at(0)), None);
+        for i in 0..=8 {
+            assert_eq!(segmenter.update(&angle(i * 10), 0, at(i as u64 * 50)), None);
+        }
+        assert!(segmenter.is_moving());
+        assert_eq!(segmenter.poll(at(500)), None);
+        let gesture = segmenter.poll(at(700)).unwrap();
+        assert_eq!(gesture.len(), 9);
+        assert_eq!(gesture[8], [0.0, 0.0, 80.0, 0.0]);
+        assert!(!segmenter.is_moving());
+        assert_eq!(segmenter.poll(at(1000)), None);
+
+        let template = GestureTemplate::new("turn", vec![gesture]);
+        let mut recognizer = TemplateRecognizer::new(TemplateConfig::default(), vec![template]);
+        let mut recorder = TemplateRecorder::new("turn", 1, TemplateConfig::default());
+        for i in 0..=8 {
+            assert_eq!(
+                recognizer.update(&angle(i * 10), 0, at(i as u64 * 50)),
+                None
+            );
+            assert!(!recorder.update(&angle(i * 10), 0, at(i as u64 * 50)));
+        }
+        assert_eq!(recognizer.poll(at(500)), None);
+        assert!(!recorder.poll(at(500)));
+        assert_eq!(
+            recognizer.poll(at(700)).map(|x| x.name),
+            Some("turn".to_string())
+        );
+        assert!(recorder.poll(at(700)));
+        assert!(recorder.get_template().is_some());
+    }
+
+    #[test]
+    fn templates() {
+        let record = |moves: &[(i16, i16, i16)]| feed(moves).remove(0);
+        let tilt_template = GestureTemplate::new("tilt", vec![record(&tilt(4)), record(&tilt(8))]);
+        let turn_template = GestureTemplate::new("turn", vec![record(&turn(10))]);
+        assert_eq!(turn_template.tolerance, 10.0);
+        // twice the difference of the examples
+        assert!(tilt_template.tolerance > 10.0);
+
+        let text = save_templates(&[tilt_template, turn_template]).unwrap();
+        let templates = load_templates(&text).unwrap();
+        assert_eq!(templates[0].examples.len(), 2);
+        let mut recognizer = TemplateRecognizer::new(TemplateConfig::default(), templates);
+
+        // slower and smaller tilt
+        let gesture = record(&[
+            (3, 0, 0),
+            (8, 0, 0),
+            (12, 0, 0),
+            (17, 0, 0),
+            (21, 0, 0),
+            (25, 0, 0),
+            (28, 0, 0),
+            (30, 0, 0),
+        ]);
+        let matched = recognizer.find_template(&gesture).unwrap();
+        assert_eq!(matched.name, "tilt");
+        assert!(matched.confidence > 0.5);
+        // turned to the other side
+        assert_eq!(recognizer.find_template(&record(&turn(-10))), None);
+
+        let start = time::Instant::now();
+        let angle = |yaw: i16| PostureAngle {
+            roll: 0,
+            pitch: 0,
+            yaw,
+        };
+        let at = |ms: u64| start + time::Duration::from_millis(ms);
+        let mut matched = None;
+        for i in 0..20 {
+            let yaw = (i as i16 - 2).clamp(0, 8) * 10;
+            matched = matched.or(recognizer.update(&angle(yaw), 0, at(i * 50)));
+        }
+        assert_eq!(matched.map(|x| x.name), Some("turn".to_string()));
+        assert_eq!(dtw_distance(&[[0.0; 4]], &[]), f32::INFINITY);
+
+        // two examples recorded from the stream
+        let mut recorder = TemplateRecorder::new("turn", 2, TemplateConfig::default());
+        let mut recorded = 0;
+        for i in 0..40 {
+            let yaw = (i as i16 % 20 - 2).clamp(0, 8) * 10;
+            recorded += recorder.update(&angle(yaw), 0, at(i * 50)) as usize;
+            if recorded < 2 {
+                assert!(recorder.get_template().is_none());
+            }
+        }
+        assert_eq!(recorder.get_count(), (2, 2));
+        let mut templates = recognizer.get_templates().to_vec();
+        add_template(&mut templates, recorder.get_template().unwrap());
+        assert_eq!(templates.len(), 2);
+        assert_eq!(templates[1].examples.len(), 2);
+        assert!(
+            load_templates("[[template]]\nname = \"x\"\ntolerance = 1.0\nexamples = []\n")
+                .unwrap_err()
+                .starts_with("template[0]: no examples")
+        );
+    }
+}
@@ -0,0 +1,74 @@
+/* ID information (Position ID / Standard ID) notification decoder */
+
+use log::debug;
+
+/// Position ID notification (0x01)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PositionId {
+    pub cube_x: u16,
+    pub cube_y: u16,
+    pub cube_angle: u16,
+    pub sensor_x: u16,
+    pub sensor_y: u16,
+    pub sensor_angle: u16,
+}
+
+/// Standard ID notification (0x02)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StandardId {
+    pub value: u32,
+    pub angle: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IdInfo {
+    PositionId(PositionId),
+    StandardId(StandardId),
+    PositionIdMissed,
+    StandardIdMissed,
+    Unknown,
+}
+
+fn get_u16(data: &[u8], index: usize) -> u16 {
+    (data[index + 1] as u16) << 8 | data[index] as u16
+}
+
+fn get_u32(data: &[u8], index: usize) -> u32 {
+    (get_u16(data, index + 2) as u32) << 16 | get_u16(data, index) as u32
+}
+
+pub fn get_id_info(data: &[u8]) -> IdInfo {
+    if data.is_empty() {
+        return IdInfo::Unknown;
+    }
+    match data[0] {
+        0x01 if data.len() >= 13 => IdInfo::PositionId(PositionId {
+            cube_x: get_u16(data, 1),
+            cube_y: get_u16(data, 3),
+            cube_angle: get_u16(data, 5),
+            sensor_x: get_u16(data, 7),
+            sensor_y: get_u16(data, 9),
+            sensor_angle: get_u16(data, 11),
+        }),
+        0x02 if data.len() >= 7 => IdInfo::StandardId(StandardId {
+            value: get_u32(data, 1),
+            angle: get_u16(data, 5),
+        }),
+        0x03 => IdInfo::PositionIdMissed,
+        0x04 => IdInfo::StandardIdMissed,
+        _ => {
+            debug!("unknown id information {:?}", data);
+            IdInfo::Unknown
+        }
+    }
+}
+
+/// Position ID notification with the sensor at the cube position (for tests)
+#[cfg(test)]
+pub(crate) fn get_position_id_bytes(x: u16, y: u16, angle: u16) -> Vec<u8> {
+    let mut bytes = vec![0x01];
+    for value in [x, y, angle, x, y, angle].iter() {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
@@ -0,0 +1,250 @@
+/* Tilt joystick: roll / pitch of the cube to axis values and directions */
+
+use serde::Deserialize;
+
+use crate::sensor::PostureAngle;
+use crate::units::Angle;
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JoystickConfig {
+    /// Tilt ignored around the neutral pose [deg]
+    pub dead_zone: f32,
+    /// Tilt of the full axis value [deg]
+    pub max_angle: f32,
+    /// Exponent of the response curve (1: linear, 2: finer near the neutral pose)
+    pub curve: f32,
+    /// Axis value where a direction is pressed (released at the half of it)
+    pub threshold: f32,
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl Default for JoystickConfig {
+    fn default() -> Self {
+        Self {
+            dead_zone: 5.0,
+            max_angle: 30.0,
+            curve: 1.0,
+            threshold: 0.5,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+}
+
+impl JoystickConfig {
+    pub fn check(&self) -> std::result::Result<(), String> {
+        if !(self.max_angle > 0.0 && self.max_angle <= 90.0) {
+            return Err(format!("max_angle {} must be in (0, 90]", self.max_angle));
+        }
+        if !(self.dead_zone >= 0.0 && self.dead_zone < self.max_angle) {
+            return Err(format!(
+                "dead_zone {} must be in [0, max_angle)",
+                self.dead_zone
+            ));
+        }
+        if !(self.curve > 0.0 && self.curve <= 10.0) {
+            return Err(format!("curve {} must be in (0, 10]", self.curve));
+        }
+        if !(self.threshold > 0.0 && self.threshold <= 1.0) {
+            return Err(format!("threshold {} must be in (0, 1]", self.threshold));
+        }
+        Ok(())
+    }
+
+    /// Axis value in [-1.0, 1.0] of a tilt from the neutral pose [deg]
+    pub fn get_axis_value(&self, tilt: f32) -> f32 {
+        let tilt = Angle::from_degrees(0.0).difference(Angle::from_degrees(tilt));
+        if tilt.abs() < self.dead_zone {
+            return 0.0;
+        }
+        let value = ((tilt.abs() - self.dead_zone) / (self.max_angle - self.dead_zone)).min(1.0);
+        value.powf(self.curve).copysign(tilt)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StickDirection {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JoystickEvent {
+    Pressed(StickDirection),
+    Released(StickDirection),
+}
+
+/// x: roll (positive: right), y: pitch (positive: up)
+pub struct Joystick {
+    config: JoystickConfig,
+    /// Roll and pitch of the neutral pose (None: the next angle)
+    neutral: Option<(f32, f32)>,
+    axis: (f32, f32),
+    pressed: Vec<StickDirection>,
+}
+
+impl Joystick {
+    pub fn new(config: JoystickConfig) -> Self {
+        Self {
+            config,
+            neutral: None,
+            axis: (0.0, 0.0),
+            pressed: Vec::new(),
+        }
+    }
+
+    pub fn get_config(&self) -> &JoystickConfig {
+        &self.config
+    }
+
+    /// Use the next angle as the neutral pose
+    pub fn calibrate(&mut self) {
+        self.neutral = None;
+    }
+
+    pub fn set_neutral(&mut self, roll: f32, pitch: f32) {
+        self.neutral = Some((roll, pitch));
+    }
+
+    pub fn get_neutral(&self) -> Option<(f32, f32)> {
+        self.neutral
+    }
+
+    /// Axis values (x, y) in [-1.0, 1.0]
+    pub fn get_axis(&self) -> (f32, f32) {
+        self.axis
+    }
+
+    pub fn get_pressed(&self) -> &[StickDirection] {
+        &self.pressed
+    }
+
+    /// Feed a posture angle and get the pressed / released directions
+    pub fn update(&mut self, angle: &PostureAngle) -> Vec<JoystickEvent> {
+        let (roll, pitch) = (angle.roll as f32, angle.pitch as f32);
+        let (neutral_roll, neutral_pitch) = *self.neutral.get_or_insert((roll, pitch));
+        let mut x = self.config.get_axis_value(roll - neutral_roll);
+        let mut y = self.config.get_axis_value(pitch - neutral_pitch);
+        if self.config.invert_x {
+            x = -x;
+        }
+        if self.config.invert_y {
+            y = -y;
+        }
+        self.axis = (x, y);
+
+        let mut events: Vec<JoystickEvent> = Vec::new();
+        for (direction, value) in &[
+            (StickDirection::Left, -x),
+            (StickDirection::Right, x),
+            (StickDirection::Up, y),
+            (StickDirection::Down, -y),
+        ] {
+            // hysteresis against the jitter at the threshold
+            let pressed = self.pressed.contains(direction);
+            if !pressed && *value >= self.config.threshold {
+                self.pressed.push(*direction);
+                events.push(JoystickEvent::Pressed(*direction));
+            } else if pressed && *value < self.config.threshold / 2.0 {
+                self.pressed.retain(|x| x != direction);
+                events.push(JoystickEvent::Released(*direction));
+            }
+        }
+        events
+    }
+
+    /// Release all the directions (e.g. the joystick is stopped)
+    pub fn release_all(&mut self) -> Vec<JoystickEvent> {
+        self.axis = (0.0, 0.0);
+        self.pressed
+            .drain(..)
+            .map(JoystickEvent::Released)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn angle(roll: i16, pitch: i16) -> PostureAngle {
+        PostureAngle {
+            roll,
+            pitch,
+            yaw: 0,
+        }
+    }
+
+    #[test]
+    fn axis_values() {
+        let config = JoystickConfig::default();
+        assert_eq!(config.get_axis_value(4.0), 0.0);
+        assert_eq!(config.get_axis_value(17.5), 0.5);
+        assert_eq!(config.get_axis_value(-40.0), -1.0);
+        // across 180 deg
+        assert_eq!(config.get_axis_value(350.0), -0.2);
+        let config = JoystickConfig {
+            curve: 2.0,
+            ..config
+        };
+        assert_eq!(config.get_axis_value(-17.5), -0.25);
+        assert!(JoystickConfig {
+            dead_zone: 30.0,
+            ..JoystickConfig::default()
+        }
+        .check()
+        .is_err());
+    }
+
+    #[test]
+    fn joystick() {
+        let mut stick = Joystick::new(JoystickConfig::default());
+        // the first angle is the neutral pose
+        assert!(stick.update(&angle(10, -5)).is_empty());
+        assert_eq!(stick.get_neutral(), Some((10.0, -5.0)));
+
+        assert_eq!(
+            stick.update(&angle(40, 20)),
+            vec![
+                JoystickEvent::Pressed(StickDirection::Right),
+                JoystickEvent::Pressed(StickDirection::Up)
+            ]
+        );
+        assert_eq!(stick.get_axis(), (1.0, 0.8));
+        // hysteresis
+        assert_eq!(
+            stick.update(&angle(25, -5)),
+            vec![JoystickEvent::Released(StickDirection::Up)]
+        );
+        assert_eq!(stick.get_pressed(), &[StickDirection::Right]);
+        assert_eq!(
+            stick.update(&angle(20, -5)),
+            vec![JoystickEvent::Released(StickDirection::Right)]
+        );
+
+        stick.calibrate();
+        stick.update(&angle(0, 0));
+        assert_eq!(
+            stick.update(&angle(-30, 0)),
+            vec![JoystickEvent::Pressed(StickDirection::Left)]
+        );
+        assert_eq!(
+            stick.release_all(),
+            vec![JoystickEvent::Released(StickDirection::Left)]
+        );
+
+        let mut stick = Joystick::new(JoystickConfig {
+            invert_y: true,
+            ..JoystickConfig::default()
+        });
+        stick.update(&angle(0, 0));
+        assert_eq!(
+            stick.update(&angle(0, 30)),
+            vec![JoystickEvent::Pressed(StickDirection::Down)]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+/* Differential-drive kinematics of the core cube */
+
+use log::debug;
+use std::time;
+
+use crate::id_info::PositionId;
+use crate::motor::MotorControl;
+use crate::transport::{CoreCubeTransport, CoreCubeUuidName};
+use crate::units::*;
+
+/// Wheel speed used by turn_in_place() and arc() [mm/s]
+pub const DEFAULT_MOVING_SPEED_MM_PER_SEC: f32 = 50.0;
+
+/// Position (Position ID units) and direction of a cube
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub angle: Angle,
+}
+
+impl Pose {
+    pub fn new(x: f32, y: f32, degrees: f32) -> Self {
+        Self {
+            x,
+            y,
+            angle: Angle::from_degrees(degrees),
+        }
+    }
+
+    /// Distance to the other pose [Position ID units]
+    pub fn distance(&self, other: &Pose) -> f32 {
+        (other.x - self.x).hypot(other.y - self.y)
+    }
+
+    /// Direction from this pose to the other pose
+    pub fn direction_to(&self, other: &Pose) -> Angle {
+        Angle::from_radians((other.y - self.y).atan2(other.x - self.x))
+    }
+
+    /// Move the pose with the wheel speeds (left, right) [mm/s] for `dt`
+    pub fn advance(&self, left: f32, right: f32, dt: time::Duration, mm_per_unit: f32) -> Pose {
+        let dt = dt.as_secs_f32();
+        let (mm_per_sec, deg_per_sec) = wheel_speeds_to_velocity(left, right);
+        let distance = mm_per_sec * dt / mm_per_unit;
+        let turn = deg_per_sec * dt;
+        // integrate along the mean direction of the step
+        let direction = (self.angle + Angle::from_degrees(turn / 2.0)).radians();
+        Pose {
+            x: self.x + distance * direction.cos(),
+            y: self.y + distance * direction.sin(),
+            angle: self.angle + Angle::from_degrees(turn),
+        }
+    }
+}
+
+impl From<&PositionId> for Pose {
+    fn from(position: &PositionId) -> Self {
+        Pose {
+            x: position.cube_x as f32,
+            y: position.cube_y as f32,
+            angle: Angle::from_id_angle(position.cube_angle),
+        }
+    }
+}
+
+/// Linear / angular velocity -> wheel speeds (left, right) [mm/s]
+///
+/// Positive angular velocity turns the cube clockwise
+/// (the direction in which the Position ID angle increases).
+pub fn velocity_to_wheel_speeds(mm_per_sec: f32, deg_per_sec: f32) -> (f32, f32) {
+    let diff = deg_per_sec.to_radians() * WHEEL_BASE_MM / 2.0;
+    (mm_per_sec + diff, mm_per_sec - diff)
+}
+
+/// Wheel speeds (left, right) [mm/s] -> linear / angular velocity ([mm/s], [deg/s])
+pub fn wheel_speeds_to_velocity(left: f32, right: f32) -> (f32, f32) {
+    (
+        (left + right) / 2.0,
+        ((left - right) / WHEEL_BASE_MM).to_degrees(),
+    )
+}
+
+/// Scale down both wheel speeds to the motor range keeping the curvature
+pub fn clamp_wheel_speeds(left: f32, right: f32) -> (f32, f32) {
+    let max_mm_per_sec = speed_to_mm_per_sec(MOTOR_SPEED_MAX);
+    let fastest = left.abs().max(right.abs());
+    if fastest <= max_mm_per_sec {
+        (left, right)
+    } else {
+        let ratio = max_mm_per_sec / fastest;
+        (left * ratio, right * ratio)
+    }
+}
+
+/// Motor control for linear / angular velocity
+pub fn get_drive_control(mm_per_sec: f32, deg_per_sec: f32) -> MotorControl {
+    let (left, right) = velocity_to_wheel_speeds(mm_per_sec, deg_per_sec);
+    let (left, right) = clamp_wheel_speeds(left, right);
+    MotorControl::from_mm_per_sec(left, right)
+}
+
+// raise the speed when the move doesn't finish within a single command
+// (with half a motor speed unit lost by rounding to the motor speed)
+fn get_moving_speed(distance_mm: f32) -> f32 {
+    let min_mm_per_sec = distance_mm.abs() / MOTOR_DURATION_MAX.as_secs_f32();
+    DEFAULT_MOVING_SPEED_MM_PER_SEC.max(min_mm_per_sec + get_mm_per_sec_per_speed() / 2.0)
+}
+
+// motor control with a duration calculated from the actual wheel speed
+// (error: longer than a motor control command even at the maximum speed)
+fn get_timed_control(
+    left: f32,
+    right: f32,
+    distance_mm: f32,
+    radius_mm: f32,
+) -> std::result::Result<MotorControl, String> {
+    let (left, right) = clamp_wheel_speeds(left, right);
+    let control = MotorControl::from_mm_per_sec(left, right);
+    let (actual_left, actual_right) = control.get_mm_per_sec();
+    let (actual_mm_per_sec, actual_deg_per_sec) =
+        wheel_speeds_to_velocity(actual_left, actual_right);
+
+    // speed along the path at `radius_mm` from the center of the turn
+    let path_mm_per_sec = if radius_mm == 0.0 {
+        actual_deg_per_sec.to_radians().abs() * WHEEL_BASE_MM / 2.0
+    } else {
+        actual_mm_per_sec.abs()
+    };
+    // no move (a timed control would still drive for the shortest motor duration)
+    if path_mm_per_sec == 0.0 || distance_mm == 0.0 {
+        return Ok(MotorControl::stop());
+    }
+    let duration = time::Duration::from_secs_f32(distance_mm.abs() / path_mm_per_sec);
+    if duration > MOTOR_DURATION_MAX {
+        return Err(format!(
+            "{:.0} mm takes {:.2} s at the maximum speed (up to {:.2} s)",
+            distance_mm.abs(),
+            duration.as_secs_f32(),
+            MOTOR_DURATION_MAX.as_secs_f32()
+        ));
+    }
+    Ok(control.with_duration(duration))
+}
+
+/// Motor control to turn `degrees` (positive: clockwise) in place
+/// (error: too large to turn within a motor control command)
+pub fn get_turn_in_place_control(degrees: f32) -> std::result::Result<MotorControl, String> {
+    // distance of each wheel
+    let distance_mm = degrees.to_radians().abs() * WHEEL_BASE_MM / 2.0;
+    let speed = get_moving_speed(distance_mm);
+    let (left, right) = if degrees >= 0.0 {
+        (speed, -speed)
+    } else {
+        (-speed, speed)
+    };
+    get_timed_control(left, right, distance_mm, 0.0)
+}
+
+/// Motor control to move forward along an arc of `radius_mm` for `degrees`
+/// (positive: clockwise; error: too long for a motor control command)
+pub fn get_arc_control(radius_mm: f32, degrees: f32) -> std::result::Result<MotorControl, String> {
+    let radius_mm = radius_mm.abs();
+    if radius_mm == 0.0 {
+        return get_turn_in_place_control(degrees);
+    }
+    let distance_mm = degrees.to_radians().abs() * radius_mm;
+    let mm_per_sec = get_moving_speed(distance_mm);
+    let deg_per_sec = (mm_per_sec / radius_mm).to_degrees() * degrees.signum();
+    let (left, right) = velocity_to_wheel_speeds(mm_per_sec, deg_per_sec);
+    get_timed_control(left, right, distance_mm, radius_mm)
+}
+
+/// Velocity commands for a connected cube
+pub trait CubeMotion {
+    /// Drive at linear [mm/s] / angular [deg/s] velocity until the next command
+    fn drive(&self, mm_per_sec: f32, deg_per_sec: f32) -> std::result::Result<bool, String>;
+    /// Turn `degrees` (positive: clockwise) in place
+    fn turn_in_place(&self, degrees: f32) -> std::result::Result<bool, String>;
+    /// Move forward along an arc of `radius_mm` for `degrees` (positive: clockwise)
+    fn arc(&self, radius_mm: f32, degrees: f32) -> std::result::Result<bool, String>;
+}
+
+fn write_motor_control<T: CoreCubeTransport + ?Sized>(
+    cube: &T,
+    control: MotorControl,
+) -> std::result::Result<bool, String> {
+    debug!("motor control {:?}", control);
+    cube.write(CoreCubeUuidName::MotorCtrl, &control.get_bytes())
+}
+
+impl<T: CoreCubeTransport + ?Sized> CubeMotion for T {
+    fn drive(&self, mm_per_sec: f32, deg_per_sec: f32) -> std::result::Result<bool, String> {
+        write_motor_control(self, get_drive_control(mm_per_sec, deg_per_sec))
+    }
+
+    fn turn_in_place(&self, degrees: f32) -> std::result::Result<bool, String> {
+        write_motor_control(self, get_turn_in_place_control(degrees)?)
+    }
+
+    fn arc(&self, radius_mm: f32, degrees: f32) -> std::result::Result<bool, String> {
+        write_motor_control(self, get_arc_control(radius_mm, degrees)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_turned_degrees(control: &MotorControl) -> f32 {
+        let (left, right) = control.get_mm_per_sec();
+        let (_, deg_per_sec) = wheel_speeds_to_velocity(left, right);
+        deg_per_sec * control.duration.unwrap().as_secs_f32()
+    }
+
+    #[test]
+    fn velocity_conversion() {
+        let (left, right) = velocity_to_wheel_speeds(100.0, 90.0);
+        assert!(left > right);
+        let (mm_per_sec, deg_per_sec) = wheel_speeds_to_velocity(left, right);
+        assert!((mm_per_sec - 100.0).abs() < 0.001);
+        assert!((deg_per_sec - 90.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn pose_advance() {
+        let pose = Pose::new(100.0, 100.0, 90.0);
+        let pose = pose.advance(50.0, 50.0, time::Duration::from_secs(2), 1.0);
+        assert!((pose.x - 100.0).abs() < 0.001);
+        assert!((pose.y - 200.0).abs() < 0.001);
+
+        // clockwise turn: left wheel is faster than right wheel
+        let (left, right) = velocity_to_wheel_speeds(0.0, 90.0);
+        let pose = Pose::new(0.0, 0.0, 0.0).advance(left, right, time::Duration::from_secs(1), 1.0);
+        assert!((pose.angle.degrees() - 90.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn clamp_keeps_curvature() {
+        let (left, right) = clamp_wheel_speeds(600.0, 300.0);
+        assert!((left - speed_to_mm_per_sec(MOTOR_SPEED_MAX)).abs() < 0.001);
+        assert!((left / right - 2.0).abs() < 0.001);
+        assert_eq!(clamp_wheel_speeds(100.0, -50.0), (100.0, -50.0));
+    }
+
+    #[test]
+    fn turn_and_arc() {
+        let control = get_turn_in_place_control(90.0).unwrap();
+        assert!(control.left > 0 && control.right < 0);
+        assert!((get_turned_degrees(&control) - 90.0).abs() < 2.0);
+
+        let control = get_turn_in_place_control(-720.0).unwrap();
+        assert!(control.left < 0 && control.right > 0);
+        assert!((get_turned_degrees(&control) + 720.0).abs() < 10.0);
+
+        let control = get_arc_control(100.0, -90.0).unwrap();
+        assert!(control.left < control.right);
+        assert!((get_turned_degrees(&control) + 90.0).abs() < 5.0);
+
+        // zero angle: no motor pulse
+        assert_eq!(get_turn_in_place_control(0.0), Ok(MotorControl::stop()));
+        assert_eq!(get_arc_control(100.0, 0.0), Ok(MotorControl::stop()));
+        assert_eq!(get_arc_control(0.0, -0.0), Ok(MotorControl::stop()));
+
+        // not within 2.55 seconds at the maximum speed
+        assert!(get_turn_in_place_control(10000.0).is_err());
+        assert!(get_arc_control(500.0, 360.0).is_err());
+    }
+}
@@ -1 +1,29 @@
+pub mod clock;
+pub mod controller;
+pub mod dial;
+pub mod gesture;
+pub mod gesture_template;
+pub mod id_info;
+pub mod joystick;
+pub mod kinematics;
+pub mod light;
+pub mod mat;
+pub mod monitor;
+pub mod motion;
+pub mod motor;
+pub mod odometry;
+pub mod path;
+pub mod pointer;
+pub mod scheduler;
+pub mod sensor;
+pub mod sequence;
+pub mod show;
+pub mod simulator;
+pub mod sound;
+pub mod standard_id;
+pub mod talk_timer;
+pub mod trajectory;
+pub mod transport;
+pub mod units;
+#[cfg(windows)]
 pub mod win10;
@@ -0,0 +1,98 @@
+/* Light control packet builders */
+
+use std::time;
+
+use crate::units::duration_to_motor_duration;
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct LightColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl LightColor {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    pub const fn off() -> Self {
+        Self::new(0, 0, 0)
+    }
+}
+
+// light duration uses the same 10ms unit as the motor duration (0: no time limit)
+fn get_light_duration(duration: Option<time::Duration>) -> u8 {
+    match duration {
+        Some(x) => duration_to_motor_duration(x),
+        None => 0,
+    }
+}
+
+/// Turn on the light (0x03) for `duration` (None: until the next command)
+pub fn get_light_bytes(color: LightColor, duration: Option<time::Duration>) -> Vec<u8> {
+    vec![
+        0x03,
+        get_light_duration(duration),
+        0x01,
+        0x01,
+        color.r,
+        color.g,
+        color.b,
+    ]
+}
+
+/// Turn off all lights (0x01)
+pub fn get_light_off_bytes() -> Vec<u8> {
+    vec![0x01]
+}
+
+/// Light scenario (0x04): `repeat` times (0: forever) of (color, duration) list
+pub fn get_light_scenario_bytes(repeat: u8, scenario: &[(LightColor, time::Duration)]) -> Vec<u8> {
+    let scenario = &scenario[..scenario.len().min(29)];
+    let mut bytes = vec![0x04, repeat, scenario.len() as u8];
+    for (color, duration) in scenario {
+        bytes.extend_from_slice(&[
+            get_light_duration(Some(*duration)),
+            0x01,
+            0x01,
+            color.r,
+            color.g,
+            color.b,
+        ]);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn light_bytes() {
+        assert_eq!(
+            get_light_bytes(LightColor::new(0x00, 0x10, 0x00), None),
+            vec![0x03, 0x00, 0x01, 0x01, 0x00, 0x10, 0x00]
+        );
+        assert_eq!(
+            get_light_bytes(
+                LightColor::new(0xff, 0, 0),
+                Some(time::Duration::from_millis(500))
+            )[1],
+            50
+        );
+        assert_eq!(
+            get_light_scenario_bytes(
+                0,
+                &[
+                    (
+                        LightColor::new(0xff, 0, 0),
+                        time::Duration::from_millis(100)
+                    ),
+                    (LightColor::off(), time::Duration::from_millis(100)),
+                ]
+            ),
+            vec![0x04, 0, 2, 10, 1, 1, 0xff, 0, 0, 10, 1, 1, 0, 0, 0]
+        );
+    }
+}
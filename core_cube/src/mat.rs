@@ -0,0 +1,395 @@
+/* toio mat catalog */
+
+use log::{debug, info};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::id_info::{IdInfo, PositionId};
+
+/// Number of developer mat sheets
+pub const DEVELOPER_MAT_SHEETS: u8 = 12;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MatType {
+    /// toio collection mat (ring side)
+    ToioCollectionRing,
+    /// toio collection mat (colored tiles side)
+    ToioCollectionTiles,
+    /// gesundroid mat
+    Gesundroid,
+    /// simple mat bundled with the core cube
+    SimpleMat,
+    /// developer mat sheet #1 - #12
+    Developer(u8),
+}
+
+impl fmt::Display for MatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Short names: tc1, tc2, gesun, simple, dev1 - dev12
+impl FromStr for MatType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tc1" => Ok(MatType::ToioCollectionRing),
+            "tc2" => Ok(MatType::ToioCollectionTiles),
+            "gesun" => Ok(MatType::Gesundroid),
+            "simple" => Ok(MatType::SimpleMat),
+            _ => match s.strip_prefix("dev").map(|x| x.parse::<u8>()) {
+                Some(Ok(sheet)) if (1..=DEVELOPER_MAT_SHEETS).contains(&sheet) => {
+                    Ok(MatType::Developer(sheet))
+                }
+                _ => Err(format!(
+                    "unknown mat: {} (tc1, tc2, gesun, simple, dev1 - dev{})",
+                    s, DEVELOPER_MAT_SHEETS
+                )),
+            },
+        }
+    }
+}
+
+/// Position ID rectangle of a mat (both ends are inclusive)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MatRect {
+    pub left: u16,
+    pub top: u16,
+    pub right: u16,
+    pub bottom: u16,
+}
+
+impl MatRect {
+    pub fn width(&self) -> u16 {
+        self.right - self.left
+    }
+
+    pub fn height(&self) -> u16 {
+        self.bottom - self.top
+    }
+
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        self.left <= x && x <= self.right && self.top <= y && y <= self.bottom
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mat {
+    pub mat_type: MatType,
+    pub rect: MatRect,
+    /// grid cells (columns, rows)
+    pub grid: (u16, u16),
+}
+
+const fn rect(left: u16, top: u16, right: u16, bottom: u16) -> MatRect {
+    MatRect {
+        left,
+        top,
+        right,
+        bottom,
+    }
+}
+
+fn get_developer_mat_rect(sheet: u8) -> MatRect {
+    // sheets are laid out in 3 columns x 4 rows (numbered top to bottom)
+    const COLUMNS: [(u16, u16); 3] = [(34, 339), (340, 644), (645, 949)];
+    const ROWS: [(u16, u16); 4] = [(35, 250), (251, 466), (467, 682), (683, 898)];
+    let index = (sheet.clamp(1, DEVELOPER_MAT_SHEETS) - 1) as usize;
+    let (left, right) = COLUMNS[index / ROWS.len()];
+    let (top, bottom) = ROWS[index % ROWS.len()];
+    rect(left, top, right, bottom)
+}
+
+pub fn get_mat(mat_type: MatType) -> Mat {
+    let (mat_rect, grid) = match mat_type {
+        MatType::ToioCollectionRing => (rect(45, 45, 455, 455), (9, 9)),
+        MatType::ToioCollectionTiles => (rect(545, 45, 955, 455), (9, 9)),
+        MatType::Gesundroid => (rect(1050, 45, 1460, 455), (9, 9)),
+        MatType::SimpleMat => (rect(98, 142, 402, 358), (8, 6)),
+        MatType::Developer(sheet) => (get_developer_mat_rect(sheet), (8, 6)),
+    };
+    Mat {
+        mat_type,
+        rect: mat_rect,
+        grid,
+    }
+}
+
+/// All mats in detection order
+pub fn get_mat_list() -> Vec<Mat> {
+    let mut mat_list = vec![
+        get_mat(MatType::ToioCollectionRing),
+        get_mat(MatType::ToioCollectionTiles),
+        get_mat(MatType::Gesundroid),
+        get_mat(MatType::SimpleMat),
+    ];
+    for sheet in 1..=DEVELOPER_MAT_SHEETS {
+        mat_list.push(get_mat(MatType::Developer(sheet)));
+    }
+    mat_list
+}
+
+/// All mats which have the position
+pub fn find_mats(x: u16, y: u16) -> Vec<Mat> {
+    get_mat_list()
+        .into_iter()
+        .filter(|mat| mat.rect.contains(x, y))
+        .collect()
+}
+
+/// Find the mat which has the position.
+/// Some mats share Position ID ranges (e.g. the simple mat lies inside the
+/// ring side of the toio collection mat, and the developer mat sheets overlap both),
+/// so None is returned unless exactly one mat has the position.
+/// Specify the mat explicitly in that case.
+pub fn find_mat(x: u16, y: u16) -> Option<Mat> {
+    match find_mats(x, y).as_slice() {
+        [mat] => Some(*mat),
+        _ => None,
+    }
+}
+
+impl Mat {
+    pub fn center(&self) -> (u16, u16) {
+        (
+            self.rect.left + self.rect.width() / 2,
+            self.rect.top + self.rect.height() / 2,
+        )
+    }
+
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        self.rect.contains(x, y)
+    }
+
+    fn cell_size(&self) -> (f32, f32) {
+        (
+            self.rect.width() as f32 / self.grid.0 as f32,
+            self.rect.height() as f32 / self.grid.1 as f32,
+        )
+    }
+
+    /// Grid cell (column, row) of the position
+    pub fn get_cell(&self, x: u16, y: u16) -> Option<(u16, u16)> {
+        if !self.contains(x, y) {
+            return None;
+        }
+        let (cell_w, cell_h) = self.cell_size();
+        let column = ((x - self.rect.left) as f32 / cell_w) as u16;
+        let row = ((y - self.rect.top) as f32 / cell_h) as u16;
+        Some((column.min(self.grid.0 - 1), row.min(self.grid.1 - 1)))
+    }
+
+    /// Center position of the grid cell (column, row)
+    pub fn get_cell_center(&self, column: u16, row: u16) -> Option<(u16, u16)> {
+        if column >= self.grid.0 || row >= self.grid.1 {
+            return None;
+        }
+        let (cell_w, cell_h) = self.cell_size();
+        Some((
+            self.rect.left + ((column as f32 + 0.5) * cell_w) as u16,
+            self.rect.top + ((row as f32 + 0.5) * cell_h) as u16,
+        ))
+    }
+
+    /// Position -> normalized coordinate (0.0 - 1.0 from the top-left corner)
+    pub fn to_normalized(&self, x: u16, y: u16) -> (f32, f32) {
+        (
+            (x as f32 - self.rect.left as f32) / self.rect.width() as f32,
+            (y as f32 - self.rect.top as f32) / self.rect.height() as f32,
+        )
+    }
+
+    /// Normalized coordinate (0.0 - 1.0 from the top-left corner) -> position
+    pub fn from_normalized(&self, nx: f32, ny: f32) -> (u16, u16) {
+        let nx = nx.clamp(0.0, 1.0);
+        let ny = ny.clamp(0.0, 1.0);
+        (
+            self.rect.left + (nx * self.rect.width() as f32).round() as u16,
+            self.rect.top + (ny * self.rect.height() as f32).round() as u16,
+        )
+    }
+
+    /// Position -> relative coordinate (offset from the center of the mat)
+    pub fn to_relative(&self, x: u16, y: u16) -> (i16, i16) {
+        let (cx, cy) = self.center();
+        (x as i16 - cx as i16, y as i16 - cy as i16)
+    }
+
+    /// Relative coordinate (offset from the center of the mat) -> position
+    /// The result is clamped into the mat.
+    pub fn from_relative(&self, dx: i16, dy: i16) -> (u16, u16) {
+        let (cx, cy) = self.center();
+        let x = (cx as i32 + dx as i32).clamp(self.rect.left as i32, self.rect.right as i32);
+        let y = (cy as i32 + dy as i32).clamp(self.rect.top as i32, self.rect.bottom as i32);
+        (x as u16, y as u16)
+    }
+}
+
+/// Detect the mat from the Position IDs seen
+///
+/// Mats which don't have every position are dropped from the candidates while
+/// the cube moves, and the mat is detected when only one candidate is left.
+/// A cube on the simple mat never leaves the ring side of the toio collection mat,
+/// so the simple mat can't be detected automatically.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatDetector {
+    mat: Option<Mat>,
+    candidates: Vec<Mat>,
+}
+
+impl MatDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_mat(&self) -> Option<Mat> {
+        self.mat
+    }
+
+    /// Mats which have all positions seen so far
+    pub fn get_candidates(&self) -> &[Mat] {
+        &self.candidates
+    }
+
+    /// Forget the detected mat and the candidates
+    pub fn reset(&mut self) {
+        self.mat = None;
+        self.candidates.clear();
+    }
+
+    pub fn update_position(&mut self, position: &PositionId) -> Option<Mat> {
+        if self.mat.is_some() {
+            return self.mat;
+        }
+        let (x, y) = (position.cube_x, position.cube_y);
+        self.candidates.retain(|mat| mat.contains(x, y));
+        if self.candidates.is_empty() {
+            // the first position, or the cube has been moved to another mat
+            self.candidates = find_mats(x, y);
+        }
+        match self.candidates.as_slice() {
+            [mat] => {
+                info!("mat detected: {}", mat.mat_type);
+                self.mat = Some(*mat);
+            }
+            [] => debug!("unknown mat: ({}, {})", x, y),
+            candidates => debug!(
+                "mat candidates at ({}, {}): {:?}",
+                x,
+                y,
+                candidates.iter().map(|m| m.mat_type).collect::<Vec<_>>()
+            ),
+        }
+        self.mat
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Option<Mat> {
+        match crate::id_info::get_id_info(data) {
+            IdInfo::PositionId(position) => self.update_position(&position),
+            _ => self.mat,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(cube_x: u16, cube_y: u16) -> PositionId {
+        PositionId {
+            cube_x,
+            cube_y,
+            cube_angle: 0,
+            sensor_x: cube_x,
+            sensor_y: cube_y,
+            sensor_angle: 0,
+        }
+    }
+
+    #[test]
+    fn mat_detection() {
+        assert_eq!(find_mat(1255, 250).unwrap().mat_type, MatType::Gesundroid);
+        assert_eq!(find_mat(800, 800).unwrap().mat_type, MatType::Developer(12));
+        assert_eq!(find_mat(0, 0), None);
+        // the ring side overlaps the developer mat sheets #1 and #2
+        assert_eq!(find_mat(250, 400), None);
+        assert_eq!(
+            find_mats(250, 400)
+                .into_iter()
+                .map(|x| x.mat_type)
+                .collect::<Vec<_>>(),
+            vec![MatType::ToioCollectionRing, MatType::Developer(2)]
+        );
+        assert_eq!("tc2".parse::<MatType>(), Ok(MatType::ToioCollectionTiles));
+        assert_eq!("dev12".parse::<MatType>(), Ok(MatType::Developer(12)));
+        assert!("dev13".parse::<MatType>().is_err());
+
+        let mut detector = MatDetector::new();
+        // (750, 250): tiles side or developer mat sheet #9
+        let data = vec![0x01, 0xee, 0x02, 0xfa, 0x00, 0x5a, 0x00, 0, 0, 0, 0, 0, 0];
+        assert_eq!(detector.update(&data), None);
+        assert_eq!(detector.get_candidates().len(), 2);
+        // (750, 300): out of the sheet #9
+        let data = vec![0x01, 0xee, 0x02, 0x2c, 0x01, 0x5a, 0x00, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            detector.update(&data).unwrap().mat_type,
+            MatType::ToioCollectionTiles
+        );
+        // detection result is kept after that
+        let data = vec![0x01, 0xfa, 0x00, 0xfa, 0x00, 0x5a, 0x00, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            detector.update(&data).unwrap().mat_type,
+            MatType::ToioCollectionTiles
+        );
+    }
+
+    #[test]
+    fn simple_mat_needs_explicit_mat() {
+        // the center of the simple mat is also on the ring side and the sheet #1
+        let (x, y) = get_mat(MatType::SimpleMat).center();
+        assert_eq!(find_mat(x, y), None);
+        assert!(find_mats(x, y)
+            .iter()
+            .any(|mat| mat.mat_type == MatType::SimpleMat));
+
+        // moving around the simple mat never drops the ring side from the candidates
+        let mut detector = MatDetector::new();
+        for (x, y) in [(250, 200), (250, 300), (380, 300), (120, 160)].iter() {
+            assert_eq!(detector.update_position(&position(*x, *y)), None);
+        }
+        assert_eq!(
+            detector
+                .get_candidates()
+                .iter()
+                .map(|m| m.mat_type)
+                .collect::<Vec<_>>(),
+            vec![MatType::ToioCollectionRing, MatType::SimpleMat]
+        );
+
+        // the cube is moved to another mat: the candidates start again
+        detector.reset();
+        assert_eq!(
+            detector
+                .update_position(&position(1100, 100))
+                .map(|m| m.mat_type),
+            Some(MatType::Gesundroid)
+        );
+    }
+
+    #[test]
+    fn mat_coordinates() {
+        let mat = get_mat(MatType::ToioCollectionRing);
+        assert_eq!(mat.center(), (250, 250));
+        assert_eq!(mat.from_relative(-50, -60), (200, 190));
+        assert_eq!(mat.to_relative(200, 190), (-50, -60));
+        assert_eq!(mat.from_relative(-1000, 1000), (45, 455));
+        assert_eq!(mat.from_normalized(0.5, 0.5), (250, 250));
+        assert_eq!(mat.to_normalized(45, 455), (0.0, 1.0));
+        assert_eq!(mat.get_cell(45, 45), Some((0, 0)));
+        assert_eq!(mat.get_cell(455, 455), Some((8, 8)));
+        assert_eq!(mat.get_cell(0, 0), None);
+        assert_eq!(mat.get_cell_center(4, 4), Some((250, 250)));
+    }
+}
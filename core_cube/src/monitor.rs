@@ -0,0 +1,298 @@
+/* Cube status collected from the notifications for monitoring */
+
+use std::time;
+
+use crate::id_info::{get_id_info, IdInfo, PositionId};
+use crate::mat::{Mat, MatDetector};
+use crate::motor::{get_motor_speed_info, get_target_move_response, TargetMoveResult};
+use crate::sensor::{get_motion_detection, get_posture_angle, MotionDetection, PostureAngle};
+use crate::trajectory::get_command_label;
+use crate::transport::CoreCubeUuidName;
+
+/// The battery level is notified every 5 seconds
+pub const NO_RESPONSE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// Connected but no notification for NO_RESPONSE_TIMEOUT
+    NoResponse,
+    Disconnected,
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubeStatus {
+    pub name: String,
+    connected: Option<bool>,
+    /// Time of the last notification
+    pub last_update: Option<time::Instant>,
+    pub battery: Option<u8>,
+    pub button_pressed: Option<bool>,
+    pub motion: Option<MotionDetection>,
+    pub posture_angle: Option<PostureAngle>,
+    /// The last Position ID (kept while the cube is off the mat)
+    pub position: Option<PositionId>,
+    pub on_mat: bool,
+    pub mat: Option<Mat>,
+    mat_detector: MatDetector,
+    pub motor_speed: Option<(u8, u8)>,
+    pub target_result: Option<TargetMoveResult>,
+    /// The last motor command written to the cube
+    pub last_command: Option<(time::Instant, String)>,
+}
+
+impl CubeStatus {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            connected: None,
+            last_update: None,
+            battery: None,
+            button_pressed: None,
+            motion: None,
+            posture_angle: None,
+            position: None,
+            on_mat: false,
+            mat: None,
+            mat_detector: MatDetector::new(),
+            motor_speed: None,
+            target_result: None,
+            last_command: None,
+        }
+    }
+
+    pub fn set_connected(&mut self, connected: bool, now: time::Instant) {
+        self.connected = Some(connected);
+        if connected {
+            self.last_update = Some(now);
+        }
+    }
+
+    pub fn get_connection_state(&self, now: time::Instant) -> ConnectionState {
+        match (self.connected, self.last_update) {
+            (None, _) => ConnectionState::Connecting,
+            (Some(false), _) => ConnectionState::Disconnected,
+            (Some(true), Some(last))
+                if now.saturating_duration_since(last) > NO_RESPONSE_TIMEOUT =>
+            {
+                ConnectionState::NoResponse
+            }
+            (Some(true), _) => ConnectionState::Connected,
+        }
+    }
+
+    /// Update the status with a notification
+    pub fn update(&mut self, characteristic: CoreCubeUuidName, data: &[u8], now: time::Instant) {
+        self.last_update = Some(now);
+        match characteristic {
+            CoreCubeUuidName::IdInfo => match get_id_info(data) {
+                IdInfo::PositionId(position) => {
+                    if !matches!(self.mat, Some(mat) if mat.contains(position.cube_x, position.cube_y))
+                    {
+                        // moved to another mat
+                        if self.mat.is_some() {
+                            self.mat_detector.reset();
+                        }
+                        self.mat = self.mat_detector.update_position(&position);
+                    }
+                    self.position = Some(position);
+                    self.on_mat = true;
+                }
+                IdInfo::PositionIdMissed => self.on_mat = false,
+                _ => (),
+            },
+            CoreCubeUuidName::SensorInfo => {
+                if let Some(motion) = get_motion_detection(data) {
+                    self.motion = Some(motion);
+                } else if let Some(angle) = get_posture_angle(data) {
+                    self.posture_angle = Some(angle);
+                }
+            }
+            CoreCubeUuidName::ButtonInfo => {
+                if let [0x01, state, ..] = data {
+                    self.button_pressed = Some(*state == 0x80);
+                }
+            }
+            CoreCubeUuidName::BatteryInfo => self.battery = data.first().copied(),
+            CoreCubeUuidName::MotorCtrl => {
+                if let Some(response) = get_target_move_response(data) {
+                    self.target_result = Some(response.result);
+                } else if let Some(speed) = get_motor_speed_info(data) {
+                    self.motor_speed = Some(speed);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Record a command written to the cube
+    pub fn set_command(
+        &mut self,
+        characteristic: CoreCubeUuidName,
+        bytes: &[u8],
+        now: time::Instant,
+    ) {
+        if let Some(label) = get_command_label(characteristic, bytes) {
+            self.last_command = Some((now, label));
+        }
+    }
+}
+
+/// Map of the mat in `columns` x `rows` characters with a border.
+/// Each cube is drawn with its mark; cubes off the mat are not drawn.
+pub fn render_mat_map(
+    mat: &Mat,
+    cubes: &[(char, &PositionId)],
+    columns: usize,
+    rows: usize,
+) -> Vec<String> {
+    let (columns, rows) = (columns.max(1), rows.max(1));
+    let mut map = vec![vec![' '; columns]; rows];
+    // grid of the mat
+    for row in 1..mat.grid.1 as usize {
+        for column in 1..mat.grid.0 as usize {
+            let y = row * rows / mat.grid.1 as usize;
+            let x = column * columns / mat.grid.0 as usize;
+            if y < rows && x < columns {
+                map[y][x] = '.';
+            }
+        }
+    }
+    for (mark, position) in cubes {
+        if !mat.contains(position.cube_x, position.cube_y) {
+            continue;
+        }
+        let (nx, ny) = mat.to_normalized(position.cube_x, position.cube_y);
+        let x = ((nx * columns as f32) as usize).min(columns - 1);
+        let y = ((ny * rows as f32) as usize).min(rows - 1);
+        map[y][x] = *mark;
+    }
+
+    let border = format!("+{}+", "-".repeat(columns));
+    let mut lines = vec![border.clone()];
+    lines.extend(
+        map.into_iter()
+            .map(|x| format!("|{}|", x.into_iter().collect::<String>())),
+    );
+    lines.push(border);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat::{get_mat, MatType};
+    use crate::motor::MotorControl;
+
+    #[test]
+    fn cube_status() {
+        let start = time::Instant::now();
+        let mut status = CubeStatus::new("cube1");
+        assert_eq!(
+            status.get_connection_state(start),
+            ConnectionState::Connecting
+        );
+        status.set_connected(true, start);
+        assert_eq!(
+            status.get_connection_state(start),
+            ConnectionState::Connected
+        );
+
+        status.update(CoreCubeUuidName::BatteryInfo, &[80], start);
+        status.update(CoreCubeUuidName::ButtonInfo, &[0x01, 0x80], start);
+        status.update(
+            CoreCubeUuidName::SensorInfo,
+            &[0x01, 0x01, 0x00, 0x00, 0x02, 0x00],
+            start,
+        );
+        status.update(
+            CoreCubeUuidName::IdInfo,
+            &[
+                0x01, 0xfa, 0x00, 0xfa, 0x00, 0x5a, 0x00, 0xfa, 0x00, 0xfa, 0x00, 0x5a, 0x00,
+            ],
+            start,
+        );
+        // (250, 250) is also on the simple mat and the developer mat sheet #1
+        assert_eq!(status.mat, None);
+        status.update(
+            CoreCubeUuidName::IdInfo,
+            &[
+                0x01, 0xfa, 0x00, 0x90, 0x01, 0x5a, 0x00, 0xfa, 0x00, 0x90, 0x01, 0x5a, 0x00,
+            ],
+            start,
+        );
+        status.update(CoreCubeUuidName::MotorCtrl, &[0x83, 0x01, 0x00], start);
+        status.set_command(
+            CoreCubeUuidName::MotorCtrl,
+            &MotorControl::new(20, 20).get_bytes(),
+            start,
+        );
+        status.set_command(CoreCubeUuidName::LightCtrl, &[0x01, 0x00], start);
+
+        assert_eq!(status.battery, Some(80));
+        assert_eq!(status.button_pressed, Some(true));
+        assert_eq!(
+            status.motion.map(|x| x.posture),
+            Some(crate::sensor::Posture::Reverse)
+        );
+        assert_eq!(
+            status.position.map(|x| (x.cube_x, x.cube_y)),
+            Some((250, 400))
+        );
+        assert!(status.on_mat);
+        assert_eq!(
+            status.mat.map(|x| x.mat_type),
+            Some(MatType::ToioCollectionRing)
+        );
+        assert_eq!(status.target_result, Some(TargetMoveResult::Success));
+        assert_eq!(
+            status.last_command.as_ref().map(|x| x.1.as_str()),
+            Some("move 20,20")
+        );
+
+        status.update(CoreCubeUuidName::IdInfo, &[0x03], start);
+        assert!(!status.on_mat);
+        assert!(status.position.is_some());
+
+        let later = start + NO_RESPONSE_TIMEOUT + time::Duration::from_secs(1);
+        assert_eq!(
+            status.get_connection_state(later),
+            ConnectionState::NoResponse
+        );
+        status.set_connected(false, later);
+        assert_eq!(
+            status.get_connection_state(later),
+            ConnectionState::Disconnected
+        );
+    }
+
+    #[test]
+    fn mat_map() {
+        let mat = get_mat(MatType::ToioCollectionRing);
+        let position = |x: u16, y: u16| PositionId {
+            cube_x: x,
+            cube_y: y,
+            cube_angle: 0,
+            sensor_x: x,
+            sensor_y: y,
+            sensor_angle: 0,
+        };
+        let (a, b, c) = (position(45, 45), position(454, 454), position(10, 10));
+        let map = render_mat_map(&mat, &[('1', &a), ('2', &b), ('3', &c)], 18, 9);
+        assert_eq!(map.len(), 11);
+        assert_eq!(map[0], format!("+{}+", "-".repeat(18)));
+        assert!(map.iter().all(|x| x.chars().count() == 20));
+        assert!(map[1].starts_with("|1"));
+        assert!(map[9].ends_with("2|"));
+        assert!(!map.concat().contains('3'));
+        // 9x9 grid
+        assert_eq!(map.concat().matches('.').count(), 64);
+    }
+}
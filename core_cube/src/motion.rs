@@ -0,0 +1,457 @@
+/* Motion classifier: shake, collision, double tap and posture of cubes to motion events */
+
+use std::fmt;
+use std::time;
+
+use crate::sensor::{get_motion_detection, MotionDetection, Posture};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MotionEvent {
+    /// The cube starts shaking (cube, shake level)
+    Shake(usize, u8),
+    /// The cube is shaken until the summed shake level reaches sustained_level
+    SustainedShake(usize),
+    /// Two cubes are shaken together: the summed product of the levels reaches sync_level
+    /// (once until one of the cubes stops shaking)
+    SyncShake(usize, usize),
+    /// The cube is turned over (normal <-> reverse)
+    Flip(usize),
+    /// Double tap, or two collisions within tap_tap_time
+    TapTap(usize),
+}
+
+impl MotionEvent {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            MotionEvent::Shake(..) => "shake",
+            MotionEvent::SustainedShake(_) => "sustained_shake",
+            MotionEvent::SyncShake(..) => "sync_shake",
+            MotionEvent::Flip(_) => "flip",
+            MotionEvent::TapTap(_) => "tap_tap",
+        }
+    }
+}
+
+impl fmt::Display for MotionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MotionEvent::Shake(cube, level) => write!(f, "shake cube{} {}", cube, level),
+            MotionEvent::SyncShake(a, b) => write!(f, "sync_shake cube{} cube{}", a, b),
+            MotionEvent::SustainedShake(cube)
+            | MotionEvent::Flip(cube)
+            | MotionEvent::TapTap(cube) => {
+                write!(f, "{} cube{}", self.get_name(), cube)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MotionConfig {
+    /// Shake level is kept for this time after the notification
+    pub shake_hold_time: time::Duration,
+    /// Interval of summing the shake levels
+    pub sample_interval: time::Duration,
+    /// The shake level is summed twice a sample until the sum is over this level
+    pub sustained_level: u32,
+    pub sync_level: u32,
+    /// No shake is summed for this time after a sustained shake
+    pub cooldown: time::Duration,
+    pub tap_tap_time: time::Duration,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            shake_hold_time: time::Duration::from_millis(600),
+            sample_interval: time::Duration::from_millis(100),
+            sustained_level: 15,
+            sync_level: 8,
+            cooldown: time::Duration::from_millis(300),
+            tap_tap_time: time::Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CubeMotion {
+    last: Option<MotionDetection>,
+    shake: u8,
+    shake_time: Option<time::Instant>,
+    last_collision: Option<time::Instant>,
+    sum: u32,
+}
+
+impl CubeMotion {
+    fn get_shake(&self, now: time::Instant, hold_time: time::Duration) -> u8 {
+        match self.shake_time {
+            Some(x) if now.saturating_duration_since(x) < hold_time => self.shake,
+            _ => 0,
+        }
+    }
+}
+
+pub struct MotionClassifier {
+    config: MotionConfig,
+    cubes: Vec<CubeMotion>,
+    /// Summed products of the shake levels of the cube pairs (i < j)
+    sync_sums: Vec<((usize, usize), u32)>,
+    next_sample: Option<time::Instant>,
+    cooldown_until: Option<time::Instant>,
+}
+
+impl MotionClassifier {
+    pub fn new(config: MotionConfig) -> Self {
+        Self {
+            config,
+            cubes: Vec::new(),
+            sync_sums: Vec::new(),
+            next_sample: None,
+            cooldown_until: None,
+        }
+    }
+
+    pub fn get_config(&self) -> &MotionConfig {
+        &self.config
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// Shake level of the cube at the time (0: not shaking)
+    pub fn get_shake(&self, cube: usize, now: time::Instant) -> u8 {
+        self.cubes
+            .get(cube)
+            .map_or(0, |x| x.get_shake(now, self.config.shake_hold_time))
+    }
+
+    /// Feed a motion detection notification of the cube (cube: 0, 1, ...)
+    pub fn update(
+        &mut self,
+        cube: usize,
+        motion: &MotionDetection,
+        time: time::Instant,
+    ) -> Vec<MotionEvent> {
+        if self.cubes.len() <= cube {
+            self.cubes.resize(cube + 1, CubeMotion::default());
+        }
+        let hold_time = self.config.shake_hold_time;
+        let tap_tap_time = self.config.tap_tap_time;
+        let state = &mut self.cubes[cube];
+        let mut events: Vec<MotionEvent> = Vec::new();
+
+        if motion.shake > 0 && state.get_shake(time, hold_time) == 0 {
+            events.push(MotionEvent::Shake(cube, motion.shake));
+        }
+        state.shake = motion.shake;
+        state.shake_time = Some(time);
+
+        let last = state.last.unwrap_or(MotionDetection {
+            horizontal: true,
+            collision: false,
+            double_tap: false,
+            posture: Posture::Unknown,
+            shake: 0,
+        });
+        let flipped = matches!(
+            (last.posture, motion.posture),
+            (Posture::Normal, Posture::Reverse) | (Posture::Reverse, Posture::Normal)
+        );
+        if flipped {
+            events.push(MotionEvent::Flip(cube));
+        }
+        if motion.double_tap && !last.double_tap {
+            events.push(MotionEvent::TapTap(cube));
+            state.last_collision = None;
+        } else if motion.collision && !last.collision {
+            let since = state.last_collision.take();
+            let within = |x: time::Instant| time.saturating_duration_since(x) <= tap_tap_time;
+            if matches!(since, Some(x) if within(x)) {
+                events.push(MotionEvent::TapTap(cube));
+            } else {
+                state.last_collision = Some(time);
+            }
+        }
+        state.last = Some(*motion);
+        events
+    }
+
+    /// Sum the shake levels every sample_interval until the time (call periodically)
+    pub fn poll(&mut self, now: time::Instant) -> Vec<MotionEvent> {
+        let mut events: Vec<MotionEvent> = Vec::new();
+        let mut next = *self.next_sample.get_or_insert(now);
+        while next <= now {
+            events.extend(self.sample(next));
+            next += self.config.sample_interval;
+        }
+        self.next_sample = Some(next);
+        events
+    }
+
+    fn sample(&mut self, now: time::Instant) -> Vec<MotionEvent> {
+        let hold_time = self.config.shake_hold_time;
+        let levels: Vec<u32> = self
+            .cubes
+            .iter()
+            .map(|x| x.get_shake(now, hold_time) as u32)
+            .collect();
+        let mut events: Vec<MotionEvent> = Vec::new();
+        if matches!(self.cooldown_until, Some(x) if now < x) {
+            return events;
+        }
+
+        // shaken together: the sums of the single cubes are cleared
+        let mut synced = vec![false; levels.len()];
+        for i in 0..levels.len() {
+            for j in (i + 1)..levels.len() {
+                let product = levels[i] * levels[j];
+                let sum = match self.sync_sums.iter_mut().find(|(x, _)| *x == (i, j)) {
+                    Some((_, sum)) => sum,
+                    None => {
+                        self.sync_sums.push(((i, j), 0));
+                        &mut self.sync_sums.last_mut().unwrap().1
+                    }
+                };
+                if product == 0 {
+                    *sum = 0;
+                    continue;
+                }
+                synced[i] = true;
+                synced[j] = true;
+                *sum += product;
+                if *sum > self.config.sync_level && *sum - product <= self.config.sync_level {
+                    events.push(MotionEvent::SyncShake(i, j));
+                }
+            }
+        }
+
+        // the sum is kept while the cube is not shaken
+        for (i, cube) in self.cubes.iter_mut().enumerate() {
+            if synced[i] {
+                cube.sum = 0;
+                continue;
+            }
+            cube.sum += levels[i];
+            if cube.sum > self.config.sustained_level {
+                cube.sum = 0;
+                self.cooldown_until = Some(now + self.config.cooldown);
+                events.push(MotionEvent::SustainedShake(i));
+            } else {
+                cube.sum += levels[i];
+            }
+        }
+        events
+    }
+}
+
+/// A line of a motion trace: "<ms> <cube> <hex bytes of the sensor notification>"
+pub fn get_motion_trace_line(time: time::Duration, cube: usize, data: &[u8]) -> String {
+    let bytes: Vec<String> = data.iter().map(|x| format!("{:02x}", x)).collect();
+    format!("{} {} {}", time.as_millis(), cube, bytes.join(" "))
+}
+
+/// Parse a motion trace ('#' comments, the notifications other than the motion detection are skipped)
+pub fn parse_motion_trace(
+    text: &str,
+) -> std::result::Result<Vec<(time::Duration, usize, MotionDetection)>, String> {
+    let mut trace: Vec<(time::Duration, usize, MotionDetection)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = || format!("line {}: invalid trace \"{}\"", i + 1, line);
+        let mut values = line.split_whitespace();
+        let ms = values
+            .next()
+            .and_then(|x| x.parse::<u64>().ok())
+            .ok_or_else(error)?;
+        let cube = values
+            .next()
+            .and_then(|x| x.parse::<usize>().ok())
+            .ok_or_else(error)?;
+        let data = values
+            .map(|x| u8::from_str_radix(x, 16).map_err(|_| error()))
+            .collect::<std::result::Result<Vec<u8>, String>>()?;
+        if let Some(motion) = get_motion_detection(&data) {
+            trace.push((time::Duration::from_millis(ms), cube, motion));
+        }
+    }
+    Ok(trace)
+}
+
+/// Classify a trace from the start (polled every sample_interval until the shakes end)
+pub fn classify_trace(
+    config: MotionConfig,
+    trace: &[(time::Duration, usize, MotionDetection)],
+) -> Vec<(time::Duration, MotionEvent)> {
+    let start = time::Instant::now();
+    let end = trace.last().map_or(time::Duration::from_millis(0), |x| x.0)
+        + config.shake_hold_time
+        + config.sample_interval;
+    let mut classifier = MotionClassifier::new(config);
+    let mut events: Vec<(time::Duration, MotionEvent)> = Vec::new();
+    let mut entries = trace.iter().peekable();
+    let mut elapsed = time::Duration::from_millis(0);
+    while elapsed <= end {
+        while let Some((time, cube, motion)) = entries.peek() {
+            if *time > elapsed {
+                break;
+            }
+            let detected = classifier.update(*cube, motion, start + *time);
+            events.extend(detected.into_iter().map(|x| (*time, x)));
+            entries.next();
+        }
+        let detected = classifier.poll(start + elapsed);
+        events.extend(detected.into_iter().map(|x| (elapsed, x)));
+        elapsed += config.sample_interval;
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(x: u64) -> time::Duration {
+        time::Duration::from_millis(x)
+    }
+
+    fn classify(text: &str) -> Vec<(u64, MotionEvent)> {
+        let trace = parse_motion_trace(text).unwrap();
+        classify_trace(MotionConfig::default(), &trace)
+            .into_iter()
+            .map(|(time, event)| (time.as_millis() as u64, event))
+            .collect()
+    }
+
+    // hand-written (synthetic) notifications of two cubes
+    const SHAKE_TRACE: &str = "
+# ms cube sensor notification
+0 0 01 01 00 00 01 00
+0 1 01 01 00 00 01 00
+# cube0 is shaken for a second
+100 0 01 00 00 00 01 02
+400 0 01 00 00 00 01 04
+700 0 01 00 00 00 01 03
+1100 0 01 01 00 00 01 00
+# both cubes are shaken
+2000 0 01 00 00 00 01 02
+2050 1 01 00 00 00 01 03
+2300 1 01 00 00 00 01 02
+2400 0 01 01 00 00 01 00
+2450 1 01 01 00 00 01 00
+# shaken together again
+3000 0 01 00 00 00 01 02
+3000 1 01 00 00 00 01 03
+3400 0 01 01 00 00 01 00
+3400 1 01 01 00 00 01 00
+";
+
+    #[test]
+    fn shakes() {
+        assert_eq!(
+            classify(SHAKE_TRACE),
+            vec![
+                (100, MotionEvent::Shake(0, 2)),
+                (400, MotionEvent::SustainedShake(0)),
+                (1000, MotionEvent::SustainedShake(0)),
+                (2000, MotionEvent::Shake(0, 2)),
+                (2050, MotionEvent::Shake(1, 3)),
+                // once until a cube stops shaking
+                (2200, MotionEvent::SyncShake(0, 1)),
+                (3000, MotionEvent::Shake(0, 2)),
+                (3000, MotionEvent::Shake(1, 3)),
+                (3100, MotionEvent::SyncShake(0, 1)),
+            ]
+        );
+    }
+
+    const TAP_TRACE: &str = "
+0 0 01 01 00 00 01 00
+# two knocks on the desk
+1000 0 01 01 01 00 01 00
+1010 0 01 01 00 00 01 00
+1300 0 01 01 01 00 01 00
+1310 0 01 01 00 00 01 00
+# too slow
+3000 0 01 01 01 00 01 00
+3010 0 01 01 00 00 01 00
+3800 0 01 01 01 00 01 00
+# double tap detected by the cube, then turned over and back
+4500 0 01 01 00 01 01 00
+5000 0 01 01 00 00 02 00
+5500 0 01 01 00 00 05 00
+6000 0 01 01 00 00 01 00
+6500 0 01 01 00 00 02 00
+6600 0 03 10 00 f0 ff 20 00  # posture angle
+";
+
+    // trace files in the goto_vtravel --record format with the expected events
+    // ("# expect: <ms> <event>" lines). Only synthetic traces so far: the classifier
+    // is not checked against the notifications of real cubes until a recorded trace is added.
+    #[test]
+    fn trace_files() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../traces");
+        let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|x| x.unwrap().path())
+            .filter(|x| x.extension() == Some(std::ffi::OsStr::new("trace")))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+        for path in paths {
+            let text = std::fs::read_to_string(&path).unwrap();
+            let expected: Vec<&str> = text
+                .lines()
+                .filter_map(|x| x.strip_prefix("# expect: "))
+                .map(|x| x.trim())
+                .collect();
+            let events: Vec<String> = classify(&text)
+                .into_iter()
+                .map(|(time, event)| format!("{} {}", time, event))
+                .collect();
+            assert_eq!(events, expected, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn taps_and_flips() {
+        assert_eq!(
+            classify(TAP_TRACE),
+            vec![
+                (1300, MotionEvent::TapTap(0)),
+                (4500, MotionEvent::TapTap(0)),
+                (5000, MotionEvent::Flip(0)),
+                (6500, MotionEvent::Flip(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn traces() {
+        let data = [0x01, 0x00, 0x00, 0x00, 0x01, 0x04];
+        let line = get_motion_trace_line(ms(1234), 1, &data);
+        assert_eq!(line, "1234 1 01 00 00 00 01 04");
+        let trace = parse_motion_trace(&line).unwrap();
+        assert_eq!(trace[0].0, ms(1234));
+        assert_eq!(trace[0].2.shake, 4);
+        assert_eq!(
+            parse_motion_trace("0 0 01\n10 x 01").unwrap_err(),
+            "line 2: invalid trace \"10 x 01\""
+        );
+
+        // the shake level is kept until the hold time
+        let start = time::Instant::now();
+        let mut classifier = MotionClassifier::new(MotionConfig::default());
+        classifier.update(0, &trace[0].2, start);
+        assert_eq!(classifier.get_shake(0, start + ms(500)), 4);
+        assert_eq!(classifier.get_shake(0, start + ms(600)), 0);
+        assert_eq!(classifier.get_shake(1, start), 0);
+        assert_eq!(
+            MotionEvent::SyncShake(0, 1).to_string(),
+            "sync_shake cube0 cube1"
+        );
+    }
+}
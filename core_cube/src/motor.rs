@@ -0,0 +1,340 @@
+/* Motor control packet builders */
+
+use std::time;
+
+use crate::units::*;
+
+/// Maximum number of targets in a multiple targets (0x04) command
+pub const MULTI_TARGET_MAX: usize = 29;
+
+pub const MOTOR_LEFT: u8 = 0x01;
+pub const MOTOR_RIGHT: u8 = 0x02;
+pub const MOTOR_FW: u8 = 0x01;
+pub const MOTOR_RV: u8 = 0x02;
+
+/// Motor control (0x01) / motor control with specified duration (0x02)
+///
+/// Speeds are signed motor speed values (negative: backward).
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MotorControl {
+    pub left: i16,
+    pub right: i16,
+    pub duration: Option<time::Duration>,
+}
+
+fn get_motor_bytes(motor_id: u8, speed: i16) -> [u8; 3] {
+    let direction = if speed < 0 { MOTOR_RV } else { MOTOR_FW };
+    let value = speed.unsigned_abs().min(MOTOR_SPEED_MAX as u16) as u8;
+    [motor_id, direction, value]
+}
+
+impl MotorControl {
+    pub fn new(left: i16, right: i16) -> Self {
+        Self {
+            left,
+            right,
+            duration: None,
+        }
+    }
+
+    pub fn stop() -> Self {
+        Self::new(0, 0)
+    }
+
+    /// Wheel speeds in mm/s
+    pub fn from_mm_per_sec(left: f32, right: f32) -> Self {
+        Self::new(
+            mm_per_sec_to_signed_speed(left),
+            mm_per_sec_to_signed_speed(right),
+        )
+    }
+
+    /// Drive straight `distance_mm` (negative: backward) at `mm_per_sec`.
+    /// Error if the move takes longer than MOTOR_DURATION_MAX (2.55 seconds).
+    pub fn straight(distance_mm: f32, mm_per_sec: f32) -> std::result::Result<Self, String> {
+        let speed = mm_per_sec_to_speed(mm_per_sec) as i16;
+        let speed = if distance_mm < 0.0 { -speed } else { speed };
+        let actual_mm_per_sec = signed_speed_to_mm_per_sec(speed).abs();
+        if actual_mm_per_sec == 0.0 {
+            return Ok(Self::stop());
+        }
+        let duration = time::Duration::from_secs_f32(distance_mm.abs() / actual_mm_per_sec);
+        if duration > MOTOR_DURATION_MAX {
+            return Err(format!(
+                "straight: {} mm takes {:.2} s at {:.0} mm/s (up to {:.2} s)",
+                distance_mm,
+                duration.as_secs_f32(),
+                actual_mm_per_sec,
+                MOTOR_DURATION_MAX.as_secs_f32()
+            ));
+        }
+        Ok(Self::new(speed, speed).with_duration(duration))
+    }
+
+    pub fn with_duration(mut self, duration: time::Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Wheel speeds in mm/s (left, right)
+    pub fn get_mm_per_sec(&self) -> (f32, f32) {
+        (
+            signed_speed_to_mm_per_sec(self.left),
+            signed_speed_to_mm_per_sec(self.right),
+        )
+    }
+
+    pub fn get_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(8);
+        bytes.push(match self.duration {
+            Some(_) => 0x02,
+            None => 0x01,
+        });
+        bytes.extend_from_slice(&get_motor_bytes(MOTOR_LEFT, self.left));
+        bytes.extend_from_slice(&get_motor_bytes(MOTOR_RIGHT, self.right));
+        if let Some(duration) = self.duration {
+            bytes.push(duration_to_motor_duration(duration));
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MovingType {
+    /// Move while rotating
+    MoveWhileRotating = 0,
+    /// Move while rotating (never move backward)
+    MoveWhileRotatingForward = 1,
+    /// Rotate toward the target, then move
+    RotateAndMove = 2,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpeedChangeType {
+    Constant = 0,
+    Accelerate = 1,
+    Decelerate = 2,
+    AccelerateAndDecelerate = 3,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WriteMode {
+    /// Cancel the running command
+    Overwrite = 0,
+    /// Queue after the running command
+    Append = 1,
+}
+
+/// Common parameters of the target position (0x03) / multiple targets (0x04) commands
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TargetMoveConfig {
+    /// Timeout in seconds (0: 10 seconds)
+    pub timeout: u8,
+    pub moving_type: MovingType,
+    pub max_speed: u8,
+    pub speed_change: SpeedChangeType,
+}
+
+impl Default for TargetMoveConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 0,
+            moving_type: MovingType::MoveWhileRotating,
+            max_speed: 50,
+            speed_change: SpeedChangeType::Constant,
+        }
+    }
+}
+
+/// Target position. The cube doesn't rotate at the target if `angle` is None.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Waypoint {
+    pub x: u16,
+    pub y: u16,
+    pub angle: Option<Angle>,
+}
+
+impl Waypoint {
+    pub fn new(x: u16, y: u16, angle: Option<f32>) -> Self {
+        Self {
+            x,
+            y,
+            angle: angle.map(Angle::from_degrees),
+        }
+    }
+
+    fn get_bytes(&self) -> [u8; 6] {
+        // angle type is in the upper 3 bits (0: absolute, 5: don't rotate)
+        let angle: u16 = match self.angle {
+            Some(angle) => angle.to_id_angle(),
+            None => 0x05 << 13,
+        };
+        let x = self.x.to_le_bytes();
+        let y = self.y.to_le_bytes();
+        let angle = angle.to_le_bytes();
+        [x[0], x[1], y[0], y[1], angle[0], angle[1]]
+    }
+}
+
+fn get_target_move_header(command: u8, id: u8, config: &TargetMoveConfig) -> Vec<u8> {
+    vec![
+        command,
+        id,
+        config.timeout,
+        config.moving_type as u8,
+        config.max_speed.min(MOTOR_SPEED_MAX),
+        config.speed_change as u8,
+        0x00,
+    ]
+}
+
+/// Target position command (0x03)
+pub fn get_target_bytes(id: u8, config: &TargetMoveConfig, target: &Waypoint) -> Vec<u8> {
+    let mut bytes = get_target_move_header(0x03, id, config);
+    bytes.extend_from_slice(&target.get_bytes());
+    bytes
+}
+
+/// Multiple targets command (0x04)
+/// Targets after the first MULTI_TARGET_MAX are ignored.
+pub fn get_multi_target_bytes(
+    id: u8,
+    config: &TargetMoveConfig,
+    write_mode: WriteMode,
+    targets: &[Waypoint],
+) -> Vec<u8> {
+    let mut bytes = get_target_move_header(0x04, id, config);
+    bytes.push(write_mode as u8);
+    for target in targets.iter().take(MULTI_TARGET_MAX) {
+        bytes.extend_from_slice(&target.get_bytes());
+    }
+    bytes
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TargetMoveResult {
+    Success,
+    Timeout,
+    IdMissed,
+    InvalidParameter,
+    InvalidState,
+    /// Overwritten by another motor control
+    Overwritten,
+    NotSupported,
+    /// Too many appended commands
+    AppendRefused,
+    Unknown(u8),
+}
+
+impl From<u8> for TargetMoveResult {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => TargetMoveResult::Success,
+            0x01 => TargetMoveResult::Timeout,
+            0x02 => TargetMoveResult::IdMissed,
+            0x03 => TargetMoveResult::InvalidParameter,
+            0x04 => TargetMoveResult::InvalidState,
+            0x05 => TargetMoveResult::Overwritten,
+            0x06 => TargetMoveResult::NotSupported,
+            0x07 => TargetMoveResult::AppendRefused,
+            x => TargetMoveResult::Unknown(x),
+        }
+    }
+}
+
+/// Response of the target position (0x83) / multiple targets (0x84) commands
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TargetMoveResponse {
+    pub multi_target: bool,
+    pub id: u8,
+    pub result: TargetMoveResult,
+}
+
+pub fn get_target_move_response(data: &[u8]) -> Option<TargetMoveResponse> {
+    if data.len() < 3 {
+        return None;
+    }
+    let multi_target = match data[0] {
+        0x83 => false,
+        0x84 => true,
+        _ => return None,
+    };
+    Some(TargetMoveResponse {
+        multi_target,
+        id: data[1],
+        result: TargetMoveResult::from(data[2]),
+    })
+}
+
+/// Motor speed information notification (0xe0): speed values (left, right)
+/// The speed values have no direction.
+pub fn get_motor_speed_info(data: &[u8]) -> Option<(u8, u8)> {
+    match data {
+        [0xe0, left, right, ..] => Some((*left, *right)),
+        _ => None,
+    }
+}
+
+/// Configuration to enable / disable the motor speed information notification
+pub fn get_motor_speed_config_bytes(enable: bool) -> Vec<u8> {
+    vec![0x1c, 0x00, enable as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motor_control_bytes() {
+        assert_eq!(
+            MotorControl::new(100, -100).get_bytes(),
+            vec![0x01, 0x01, 0x01, 100, 0x02, 0x02, 100]
+        );
+        assert_eq!(
+            MotorControl::new(115, -115)
+                .with_duration(time::Duration::from_millis(1200))
+                .get_bytes(),
+            vec![0x02, 0x01, 0x01, 115, 0x02, 0x02, 115, 120]
+        );
+
+        let control = MotorControl::straight(80.0, 50.0).unwrap();
+        assert_eq!(control.left, 18);
+        assert_eq!(control.right, 18);
+        let (mm_per_sec, _) = control.get_mm_per_sec();
+        let distance = mm_per_sec * control.duration.unwrap().as_secs_f32();
+        assert!((distance - 80.0).abs() < 0.5);
+        assert_eq!(MotorControl::straight(-80.0, 50.0).unwrap().left, -18);
+        // longer than 2.55 seconds
+        assert!(MotorControl::straight(1000.0, 50.0).is_err());
+    }
+
+    #[test]
+    fn target_move_bytes() {
+        let config = TargetMoveConfig::default();
+        assert_eq!(
+            get_target_bytes(1, &config, &Waypoint::new(300, 200, Some(90.0))),
+            vec![0x03, 1, 0, 0, 50, 0, 0, 0x2c, 0x01, 0xc8, 0x00, 90, 0x00]
+        );
+        let targets = vec![Waypoint::new(100, 100, None); 40];
+        let bytes = get_multi_target_bytes(2, &config, WriteMode::Overwrite, &targets);
+        assert_eq!(bytes.len(), 8 + 6 * MULTI_TARGET_MAX);
+        assert_eq!(&bytes[..10], &[0x04, 2, 0, 0, 50, 0, 0, 0, 100, 0]);
+        assert_eq!(&bytes[12..14], &[0x00, 0xa0]);
+
+        assert_eq!(
+            get_target_move_response(&[0x84, 2, 0x00]),
+            Some(TargetMoveResponse {
+                multi_target: true,
+                id: 2,
+                result: TargetMoveResult::Success
+            })
+        );
+        assert_eq!(
+            get_target_move_response(&[0x83, 1, 0x02]).unwrap().result,
+            TargetMoveResult::IdMissed
+        );
+        assert_eq!(get_target_move_response(&[0x01, 0x00]), None);
+        assert_eq!(get_motor_speed_info(&[0xe0, 20, 30]), Some((20, 30)));
+        assert_eq!(get_motor_speed_info(&[0x84, 0, 0]), None);
+    }
+}
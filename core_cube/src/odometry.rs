@@ -0,0 +1,392 @@
+/* Dead-reckoning odometry: pose estimation while the cube is off the mat */
+
+use log::{debug, info};
+use std::time;
+
+use crate::id_info::{get_id_info, IdInfo};
+use crate::kinematics::{wheel_speeds_to_velocity, Pose};
+use crate::motor::{get_motor_speed_info, MotorControl};
+use crate::sensor::get_posture_angle;
+use crate::units::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PoseSource {
+    /// Pose of the last Position ID
+    PositionId,
+    /// Pose integrated from the wheel speeds
+    DeadReckoning,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PoseEstimate {
+    pub pose: Pose,
+    pub source: PoseSource,
+    /// Estimated position error [Position ID units]
+    pub position_uncertainty: f32,
+    /// Estimated angle error [deg]
+    pub angle_uncertainty: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OdometryConfig {
+    pub mm_per_unit: f32,
+    /// Position error per moved distance (0.05: 5%)
+    pub distance_error: f32,
+    /// Angle error per turned angle (0.1: 10%)
+    pub turn_error: f32,
+    /// Drift of the posture angle (yaw) [deg/s]
+    pub yaw_drift_per_sec: f32,
+    /// Motor speed / posture angle notifications older than this are not used
+    pub sensor_timeout: time::Duration,
+}
+
+impl Default for OdometryConfig {
+    fn default() -> Self {
+        Self {
+            mm_per_unit: MM_PER_UNIT,
+            distance_error: 0.05,
+            turn_error: 0.1,
+            yaw_drift_per_sec: 0.5,
+            sensor_timeout: time::Duration::from_millis(300),
+        }
+    }
+}
+
+/// Pose estimator
+///
+/// The pose follows the Position ID while the cube is on the mat. Off the mat,
+/// the commanded wheel speeds are integrated. The motor speed information
+/// corrects the speeds and the posture angle (yaw) corrects the direction
+/// when the notifications are available.
+pub struct Odometry {
+    config: OdometryConfig,
+    estimate: Option<PoseEstimate>,
+    last_time: Option<time::Instant>,
+    command: Option<(MotorControl, time::Instant)>,
+    motor_speed: Option<((u8, u8), time::Instant)>,
+    yaw: Option<(f32, time::Instant)>,
+    /// mat angle - yaw, measured on the mat
+    yaw_offset: Option<f32>,
+}
+
+fn is_fresh(time: time::Instant, now: time::Instant, timeout: time::Duration) -> bool {
+    now.saturating_duration_since(time) <= timeout
+}
+
+impl Odometry {
+    pub fn new(config: OdometryConfig) -> Self {
+        Self {
+            config,
+            estimate: None,
+            last_time: None,
+            command: None,
+            motor_speed: None,
+            yaw: None,
+            yaw_offset: None,
+        }
+    }
+
+    pub fn get_estimate(&self) -> Option<PoseEstimate> {
+        self.estimate
+    }
+
+    /// Tell the odometry the motor control written to the cube
+    pub fn set_motor_control(&mut self, control: MotorControl, now: time::Instant) {
+        self.update(now);
+        self.command = Some((control, now));
+    }
+
+    /// Feed a motor control notification (motor speed information)
+    pub fn update_motor_speed(&mut self, data: &[u8], now: time::Instant) {
+        if let Some(speeds) = get_motor_speed_info(data) {
+            self.update(now);
+            self.motor_speed = Some((speeds, now));
+        }
+    }
+
+    /// Feed a sensor information notification (posture angle)
+    pub fn update_posture_angle(&mut self, data: &[u8], now: time::Instant) {
+        if let Some(angle) = get_posture_angle(data) {
+            self.update(now);
+            self.yaw = Some((angle.yaw as f32, now));
+            let yaw_angle = self.get_yaw_angle(now);
+            if let (Some(estimate), Some(angle)) = (self.estimate.as_mut(), yaw_angle) {
+                if estimate.source == PoseSource::DeadReckoning {
+                    estimate.pose.angle = angle;
+                }
+            }
+        }
+    }
+
+    /// Feed an ID information notification
+    pub fn update_id_info(&mut self, data: &[u8], now: time::Instant) -> Option<PoseEstimate> {
+        match get_id_info(data) {
+            IdInfo::PositionId(position) => {
+                let pose = Pose::from(&position);
+                if let Some(estimate) = self.estimate {
+                    if estimate.source == PoseSource::DeadReckoning {
+                        info!(
+                            "odometry: back on the mat (error {:.1} / uncertainty {:.1})",
+                            estimate.pose.distance(&pose),
+                            estimate.position_uncertainty
+                        );
+                    }
+                }
+                // assume the yaw turns in the same direction as the mat angle
+                if let Some((yaw, time)) = self.yaw {
+                    if is_fresh(time, now, self.config.sensor_timeout) {
+                        self.yaw_offset = Some(pose.angle.degrees() - yaw);
+                    }
+                }
+                self.estimate = Some(PoseEstimate {
+                    pose,
+                    source: PoseSource::PositionId,
+                    position_uncertainty: 0.0,
+                    angle_uncertainty: 0.0,
+                });
+                self.last_time = Some(now);
+            }
+            IdInfo::PositionIdMissed => {
+                self.update(now);
+                if let Some(estimate) = self.estimate.as_mut() {
+                    if estimate.source == PoseSource::PositionId {
+                        info!("odometry: off the mat at {:?}", estimate.pose);
+                    }
+                    estimate.source = PoseSource::DeadReckoning;
+                }
+            }
+            _ => (),
+        }
+        self.estimate
+    }
+
+    /// Integrate the wheel speeds until `now`
+    pub fn update(&mut self, now: time::Instant) -> Option<PoseEstimate> {
+        let last_time = match self.last_time {
+            Some(x) if now > x => x,
+            Some(_) => return self.estimate,
+            None => {
+                self.last_time = Some(now);
+                return self.estimate;
+            }
+        };
+        let mut time = last_time;
+        // the cube stops by itself at the end of the command duration
+        if let Some((
+            MotorControl {
+                duration: Some(duration),
+                ..
+            },
+            start,
+        )) = self.command
+        {
+            let end = start + duration;
+            if time < end && end < now {
+                self.advance(time, end);
+                time = end;
+            }
+        }
+        self.advance(time, now);
+        self.last_time = Some(now);
+        self.estimate
+    }
+
+    /// Wheel speeds (left, right) [mm/s] at `time`
+    fn get_wheel_speeds(&self, time: time::Instant) -> (f32, f32) {
+        let commanded = match self.command {
+            Some((control, start)) => match control.duration {
+                Some(duration) if time >= start + duration => MotorControl::stop(),
+                _ => control,
+            },
+            None => MotorControl::stop(),
+        };
+        match self.motor_speed {
+            Some(((left, right), measured))
+                if is_fresh(measured, time, self.config.sensor_timeout) =>
+            {
+                // the measured speeds have no direction
+                let signed = |speed: u8, command: i16| {
+                    let mm_per_sec = speed_to_mm_per_sec(speed);
+                    if command < 0 {
+                        -mm_per_sec
+                    } else {
+                        mm_per_sec
+                    }
+                };
+                (signed(left, commanded.left), signed(right, commanded.right))
+            }
+            _ => commanded.get_mm_per_sec(),
+        }
+    }
+
+    fn get_yaw_angle(&self, time: time::Instant) -> Option<Angle> {
+        match (self.yaw, self.yaw_offset) {
+            (Some((yaw, measured)), Some(offset))
+                if is_fresh(measured, time, self.config.sensor_timeout) =>
+            {
+                Some(Angle::from_degrees(yaw + offset))
+            }
+            _ => None,
+        }
+    }
+
+    fn advance(&mut self, from: time::Instant, to: time::Instant) {
+        let (left, right) = self.get_wheel_speeds(from);
+        let yaw_angle = self.get_yaw_angle(to);
+        let config = self.config;
+        let estimate = match self.estimate.as_mut() {
+            Some(x) if x.source == PoseSource::DeadReckoning => x,
+            _ => return,
+        };
+        let dt = to - from;
+        let (mm_per_sec, deg_per_sec) = wheel_speeds_to_velocity(left, right);
+        let distance = mm_per_sec.abs() * dt.as_secs_f32() / config.mm_per_unit;
+        let turn = deg_per_sec.abs() * dt.as_secs_f32();
+
+        let mut pose = estimate.pose.advance(left, right, dt, config.mm_per_unit);
+        match yaw_angle {
+            Some(angle) => {
+                pose.angle = angle;
+                estimate.angle_uncertainty += config.yaw_drift_per_sec * dt.as_secs_f32();
+            }
+            None => estimate.angle_uncertainty += turn * config.turn_error,
+        }
+        estimate.angle_uncertainty = estimate.angle_uncertainty.min(180.0);
+        // a wrong direction moves the cube sideways
+        estimate.position_uncertainty += distance
+            * (config.distance_error + estimate.angle_uncertainty.to_radians().sin().abs());
+        estimate.pose = pose;
+        debug!("odometry: {:?}", estimate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id_info::get_position_id_bytes;
+
+    fn get_odometry_off_the_mat(now: time::Instant) -> Odometry {
+        let mut odometry = Odometry::new(OdometryConfig::default());
+        odometry.update_id_info(&get_position_id_bytes(400, 200, 0), now);
+        odometry.update_id_info(&[0x03], now);
+        odometry
+    }
+
+    #[test]
+    fn dead_reckoning() {
+        let now = time::Instant::now();
+        let mut odometry = get_odometry_off_the_mat(now);
+        assert_eq!(
+            odometry.get_estimate().unwrap().source,
+            PoseSource::DeadReckoning
+        );
+
+        let control = MotorControl::new(30, 30);
+        let (mm_per_sec, _) = control.get_mm_per_sec();
+        odometry.set_motor_control(control, now);
+        let estimate = odometry.update(now + time::Duration::from_secs(1)).unwrap();
+        let expected_x = 400.0 + mm_per_sec / MM_PER_UNIT;
+        assert!((estimate.pose.x - expected_x).abs() < 0.1);
+        assert!((estimate.pose.y - 200.0).abs() < 0.1);
+        assert!(estimate.position_uncertainty > 0.0);
+
+        // back on the mat
+        let estimate = odometry
+            .update_id_info(
+                &get_position_id_bytes(460, 205, 5),
+                now + time::Duration::from_secs(1),
+            )
+            .unwrap();
+        assert_eq!(estimate.source, PoseSource::PositionId);
+        assert_eq!(estimate.position_uncertainty, 0.0);
+        assert_eq!(estimate.pose, Pose::new(460.0, 205.0, 5.0));
+    }
+
+    #[test]
+    fn command_duration_and_motor_speed() {
+        let now = time::Instant::now();
+        let mut odometry = get_odometry_off_the_mat(now);
+        let control = MotorControl::new(-30, -30).with_duration(time::Duration::from_millis(500));
+        odometry.set_motor_control(control, now);
+        let estimate = odometry.update(now + time::Duration::from_secs(2)).unwrap();
+        // moved backward only for 0.5 seconds
+        let expected_x = 400.0 - speed_to_mm_per_sec(30) * 0.5 / MM_PER_UNIT;
+        assert!((estimate.pose.x - expected_x).abs() < 0.1);
+
+        // the measured speed is slower than the command
+        let mut odometry = get_odometry_off_the_mat(now);
+        odometry.set_motor_control(MotorControl::new(30, 30), now);
+        odometry.update_motor_speed(&[0xe0, 20, 20], now);
+        let estimate = odometry
+            .update(now + time::Duration::from_millis(200))
+            .unwrap();
+        let expected_x = 400.0 + speed_to_mm_per_sec(20) * 0.2 / MM_PER_UNIT;
+        assert!((estimate.pose.x - expected_x).abs() < 0.1);
+    }
+
+    fn yaw_data(yaw: i16) -> Vec<u8> {
+        let yaw = yaw.to_le_bytes();
+        vec![0x03, 0x01, 0x00, 0x00, 0x00, 0x00, yaw[0], yaw[1]]
+    }
+
+    #[test]
+    fn posture_angle_yaw() {
+        let now = time::Instant::now();
+        let mut odometry = Odometry::new(OdometryConfig::default());
+        odometry.update_posture_angle(&yaw_data(10), now);
+        odometry.update_id_info(&get_position_id_bytes(400, 200, 90), now);
+        odometry.update_id_info(&[0x03], now);
+
+        // the wheels slipped: turned 30 degrees without moving the wheels
+        let later = now + time::Duration::from_millis(100);
+        odometry.update_posture_angle(&yaw_data(40), later);
+        let estimate = odometry.update(later).unwrap();
+        assert!((estimate.pose.angle.degrees() - 120.0).abs() < 0.01);
+        assert!(estimate.angle_uncertainty < 1.0);
+    }
+
+    #[test]
+    fn fused_heading() {
+        let now = time::Instant::now();
+        let later = now + time::Duration::from_millis(100);
+        let mut odometry = Odometry::new(OdometryConfig::default());
+        odometry.update_posture_angle(&yaw_data(-30), now);
+        odometry.update_id_info(&get_position_id_bytes(400, 200, 200), now);
+        odometry.update_id_info(&[0x03], now);
+
+        // the same yaw: the heading stays at the mat angle
+        odometry.update_posture_angle(&yaw_data(-30), later);
+        let estimate = odometry.update(later).unwrap();
+        assert!(
+            estimate
+                .pose
+                .angle
+                .difference(Angle::from_degrees(200.0))
+                .abs()
+                < 0.01
+        );
+
+        // the heading turns with the yaw (across -180 / 180)
+        let later = later + time::Duration::from_millis(100);
+        odometry.update_posture_angle(&yaw_data(-170), later);
+        let estimate = odometry.update(later).unwrap();
+        assert!(
+            estimate
+                .pose
+                .angle
+                .difference(Angle::from_degrees(60.0))
+                .abs()
+                < 0.01
+        );
+        odometry.update_posture_angle(&yaw_data(170), later);
+        let estimate = odometry.update(later).unwrap();
+        assert!(
+            estimate
+                .pose
+                .angle
+                .difference(Angle::from_degrees(40.0))
+                .abs()
+                < 0.01
+        );
+    }
+}
@@ -0,0 +1,446 @@
+/* Waypoint paths and multiple targets (0x04) path following */
+
+use log::{debug, info};
+use std::f32::consts::PI;
+
+use crate::id_info::{get_id_info, IdInfo};
+use crate::motor::*;
+
+/// Default distance between waypoints [Position ID units]
+pub const DEFAULT_WAYPOINT_STEP: f32 = 20.0;
+
+/// Number of points to approximate a curve before resampling
+const CURVE_RESOLUTION: usize = 360;
+
+/// Paths on the mat (Position ID units)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Path {
+    /// Straight lines through the points
+    Polyline(Vec<(f32, f32)>),
+    /// Smooth curve (Catmull-Rom spline) through the points
+    Spline(Vec<(f32, f32)>),
+    /// Clockwise circle starting from the right end
+    Circle { center: (f32, f32), radius: f32 },
+    /// Figure-eight lying on its side
+    FigureEight {
+        center: (f32, f32),
+        width: f32,
+        height: f32,
+    },
+    /// x = amplitude.0 * sin(frequency.0 * t + phase), y = amplitude.1 * sin(frequency.1 * t)
+    Lissajous {
+        center: (f32, f32),
+        amplitude: (f32, f32),
+        frequency: (u8, u8),
+        phase_degrees: f32,
+    },
+}
+
+fn get_parametric_points<F: Fn(f32) -> (f32, f32)>(f: F) -> Vec<(f32, f32)> {
+    (0..=CURVE_RESOLUTION)
+        .map(|i| f(2.0 * PI * i as f32 / CURVE_RESOLUTION as f32))
+        .collect()
+}
+
+fn catmull_rom(
+    p0: (f32, f32),
+    p1: (f32, f32),
+    p2: (f32, f32),
+    p3: (f32, f32),
+    t: f32,
+) -> (f32, f32) {
+    let f = |a: f32, b: f32, c: f32, d: f32| {
+        0.5 * (2.0 * b
+            + (c - a) * t
+            + (2.0 * a - 5.0 * b + 4.0 * c - d) * t * t
+            + (3.0 * b - a - 3.0 * c + d) * t * t * t)
+    };
+    (f(p0.0, p1.0, p2.0, p3.0), f(p0.1, p1.1, p2.1, p3.1))
+}
+
+fn get_spline_points(points: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    let segments = points.len() - 1;
+    let steps = (CURVE_RESOLUTION / segments).max(8);
+    let mut spline = Vec::with_capacity(segments * steps + 1);
+    for i in 0..segments {
+        // the end points are repeated as the outer control points
+        let p0 = points[i.saturating_sub(1)];
+        let p3 = points[(i + 2).min(segments)];
+        for step in 0..steps {
+            let t = step as f32 / steps as f32;
+            spline.push(catmull_rom(p0, points[i], points[i + 1], p3, t));
+        }
+    }
+    spline.push(points[segments]);
+    spline
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (b.0 - a.0).hypot(b.1 - a.1)
+}
+
+/// Pick points every `step` along the line. The first / last points (and all
+/// vertices if `keep_vertices`) are always included.
+fn resample(points: &[(f32, f32)], step: f32, keep_vertices: bool) -> Vec<(f32, f32)> {
+    let mut samples: Vec<(f32, f32)> = Vec::new();
+    let first = match points.first() {
+        Some(x) => *x,
+        None => return samples,
+    };
+    samples.push(first);
+    // distance travelled since the last sample
+    let mut travelled = 0.0;
+    for (i, pair) in points.windows(2).enumerate() {
+        let (start, end) = (pair[0], pair[1]);
+        let length = distance(start, end);
+        let mut position = step - travelled;
+        while position < length {
+            let t = position / length;
+            samples.push((
+                start.0 + (end.0 - start.0) * t,
+                start.1 + (end.1 - start.1) * t,
+            ));
+            position += step;
+        }
+        travelled = length - (position - step);
+        let is_last = i + 2 == points.len();
+        if (keep_vertices || is_last) && distance(*samples.last().unwrap(), end) > step / 4.0 {
+            samples.push(end);
+            travelled = 0.0;
+        }
+    }
+    samples
+}
+
+impl Path {
+    /// Approximate the path with points
+    pub fn get_points(&self) -> Vec<(f32, f32)> {
+        match self {
+            Path::Polyline(points) => points.clone(),
+            Path::Spline(points) => get_spline_points(points),
+            Path::Circle { center, radius } => get_parametric_points(|t| {
+                (center.0 + radius * t.cos(), center.1 + radius * t.sin())
+            }),
+            Path::FigureEight {
+                center,
+                width,
+                height,
+            } => get_parametric_points(|t| {
+                (
+                    center.0 + width / 2.0 * t.sin(),
+                    center.1 + height / 2.0 * (2.0 * t).sin(),
+                )
+            }),
+            Path::Lissajous {
+                center,
+                amplitude,
+                frequency,
+                phase_degrees,
+            } => get_parametric_points(|t| {
+                (
+                    center.0
+                        + amplitude.0 * (frequency.0 as f32 * t + phase_degrees.to_radians()).sin(),
+                    center.1 + amplitude.1 * (frequency.1 as f32 * t).sin(),
+                )
+            }),
+        }
+    }
+
+    /// Sample waypoints every `step` Position ID units
+    pub fn sample(&self, step: f32) -> Vec<Waypoint> {
+        let keep_vertices = matches!(self, Path::Polyline(_));
+        let to_u16 = |v: f32| v.round().max(0.0) as u16;
+        resample(&self.get_points(), step.max(1.0), keep_vertices)
+            .into_iter()
+            .map(|(x, y)| Waypoint::new(to_u16(x), to_u16(y), None))
+            .collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PathStatus {
+    /// The first command is not sent yet
+    Ready,
+    /// Running the chunk
+    Running(usize),
+    Finished,
+    /// Failed in the chunk
+    Failed(usize, TargetMoveResult),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PathProgress {
+    pub passed_waypoints: usize,
+    pub total_waypoints: usize,
+    pub chunk: usize,
+    pub chunk_count: usize,
+}
+
+impl PathProgress {
+    /// 0.0 - 1.0
+    pub fn ratio(&self) -> f32 {
+        if self.total_waypoints == 0 {
+            return 1.0;
+        }
+        self.passed_waypoints as f32 / self.total_waypoints as f32
+    }
+}
+
+/// Follow waypoints with multiple targets commands
+///
+/// The waypoints are split into chunks of MULTI_TARGET_MAX and the next chunk
+/// is sent when the response of the previous one arrives.
+pub struct PathFollower {
+    config: TargetMoveConfig,
+    chunks: Vec<Vec<Waypoint>>,
+    status: PathStatus,
+    /// id of the command waiting for the response
+    sent_id: Option<u8>,
+    next_id: u8,
+    passed_in_chunk: usize,
+}
+
+impl PathFollower {
+    pub fn new(config: TargetMoveConfig, waypoints: &[Waypoint]) -> Self {
+        Self {
+            config,
+            chunks: waypoints
+                .chunks(MULTI_TARGET_MAX)
+                .map(|x| x.to_vec())
+                .collect(),
+            status: PathStatus::Ready,
+            sent_id: None,
+            next_id: 0,
+            passed_in_chunk: 0,
+        }
+    }
+
+    pub fn get_status(&self) -> PathStatus {
+        self.status
+    }
+
+    pub fn get_chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.status, PathStatus::Finished | PathStatus::Failed(..))
+    }
+
+    fn get_chunk_index(&self) -> usize {
+        match self.status {
+            PathStatus::Ready => 0,
+            PathStatus::Running(chunk) | PathStatus::Failed(chunk, _) => chunk,
+            PathStatus::Finished => self.chunks.len(),
+        }
+    }
+
+    pub fn get_progress(&self) -> PathProgress {
+        let chunk = self.get_chunk_index();
+        let total_waypoints = self.chunks.iter().map(|x| x.len()).sum();
+        let passed_waypoints = match self.status {
+            PathStatus::Finished => total_waypoints,
+            _ => {
+                self.chunks[..chunk.min(self.chunks.len())]
+                    .iter()
+                    .map(|x| x.len())
+                    .sum::<usize>()
+                    + self.passed_in_chunk
+            }
+        };
+        PathProgress {
+            passed_waypoints,
+            total_waypoints,
+            chunk,
+            chunk_count: self.chunks.len(),
+        }
+    }
+
+    /// Command to send next (None: waiting for the response or done)
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        if self.sent_id.is_some() || self.is_done() {
+            return None;
+        }
+        let chunk = self.get_chunk_index();
+        if chunk >= self.chunks.len() {
+            self.status = PathStatus::Finished;
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.sent_id = Some(id);
+        self.status = PathStatus::Running(chunk);
+        self.passed_in_chunk = 0;
+        debug!(
+            "path: send chunk {}/{} (id {})",
+            chunk + 1,
+            self.chunks.len(),
+            id
+        );
+        Some(get_multi_target_bytes(
+            id,
+            &self.config,
+            WriteMode::Overwrite,
+            &self.chunks[chunk],
+        ))
+    }
+
+    /// Feed a motor control notification (responses of the commands)
+    pub fn update_response(&mut self, data: &[u8]) -> PathStatus {
+        let response = match get_target_move_response(data) {
+            Some(x) if x.multi_target && Some(x.id) == self.sent_id => x,
+            _ => return self.status,
+        };
+        self.sent_id = None;
+        let chunk = self.get_chunk_index();
+        self.status = match response.result {
+            TargetMoveResult::Success if chunk + 1 < self.chunks.len() => {
+                PathStatus::Running(chunk + 1)
+            }
+            TargetMoveResult::Success => PathStatus::Finished,
+            // keep the progress in the failed chunk
+            result => PathStatus::Failed(chunk, result),
+        };
+        if !matches!(self.status, PathStatus::Failed(..)) {
+            self.passed_in_chunk = 0;
+        }
+        info!("path: {:?} {:?}", self.status, self.get_progress());
+        self.status
+    }
+
+    /// Feed an ID information notification to update the progress in the chunk
+    pub fn update_id_info(&mut self, data: &[u8]) -> PathProgress {
+        if let (PathStatus::Running(chunk), IdInfo::PositionId(position)) =
+            (self.status, get_id_info(data))
+        {
+            let cube = (position.cube_x as f32, position.cube_y as f32);
+            // the nearest waypoint ahead of the passed ones
+            let nearest = self.chunks[chunk]
+                .iter()
+                .enumerate()
+                .skip(self.passed_in_chunk)
+                .min_by(|(_, a), (_, b)| {
+                    distance(cube, (a.x as f32, a.y as f32))
+                        .partial_cmp(&distance(cube, (b.x as f32, b.y as f32)))
+                        .unwrap()
+                });
+            if let Some((index, _)) = nearest {
+                self.passed_in_chunk = index;
+            }
+        }
+        self.get_progress()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling() {
+        let waypoints =
+            Path::Polyline(vec![(100.0, 100.0), (150.0, 100.0), (150.0, 130.0)]).sample(20.0);
+        let points: Vec<(u16, u16)> = waypoints.iter().map(|w| (w.x, w.y)).collect();
+        assert_eq!(
+            points,
+            vec![
+                (100, 100),
+                (120, 100),
+                (140, 100),
+                (150, 100),
+                (150, 120),
+                (150, 130)
+            ]
+        );
+
+        let waypoints = Path::Circle {
+            center: (250.0, 250.0),
+            radius: 100.0,
+        }
+        .sample(20.0);
+        // circumference 628 -> 32 waypoints + end point
+        assert!((32..=33).contains(&waypoints.len()));
+        for waypoint in &waypoints {
+            let r = distance((250.0, 250.0), (waypoint.x as f32, waypoint.y as f32));
+            assert!((r - 100.0).abs() < 1.5);
+        }
+
+        let spline =
+            Path::Spline(vec![(100.0, 100.0), (200.0, 150.0), (300.0, 100.0)]).sample(10.0);
+        assert_eq!((spline[0].x, spline[0].y), (100, 100));
+        let last = spline.last().unwrap();
+        assert_eq!((last.x, last.y), (300, 100));
+        // the spline passes through the control point
+        assert!(spline
+            .iter()
+            .any(|w| distance((200.0, 150.0), (w.x as f32, w.y as f32)) < 5.0));
+
+        let eight = Path::FigureEight {
+            center: (250.0, 250.0),
+            width: 200.0,
+            height: 100.0,
+        }
+        .get_points();
+        assert!(eight.iter().all(|p| (150.0..=350.0).contains(&p.0)));
+        assert!(eight.iter().all(|p| (200.0..=300.0).contains(&p.1)));
+    }
+
+    #[test]
+    fn chunked_following() {
+        let waypoints: Vec<Waypoint> = (0..70).map(|i| Waypoint::new(100 + i, 200, None)).collect();
+        let mut follower = PathFollower::new(TargetMoveConfig::default(), &waypoints);
+        assert_eq!(follower.get_chunk_count(), 3);
+        assert_eq!(follower.get_status(), PathStatus::Ready);
+
+        let packet = follower.next_packet().unwrap();
+        assert_eq!(packet.len(), 8 + 6 * MULTI_TARGET_MAX);
+        // waiting for the response
+        assert_eq!(follower.next_packet(), None);
+        // progress from the Position ID (x = 110)
+        let data = vec![0x01, 110, 0x00, 200, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0];
+        assert_eq!(follower.update_id_info(&data).passed_waypoints, 10);
+        // response of another command is ignored
+        assert_eq!(
+            follower.update_response(&[0x84, 9, 0x00]),
+            PathStatus::Running(0)
+        );
+        assert_eq!(
+            follower.update_response(&[0x84, 0, 0x00]),
+            PathStatus::Running(1)
+        );
+        assert_eq!(follower.get_progress().passed_waypoints, MULTI_TARGET_MAX);
+
+        let packet = follower.next_packet().unwrap();
+        assert_eq!(packet[1], 1);
+        follower.update_response(&[0x84, 1, 0x00]);
+        let packet = follower.next_packet().unwrap();
+        assert_eq!(packet.len(), 8 + 6 * (70 - 2 * MULTI_TARGET_MAX));
+        assert_eq!(
+            follower.update_response(&[0x84, 2, 0x00]),
+            PathStatus::Finished
+        );
+        assert_eq!(follower.get_progress().ratio(), 1.0);
+        assert_eq!(follower.next_packet(), None);
+    }
+
+    #[test]
+    fn failed_following() {
+        let waypoints: Vec<Waypoint> = (0..40).map(|i| Waypoint::new(100 + i, 100, None)).collect();
+        let mut follower = PathFollower::new(TargetMoveConfig::default(), &waypoints);
+        follower.next_packet();
+        let data = vec![0x01, 110, 0x00, 100, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0, 0];
+        follower.update_id_info(&data);
+        assert_eq!(
+            follower.update_response(&[0x84, 0, 0x02]),
+            PathStatus::Failed(0, TargetMoveResult::IdMissed)
+        );
+        assert!(follower.is_done());
+        let progress = follower.get_progress();
+        assert_eq!((progress.passed_waypoints, progress.chunk), (10, 0));
+        assert_eq!(progress.ratio(), 0.25);
+        assert_eq!(follower.next_packet(), None);
+    }
+}
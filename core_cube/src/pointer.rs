@@ -0,0 +1,295 @@
+/* Mat pointer: the cube position on a mat area to the screen position */
+
+use std::str::FromStr;
+use std::time;
+
+use crate::id_info::PositionId;
+use crate::mat::{get_mat, MatDetector, MatRect, MatType};
+
+/// Screen area of the pointer
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ScreenRect {
+    pub left: i32,
+    pub top: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for ScreenRect {
+    fn default() -> Self {
+        Self {
+            left: 0,
+            top: 0,
+            width: 1920,
+            height: 1080,
+        }
+    }
+}
+
+/// "1920x1080" or "1920x1080+1920+0" (width x height + left + top)
+impl FromStr for ScreenRect {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let error = || format!("invalid screen \"{}\" (1920x1080, 1920x1080+1920+0)", s);
+        let mut values = s.split('+');
+        let (width, height) = values
+            .next()
+            .and_then(|x| x.split_once('x'))
+            .ok_or_else(error)?;
+        let width = width.parse::<u32>().map_err(|_| error())?;
+        let height = height.parse::<u32>().map_err(|_| error())?;
+        let offset = values
+            .map(|x| x.parse::<i32>().map_err(|_| error()))
+            .collect::<std::result::Result<Vec<i32>, String>>()?;
+        let (left, top) = match offset[..] {
+            [] => (0, 0),
+            [left, top] => (left, top),
+            _ => return Err(error()),
+        };
+        if width == 0 || height == 0 {
+            return Err(error());
+        }
+        Ok(Self {
+            left,
+            top,
+            width,
+            height,
+        })
+    }
+}
+
+/// Mat area: a mat name (tc1, simple, dev1, ...) or "left,top,right,bottom" of Position ID
+pub fn parse_mat_area(s: &str) -> std::result::Result<MatRect, String> {
+    if let Ok(mat_type) = s.parse::<MatType>() {
+        return Ok(get_mat(mat_type).rect);
+    }
+    let values = s
+        .split(',')
+        .map(|x| x.trim().parse::<u16>())
+        .collect::<std::result::Result<Vec<u16>, _>>();
+    match values.as_deref() {
+        Ok([left, top, right, bottom]) if left < right && top < bottom => Ok(MatRect {
+            left: *left,
+            top: *top,
+            right: *right,
+            bottom: *bottom,
+        }),
+        _ => Err(format!(
+            "invalid mat area \"{}\" (mat name or left,top,right,bottom)",
+            s
+        )),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PointerConfig {
+    /// Mat area mapped to the screen (None: the mat detected first)
+    pub area: Option<MatRect>,
+    pub screen: ScreenRect,
+    /// 0.0 (no smoothing) - 0.95
+    pub smoothing: f32,
+    /// Position ID missed longer than this is a lift-off
+    /// (the pointer jumps to the next position without the smoothing)
+    pub lift_off_time: time::Duration,
+}
+
+impl Default for PointerConfig {
+    fn default() -> Self {
+        Self {
+            area: None,
+            screen: ScreenRect::default(),
+            smoothing: 0.5,
+            lift_off_time: time::Duration::from_millis(300),
+        }
+    }
+}
+
+pub struct MatPointer {
+    config: PointerConfig,
+    detector: MatDetector,
+    smoothed: Option<(f32, f32)>,
+    missed_since: Option<time::Instant>,
+    last: Option<(i32, i32)>,
+}
+
+impl MatPointer {
+    pub fn new(config: PointerConfig) -> Self {
+        Self {
+            config: PointerConfig {
+                smoothing: config.smoothing.clamp(0.0, 0.95),
+                ..config
+            },
+            detector: MatDetector::new(),
+            smoothed: None,
+            missed_since: None,
+            last: None,
+        }
+    }
+
+    pub fn get_config(&self) -> &PointerConfig {
+        &self.config
+    }
+
+    pub fn get_area(&self) -> Option<MatRect> {
+        self.config
+            .area
+            .or_else(|| self.detector.get_mat().map(|x| x.rect))
+    }
+
+    /// Screen position of a Position ID (None: not moved or the mat area is unknown)
+    ///
+    /// Positions out of the mat area stick to the screen edge.
+    pub fn update_position(
+        &mut self,
+        position: &PositionId,
+        now: time::Instant,
+    ) -> Option<(i32, i32)> {
+        if self.config.area.is_none() {
+            self.detector.update_position(position);
+        }
+        let area = self.get_area()?;
+        let lifted = self.is_lifted(now);
+        self.missed_since = None;
+
+        let nx = (position.cube_x as f32 - area.left as f32) / area.width() as f32;
+        let ny = (position.cube_y as f32 - area.top as f32) / area.height() as f32;
+        let screen = &self.config.screen;
+        let target = (
+            screen.left as f32 + nx.clamp(0.0, 1.0) * (screen.width - 1) as f32,
+            screen.top as f32 + ny.clamp(0.0, 1.0) * (screen.height - 1) as f32,
+        );
+        let smoothed = match self.smoothed {
+            Some((x, y)) if !lifted => {
+                let k = 1.0 - self.config.smoothing;
+                (x + (target.0 - x) * k, y + (target.1 - y) * k)
+            }
+            // placed again: jump to the position
+            _ => target,
+        };
+        self.smoothed = Some(smoothed);
+
+        let pointer = (smoothed.0.round() as i32, smoothed.1.round() as i32);
+        if self.last == Some(pointer) {
+            return None;
+        }
+        self.last = Some(pointer);
+        Some(pointer)
+    }
+
+    /// Position ID missed
+    pub fn lift(&mut self, now: time::Instant) {
+        self.missed_since.get_or_insert(now);
+    }
+
+    pub fn is_lifted(&self, now: time::Instant) -> bool {
+        let lift_off_time = self.config.lift_off_time;
+        matches!(self.missed_since, Some(x) if now.saturating_duration_since(x) >= lift_off_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(x: u16, y: u16) -> PositionId {
+        PositionId {
+            cube_x: x,
+            cube_y: y,
+            cube_angle: 0,
+            sensor_x: x,
+            sensor_y: y,
+            sensor_angle: 0,
+        }
+    }
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(
+            "1280x720+1920+0".parse::<ScreenRect>(),
+            Ok(ScreenRect {
+                left: 1920,
+                top: 0,
+                width: 1280,
+                height: 720
+            })
+        );
+        assert_eq!("800x600".parse::<ScreenRect>().map(|x| x.left), Ok(0));
+        assert!("800x600+1".parse::<ScreenRect>().is_err());
+        assert!("0x600".parse::<ScreenRect>().is_err());
+        assert_eq!(
+            parse_mat_area("simple"),
+            Ok(get_mat(MatType::SimpleMat).rect)
+        );
+        assert_eq!(parse_mat_area("0, 0, 100, 50").map(|x| x.width()), Ok(100));
+        assert!(parse_mat_area("100,0,0,50").is_err());
+    }
+
+    #[test]
+    fn mat_pointer() {
+        let start = time::Instant::now();
+        let at = |ms: u64| start + time::Duration::from_millis(ms);
+        let config = PointerConfig {
+            area: Some(parse_mat_area("100,100,300,200").unwrap()),
+            screen: "201x101+10+0".parse().unwrap(),
+            smoothing: 0.0,
+            ..PointerConfig::default()
+        };
+        let mut pointer = MatPointer::new(config);
+        assert_eq!(
+            pointer.update_position(&position(100, 100), at(0)),
+            Some((10, 0))
+        );
+        assert_eq!(
+            pointer.update_position(&position(200, 150), at(10)),
+            Some((110, 50))
+        );
+        assert_eq!(pointer.update_position(&position(200, 150), at(20)), None);
+        // clamped into the screen
+        assert_eq!(
+            pointer.update_position(&position(400, 50), at(30)),
+            Some((210, 0))
+        );
+
+        let mut pointer = MatPointer::new(PointerConfig {
+            smoothing: 0.5,
+            ..config
+        });
+        pointer.update_position(&position(100, 100), at(0));
+        assert_eq!(
+            pointer.update_position(&position(300, 100), at(10)),
+            Some((110, 0))
+        );
+        // short miss keeps the smoothing
+        pointer.lift(at(20));
+        assert!(!pointer.is_lifted(at(100)));
+        assert_eq!(
+            pointer.update_position(&position(300, 100), at(100)),
+            Some((160, 0))
+        );
+        // placed again after the lift-off
+        pointer.lift(at(200));
+        pointer.lift(at(300));
+        assert!(pointer.is_lifted(at(500)));
+        assert_eq!(
+            pointer.update_position(&position(100, 200), at(500)),
+            Some((10, 100))
+        );
+    }
+
+    #[test]
+    fn detected_area() {
+        let start = time::Instant::now();
+        let mut pointer = MatPointer::new(PointerConfig {
+            smoothing: 0.0,
+            ..PointerConfig::default()
+        });
+        assert_eq!(pointer.update_position(&position(10, 10), start), None);
+        let center = get_mat(MatType::Gesundroid).center();
+        assert_eq!(
+            pointer.update_position(&position(center.0, center.1), start),
+            Some((960, 540))
+        );
+        assert_eq!(pointer.get_area(), Some(get_mat(MatType::Gesundroid).rect));
+    }
+}
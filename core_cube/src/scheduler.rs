@@ -0,0 +1,569 @@
+/* Beat-synchronized scheduler: writes commands on the beats of a monotonic clock */
+
+use log::{debug, info};
+use std::sync::Arc;
+use std::time;
+
+use crate::clock::*;
+use crate::sequence::Timeline;
+use crate::transport::{CoreCubeTransport, CoreCubeUuidName};
+
+// weight of a new latency sample (exponential moving average)
+const LATENCY_SMOOTHING: f64 = 0.125;
+
+fn check_tempo(tempo: f32) -> std::result::Result<f32, String> {
+    if tempo.is_finite() && tempo > 0.0 {
+        Ok(tempo)
+    } else {
+        Err(format!("invalid tempo {}", tempo))
+    }
+}
+
+fn seconds_per_beat(tempo: f32) -> f64 {
+    60.0 / tempo as f64
+}
+
+// signed seconds from `base` to `time`
+fn signed_seconds(base: time::Instant, time: time::Instant) -> f64 {
+    if time >= base {
+        (time - base).as_secs_f64()
+    } else {
+        -(base - time).as_secs_f64()
+    }
+}
+
+fn add_signed_seconds(base: time::Instant, seconds: f64) -> time::Instant {
+    if seconds >= 0.0 {
+        base + time::Duration::from_secs_f64(seconds)
+    } else {
+        base - time::Duration::from_secs_f64(-seconds)
+    }
+}
+
+/// Beat position of a monotonic clock with tempo changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeatClock {
+    start_time: Option<time::Instant>,
+    /// (start beat, tempo) sorted by the beat. The first one starts at beat 0.
+    tempo_map: Vec<(f64, f32)>,
+}
+
+impl BeatClock {
+    pub fn new(tempo: f32) -> std::result::Result<Self, String> {
+        Ok(Self {
+            start_time: None,
+            tempo_map: vec![(0.0, check_tempo(tempo)?)],
+        })
+    }
+
+    pub fn start(&mut self, now: time::Instant) {
+        self.start_time = Some(now);
+    }
+
+    /// Time of beat 0 (None: not started)
+    pub fn get_start_time(&self) -> Option<time::Instant> {
+        self.start_time
+    }
+
+    /// Tempo at `beat`
+    pub fn get_tempo(&self, beat: f64) -> f32 {
+        self.tempo_map
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= beat)
+            .unwrap_or(&self.tempo_map[0])
+            .1
+    }
+
+    /// Change the tempo from `beat`. The tempo changes later than `beat` are kept.
+    pub fn set_tempo_at(&mut self, beat: f64, tempo: f32) -> std::result::Result<(), String> {
+        let tempo = check_tempo(tempo)?;
+        let beat = beat.max(0.0);
+        match self.tempo_map.iter().position(|(start, _)| *start >= beat) {
+            Some(i) if self.tempo_map[i].0 == beat => self.tempo_map[i].1 = tempo,
+            Some(i) => self.tempo_map.insert(i, (beat, tempo)),
+            None => self.tempo_map.push((beat, tempo)),
+        }
+        Ok(())
+    }
+
+    // seconds from beat 0
+    fn get_seconds(&self, beat: f64) -> f64 {
+        let mut seconds = 0.0;
+        for (i, (start, tempo)) in self.tempo_map.iter().enumerate() {
+            let end = self.tempo_map.get(i + 1).map_or(f64::INFINITY, |x| x.0);
+            if beat < end {
+                return seconds + (beat - start) * seconds_per_beat(*tempo);
+            }
+            seconds += (end - start) * seconds_per_beat(*tempo);
+        }
+        seconds
+    }
+
+    /// Time of `beat` (None: not started)
+    pub fn get_time(&self, beat: f64) -> Option<time::Instant> {
+        let start_time = self.start_time?;
+        Some(add_signed_seconds(start_time, self.get_seconds(beat)))
+    }
+
+    /// Beat at `now` (negative before the start, 0.0: not started)
+    pub fn get_beat(&self, now: time::Instant) -> f64 {
+        let start_time = match self.start_time {
+            Some(x) => x,
+            None => return 0.0,
+        };
+        let mut seconds = signed_seconds(start_time, now);
+        for (i, (start, tempo)) in self.tempo_map.iter().enumerate() {
+            let length = match self.tempo_map.get(i + 1) {
+                Some((end, _)) => (end - start) * seconds_per_beat(*tempo),
+                None => f64::INFINITY,
+            };
+            if seconds < length {
+                return start + seconds / seconds_per_beat(*tempo);
+            }
+            seconds -= length;
+        }
+        unreachable!()
+    }
+}
+
+/// Beat callback parameter
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BeatEvent {
+    pub beat: u64,
+    pub bar: u64,
+    /// Position in the bar (0: the first beat of the bar)
+    pub beat_in_bar: u32,
+    pub tempo: f32,
+}
+
+impl BeatEvent {
+    pub fn is_bar_start(&self) -> bool {
+        self.beat_in_bar == 0
+    }
+}
+
+pub type BeatHandlerFunction = Box<dyn FnMut(&BeatEvent)>;
+
+/// Difference between the time a command reaches the cube and its beat
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct DriftStats {
+    pub count: u64,
+    /// Last drift [us] (positive: late)
+    pub last_us: i64,
+    /// Largest absolute drift [us]
+    pub max_us: i64,
+    total_us: i64,
+}
+
+impl DriftStats {
+    pub fn update(&mut self, drift_us: i64) {
+        self.count += 1;
+        self.last_us = drift_us;
+        self.max_us = self.max_us.max(drift_us.abs());
+        self.total_us += drift_us.abs();
+    }
+
+    /// Mean of the absolute drift [us]
+    pub fn get_mean_us(&self) -> i64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.total_us / self.count as i64
+        }
+    }
+}
+
+/// Estimate the time from the write to the cube (half of the write with response)
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct LatencyEstimator {
+    latency: time::Duration,
+    samples: u64,
+}
+
+impl LatencyEstimator {
+    pub fn get_latency(&self) -> time::Duration {
+        self.latency
+    }
+
+    /// Replace the estimation with a measured latency
+    pub fn set_latency(&mut self, latency: time::Duration) {
+        self.latency = latency;
+        self.samples = 1;
+    }
+
+    pub fn update_write_time(&mut self, write_time: time::Duration) {
+        let sample = write_time.as_secs_f64() / 2.0;
+        let latency = if self.samples == 0 {
+            sample
+        } else {
+            let latency = self.latency.as_secs_f64();
+            latency + (sample - latency) * LATENCY_SMOOTHING
+        };
+        self.latency = time::Duration::from_secs_f64(latency);
+        self.samples += 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BeatCommand {
+    beat: f64,
+    characteristic: CoreCubeUuidName,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct CubeQueue {
+    commands: Vec<BeatCommand>,
+    next: usize,
+    latency: LatencyEstimator,
+    drift: DriftStats,
+}
+
+/// Write the commands of the cubes on the beats.
+/// The commands are written ahead of the beat by the latency of each cube.
+pub struct BeatScheduler {
+    beat_clock: BeatClock,
+    /// Clock to measure the time of the writes
+    clock: Arc<dyn Clock>,
+    beats_per_bar: u32,
+    cubes: Vec<CubeQueue>,
+    end_beat: f64,
+    next_beat: u64,
+    measure_latency: bool,
+    beat_handlers: Vec<BeatHandlerFunction>,
+}
+
+impl BeatScheduler {
+    pub fn new(
+        tempo: f32,
+        beats_per_bar: u32,
+        cube_count: usize,
+    ) -> std::result::Result<Self, String> {
+        Ok(Self {
+            beat_clock: BeatClock::new(tempo)?,
+            clock: get_system_clock(),
+            beats_per_bar: beats_per_bar.max(1),
+            cubes: vec![CubeQueue::default(); cube_count],
+            end_beat: 0.0,
+            next_beat: 0,
+            measure_latency: true,
+            beat_handlers: Vec::new(),
+        })
+    }
+
+    pub fn get_clock(&self) -> &BeatClock {
+        &self.beat_clock
+    }
+
+    pub fn get_beat(&self, now: time::Instant) -> f64 {
+        self.beat_clock.get_beat(now)
+    }
+
+    /// End of the scheduled commands and timelines
+    pub fn get_end_beat(&self) -> f64 {
+        self.end_beat
+    }
+
+    /// Start beat 0 at `now`. The first poll starts the clock if not started.
+    pub fn start(&mut self, now: time::Instant) {
+        self.beat_clock.start(now);
+    }
+
+    /// Change the tempo at `now`. The scheduled commands keep their beats.
+    pub fn set_tempo(&mut self, now: time::Instant, tempo: f32) -> std::result::Result<(), String> {
+        let beat = self.beat_clock.get_beat(now);
+        self.set_tempo_at(beat, tempo)
+    }
+
+    pub fn set_tempo_at(&mut self, beat: f64, tempo: f32) -> std::result::Result<(), String> {
+        info!("tempo {} from beat {:.2}", tempo, beat);
+        self.beat_clock.set_tempo_at(beat, tempo)
+    }
+
+    /// Clock to measure the time of the writes (default: the system clock)
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Measure the latency from the time of the writes (default: true)
+    pub fn set_latency_measurement(&mut self, enable: bool) {
+        self.measure_latency = enable;
+    }
+
+    pub fn set_latency(&mut self, cube: usize, latency: time::Duration) {
+        self.cubes[cube].latency.set_latency(latency);
+    }
+
+    pub fn get_latency(&self, cube: usize) -> time::Duration {
+        self.cubes[cube].latency.get_latency()
+    }
+
+    pub fn get_drift(&self, cube: usize) -> DriftStats {
+        self.cubes[cube].drift
+    }
+
+    pub fn add_beat_handler(&mut self, handler: BeatHandlerFunction) {
+        self.beat_handlers.push(handler);
+    }
+
+    pub fn schedule(
+        &mut self,
+        cube: usize,
+        beat: f64,
+        characteristic: CoreCubeUuidName,
+        bytes: Vec<u8>,
+    ) {
+        let queue = &mut self.cubes[cube];
+        // keep the scheduled order of the commands on the same beat
+        let index = queue.next
+            + queue.commands[queue.next..]
+                .iter()
+                .position(|x| x.beat > beat)
+                .unwrap_or(queue.commands.len() - queue.next);
+        queue.commands.insert(
+            index,
+            BeatCommand {
+                beat,
+                characteristic,
+                bytes,
+            },
+        );
+        self.end_beat = self.end_beat.max(beat);
+    }
+
+    /// Schedule a timeline compiled with `beat` length from `start_beat`.
+    /// Returns the end beat of the timeline.
+    /// Durations in the commands (e.g. motor time) are not changed by tempo changes.
+    pub fn schedule_timeline(
+        &mut self,
+        cube: usize,
+        start_beat: f64,
+        timeline: &Timeline,
+        beat: time::Duration,
+    ) -> f64 {
+        let beat_seconds = beat.as_secs_f64();
+        for command in &timeline.commands {
+            self.schedule(
+                cube,
+                start_beat + command.time.as_secs_f64() / beat_seconds,
+                command.characteristic,
+                command.bytes.clone(),
+            );
+        }
+        let end_beat = start_beat + timeline.length.as_secs_f64() / beat_seconds;
+        self.end_beat = self.end_beat.max(end_beat);
+        end_beat
+    }
+
+    // call the beat handlers up to `now`
+    fn update_beats(&mut self, now: time::Instant) {
+        let beat = self.beat_clock.get_beat(now);
+        while self.next_beat as f64 <= beat {
+            let event = BeatEvent {
+                beat: self.next_beat,
+                bar: self.next_beat / self.beats_per_bar as u64,
+                beat_in_bar: (self.next_beat % self.beats_per_bar as u64) as u32,
+                tempo: self.beat_clock.get_tempo(self.next_beat as f64),
+            };
+            for handler in self.beat_handlers.iter_mut() {
+                handler(&event);
+            }
+            self.next_beat += 1;
+        }
+    }
+
+    /// Write the commands due until `now` plus the latency of each cube.
+    /// Returns true when all commands are written and the end beat has passed.
+    pub fn poll<T: CoreCubeTransport + ?Sized>(
+        &mut self,
+        cubes: &[&T],
+        now: time::Instant,
+    ) -> std::result::Result<bool, String> {
+        if self.beat_clock.get_start_time().is_none() {
+            self.beat_clock.start(now);
+        }
+        self.update_beats(now);
+        for (queue, cube) in self.cubes.iter_mut().zip(cubes) {
+            while let Some(command) = queue.commands.get(queue.next) {
+                let latency = queue.latency.get_latency();
+                let beat_time = self.beat_clock.get_time(command.beat).unwrap();
+                if now + latency < beat_time {
+                    break;
+                }
+                let write_start = self.clock.now();
+                cube.write(command.characteristic, &command.bytes)?;
+                let write_time = self.clock.now().saturating_duration_since(write_start);
+                // the command reaches the cube `latency` after `now`
+                let drift_us = (signed_seconds(beat_time, now + latency) * 1e6).round() as i64;
+                debug!(
+                    "beat {:.2}: {} {:?} (drift {}us)",
+                    command.beat, command.characteristic, command.bytes, drift_us
+                );
+                queue.drift.update(drift_us);
+                if self.measure_latency {
+                    queue.latency.update_write_time(write_time);
+                }
+                queue.next += 1;
+            }
+        }
+        Ok(self.is_finished(now))
+    }
+
+    pub fn is_finished(&self, now: time::Instant) -> bool {
+        self.beat_clock.get_start_time().is_some()
+            && self.cubes.iter().all(|x| x.next >= x.commands.len())
+            && self.beat_clock.get_beat(now) >= self.end_beat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::recording::RecordingCube;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn ms(x: u64) -> time::Duration {
+        time::Duration::from_millis(x)
+    }
+
+    #[test]
+    fn beat_clock() {
+        let start = time::Instant::now();
+        let mut clock = BeatClock::new(120.0).unwrap();
+        assert_eq!(clock.get_time(1.0), None);
+        clock.start(start);
+        assert_eq!(clock.get_time(2.0), Some(start + ms(1000)));
+        assert!((clock.get_beat(start + ms(250)) - 0.5).abs() < 1e-9);
+
+        // 60bpm from beat 4 (2s)
+        clock.set_tempo_at(4.0, 60.0).unwrap();
+        assert_eq!(clock.get_tempo(3.9), 120.0);
+        assert_eq!(clock.get_tempo(4.0), 60.0);
+        assert_eq!(clock.get_time(2.0), Some(start + ms(1000)));
+        assert_eq!(clock.get_time(6.0), Some(start + ms(4000)));
+        assert!((clock.get_beat(start + ms(3500)) - 5.5).abs() < 1e-9);
+        assert!(clock.set_tempo_at(8.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn latency_compensation() {
+        let start = time::Instant::now();
+        let cube = RecordingCube::default();
+        let mut scheduler = BeatScheduler::new(120.0, 4, 1).unwrap();
+        scheduler.set_latency_measurement(false);
+        scheduler.set_latency(0, ms(40));
+        scheduler.schedule(0, 1.0, CoreCubeUuidName::LightCtrl, vec![1]);
+        scheduler.schedule(0, 1.0, CoreCubeUuidName::LightCtrl, vec![2]);
+        scheduler.schedule(0, 0.0, CoreCubeUuidName::LightCtrl, vec![0]);
+
+        assert_eq!(scheduler.poll(&[&cube], start), Ok(false));
+        assert_eq!(*cube.written.borrow(), vec![vec![0]]);
+        // beat 1 is at 500ms: written 40ms ahead
+        assert_eq!(scheduler.poll(&[&cube], start + ms(450)), Ok(false));
+        assert_eq!(cube.written.borrow().len(), 1);
+        assert_eq!(scheduler.poll(&[&cube], start + ms(460)), Ok(false));
+        assert_eq!(*cube.written.borrow(), vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(scheduler.poll(&[&cube], start + ms(500)), Ok(true));
+
+        let drift = scheduler.get_drift(0);
+        assert_eq!(drift.count, 3);
+        assert_eq!(drift.last_us, 0);
+        assert_eq!(drift.max_us, 40_000);
+    }
+
+    #[test]
+    fn tempo_change_and_beats() {
+        let start = time::Instant::now();
+        let cube = RecordingCube::default();
+        let mut scheduler = BeatScheduler::new(60.0, 4, 1).unwrap();
+        scheduler.set_latency_measurement(false);
+        let timeline = Timeline {
+            commands: vec![crate::sequence::TimedCommand {
+                time: ms(2000),
+                characteristic: CoreCubeUuidName::MotorCtrl,
+                bytes: vec![1],
+            }],
+            length: ms(4000),
+        };
+        // compiled with 60bpm (1s/beat)
+        assert_eq!(
+            scheduler.schedule_timeline(0, 2.0, &timeline, ms(1000)),
+            6.0
+        );
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let e = events.clone();
+        scheduler.add_beat_handler(Box::new(move |event| e.borrow_mut().push(*event)));
+
+        assert_eq!(scheduler.poll(&[&cube], start), Ok(false));
+        // 120bpm from 1s: beat 4 is at 2.5s
+        scheduler.set_tempo(start + ms(1000), 120.0).unwrap();
+        assert_eq!(scheduler.poll(&[&cube], start + ms(2400)), Ok(false));
+        assert!(cube.written.borrow().is_empty());
+        assert_eq!(scheduler.poll(&[&cube], start + ms(2500)), Ok(false));
+        assert_eq!(cube.written.borrow().len(), 1);
+        assert_eq!(scheduler.poll(&[&cube], start + ms(3500)), Ok(true));
+
+        let events = events.borrow();
+        assert_eq!(
+            events.iter().map(|x| x.beat).collect::<Vec<u64>>(),
+            vec![0, 1, 2, 3, 4, 5, 6]
+        );
+        assert!(events[4].is_bar_start());
+        assert_eq!(events[4].bar, 1);
+        assert_eq!(events[1].tempo, 120.0);
+        assert_eq!(events[5].beat_in_bar, 1);
+    }
+
+    // write with response which takes `write_time` of the clock
+    struct SlowCube {
+        clock: ManualClock,
+        write_time: time::Duration,
+    }
+
+    impl CoreCubeTransport for SlowCube {
+        fn read(&self, _: CoreCubeUuidName) -> std::result::Result<Vec<u8>, String> {
+            Err("not supported".to_string())
+        }
+
+        fn write(&self, _: CoreCubeUuidName, _: &[u8]) -> std::result::Result<bool, String> {
+            self.clock.advance(self.write_time);
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn latency_measurement() {
+        let clock = ManualClock::new();
+        let cube = SlowCube {
+            clock: clock.clone(),
+            write_time: ms(60),
+        };
+        let mut scheduler = BeatScheduler::new(120.0, 4, 1).unwrap();
+        scheduler.set_clock(Arc::new(clock.clone()));
+        for beat in 0..8 {
+            scheduler.schedule(0, beat as f64, CoreCubeUuidName::LightCtrl, vec![1]);
+        }
+        while !scheduler.poll(&[&cube], clock.now()).unwrap() {
+            clock.sleep(ms(10));
+        }
+        // half of the write time
+        assert_eq!(scheduler.get_latency(0), ms(30));
+        // written ahead of the beats by the latency
+        let drift = scheduler.get_drift(0);
+        assert_eq!(drift.count, 8);
+        assert!(drift.last_us.abs() <= 10_000, "{:?}", drift);
+    }
+
+    #[test]
+    fn latency_estimation() {
+        let mut latency = LatencyEstimator::default();
+        latency.update_write_time(ms(40));
+        assert_eq!(latency.get_latency(), ms(20));
+        latency.update_write_time(ms(200));
+        // 20ms + (100ms - 20ms) / 8
+        assert_eq!(latency.get_latency(), ms(30));
+    }
+}
@@ -0,0 +1,143 @@
+/* Sensor information notification decoder */
+
+use log::debug;
+use std::fmt;
+
+/// Which side of the cube faces up
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Posture {
+    Unknown,
+    Normal,
+    Reverse,
+    Downward,
+    Upward,
+    RightSideUp,
+    LeftSideUp,
+}
+
+impl From<u8> for Posture {
+    fn from(value: u8) -> Self {
+        match value {
+            0x01 => Posture::Normal,
+            0x02 => Posture::Reverse,
+            0x03 => Posture::Downward,
+            0x04 => Posture::Upward,
+            0x05 => Posture::RightSideUp,
+            0x06 => Posture::LeftSideUp,
+            _ => Posture::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Posture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Motion detection notification (0x01)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MotionDetection {
+    pub horizontal: bool,
+    pub collision: bool,
+    pub double_tap: bool,
+    pub posture: Posture,
+    /// Shake level (0: not shaken)
+    pub shake: u8,
+}
+
+pub fn get_motion_detection(data: &[u8]) -> Option<MotionDetection> {
+    match data {
+        [0x01, horizontal, collision, double_tap, posture, rest @ ..] => Some(MotionDetection {
+            horizontal: *horizontal == 0x01,
+            collision: *collision == 0x01,
+            double_tap: *double_tap == 0x01,
+            posture: Posture::from(*posture),
+            shake: rest.first().copied().unwrap_or(0),
+        }),
+        _ => None,
+    }
+}
+
+/// Posture angle notification (0x03) in Euler angles [deg]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct PostureAngle {
+    pub roll: i16,
+    pub pitch: i16,
+    pub yaw: i16,
+}
+
+fn get_i16(data: &[u8], index: usize) -> i16 {
+    i16::from_le_bytes([data[index], data[index + 1]])
+}
+
+/// Posture angle (Euler angles) notification
+/// Quaternion / high precision notifications are not supported.
+pub fn get_posture_angle(data: &[u8]) -> Option<PostureAngle> {
+    match data {
+        [0x03, 0x01, ..] if data.len() >= 8 => Some(PostureAngle {
+            roll: get_i16(data, 2),
+            pitch: get_i16(data, 4),
+            yaw: get_i16(data, 6),
+        }),
+        [0x03, ..] => {
+            debug!("unsupported posture angle type {:?}", data);
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Configuration to enable the posture angle (Euler angles) notification
+/// every `interval_ms` (10ms unit, 0: disable)
+pub fn get_posture_angle_config_bytes(interval_ms: u16) -> Vec<u8> {
+    let interval = (interval_ms / 10).min(u8::MAX as u16) as u8;
+    // notify only when the angle changes
+    vec![0x1d, 0x00, 0x01, interval, 0x01]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motion_detection() {
+        assert_eq!(
+            get_motion_detection(&[0x01, 0x01, 0x00, 0x01, 0x05, 0x03]),
+            Some(MotionDetection {
+                horizontal: true,
+                collision: false,
+                double_tap: true,
+                posture: Posture::RightSideUp,
+                shake: 3
+            })
+        );
+        // firmware 2.0.0 without shake
+        assert_eq!(
+            get_motion_detection(&[0x01, 0x00, 0x01, 0x00, 0x09]).map(|x| (x.posture, x.shake)),
+            Some((Posture::Unknown, 0))
+        );
+        assert_eq!(get_motion_detection(&[0x03, 0x01, 0x00, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn posture_angle() {
+        assert_eq!(
+            get_posture_angle(&[0x03, 0x01, 0x05, 0x00, 0xfe, 0xff, 0xb4, 0x00]),
+            Some(PostureAngle {
+                roll: 5,
+                pitch: -2,
+                yaw: 180
+            })
+        );
+        assert_eq!(get_posture_angle(&[0x03, 0x02, 0x00]), None);
+        assert_eq!(
+            get_posture_angle(&[0x01, 0x00, 0x00, 0x00, 0x01, 0x00]),
+            None
+        );
+        assert_eq!(
+            get_posture_angle_config_bytes(100),
+            vec![0x1d, 0x00, 0x01, 10, 0x01]
+        );
+    }
+}
@@ -0,0 +1,617 @@
+/* Action sequences: moves, lights and sounds played by a cube */
+
+use log::{debug, info};
+use std::fmt;
+use std::str::FromStr;
+use std::time;
+
+use crate::light::{get_light_bytes, LightColor};
+use crate::mat::Mat;
+use crate::motor::*;
+use crate::sound::{get_midi_bytes, Note};
+use crate::transport::{CoreCubeTransport, CoreCubeUuidName};
+
+/// Length of a step: beats of the tempo or a fixed time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StepTime {
+    Beats(f32),
+    Millis(u64),
+}
+
+impl StepTime {
+    pub fn to_duration(&self, beat: time::Duration) -> time::Duration {
+        match *self {
+            // round to microseconds to keep the beats on the grid
+            StepTime::Beats(beats) => time::Duration::from_micros(
+                (beat.as_micros() as f64 * beats.max(0.0) as f64).round() as u64,
+            ),
+            StepTime::Millis(ms) => time::Duration::from_millis(ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Motor control (signed speed values). The motors stop after `motor_time`
+    /// (None: keep running until the next command). The next step starts after `term`.
+    Move {
+        left: i16,
+        right: i16,
+        motor_time: Option<StepTime>,
+        term: StepTime,
+    },
+    /// Target position relative to the center of the mat (skipped without mat)
+    MoveTo {
+        dx: i16,
+        dy: i16,
+        angle: Option<f32>,
+        config: TargetMoveConfig,
+        term: StepTime,
+    },
+    Wait(StepTime),
+    /// Turn on the light for `light_time` (None: until the next command). Takes no time.
+    Light {
+        color: LightColor,
+        light_time: Option<StepTime>,
+    },
+    /// Play (MIDI note number, length) list. Takes no time.
+    Sound(Vec<(u8, StepTime)>),
+    /// Start all branches at the same time. Ends with the longest branch.
+    Parallel(Vec<Vec<Step>>),
+    Loop {
+        count: usize,
+        steps: Vec<Step>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Sequence {
+    pub steps: Vec<Step>,
+}
+
+impl Sequence {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Self { steps }
+    }
+
+    pub fn then(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Resolve the beats and the mat coordinates into a timeline
+    pub fn compile(&self, context: &SequenceContext) -> Timeline {
+        let mut commands: Vec<TimedCommand> = Vec::new();
+        let length = compile_steps(&self.steps, context, time::Duration::ZERO, &mut commands);
+        // keep the written order of the commands at the same time
+        commands.sort_by_key(|x| x.time);
+        Timeline { commands, length }
+    }
+}
+
+/// Parameters to compile a sequence
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SequenceContext {
+    pub beat: time::Duration,
+    pub mat: Option<Mat>,
+}
+
+impl Default for SequenceContext {
+    fn default() -> Self {
+        Self {
+            beat: time::Duration::from_millis(600),
+            mat: None,
+        }
+    }
+}
+
+/// Command to write at `time` from the start of the sequence
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedCommand {
+    pub time: time::Duration,
+    pub characteristic: CoreCubeUuidName,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Timeline {
+    pub commands: Vec<TimedCommand>,
+    pub length: time::Duration,
+}
+
+fn timed(time: time::Duration, characteristic: CoreCubeUuidName, bytes: Vec<u8>) -> TimedCommand {
+    TimedCommand {
+        time,
+        characteristic,
+        bytes,
+    }
+}
+
+// compile the steps starting at `start` and return the end time
+// (the commands are sorted later)
+fn compile_steps(
+    steps: &[Step],
+    context: &SequenceContext,
+    start: time::Duration,
+    commands: &mut Vec<TimedCommand>,
+) -> time::Duration {
+    let mut time = start;
+    for step in steps {
+        match step {
+            Step::Move {
+                left,
+                right,
+                motor_time,
+                term,
+            } => {
+                let mut control = MotorControl::new(*left, *right);
+                if let Some(motor_time) = motor_time {
+                    control = control.with_duration(motor_time.to_duration(context.beat));
+                }
+                commands.push(timed(
+                    time,
+                    CoreCubeUuidName::MotorCtrl,
+                    control.get_bytes(),
+                ));
+                time += term.to_duration(context.beat);
+            }
+            Step::MoveTo {
+                dx,
+                dy,
+                angle,
+                config,
+                term,
+            } => {
+                match context.mat {
+                    Some(mat) => {
+                        let (x, y) = mat.from_relative(*dx, *dy);
+                        let target = Waypoint::new(x, y, *angle);
+                        let bytes = get_target_bytes(0, config, &target);
+                        commands.push(timed(time, CoreCubeUuidName::MotorCtrl, bytes));
+                    }
+                    None => debug!("sequence: no mat, skip {:?}", step),
+                }
+                time += term.to_duration(context.beat);
+            }
+            Step::Wait(term) => time += term.to_duration(context.beat),
+            Step::Light { color, light_time } => {
+                let light_time = light_time.map(|x| x.to_duration(context.beat));
+                let bytes = get_light_bytes(*color, light_time);
+                commands.push(timed(time, CoreCubeUuidName::LightCtrl, bytes));
+            }
+            Step::Sound(notes) => {
+                let notes: Vec<Note> = notes
+                    .iter()
+                    .map(|(note, length)| Note::new(*note, length.to_duration(context.beat)))
+                    .collect();
+                let bytes = get_midi_bytes(1, &notes);
+                commands.push(timed(time, CoreCubeUuidName::SoundCtrl, bytes));
+            }
+            Step::Parallel(branches) => {
+                let mut end = time;
+                for branch in branches {
+                    end = end.max(compile_steps(branch, context, time, commands));
+                }
+                time = end;
+            }
+            Step::Loop { count, steps } => {
+                for _ in 0..*count {
+                    time = compile_steps(steps, context, time, commands);
+                }
+            }
+        }
+    }
+    time
+}
+
+/// Play a timeline on a cube
+///
+/// `poll()` writes the commands whose time has come. Call it repeatedly
+/// (e.g. every 10ms) until it returns true.
+pub struct SequenceExecutor {
+    timeline: Timeline,
+    next: usize,
+    start_time: Option<time::Instant>,
+}
+
+impl SequenceExecutor {
+    pub fn new(timeline: Timeline) -> Self {
+        Self {
+            timeline,
+            next: 0,
+            start_time: None,
+        }
+    }
+
+    pub fn get_timeline(&self) -> &Timeline {
+        &self.timeline
+    }
+
+    /// Start time of the timeline (None: not started)
+    pub fn get_start_time(&self) -> Option<time::Instant> {
+        self.start_time
+    }
+
+    pub fn start(&mut self, now: time::Instant) {
+        self.start_time = Some(now);
+        self.next = 0;
+    }
+
+    pub fn is_finished(&self, now: time::Instant) -> bool {
+        match self.start_time {
+            Some(start_time) => {
+                self.next >= self.timeline.commands.len()
+                    && now.saturating_duration_since(start_time) >= self.timeline.length
+            }
+            None => false,
+        }
+    }
+
+    /// Time to write the next command (None: no more commands)
+    pub fn get_next_time(&self) -> Option<time::Instant> {
+        let start_time = self.start_time?;
+        self.timeline
+            .commands
+            .get(self.next)
+            .map(|x| start_time + x.time)
+    }
+
+    /// Write the commands due until `now`. Starts the timeline at the first call.
+    pub fn poll<T: CoreCubeTransport + ?Sized>(
+        &mut self,
+        cube: &T,
+        now: time::Instant,
+    ) -> std::result::Result<bool, String> {
+        let start_time = *self.start_time.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start_time);
+        while let Some(command) = self.timeline.commands.get(self.next) {
+            if command.time > elapsed {
+                break;
+            }
+            debug!(
+                "sequence: {:?} {} {:?}",
+                command.time, command.characteristic, command.bytes
+            );
+            cube.write(command.characteristic, &command.bytes)?;
+            self.next += 1;
+        }
+        Ok(self.is_finished(now))
+    }
+}
+
+/// Moves of the tokyo2020 example
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BuiltinMove {
+    SwingR,
+    SwingL,
+    Step2,
+    Step4,
+    Step8,
+    StepRL2,
+    RollingR,
+    RollingL,
+    GetReady,
+    HomePosition,
+    ByeBye,
+    Circle1,
+    Circle2,
+}
+
+pub const BUILTIN_MOVES: [BuiltinMove; 13] = [
+    BuiltinMove::SwingR,
+    BuiltinMove::SwingL,
+    BuiltinMove::Step2,
+    BuiltinMove::Step4,
+    BuiltinMove::Step8,
+    BuiltinMove::StepRL2,
+    BuiltinMove::RollingR,
+    BuiltinMove::RollingL,
+    BuiltinMove::GetReady,
+    BuiltinMove::HomePosition,
+    BuiltinMove::ByeBye,
+    BuiltinMove::Circle1,
+    BuiltinMove::Circle2,
+];
+
+impl fmt::Display for BuiltinMove {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for BuiltinMove {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        BUILTIN_MOVES
+            .iter()
+            .find(|x| x.to_string().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or(format!("unknown move: {}", s))
+    }
+}
+
+const STEP_SPEED: i16 = 20;
+const ROLLING_SPEED: i16 = 37;
+const CIRCLE_SPEED: i16 = 50;
+const CIRCLE_TERM_MS: u64 = 7500;
+
+/// Move for a beat. The motors stop a little before the next beat.
+fn beat_move(left: i16, right: i16) -> Step {
+    Step::Move {
+        left,
+        right,
+        motor_time: Some(StepTime::Beats(0.8)),
+        term: StepTime::Beats(1.0),
+    }
+}
+
+fn timed_move(left: i16, right: i16, term_ms: u64) -> Step {
+    Step::Move {
+        left,
+        right,
+        motor_time: None,
+        term: StepTime::Millis(term_ms),
+    }
+}
+
+fn move_to(dx: i16, dy: i16, angle: f32, max_speed: u8, term_ms: u64) -> Step {
+    let speed_change = if max_speed > 50 {
+        SpeedChangeType::AccelerateAndDecelerate
+    } else {
+        SpeedChangeType::Constant
+    };
+    Step::MoveTo {
+        dx,
+        dy,
+        angle: Some(angle),
+        config: TargetMoveConfig {
+            timeout: 4,
+            moving_type: MovingType::MoveWhileRotating,
+            max_speed,
+            speed_change,
+        },
+        term: StepTime::Millis(term_ms),
+    }
+}
+
+/// Sequence of the built-in move for the cube `cube_id` (0, 1, ...)
+pub fn get_builtin_sequence(builtin: BuiltinMove, cube_id: usize, has_mat: bool) -> Sequence {
+    let (fw, rv) = (STEP_SPEED, -STEP_SPEED);
+    let (half_fw, half_rv) = (STEP_SPEED / 2, -STEP_SPEED / 2);
+    let steps = match builtin {
+        BuiltinMove::SwingR => vec![
+            beat_move(fw, rv),
+            beat_move(rv, fw),
+            beat_move(rv, fw),
+            beat_move(fw, rv),
+        ],
+        BuiltinMove::SwingL => vec![
+            beat_move(rv, fw),
+            beat_move(fw, rv),
+            beat_move(fw, rv),
+            beat_move(rv, fw),
+        ],
+        BuiltinMove::Step2 => vec![
+            beat_move(fw, fw),
+            beat_move(rv, rv),
+            beat_move(rv, rv),
+            beat_move(fw, fw),
+        ],
+        BuiltinMove::Step4 => vec![
+            beat_move(fw, fw),
+            beat_move(fw, fw),
+            beat_move(rv, rv),
+            beat_move(rv, rv),
+        ],
+        BuiltinMove::Step8 => vec![
+            Step::Loop {
+                count: 4,
+                steps: vec![beat_move(fw, fw)],
+            },
+            Step::Loop {
+                count: 4,
+                steps: vec![beat_move(rv, rv)],
+            },
+        ],
+        BuiltinMove::StepRL2 => vec![
+            beat_move(fw, half_fw),
+            beat_move(half_fw, fw),
+            beat_move(half_fw, fw),
+            beat_move(fw, half_fw),
+            beat_move(half_rv, rv),
+            beat_move(rv, half_rv),
+            beat_move(rv, half_rv),
+            beat_move(half_rv, rv),
+        ],
+        BuiltinMove::RollingR | BuiltinMove::RollingL => {
+            let speed = if builtin == BuiltinMove::RollingR {
+                ROLLING_SPEED
+            } else {
+                -ROLLING_SPEED
+            };
+            vec![
+                Step::Move {
+                    left: speed,
+                    right: -speed,
+                    motor_time: Some(StepTime::Beats(4.0)),
+                    term: StepTime::Beats(1.0),
+                },
+                Step::Wait(StepTime::Beats(3.0)),
+            ]
+        }
+        BuiltinMove::GetReady | BuiltinMove::HomePosition if has_mat => {
+            let dx: i16 = match cube_id % 4 {
+                0 => -50,
+                1 => 50,
+                2 => -110,
+                _ => 110,
+            };
+            let y_offset = ((cube_id / 2) * 30) as i16;
+            if builtin == BuiltinMove::GetReady {
+                vec![move_to(dx, -60 + y_offset, 90.0, 80, 4000)]
+            } else {
+                vec![move_to(dx, -40 + y_offset, 90.0, 30, 400)]
+            }
+        }
+        BuiltinMove::GetReady => vec![
+            timed_move(30, 30, 3000),
+            // turn left
+            Step::Move {
+                left: -15,
+                right: 15,
+                motor_time: Some(StepTime::Millis(550)),
+                term: StepTime::Beats(1.0),
+            },
+            Step::Wait(StepTime::Millis(2000)),
+        ],
+        BuiltinMove::HomePosition => vec![],
+        BuiltinMove::ByeBye if has_mat => {
+            let dx: i16 = match cube_id % 2 {
+                0 => -170,
+                _ => 170,
+            };
+            let dy: i16 = if cube_id % 4 < 2 { -180 } else { 180 };
+            vec![
+                Step::Loop {
+                    count: 10,
+                    steps: vec![timed_move(30, 30, 100), timed_move(-30, -30, 100)],
+                },
+                timed_move(-30, -30, 100),
+                timed_move(0, 0, 30),
+                move_to(dx, dy, 270.0, 80, 5000),
+            ]
+        }
+        BuiltinMove::ByeBye => vec![],
+        // the cube draws a circle around the other cube
+        BuiltinMove::Circle1 if cube_id == 0 => {
+            vec![timed_move(CIRCLE_SPEED - 8, CIRCLE_SPEED, CIRCLE_TERM_MS)]
+        }
+        BuiltinMove::Circle2 if cube_id == 1 => {
+            vec![timed_move(
+                CIRCLE_SPEED,
+                CIRCLE_SPEED - 8,
+                CIRCLE_TERM_MS - 100,
+            )]
+        }
+        BuiltinMove::Circle1 => vec![timed_move(0, 0, CIRCLE_TERM_MS)],
+        BuiltinMove::Circle2 => vec![timed_move(0, 0, CIRCLE_TERM_MS - 100)],
+    };
+    info!("cube {}: {} ({} steps)", cube_id, builtin, steps.len());
+    Sequence::new(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat::{get_mat, MatType};
+    use crate::transport::recording::RecordingCube;
+
+    fn ms(ms: u64) -> time::Duration {
+        time::Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn compile_builtin() {
+        let context = SequenceContext {
+            beat: ms(500),
+            mat: None,
+        };
+        let timeline = get_builtin_sequence(BuiltinMove::Step2, 0, false).compile(&context);
+        assert_eq!(timeline.length, ms(2000));
+        let times: Vec<time::Duration> = timeline.commands.iter().map(|x| x.time).collect();
+        assert_eq!(times, vec![ms(0), ms(500), ms(1000), ms(1500)]);
+        assert_eq!(
+            timeline.commands[0].bytes,
+            vec![0x02, 0x01, 0x01, 20, 0x02, 0x01, 20, 40]
+        );
+        assert_eq!(
+            get_builtin_sequence(BuiltinMove::Step8, 0, false)
+                .compile(&context)
+                .length,
+            ms(4000)
+        );
+
+        // target positions need the mat
+        assert!(get_builtin_sequence(BuiltinMove::ByeBye, 0, false).is_empty());
+        let context = SequenceContext {
+            beat: ms(500),
+            mat: Some(get_mat(MatType::ToioCollectionRing)),
+        };
+        let timeline = get_builtin_sequence(BuiltinMove::HomePosition, 1, true).compile(&context);
+        // (300, 210) 90 degrees
+        assert_eq!(
+            timeline.commands[0].bytes,
+            vec![0x03, 0, 4, 0, 30, 0, 0, 0x2c, 0x01, 0xd2, 0x00, 90, 0x00]
+        );
+        assert_eq!("step2".parse::<BuiltinMove>(), Ok(BuiltinMove::Step2));
+        assert!("jump".parse::<BuiltinMove>().is_err());
+    }
+
+    #[test]
+    fn parallel_and_loop() {
+        let red = LightColor::new(0xff, 0, 0);
+        let sequence = Sequence::new(vec![
+            Step::Parallel(vec![
+                vec![Step::Loop {
+                    count: 3,
+                    steps: vec![
+                        Step::Light {
+                            color: red,
+                            light_time: Some(StepTime::Millis(100)),
+                        },
+                        Step::Wait(StepTime::Beats(0.5)),
+                    ],
+                }],
+                vec![
+                    Step::Wait(StepTime::Millis(100)),
+                    Step::Sound(vec![(60, StepTime::Millis(200))]),
+                ],
+            ]),
+            timed_move(0, 0, 10),
+        ]);
+        let timeline = sequence.compile(&SequenceContext {
+            beat: ms(400),
+            mat: None,
+        });
+        let commands: Vec<(time::Duration, CoreCubeUuidName)> = timeline
+            .commands
+            .iter()
+            .map(|x| (x.time, x.characteristic))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                (ms(0), CoreCubeUuidName::LightCtrl),
+                (ms(100), CoreCubeUuidName::SoundCtrl),
+                (ms(200), CoreCubeUuidName::LightCtrl),
+                (ms(400), CoreCubeUuidName::LightCtrl),
+                (ms(600), CoreCubeUuidName::MotorCtrl),
+            ]
+        );
+        assert_eq!(timeline.length, ms(610));
+    }
+
+    #[test]
+    fn executor() {
+        let cube = RecordingCube::default();
+        let context = SequenceContext {
+            beat: ms(100),
+            mat: None,
+        };
+        let timeline = get_builtin_sequence(BuiltinMove::SwingR, 0, false).compile(&context);
+        let mut executor = SequenceExecutor::new(timeline);
+        let start = time::Instant::now();
+        assert_eq!(executor.poll(&cube, start), Ok(false));
+        assert_eq!(cube.written.borrow().len(), 1);
+        assert_eq!(executor.get_next_time(), Some(start + ms(100)));
+        assert_eq!(executor.poll(&cube, start + ms(250)), Ok(false));
+        assert_eq!(cube.written.borrow().len(), 3);
+        assert_eq!(executor.poll(&cube, start + ms(400)), Ok(true));
+        assert_eq!(cube.written.borrow().len(), 4);
+    }
+}
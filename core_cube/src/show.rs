@@ -0,0 +1,569 @@
+/* Show files: multi-cube choreography written in TOML */
+
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time;
+
+use crate::light::LightColor;
+use crate::mat::{Mat, MatType};
+use crate::sequence::*;
+use crate::sound::NOTE_REST;
+
+/// Role name to give an action to every cube without its own action
+pub const ROLE_ALL: &str = "all";
+
+pub const TEMPO_MAX: f32 = 300.0;
+
+// next entries start within this error after the end of the previous one
+const OVERLAP_TOLERANCE: time::Duration = time::Duration::from_millis(1);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawShow {
+    title: Option<String>,
+    tempo: f32,
+    mat: Option<String>,
+    #[serde(default)]
+    cube: Vec<RawCube>,
+    #[serde(default)]
+    timeline: Vec<RawEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCube {
+    role: String,
+    mat: Option<String>,
+    address: Option<String>,
+    light: Option<[u8; 3]>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEntry {
+    at: Option<f32>,
+    actions: BTreeMap<String, RawAction>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAction {
+    Move(String),
+    Detail(RawActionDetail),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawActionDetail {
+    #[serde(rename = "move")]
+    builtin: Option<String>,
+    light: Option<[u8; 3]>,
+    light_beats: Option<f32>,
+    sound: Option<Vec<(u8, f32)>>,
+    wait: Option<f32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShowMat {
+    /// Without mat: moves to target positions are skipped
+    None,
+    /// Detect the mat from the first Position ID of the cube
+    Auto,
+    Mat(MatType),
+}
+
+impl fmt::Display for ShowMat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShowMat::None => write!(f, "none"),
+            ShowMat::Auto => write!(f, "auto"),
+            ShowMat::Mat(mat_type) => write!(f, "{}", mat_type),
+        }
+    }
+}
+
+impl FromStr for ShowMat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ShowMat::None),
+            "auto" => Ok(ShowMat::Auto),
+            _ => s.parse::<MatType>().map(ShowMat::Mat),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShowCube {
+    pub role: String,
+    pub mat: ShowMat,
+    /// BLE address (None: any paired cube)
+    pub address: Option<u64>,
+    /// Light color at the start of the show
+    pub light: Option<LightColor>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ShowAction {
+    pub builtin: Option<BuiltinMove>,
+    pub light: Option<LightColor>,
+    /// Light time in beats (None: until the next light)
+    pub light_beats: Option<f32>,
+    /// (MIDI note number, beats)
+    pub sound: Vec<(u8, f32)>,
+    /// Beats to wait after the move
+    pub wait: Option<f32>,
+}
+
+impl ShowAction {
+    /// Light and sound start with the move
+    pub fn get_sequence(&self, cube_id: usize, has_mat: bool) -> Sequence {
+        let mut steps: Vec<Step> = Vec::new();
+        if let Some(color) = self.light {
+            steps.push(Step::Light {
+                color,
+                light_time: self.light_beats.map(StepTime::Beats),
+            });
+        }
+        if !self.sound.is_empty() {
+            let notes = self
+                .sound
+                .iter()
+                .map(|(note, beats)| (*note, StepTime::Beats(*beats)))
+                .collect();
+            steps.push(Step::Sound(notes));
+        }
+        if let Some(builtin) = self.builtin {
+            steps.extend(get_builtin_sequence(builtin, cube_id, has_mat).steps);
+        }
+        if let Some(wait) = self.wait {
+            steps.push(Step::Wait(StepTime::Beats(wait)));
+        }
+        Sequence::new(steps)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShowEntry {
+    /// Start beat (None: when the previous entry ends)
+    pub at: Option<f32>,
+    /// (index of the cube, action)
+    pub actions: Vec<(usize, ShowAction)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Show {
+    pub title: String,
+    /// Beats per minute
+    pub tempo: f32,
+    pub cubes: Vec<ShowCube>,
+    pub timeline: Vec<ShowEntry>,
+}
+
+/// Parse "e0:12:34:56:78:9a" style BLE address
+pub fn parse_ble_address(address: &str) -> std::result::Result<u64, String> {
+    let mut hex = address.to_string();
+    hex.retain(|c| c != ':' && c != '-');
+    u64::from_str_radix(&hex, 16).map_err(|_| format!("invalid BLE address \"{}\"", address))
+}
+
+fn check_beats(path: &str, beats: f32) -> std::result::Result<f32, String> {
+    if beats.is_finite() && beats >= 0.0 {
+        Ok(beats)
+    } else {
+        Err(format!("{}: beats must be 0 or more (got {})", path, beats))
+    }
+}
+
+fn get_color(color: [u8; 3]) -> LightColor {
+    LightColor::new(color[0], color[1], color[2])
+}
+
+fn get_action(path: &str, raw: RawAction) -> std::result::Result<ShowAction, String> {
+    let detail = match raw {
+        RawAction::Move(name) => RawActionDetail {
+            builtin: Some(name),
+            light: None,
+            light_beats: None,
+            sound: None,
+            wait: None,
+        },
+        RawAction::Detail(detail) => detail,
+    };
+    let builtin = match detail.builtin {
+        Some(name) => Some(
+            name.parse::<BuiltinMove>()
+                .map_err(|e| format!("{}.move: {}", path, e))?,
+        ),
+        None => None,
+    };
+    if detail.light_beats.is_some() && detail.light.is_none() {
+        return Err(format!("{}.light_beats: needs light", path));
+    }
+    let light_beats = match detail.light_beats {
+        Some(beats) => Some(check_beats(&format!("{}.light_beats", path), beats)?),
+        None => None,
+    };
+    let sound = detail.sound.unwrap_or_default();
+    for (i, (note, beats)) in sound.iter().enumerate() {
+        if *note > NOTE_REST {
+            return Err(format!(
+                "{}.sound[{}]: note must be 0 - 127 or {} (rest) (got {})",
+                path, i, NOTE_REST, note
+            ));
+        }
+        check_beats(&format!("{}.sound[{}]", path, i), *beats)?;
+    }
+    let wait = match detail.wait {
+        Some(beats) => Some(check_beats(&format!("{}.wait", path), beats)?),
+        None => None,
+    };
+    let action = ShowAction {
+        builtin,
+        light: detail.light.map(get_color),
+        light_beats,
+        sound,
+        wait,
+    };
+    if action == ShowAction::default() {
+        return Err(format!("{}: no move, light, sound or wait", path));
+    }
+    Ok(action)
+}
+
+fn check_tempo(tempo: f32) -> std::result::Result<(), String> {
+    if !(tempo > 0.0 && tempo <= TEMPO_MAX) {
+        return Err(format!(
+            "tempo: must be more than 0 and up to {} (got {})",
+            TEMPO_MAX, tempo
+        ));
+    }
+    Ok(())
+}
+
+/// Load a show from TOML text
+pub fn load_show(text: &str) -> std::result::Result<Show, String> {
+    let raw: RawShow = toml::from_str(text).map_err(|e| e.to_string())?;
+
+    check_tempo(raw.tempo)?;
+    let show_mat = match raw.mat {
+        Some(name) => name.parse::<ShowMat>().map_err(|e| format!("mat: {}", e))?,
+        None => ShowMat::None,
+    };
+
+    if raw.cube.is_empty() {
+        return Err("cube: at least one cube is required".to_string());
+    }
+    let mut cubes: Vec<ShowCube> = Vec::with_capacity(raw.cube.len());
+    for (i, cube) in raw.cube.into_iter().enumerate() {
+        if cube.role.is_empty() || cube.role == ROLE_ALL {
+            return Err(format!("cube[{}].role: invalid role \"{}\"", i, cube.role));
+        }
+        if cubes.iter().any(|x| x.role == cube.role) {
+            return Err(format!(
+                "cube[{}].role: duplicated role \"{}\"",
+                i, cube.role
+            ));
+        }
+        let mat = match cube.mat {
+            Some(name) => name
+                .parse::<ShowMat>()
+                .map_err(|e| format!("cube[{}].mat: {}", i, e))?,
+            None => show_mat,
+        };
+        let address = match cube.address {
+            Some(address) => Some(
+                parse_ble_address(&address).map_err(|e| format!("cube[{}].address: {}", i, e))?,
+            ),
+            None => None,
+        };
+        cubes.push(ShowCube {
+            role: cube.role,
+            mat,
+            address,
+            light: cube.light.map(get_color),
+        });
+    }
+    let roles: Vec<&str> = cubes.iter().map(|x| x.role.as_str()).collect();
+
+    let mut timeline: Vec<ShowEntry> = Vec::with_capacity(raw.timeline.len());
+    let mut last_at: Option<f32> = None;
+    for (i, entry) in raw.timeline.into_iter().enumerate() {
+        if let Some(at) = entry.at {
+            check_beats(&format!("timeline[{}].at", i), at)?;
+            if let Some(last_at) = last_at {
+                if at < last_at {
+                    return Err(format!(
+                        "timeline[{}].at: beat {} is before the previous entry (beat {})",
+                        i, at, last_at
+                    ));
+                }
+            }
+            last_at = Some(at);
+        }
+        if entry.actions.is_empty() {
+            return Err(format!("timeline[{}].actions: no action", i));
+        }
+        let mut actions: Vec<(usize, ShowAction)> = Vec::new();
+        let mut all: Option<ShowAction> = None;
+        for (role, raw_action) in entry.actions {
+            let path = format!("timeline[{}].actions.{}", i, role);
+            let action = get_action(&path, raw_action)?;
+            if role == ROLE_ALL {
+                all = Some(action);
+                continue;
+            }
+            match roles.iter().position(|x| *x == role) {
+                Some(index) => actions.push((index, action)),
+                None => {
+                    return Err(format!(
+                        "{}: unknown role \"{}\" (roles: {}, {})",
+                        path,
+                        role,
+                        roles.join(", "),
+                        ROLE_ALL
+                    ))
+                }
+            }
+        }
+        if let Some(all) = all {
+            for index in 0..cubes.len() {
+                if !actions.iter().any(|(x, _)| *x == index) {
+                    actions.push((index, all.clone()));
+                }
+            }
+        }
+        actions.sort_by_key(|(index, _)| *index);
+        timeline.push(ShowEntry {
+            at: entry.at,
+            actions,
+        });
+    }
+
+    Ok(Show {
+        title: raw.title.unwrap_or_default(),
+        tempo: raw.tempo,
+        cubes,
+        timeline,
+    })
+}
+
+/// Load a show file. Errors start with the file name.
+pub fn load_show_file(path: &std::path::Path) -> std::result::Result<Show, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    load_show(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+impl Show {
+    pub fn get_beat(&self) -> time::Duration {
+        time::Duration::from_micros((60_000_000.0 / self.tempo as f64).round() as u64)
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) -> std::result::Result<(), String> {
+        check_tempo(tempo)?;
+        self.tempo = tempo;
+        Ok(())
+    }
+
+    /// Resolve the timeline into a timeline for each cube.
+    /// `mats` are the mats of the cubes (detected or specified).
+    pub fn compile(&self, mats: &[Option<Mat>]) -> std::result::Result<Vec<Timeline>, String> {
+        let beat = self.get_beat();
+        let mut timelines: Vec<Timeline> = vec![Timeline::default(); self.cubes.len()];
+        // end of the previous entry
+        let mut end = time::Duration::ZERO;
+        for (i, entry) in self.timeline.iter().enumerate() {
+            let start = match entry.at {
+                Some(at) => {
+                    let start = StepTime::Beats(at).to_duration(beat);
+                    if start + OVERLAP_TOLERANCE < end {
+                        return Err(format!(
+                            "timeline[{}].at: beat {} is before the previous entry ends (beat {:.2})",
+                            i,
+                            at,
+                            end.as_secs_f64() / beat.as_secs_f64()
+                        ));
+                    }
+                    start
+                }
+                None => end,
+            };
+            let mut entry_end = start;
+            for (cube, action) in &entry.actions {
+                let context = SequenceContext {
+                    beat,
+                    mat: mats.get(*cube).copied().flatten(),
+                };
+                let sequence = action.get_sequence(*cube, context.mat.is_some());
+                let timeline = sequence.compile(&context);
+                for command in timeline.commands {
+                    timelines[*cube].commands.push(TimedCommand {
+                        time: start + command.time,
+                        ..command
+                    });
+                }
+                entry_end = entry_end.max(start + timeline.length);
+            }
+            // the next entry starts on a beat
+            let beats = (entry_end.as_secs_f64() / beat.as_secs_f64() - 0.001).ceil();
+            end = StepTime::Beats(beats as f32).to_duration(beat);
+        }
+        for timeline in timelines.iter_mut() {
+            timeline.length = end;
+        }
+        info!(
+            "show \"{}\": {} entries, {:?}",
+            self.title,
+            self.timeline.len(),
+            end
+        );
+        Ok(timelines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mat::get_mat;
+
+    const SHOW: &str = r#"
+title = "test"
+tempo = 120
+mat = "tc1"
+
+[[cube]]
+role = "left"
+light = [0, 0, 16]
+
+[[cube]]
+role = "right"
+address = "e0:12:34:56:78:9a"
+
+[[timeline]]
+actions = { all = "Step2" }
+
+[[timeline]]
+at = 8
+actions = { left = "SwingR", right = { move = "SwingL", light = [255, 0, 0], light_beats = 1 } }
+
+[[timeline]]
+actions = { right = { sound = [[60, 0.5], [128, 0.5]], wait = 2 } }
+"#;
+
+    fn get_error(text: &str) -> String {
+        load_show(text).unwrap_err()
+    }
+
+    #[test]
+    fn load() {
+        let show = load_show(SHOW).unwrap();
+        assert_eq!(show.title, "test");
+        assert_eq!(show.get_beat(), time::Duration::from_millis(500));
+        assert_eq!(show.cubes[0].mat, ShowMat::Mat(MatType::ToioCollectionRing));
+        assert_eq!(show.cubes[1].address, Some(0xe0123456789a));
+        assert_eq!(show.timeline[0].actions.len(), 2);
+        assert_eq!(show.timeline[1].at, Some(8.0));
+        assert_eq!(
+            show.timeline[1].actions[1].1.light,
+            Some(LightColor::new(255, 0, 0))
+        );
+
+        let mat = Some(get_mat(MatType::ToioCollectionRing));
+        let timelines = show.compile(&[mat, mat]).unwrap();
+        let times: Vec<u64> = timelines[1]
+            .commands
+            .iter()
+            .map(|x| x.time.as_millis() as u64)
+            .collect();
+        // Step2 (4 beats), SwingL with light at beat 8, sound at beat 12
+        assert_eq!(
+            times,
+            vec![0, 500, 1000, 1500, 4000, 4000, 4500, 5000, 5500, 6000]
+        );
+        assert_eq!(timelines[0].length, time::Duration::from_millis(7000));
+
+        let show = load_show(include_str!("../../shows/tokyo2020.toml")).unwrap();
+        assert!(show.compile(&[None, None]).is_ok());
+    }
+
+    #[test]
+    fn validation_errors() {
+        assert!(get_error(
+            "tempo = 120\n[[cube]]\nrole = \"a\"\n[[timeline]]\nactions = { a = 1 }"
+        )
+        .contains("line 5"));
+        assert_eq!(
+            get_error("tempo = 0\n[[cube]]\nrole = \"a\""),
+            "tempo: must be more than 0 and up to 300 (got 0)"
+        );
+        let mut show = load_show("tempo = 120\n[[cube]]\nrole = \"a\"").unwrap();
+        assert_eq!(
+            show.set_tempo(400.0).unwrap_err(),
+            "tempo: must be more than 0 and up to 300 (got 400)"
+        );
+        assert_eq!(show.tempo, 120.0);
+        assert_eq!(
+            get_error("tempo = 120\nmat = \"tc3\"\n[[cube]]\nrole = \"a\""),
+            "mat: unknown mat: tc3 (tc1, tc2, gesun, simple, dev1 - dev12)"
+        );
+        assert_eq!(
+            get_error("tempo = 120\n[[cube]]\nrole = \"a\"\n[[cube]]\nrole = \"a\""),
+            "cube[1].role: duplicated role \"a\""
+        );
+        assert_eq!(
+            get_error("tempo = 120\n[[cube]]\nrole = \"a\"\naddress = \"xyz\""),
+            "cube[0].address: invalid BLE address \"xyz\""
+        );
+        let cube = "tempo = 120\n[[cube]]\nrole = \"a\"\n";
+        assert_eq!(
+            get_error(&format!(
+                "{}[[timeline]]\nactions = {{ b = \"Step2\" }}",
+                cube
+            )),
+            "timeline[0].actions.b: unknown role \"b\" (roles: a, all)"
+        );
+        assert_eq!(
+            get_error(&format!(
+                "{}[[timeline]]\nactions = {{ a = \"Jump\" }}",
+                cube
+            )),
+            "timeline[0].actions.a.move: unknown move: Jump"
+        );
+        assert_eq!(
+            get_error(&format!(
+                "{}[[timeline]]\nactions = {{ a = {{ light_beats = 1 }} }}",
+                cube
+            )),
+            "timeline[0].actions.a.light_beats: needs light"
+        );
+        assert_eq!(
+            get_error(&format!(
+                "{}[[timeline]]\nactions = {{ a = {{ sound = [[200, 1]] }} }}",
+                cube
+            )),
+            "timeline[0].actions.a.sound[0]: note must be 0 - 127 or 128 (rest) (got 200)"
+        );
+        assert_eq!(
+            get_error(&format!(
+                "{}[[timeline]]\nat = 4\nactions = {{ a = \"Step2\" }}\n[[timeline]]\nat = 2\nactions = {{ a = \"Step2\" }}",
+                cube
+            )),
+            "timeline[1].at: beat 2 is before the previous entry (beat 4)"
+        );
+
+        // the entries overlap
+        let show = load_show(&format!(
+            "{}[[timeline]]\nactions = {{ a = \"Step8\" }}\n[[timeline]]\nat = 4\nactions = {{ a = \"Step2\" }}",
+            cube
+        ))
+        .unwrap();
+        assert_eq!(
+            show.compile(&[None]).unwrap_err(),
+            "timeline[1].at: beat 4 is before the previous entry ends (beat 8.00)"
+        );
+    }
+}
@@ -0,0 +1,1199 @@
+/* Virtual core cube: simulates a cube behind the transport */
+
+use log::{debug, info};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use crate::clock::*;
+use crate::controller::{ControllerStatus, GoToPoseConfig, GoToPoseController, Target};
+use crate::kinematics::Pose;
+use crate::light::LightColor;
+use crate::mat::{get_mat, Mat, MatType};
+use crate::motor::{MotorControl, MULTI_TARGET_MAX};
+use crate::transport::{CoreCubeNotifyHandlerFunction, CoreCubeTransport, CoreCubeUuidName};
+use crate::units::*;
+
+/// Simulation step
+pub const SIMULATION_STEP: time::Duration = time::Duration::from_millis(10);
+
+const BATTERY_NOTIFY_INTERVAL: time::Duration = time::Duration::from_secs(5);
+// notifications without changes are suppressed for this time (condition 0xff)
+const SUPPRESS_INTERVAL: time::Duration = time::Duration::from_millis(300);
+const SOUND_EFFECT_TIME: time::Duration = time::Duration::from_millis(500);
+const TARGET_MOVE_DEFAULT_TIMEOUT_SEC: u64 = 10;
+const BLE_PROTOCOL_VERSION: &[u8] = b"2.3.0";
+
+const RESULT_SUCCESS: u8 = 0x00;
+const RESULT_TIMEOUT: u8 = 0x01;
+const RESULT_ID_MISSED: u8 = 0x02;
+const RESULT_INVALID_PARAMETER: u8 = 0x03;
+const RESULT_OVERWRITTEN: u8 = 0x05;
+const RESULT_APPEND_REFUSED: u8 = 0x07;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SimulatorConfig {
+    /// Mat under the cube (None: no Position ID)
+    pub mat: Option<Mat>,
+    pub pose: Pose,
+    /// Battery level [%]
+    pub battery: u8,
+}
+
+impl SimulatorConfig {
+    /// Put the cube on the center of the mat
+    pub fn on_mat(mat: Mat) -> Self {
+        let (x, y) = mat.center();
+        Self {
+            mat: Some(mat),
+            pose: Pose::new(x as f32, y as f32, 0.0),
+            battery: 100,
+        }
+    }
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self::on_mat(get_mat(MatType::ToioCollectionRing))
+    }
+}
+
+/// Motion detection information (0x01 of the sensor information)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MotionState {
+    pub horizontal: bool,
+    pub collision: bool,
+    pub double_tap: bool,
+    /// 1: top side up ... 6: left side up
+    pub posture: u8,
+    /// Shake level (0: not shaken)
+    pub shake: u8,
+}
+
+impl Default for MotionState {
+    fn default() -> Self {
+        Self {
+            horizontal: true,
+            collision: false,
+            double_tap: false,
+            posture: 1,
+            shake: 0,
+        }
+    }
+}
+
+impl MotionState {
+    pub fn get_bytes(&self) -> Vec<u8> {
+        vec![
+            0x01,
+            self.horizontal as u8,
+            self.collision as u8,
+            self.double_tap as u8,
+            self.posture,
+            self.shake,
+        ]
+    }
+}
+
+// notification interval and condition (0x00: always, 0x01: changed, 0xff: changed or 300ms)
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct NotifySetting {
+    interval: time::Duration,
+    condition: u8,
+}
+
+impl NotifySetting {
+    fn is_due(
+        &self,
+        last: &Option<(time::Instant, Vec<u8>)>,
+        bytes: &[u8],
+        now: time::Instant,
+    ) -> bool {
+        match last {
+            Some((time, last_bytes)) => {
+                let elapsed = now.saturating_duration_since(*time);
+                let changed = last_bytes.as_slice() != bytes;
+                elapsed >= self.interval
+                    && match self.condition {
+                        0x00 => true,
+                        0xff => changed || elapsed >= SUPPRESS_INTERVAL,
+                        _ => changed,
+                    }
+            }
+            None => true,
+        }
+    }
+}
+
+struct TargetMove {
+    multi_target: bool,
+    id: u8,
+    controller: GoToPoseController,
+    /// Remaining targets of the multi-target moves (id, target)
+    targets: VecDeque<(u8, Target)>,
+}
+
+fn get_u16(data: &[u8], index: usize) -> u16 {
+    u16::from_le_bytes([data[index], data[index + 1]])
+}
+
+fn get_signed_speed(direction: u8, speed: u8) -> i16 {
+    match direction {
+        0x02 => -(speed as i16),
+        _ => speed as i16,
+    }
+}
+
+struct SimulatorState {
+    config: SimulatorConfig,
+    mm_per_unit: f32,
+    time: Option<time::Instant>,
+    pose: Pose,
+    lifted: bool,
+    motor: MotorControl,
+    motor_end: Option<time::Instant>,
+    target_move: Option<TargetMove>,
+    light: Option<LightColor>,
+    light_end: Option<time::Instant>,
+    sound_playing: bool,
+    sound_end: Option<time::Instant>,
+    button: bool,
+    battery: u8,
+    motion: MotionState,
+    id_setting: NotifySetting,
+    last_id: Option<(time::Instant, Vec<u8>)>,
+    posture_setting: Option<NotifySetting>,
+    last_posture: Option<(time::Instant, Vec<u8>)>,
+    motor_speed_notify: bool,
+    last_motor_speed: Vec<u8>,
+    last_battery_time: Option<time::Instant>,
+    last_config_response: Vec<u8>,
+    notifications: Vec<(CoreCubeUuidName, Vec<u8>)>,
+}
+
+impl SimulatorState {
+    fn new(config: SimulatorConfig) -> Self {
+        Self {
+            config,
+            mm_per_unit: match config.mat {
+                Some(mat) => get_mm_per_unit(mat.mat_type),
+                None => MM_PER_UNIT,
+            },
+            time: None,
+            pose: config.pose,
+            lifted: false,
+            motor: MotorControl::stop(),
+            motor_end: None,
+            target_move: None,
+            light: None,
+            light_end: None,
+            sound_playing: false,
+            sound_end: None,
+            button: false,
+            battery: config.battery,
+            motion: MotionState::default(),
+            id_setting: NotifySetting {
+                interval: time::Duration::ZERO,
+                condition: 0x01,
+            },
+            last_id: None,
+            posture_setting: None,
+            last_posture: None,
+            motor_speed_notify: false,
+            last_motor_speed: vec![0xe0, 0x00, 0x00],
+            last_battery_time: None,
+            last_config_response: Vec::new(),
+            notifications: Vec::new(),
+        }
+    }
+
+    // the simulation time is set by SimulatedCube before the writes
+    fn get_now(&self) -> time::Instant {
+        self.time.unwrap()
+    }
+
+    fn notify(&mut self, characteristic: CoreCubeUuidName, bytes: Vec<u8>) {
+        self.notifications.push((characteristic, bytes));
+    }
+
+    fn get_position_id_bytes(&self) -> Option<Vec<u8>> {
+        let mat = self.config.mat?;
+        if self.lifted || self.pose.x < 0.0 || self.pose.y < 0.0 {
+            return None;
+        }
+        let x = self.pose.x.round() as u16;
+        let y = self.pose.y.round() as u16;
+        if !mat.contains(x, y) {
+            return None;
+        }
+        let mut bytes = vec![0x01];
+        for value in [x, y, self.pose.angle.to_id_angle()].iter().cycle().take(6) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        Some(bytes)
+    }
+
+    fn get_id_bytes(&self) -> Vec<u8> {
+        self.get_position_id_bytes().unwrap_or_else(|| vec![0x03])
+    }
+
+    fn get_posture_angle_bytes(&self) -> Vec<u8> {
+        // the cube sends the yaw in (-180, 180]
+        let mut yaw = self.pose.angle.degrees().round() as i16;
+        if yaw > 180 {
+            yaw -= 360;
+        }
+        let mut bytes = vec![0x03, 0x01, 0x00, 0x00, 0x00, 0x00];
+        bytes.extend_from_slice(&yaw.to_le_bytes());
+        bytes
+    }
+
+    fn get_motor_speed_bytes(&self) -> Vec<u8> {
+        let get_speed = |speed: i16| speed.unsigned_abs().min(MOTOR_SPEED_MAX as u16) as u8;
+        vec![
+            0xe0,
+            get_speed(self.motor.left),
+            get_speed(self.motor.right),
+        ]
+    }
+
+    fn respond_target_move(&mut self, multi_target: bool, id: u8, result: u8) {
+        let command = if multi_target { 0x84 } else { 0x83 };
+        info!("simulator: target move {} result {}", id, result);
+        self.notify(CoreCubeUuidName::MotorCtrl, vec![command, id, result]);
+    }
+
+    fn finish_target_move(&mut self, result: u8) {
+        if let Some(target_move) = self.target_move.take() {
+            self.respond_target_move(target_move.multi_target, target_move.id, result);
+            self.motor = MotorControl::stop();
+        }
+    }
+
+    fn update_target_move(&mut self, now: time::Instant) {
+        let mut target_move = match self.target_move.take() {
+            Some(x) => x,
+            None => return,
+        };
+        if self.get_position_id_bytes().is_some() {
+            target_move.controller.update_pose(self.pose, now);
+        }
+        let result = match target_move.controller.update(now) {
+            ControllerStatus::Moving(control) => {
+                self.motor = MotorControl::new(control.left, control.right);
+                self.target_move = Some(target_move);
+                return;
+            }
+            ControllerStatus::Arrived => match target_move.targets.pop_front() {
+                Some((id, target)) => {
+                    if id != target_move.id {
+                        // the appended move starts
+                        self.respond_target_move(true, target_move.id, RESULT_SUCCESS);
+                        target_move.id = id;
+                    }
+                    target_move.controller.set_target(target);
+                    self.target_move = Some(target_move);
+                    return;
+                }
+                None => RESULT_SUCCESS,
+            },
+            ControllerStatus::Timeout => RESULT_TIMEOUT,
+            ControllerStatus::PoseLost => RESULT_ID_MISSED,
+        };
+        self.target_move = Some(target_move);
+        self.finish_target_move(result);
+    }
+
+    fn update_notifications(&mut self, now: time::Instant) {
+        let id_bytes = self.get_id_bytes();
+        let missed_again =
+            id_bytes.len() == 1 && self.last_id.as_ref().map(|x| &x.1) == Some(&id_bytes);
+        if !missed_again && self.id_setting.is_due(&self.last_id, &id_bytes, now) {
+            self.last_id = Some((now, id_bytes.clone()));
+            self.notify(CoreCubeUuidName::IdInfo, id_bytes);
+        }
+
+        if let Some(setting) = self.posture_setting {
+            let bytes = self.get_posture_angle_bytes();
+            if setting.is_due(&self.last_posture, &bytes, now) {
+                self.last_posture = Some((now, bytes.clone()));
+                self.notify(CoreCubeUuidName::SensorInfo, bytes);
+            }
+        }
+
+        let bytes = self.get_motor_speed_bytes();
+        if bytes != self.last_motor_speed {
+            self.last_motor_speed = bytes.clone();
+            if self.motor_speed_notify {
+                self.notify(CoreCubeUuidName::MotorCtrl, bytes);
+            }
+        }
+
+        let battery_due = match self.last_battery_time {
+            Some(time) => now.saturating_duration_since(time) >= BATTERY_NOTIFY_INTERVAL,
+            None => true,
+        };
+        if battery_due {
+            self.last_battery_time = Some(now);
+            let battery = self.battery;
+            self.notify(CoreCubeUuidName::BatteryInfo, vec![battery]);
+        }
+    }
+
+    // simulate the step which ends at `now`
+    fn step(&mut self, now: time::Instant) {
+        self.update_target_move(now);
+        if !self.lifted {
+            let (left, right) = self.motor.get_mm_per_sec();
+            self.pose = self
+                .pose
+                .advance(left, right, SIMULATION_STEP, self.mm_per_unit);
+        }
+        if matches!(self.motor_end, Some(end) if now >= end) {
+            self.motor = MotorControl::stop();
+            self.motor_end = None;
+        }
+        if matches!(self.light_end, Some(end) if now >= end) {
+            self.light = None;
+            self.light_end = None;
+        }
+        if matches!(self.sound_end, Some(end) if now >= end) {
+            self.sound_playing = false;
+            self.sound_end = None;
+        }
+        self.update_notifications(now);
+    }
+
+    fn update(&mut self, now: time::Instant) {
+        let mut time = match self.time {
+            Some(x) => x,
+            None => {
+                self.time = Some(now);
+                self.update_notifications(now);
+                return;
+            }
+        };
+        while time + SIMULATION_STEP <= now {
+            time += SIMULATION_STEP;
+            self.step(time);
+        }
+        self.time = Some(time);
+    }
+
+    fn set_motor(&mut self, left: i16, right: i16, duration: Option<time::Duration>) {
+        let now = self.get_now();
+        if self.target_move.is_some() {
+            self.finish_target_move(RESULT_OVERWRITTEN);
+        }
+        self.motor = MotorControl::new(left, right);
+        self.motor_end = duration.map(|x| now + x);
+    }
+
+    // (x, y, angle) with the angle type in the upper 3 bits
+    fn get_target(&self, data: &[u8], previous: Option<&Target>) -> Target {
+        let (base_x, base_y, base_angle) = match previous {
+            Some(target) => (target.x, target.y, target.angle.unwrap_or(self.pose.angle)),
+            None => (self.pose.x, self.pose.y, self.pose.angle),
+        };
+        // 0xffff: same as the current position
+        let get_position = |value: u16, base: f32| {
+            if value == 0xffff {
+                base
+            } else {
+                value as f32
+            }
+        };
+        let angle = get_u16(data, 4);
+        let degrees = Angle::from_degrees((angle & 0x1fff) as f32);
+        Target {
+            x: get_position(get_u16(data, 0), base_x),
+            y: get_position(get_u16(data, 2), base_y),
+            // 0-2: absolute, 3/4: relative, 5: no angle control,
+            // 6: relative to the angle when the command was written
+            angle: match angle >> 13 {
+                0..=2 => Some(degrees),
+                3 => Some(base_angle + degrees),
+                4 => Some(base_angle - degrees),
+                6 => Some(self.pose.angle + degrees),
+                _ => None,
+            },
+        }
+    }
+
+    fn start_target_move(&mut self, multi_target: bool, header: &[u8], append: bool, data: &[u8]) {
+        let now = self.get_now();
+        let id = header[1];
+        let (timeout, moving_type, max_speed) = (header[2], header[3], header[4]);
+        if moving_type > 2 || !(MOTOR_SPEED_MIN..=MOTOR_SPEED_MAX).contains(&max_speed) {
+            self.respond_target_move(multi_target, id, RESULT_INVALID_PARAMETER);
+            return;
+        }
+        if self.get_position_id_bytes().is_none() {
+            self.respond_target_move(multi_target, id, RESULT_ID_MISSED);
+            return;
+        }
+
+        let mut previous: Option<Target> = None;
+        if append {
+            if let Some(target_move) = &self.target_move {
+                if !target_move.multi_target
+                    || target_move.targets.len() + data.len() / 6 > MULTI_TARGET_MAX
+                {
+                    self.respond_target_move(multi_target, id, RESULT_APPEND_REFUSED);
+                    return;
+                }
+                previous = target_move
+                    .targets
+                    .back()
+                    .map(|x| x.1)
+                    .or_else(|| Some(target_move.controller.get_target()));
+            }
+        }
+        let mut targets: VecDeque<(u8, Target)> = VecDeque::new();
+        for chunk in data.chunks(6) {
+            let target = self.get_target(chunk, previous.as_ref());
+            targets.push_back((id, target));
+            previous = Some(target);
+        }
+        debug!("simulator: target move {} {:?}", id, targets);
+
+        match &mut self.target_move {
+            Some(target_move) if append => {
+                target_move.targets.extend(targets);
+                return;
+            }
+            Some(_) => self.finish_target_move(RESULT_OVERWRITTEN),
+            None => (),
+        }
+        let timeout = match timeout {
+            0 => TARGET_MOVE_DEFAULT_TIMEOUT_SEC,
+            x => x as u64,
+        };
+        let config = GoToPoseConfig {
+            max_mm_per_sec: speed_to_mm_per_sec(max_speed),
+            // moving type 1: never move backward
+            allow_backward: moving_type != 1,
+            timeout: time::Duration::from_secs(timeout),
+            mm_per_unit: self.mm_per_unit,
+            ..GoToPoseConfig::default()
+        };
+        let (_, first) = targets.pop_front().unwrap();
+        let mut controller = GoToPoseController::new(config, first);
+        controller.update_pose(self.pose, now);
+        self.motor_end = None;
+        self.target_move = Some(TargetMove {
+            multi_target,
+            id,
+            controller,
+            targets,
+        });
+    }
+
+    fn write_motor(&mut self, bytes: &[u8]) -> bool {
+        match bytes {
+            [0x01, 0x01, left_dir, left, 0x02, right_dir, right] => self.set_motor(
+                get_signed_speed(*left_dir, *left),
+                get_signed_speed(*right_dir, *right),
+                None,
+            ),
+            [0x02, 0x01, left_dir, left, 0x02, right_dir, right, duration] => self.set_motor(
+                get_signed_speed(*left_dir, *left),
+                get_signed_speed(*right_dir, *right),
+                motor_duration_to_duration(*duration),
+            ),
+            [0x03, ..] if bytes.len() == 13 => {
+                self.start_target_move(false, &bytes[0..7], false, &bytes[7..])
+            }
+            [0x04, ..]
+                if bytes.len() > 8
+                    && bytes[8..].chunks_exact(6).remainder().is_empty()
+                    && (bytes.len() - 8) / 6 <= MULTI_TARGET_MAX =>
+            {
+                self.start_target_move(true, &bytes[0..7], bytes[7] == 0x01, &bytes[8..])
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn write_light(&mut self, bytes: &[u8]) -> bool {
+        let now = self.get_now();
+        match bytes {
+            [0x01] | [0x02, ..] => {
+                self.light = None;
+                self.light_end = None;
+            }
+            [0x03, duration, 0x01, _, r, g, b] => {
+                self.light = Some(LightColor::new(*r, *g, *b));
+                self.light_end = motor_duration_to_duration(*duration).map(|x| now + x);
+            }
+            // scenario: only the first color
+            [0x04, _, count, rest @ ..] if *count > 0 && rest.len() >= 6 => {
+                self.light = Some(LightColor::new(rest[3], rest[4], rest[5]));
+                self.light_end = None;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn write_sound(&mut self, bytes: &[u8]) -> bool {
+        let now = self.get_now();
+        match bytes {
+            [0x01] => {
+                self.sound_playing = false;
+                self.sound_end = None;
+            }
+            [0x02, _, _] => {
+                self.sound_playing = true;
+                self.sound_end = Some(now + SOUND_EFFECT_TIME);
+            }
+            [0x03, repeat, count, notes @ ..]
+                if *count > 0 && notes.len() == *count as usize * 3 =>
+            {
+                let length: u64 = notes
+                    .chunks(3)
+                    .map(|x| x[0] as u64 * MOTOR_DURATION_UNIT_MS)
+                    .sum();
+                self.sound_playing = true;
+                // repeat 0: forever
+                self.sound_end = match repeat {
+                    0 => None,
+                    x => Some(now + time::Duration::from_millis(length * *x as u64)),
+                };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn write_configuration(&mut self, bytes: &[u8]) -> bool {
+        let response = match bytes {
+            [0x01, 0x00] => {
+                let mut response = vec![0x81, 0x00];
+                response.extend_from_slice(BLE_PROTOCOL_VERSION);
+                response
+            }
+            [0x18, 0x00, interval, condition] => {
+                self.id_setting = NotifySetting {
+                    interval: time::Duration::from_millis(*interval as u64 * 10),
+                    condition: *condition,
+                };
+                vec![0x98, 0x00, 0x00]
+            }
+            [0x1c, 0x00, enable] => {
+                self.motor_speed_notify = *enable != 0;
+                vec![0x9c, 0x00, 0x00]
+            }
+            [0x1d, 0x00, angle_type, interval, condition] => {
+                // only Euler angles are simulated
+                self.posture_setting = match (angle_type, interval) {
+                    (0x01, 0) => None,
+                    (0x01, interval) => Some(NotifySetting {
+                        interval: time::Duration::from_millis(*interval as u64 * 10),
+                        condition: *condition,
+                    }),
+                    _ => None,
+                };
+                self.last_posture = None;
+                vec![0x9d, 0x00, 0x00]
+            }
+            [0x06, ..] | [0x17, ..] | [0x1e, ..] | [0x1b, ..] => {
+                debug!("simulator: configuration {:02x?} is ignored", bytes);
+                return true;
+            }
+            _ => return false,
+        };
+        self.last_config_response = response.clone();
+        self.notify(CoreCubeUuidName::Configuration, response);
+        true
+    }
+
+    fn write(&mut self, characteristic: CoreCubeUuidName, bytes: &[u8]) -> bool {
+        match characteristic {
+            CoreCubeUuidName::MotorCtrl => self.write_motor(bytes),
+            CoreCubeUuidName::LightCtrl => self.write_light(bytes),
+            CoreCubeUuidName::SoundCtrl => self.write_sound(bytes),
+            CoreCubeUuidName::Configuration => self.write_configuration(bytes),
+            _ => false,
+        }
+    }
+
+    fn notify_motion(&mut self) {
+        let bytes = self.motion.get_bytes();
+        self.notify(CoreCubeUuidName::SensorInfo, bytes);
+        // collision and double tap are events
+        self.motion.collision = false;
+        self.motion.double_tap = false;
+    }
+}
+
+type NotifyHandler = Arc<Mutex<CoreCubeNotifyHandlerFunction>>;
+type NotifyHandlerList = Vec<(usize, CoreCubeUuidName, NotifyHandler)>;
+
+thread_local! {
+    // cubes dispatching the notifications on this thread
+    static DISPATCHING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Simulated cube. Clones share the same cube.
+#[derive(Clone)]
+pub struct SimulatedCube {
+    clock: Arc<dyn Clock>,
+    state: Arc<Mutex<SimulatorState>>,
+    handlers: Arc<Mutex<NotifyHandlerList>>,
+    next_handler_id: Arc<AtomicUsize>,
+}
+
+impl SimulatedCube {
+    pub fn new(config: SimulatorConfig) -> Self {
+        Self::with_clock(config, get_system_clock())
+    }
+
+    /// The clock is used by spawn() and by the writes before the first update
+    pub fn with_clock(config: SimulatorConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            state: Arc::new(Mutex::new(SimulatorState::new(config))),
+            handlers: Arc::new(Mutex::new(Vec::new())),
+            next_handler_id: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the id to unregister the handler
+    pub fn register_notify(
+        &self,
+        characteristic_name: CoreCubeUuidName,
+        handler_func: CoreCubeNotifyHandlerFunction,
+    ) -> usize {
+        let id = self.next_handler_id.fetch_add(1, Ordering::SeqCst);
+        self.handlers.lock().unwrap().push((
+            id,
+            characteristic_name,
+            Arc::new(Mutex::new(handler_func)),
+        ));
+        id
+    }
+
+    pub fn unregister_notify(&self, id: usize) -> bool {
+        let mut handlers = self.handlers.lock().unwrap();
+        let count = handlers.len();
+        handlers.retain(|x| x.0 != id);
+        handlers.len() != count
+    }
+
+    // call the handlers out of the locks (handlers may write to the cube).
+    // The notifications of a write from a handler are sent by the running dispatch.
+    fn dispatch(&self) {
+        let cube = Arc::as_ptr(&self.state) as usize;
+        let nested = DISPATCHING.with(|x| {
+            let mut cubes = x.borrow_mut();
+            if cubes.contains(&cube) {
+                return true;
+            }
+            cubes.push(cube);
+            false
+        });
+        if nested {
+            return;
+        }
+        loop {
+            let notifications = std::mem::take(&mut self.state.lock().unwrap().notifications);
+            if notifications.is_empty() {
+                break;
+            }
+            for (characteristic, bytes) in notifications {
+                let handlers: Vec<NotifyHandler> = self
+                    .handlers
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|x| x.1 == characteristic)
+                    .map(|x| x.2.clone())
+                    .collect();
+                for handler in handlers {
+                    (handler.lock().unwrap())(bytes.clone());
+                }
+            }
+        }
+        DISPATCHING.with(|x| x.borrow_mut().retain(|x| *x != cube));
+    }
+
+    /// Advance the simulation to `now` by SIMULATION_STEP
+    pub fn update(&self, now: time::Instant) {
+        self.state.lock().unwrap().update(now);
+        self.dispatch();
+    }
+
+    /// Advance the simulation to the current time of the clock
+    pub fn update_now(&self) {
+        self.update(self.clock.now());
+    }
+
+    /// Run the simulation with the clock (updated every SIMULATION_STEP of the real time)
+    pub fn spawn(&self) -> SimulatorRunner {
+        let running = Arc::new(AtomicBool::new(true));
+        let r = running.clone();
+        let cube = self.clone();
+        let thread = thread::spawn(move || {
+            while r.load(Ordering::SeqCst) {
+                cube.update(cube.clock.now());
+                // not the clock: a manual clock would advance without waiting
+                thread::sleep(SIMULATION_STEP);
+            }
+        });
+        SimulatorRunner {
+            running,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn get_pose(&self) -> Pose {
+        self.state.lock().unwrap().pose
+    }
+
+    /// Move the cube by hand
+    pub fn set_pose(&self, pose: Pose) {
+        self.state.lock().unwrap().pose = pose;
+    }
+
+    /// Lift the cube from the mat (no Position ID, the wheels don't move it)
+    pub fn set_lifted(&self, lifted: bool) {
+        self.state.lock().unwrap().lifted = lifted;
+    }
+
+    pub fn get_motor_control(&self) -> MotorControl {
+        self.state.lock().unwrap().motor
+    }
+
+    pub fn is_target_moving(&self) -> bool {
+        self.state.lock().unwrap().target_move.is_some()
+    }
+
+    pub fn get_light(&self) -> Option<LightColor> {
+        self.state.lock().unwrap().light
+    }
+
+    pub fn is_playing_sound(&self) -> bool {
+        self.state.lock().unwrap().sound_playing
+    }
+
+    pub fn set_button(&self, pressed: bool) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.button = pressed;
+            let bytes = vec![0x01, if pressed { 0x80 } else { 0x00 }];
+            state.notify(CoreCubeUuidName::ButtonInfo, bytes);
+        }
+        self.dispatch();
+    }
+
+    /// Notified at the next battery notification
+    pub fn set_battery(&self, level: u8) {
+        self.state.lock().unwrap().battery = level.min(100);
+    }
+
+    /// Change the motion detection state and notify it
+    pub fn set_motion(&self, motion: MotionState) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.motion = motion;
+            state.notify_motion();
+        }
+        self.dispatch();
+    }
+
+    pub fn get_motion(&self) -> MotionState {
+        self.state.lock().unwrap().motion
+    }
+}
+
+impl CoreCubeTransport for SimulatedCube {
+    fn read(&self, characteristic_name: CoreCubeUuidName) -> std::result::Result<Vec<u8>, String> {
+        let state = self.state.lock().unwrap();
+        match characteristic_name {
+            CoreCubeUuidName::IdInfo => Ok(state.get_id_bytes()),
+            CoreCubeUuidName::SensorInfo => Ok(state.motion.get_bytes()),
+            CoreCubeUuidName::ButtonInfo => Ok(vec![0x01, if state.button { 0x80 } else { 0x00 }]),
+            CoreCubeUuidName::BatteryInfo => Ok(vec![state.battery]),
+            CoreCubeUuidName::Configuration => Ok(state.last_config_response.clone()),
+            x => Err(format!("simulator: {} is not readable", x)),
+        }
+    }
+
+    fn write(
+        &self,
+        characteristic_name: CoreCubeUuidName,
+        bytes: &[u8],
+    ) -> std::result::Result<bool, String> {
+        let result = {
+            let mut state = self.state.lock().unwrap();
+            if state.time.is_none() {
+                state.time = Some(self.clock.now());
+            }
+            state.write(characteristic_name, bytes)
+        };
+        self.dispatch();
+        if result {
+            Ok(true)
+        } else {
+            Err(format!(
+                "simulator: invalid {} packet {:02x?}",
+                characteristic_name, bytes
+            ))
+        }
+    }
+}
+
+/// Stops the simulation thread when dropped
+pub struct SimulatorRunner {
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SimulatorRunner {
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SimulatorRunner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motor::*;
+
+    type Received = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    fn record(cube: &SimulatedCube, characteristic: CoreCubeUuidName) -> Received {
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let r = received.clone();
+        cube.register_notify(
+            characteristic,
+            Box::new(move |data: Vec<u8>| r.lock().unwrap().push(data)),
+        );
+        received
+    }
+
+    fn run_until(cube: &SimulatedCube, now: &mut time::Instant, end: time::Duration) {
+        let end = *now + end;
+        while *now < end {
+            *now += time::Duration::from_millis(100);
+            cube.update(*now);
+        }
+    }
+
+    #[test]
+    fn motor_and_notifications() {
+        let mut now = time::Instant::now();
+        let cube = SimulatedCube::new(SimulatorConfig::default());
+        let id_info = record(&cube, CoreCubeUuidName::IdInfo);
+        let battery = record(&cube, CoreCubeUuidName::BatteryInfo);
+        let start = cube.get_pose();
+        cube.update(now);
+        assert_eq!(id_info.lock().unwrap().len(), 1);
+        // no notification without changes
+        run_until(&cube, &mut now, time::Duration::from_secs(1));
+        assert_eq!(id_info.lock().unwrap().len(), 1);
+
+        // forward for 1s
+        let bytes = MotorControl::new(50, 50)
+            .with_duration(time::Duration::from_secs(1))
+            .get_bytes();
+        assert_eq!(cube.write(CoreCubeUuidName::MotorCtrl, &bytes), Ok(true));
+        run_until(&cube, &mut now, time::Duration::from_secs(2));
+        let pose = cube.get_pose();
+        let expected = speed_to_mm_per_sec(50) / MM_PER_UNIT;
+        assert!((pose.x - start.x - expected).abs() < 1.0, "{:?}", pose);
+        assert!((pose.y - start.y).abs() < 0.1);
+        assert_eq!(cube.get_motor_control(), MotorControl::stop());
+        // Position ID every 10ms while moving
+        assert!(id_info.lock().unwrap().len() > 90);
+        assert_eq!(
+            cube.read(CoreCubeUuidName::IdInfo).unwrap(),
+            *id_info.lock().unwrap().last().unwrap()
+        );
+        assert_eq!(*battery.lock().unwrap(), vec![vec![100]]);
+
+        // lift: Position ID missed only once
+        cube.set_lifted(true);
+        run_until(&cube, &mut now, time::Duration::from_secs(1));
+        assert_eq!(*id_info.lock().unwrap().last().unwrap(), vec![0x03]);
+        assert_eq!(
+            id_info
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|x| x[0] == 0x03)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn target_move() {
+        let mut now = time::Instant::now();
+        let cube = SimulatedCube::new(SimulatorConfig::default());
+        let motor = record(&cube, CoreCubeUuidName::MotorCtrl);
+        cube.update(now);
+        let start = cube.get_pose();
+
+        let config = TargetMoveConfig {
+            max_speed: 80,
+            ..TargetMoveConfig::default()
+        };
+        let target = Waypoint::new(start.x as u16 + 60, start.y as u16 + 40, Some(90.0));
+        let bytes = get_target_bytes(1, &config, &target);
+        cube.write(CoreCubeUuidName::MotorCtrl, &bytes).unwrap();
+        run_until(&cube, &mut now, time::Duration::from_secs(5));
+        assert_eq!(*motor.lock().unwrap(), vec![vec![0x83, 0x01, 0x00]]);
+        let pose = cube.get_pose();
+        assert!(pose.distance(&Pose::new(target.x as f32, target.y as f32, 0.0)) < 10.0);
+        assert!(pose.angle.difference(Angle::from_degrees(90.0)).abs() < 15.0);
+
+        // overwritten by the next command
+        motor.lock().unwrap().clear();
+        let targets = [Waypoint::new(200, 200, None), Waypoint::new(300, 200, None)];
+        let bytes = get_multi_target_bytes(2, &config, WriteMode::Overwrite, &targets);
+        cube.write(CoreCubeUuidName::MotorCtrl, &bytes).unwrap();
+        run_until(&cube, &mut now, time::Duration::from_millis(200));
+        cube.write(CoreCubeUuidName::MotorCtrl, &bytes).unwrap();
+        let bytes = get_multi_target_bytes(3, &config, WriteMode::Append, &targets[0..1]);
+        cube.write(CoreCubeUuidName::MotorCtrl, &bytes).unwrap();
+        run_until(&cube, &mut now, time::Duration::from_secs(10));
+        assert_eq!(
+            *motor.lock().unwrap(),
+            vec![
+                vec![0x84, 2, 0x05],
+                vec![0x84, 2, 0x00],
+                vec![0x84, 3, 0x00]
+            ]
+        );
+
+        // invalid parameter
+        motor.lock().unwrap().clear();
+        let config = TargetMoveConfig {
+            max_speed: 5,
+            ..TargetMoveConfig::default()
+        };
+        let bytes = get_target_bytes(4, &config, &target);
+        cube.write(CoreCubeUuidName::MotorCtrl, &bytes).unwrap();
+        assert_eq!(*motor.lock().unwrap(), vec![vec![0x83, 4, 0x03]]);
+    }
+
+    // target angle (degrees from 90) of the angle type written at 30 degrees
+    fn get_target_angle(angle_type: u16, previous_angle: Option<f32>) -> Option<f32> {
+        let mut state = SimulatorState::new(SimulatorConfig::default());
+        state.pose.angle = Angle::from_degrees(30.0);
+        let previous = previous_angle.map(|x| Target {
+            x: 100.0,
+            y: 100.0,
+            angle: Some(Angle::from_degrees(x)),
+        });
+        let angle = (angle_type << 13) | 90;
+        let data = [0xff, 0xff, 0xff, 0xff, angle as u8, (angle >> 8) as u8];
+        state
+            .get_target(&data, previous.as_ref())
+            .angle
+            .map(|x| Angle::from_degrees(90.0).difference(x).round())
+    }
+
+    #[test]
+    fn target_angle_absolute() {
+        // the type only selects the rotation direction
+        for angle_type in 0..=2 {
+            assert_eq!(get_target_angle(angle_type, None), Some(0.0));
+            assert_eq!(get_target_angle(angle_type, Some(-60.0)), Some(0.0));
+        }
+    }
+
+    #[test]
+    fn target_angle_relative_positive() {
+        assert_eq!(get_target_angle(3, None), Some(30.0));
+        assert_eq!(get_target_angle(3, Some(-60.0)), Some(-60.0));
+    }
+
+    #[test]
+    fn target_angle_relative_negative() {
+        assert_eq!(get_target_angle(4, None), Some(-150.0));
+        assert_eq!(get_target_angle(4, Some(-60.0)), Some(120.0));
+    }
+
+    #[test]
+    fn target_angle_none() {
+        assert_eq!(get_target_angle(5, None), None);
+        assert_eq!(get_target_angle(5, Some(-60.0)), None);
+    }
+
+    #[test]
+    fn target_angle_written() {
+        // not relative to the previous target
+        assert_eq!(get_target_angle(6, None), Some(30.0));
+        assert_eq!(get_target_angle(6, Some(-60.0)), Some(30.0));
+    }
+
+    #[test]
+    fn without_mat() {
+        let now = time::Instant::now();
+        let cube = SimulatedCube::new(SimulatorConfig {
+            mat: None,
+            ..SimulatorConfig::default()
+        });
+        let motor = record(&cube, CoreCubeUuidName::MotorCtrl);
+        cube.update(now);
+        assert_eq!(cube.read(CoreCubeUuidName::IdInfo).unwrap(), vec![0x03]);
+        let bytes = get_target_bytes(
+            1,
+            &TargetMoveConfig::default(),
+            &Waypoint::new(100, 100, None),
+        );
+        cube.write(CoreCubeUuidName::MotorCtrl, &bytes).unwrap();
+        assert_eq!(*motor.lock().unwrap(), vec![vec![0x83, 0x01, 0x02]]);
+    }
+
+    #[test]
+    fn handler_writes_to_the_cube() {
+        let cube = SimulatedCube::new(SimulatorConfig::default());
+        let config = record(&cube, CoreCubeUuidName::Configuration);
+        cube.update(time::Instant::now());
+
+        // button -> light and a configuration request -> light off from its response
+        let c = cube.clone();
+        cube.register_notify(
+            CoreCubeUuidName::ButtonInfo,
+            Box::new(move |_: Vec<u8>| {
+                let color = LightColor::new(255, 0, 0);
+                let bytes = crate::light::get_light_bytes(color, None);
+                c.write(CoreCubeUuidName::LightCtrl, &bytes).unwrap();
+                c.write(CoreCubeUuidName::Configuration, &[0x01, 0x00])
+                    .unwrap();
+            }),
+        );
+        let c = cube.clone();
+        let lights: Received = Arc::new(Mutex::new(Vec::new()));
+        let l = lights.clone();
+        cube.register_notify(
+            CoreCubeUuidName::Configuration,
+            Box::new(move |_: Vec<u8>| {
+                l.lock().unwrap().push(vec![c.get_light().is_some() as u8]);
+                c.write(CoreCubeUuidName::LightCtrl, &[0x01]).unwrap();
+            }),
+        );
+        cube.set_button(true);
+        assert_eq!(*config.lock().unwrap(), vec![b"\x81\x002.3.0".to_vec()]);
+        assert_eq!(*lights.lock().unwrap(), vec![vec![1]]);
+        assert_eq!(cube.get_light(), None);
+    }
+
+    #[test]
+    fn posture_angle_yaw() {
+        let mut now = time::Instant::now();
+        let cube = SimulatedCube::new(SimulatorConfig::default());
+        let sensor = record(&cube, CoreCubeUuidName::SensorInfo);
+        cube.update(now);
+        cube.write(
+            CoreCubeUuidName::Configuration,
+            &crate::sensor::get_posture_angle_config_bytes(10),
+        )
+        .unwrap();
+        let mut yaw = |degrees: f32| {
+            cube.set_pose(Pose::new(200.0, 200.0, degrees));
+            sensor.lock().unwrap().clear();
+            run_until(&cube, &mut now, time::Duration::from_millis(100));
+            let posture = sensor.lock().unwrap().clone();
+            crate::sensor::get_posture_angle(posture.last().unwrap())
+                .unwrap()
+                .yaw
+        };
+        assert_eq!(yaw(90.0), 90);
+        assert_eq!(yaw(180.0), 180);
+        assert_eq!(yaw(270.0), -90);
+        assert_eq!(yaw(359.7), 0);
+    }
+
+    #[test]
+    fn configuration_light_and_sound() {
+        let mut now = time::Instant::now();
+        let cube = SimulatedCube::new(SimulatorConfig::default());
+        let config = record(&cube, CoreCubeUuidName::Configuration);
+        let motor = record(&cube, CoreCubeUuidName::MotorCtrl);
+        let sensor = record(&cube, CoreCubeUuidName::SensorInfo);
+        let button = record(&cube, CoreCubeUuidName::ButtonInfo);
+        cube.update(now);
+
+        cube.write(CoreCubeUuidName::Configuration, &[0x01, 0x00])
+            .unwrap();
+        cube.write(
+            CoreCubeUuidName::Configuration,
+            &get_motor_speed_config_bytes(true),
+        )
+        .unwrap();
+        cube.write(
+            CoreCubeUuidName::Configuration,
+            &crate::sensor::get_posture_angle_config_bytes(10),
+        )
+        .unwrap();
+        assert_eq!(
+            *config.lock().unwrap(),
+            vec![
+                b"\x81\x002.3.0".to_vec(),
+                vec![0x9c, 0x00, 0x00],
+                vec![0x9d, 0x00, 0x00]
+            ]
+        );
+
+        cube.write(
+            CoreCubeUuidName::MotorCtrl,
+            &MotorControl::new(30, -30).get_bytes(),
+        )
+        .unwrap();
+        run_until(&cube, &mut now, time::Duration::from_millis(500));
+        assert_eq!(*motor.lock().unwrap(), vec![vec![0xe0, 30, 30]]);
+        let posture = sensor.lock().unwrap().clone();
+        assert!(posture.len() >= 45);
+        let angle = crate::sensor::get_posture_angle(posture.last().unwrap()).unwrap();
+        let yaw = Angle::from_degrees(angle.yaw as f32);
+        assert!(yaw.difference(cube.get_pose().angle).abs() <= 0.5);
+
+        cube.set_button(true);
+        assert_eq!(*button.lock().unwrap(), vec![vec![0x01, 0x80]]);
+        cube.set_motion(MotionState {
+            double_tap: true,
+            ..MotionState::default()
+        });
+        assert_eq!(
+            *sensor.lock().unwrap().last().unwrap(),
+            vec![0x01, 0x01, 0x00, 0x01, 0x01, 0x00]
+        );
+        assert!(!cube.get_motion().double_tap);
+
+        let color = LightColor::new(0, 0, 255);
+        let bytes = crate::light::get_light_bytes(color, Some(time::Duration::from_millis(300)));
+        cube.write(CoreCubeUuidName::LightCtrl, &bytes).unwrap();
+        assert_eq!(cube.get_light(), Some(color));
+        cube.write(
+            CoreCubeUuidName::SoundCtrl,
+            &[0x03, 0x01, 0x01, 0x0a, 57, 0xff],
+        )
+        .unwrap();
+        assert!(cube.is_playing_sound());
+        run_until(&cube, &mut now, time::Duration::from_millis(400));
+        assert_eq!(cube.get_light(), None);
+        assert!(!cube.is_playing_sound());
+
+        assert!(cube
+            .write(CoreCubeUuidName::MotorCtrl, &[0x01, 0x01])
+            .is_err());
+        assert!(cube.read(CoreCubeUuidName::MotorCtrl).is_err());
+    }
+}
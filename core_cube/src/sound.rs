@@ -0,0 +1,75 @@
+/* Sound control packet builders */
+
+use std::time;
+
+use crate::units::duration_to_motor_duration;
+
+/// MIDI note number of a rest
+pub const NOTE_REST: u8 = 128;
+/// Maximum number of notes in a MIDI command
+pub const MIDI_NOTES_MAX: usize = 59;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Note {
+    /// MIDI note number (69: A4, NOTE_REST: rest)
+    pub note: u8,
+    pub duration: time::Duration,
+    /// 0 - 255
+    pub volume: u8,
+}
+
+impl Note {
+    pub fn new(note: u8, duration: time::Duration) -> Self {
+        Self {
+            note,
+            duration,
+            volume: 0xff,
+        }
+    }
+
+    pub fn rest(duration: time::Duration) -> Self {
+        Self::new(NOTE_REST, duration)
+    }
+}
+
+/// Play MIDI notes (0x03) `repeat` times (0: forever)
+/// Notes after the first MIDI_NOTES_MAX are ignored.
+pub fn get_midi_bytes(repeat: u8, notes: &[Note]) -> Vec<u8> {
+    let notes = &notes[..notes.len().min(MIDI_NOTES_MAX)];
+    let mut bytes = vec![0x03, repeat, notes.len() as u8];
+    for note in notes {
+        bytes.extend_from_slice(&[
+            duration_to_motor_duration(note.duration),
+            note.note.min(NOTE_REST),
+            note.volume,
+        ]);
+    }
+    bytes
+}
+
+/// Play a sound effect (0x02)
+pub fn get_sound_effect_bytes(sound_effect_id: u8, volume: u8) -> Vec<u8> {
+    vec![0x02, sound_effect_id, volume]
+}
+
+/// Stop playing (0x01)
+pub fn get_sound_stop_bytes() -> Vec<u8> {
+    vec![0x01]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midi_bytes() {
+        assert_eq!(
+            get_midi_bytes(1, &[Note::new(57, time::Duration::from_millis(100))]),
+            vec![0x03, 0x01, 0x01, 0x0a, 57, 0xff]
+        );
+        let notes = vec![Note::rest(time::Duration::from_millis(10)); 70];
+        let bytes = get_midi_bytes(0, &notes);
+        assert_eq!(bytes[2] as usize, MIDI_NOTES_MAX);
+        assert_eq!(bytes.len(), 3 + 3 * MIDI_NOTES_MAX);
+    }
+}
@@ -0,0 +1,258 @@
+/* Standard ID catalog (toio cards, stickers) and card events */
+/*
+ * Names and values follow the Standard ID list of the toio Core Cube specification:
+ * https://toio.github.io/toio-spec/en/docs/hardware_standard_id
+ *
+ * Both sides of the toio collection mat (ring and colored tiles) are printed with
+ * Position IDs, not Standard IDs: a tile is found with `mat::Mat::get_cell()` of
+ * `MatType::ToioCollectionTiles`.
+ *
+ * The symbol and arrow cards of the simple card set are left out: their values are not
+ * checked against the list yet, and a wrong name is worse than none. They are reported as
+ * unknown values (a key mapping matches them with the value).
+ */
+
+use log::{debug, info};
+
+use crate::id_info::{get_id_info, IdInfo};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StandardIdCategory {
+    /// toio collection cards
+    ToioCollectionCard,
+    /// toio collection stickers
+    ToioCollectionSticker,
+    /// simple cards: numbers (0 - 9)
+    Number,
+    /// simple cards: letters (A - Z)
+    Letter,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StandardIdItem {
+    pub value: u32,
+    pub name: &'static str,
+    pub category: StandardIdCategory,
+}
+
+const fn item(value: u32, name: &'static str, category: StandardIdCategory) -> StandardIdItem {
+    StandardIdItem {
+        value,
+        name,
+        category,
+    }
+}
+
+const NUMBER_BASE: u32 = 3670320;
+const LETTER_BASE: u32 = 3670337;
+
+const NUMBER_NAMES: [&str; 10] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+const LETTER_NAMES: [&str; 26] = [
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S",
+    "T", "U", "V", "W", "X", "Y", "Z",
+];
+
+static STANDARD_ID_LIST: [StandardIdItem; 14] = [
+    // toio collection: cards
+    item(3670016, "Typhoon", StandardIdCategory::ToioCollectionCard),
+    item(3670054, "Rush", StandardIdCategory::ToioCollectionCard),
+    item(
+        3670018,
+        "Auto Tackle",
+        StandardIdCategory::ToioCollectionCard,
+    ),
+    item(3670056, "Random", StandardIdCategory::ToioCollectionCard),
+    item(
+        3670020,
+        "Push Power Up",
+        StandardIdCategory::ToioCollectionCard,
+    ),
+    item(
+        3670058,
+        "Strut Power Up",
+        StandardIdCategory::ToioCollectionCard,
+    ),
+    item(
+        3670022,
+        "Side Attack",
+        StandardIdCategory::ToioCollectionCard,
+    ),
+    item(3670060, "Easy Mode", StandardIdCategory::ToioCollectionCard),
+    // toio collection: stickers
+    item(
+        3670024,
+        "Speed Up",
+        StandardIdCategory::ToioCollectionSticker,
+    ),
+    item(
+        3670062,
+        "Speed Down",
+        StandardIdCategory::ToioCollectionSticker,
+    ),
+    item(3670026, "Wobble", StandardIdCategory::ToioCollectionSticker),
+    item(3670064, "Panic", StandardIdCategory::ToioCollectionSticker),
+    item(3670028, "Spin", StandardIdCategory::ToioCollectionSticker),
+    item(3670066, "Shock", StandardIdCategory::ToioCollectionSticker),
+];
+
+/// Find the catalog item of the Standard ID value
+pub fn find_standard_id(value: u32) -> Option<StandardIdItem> {
+    if (NUMBER_BASE..NUMBER_BASE + NUMBER_NAMES.len() as u32).contains(&value) {
+        return Some(item(
+            value,
+            NUMBER_NAMES[(value - NUMBER_BASE) as usize],
+            StandardIdCategory::Number,
+        ));
+    }
+    if (LETTER_BASE..LETTER_BASE + LETTER_NAMES.len() as u32).contains(&value) {
+        return Some(item(
+            value,
+            LETTER_NAMES[(value - LETTER_BASE) as usize],
+            StandardIdCategory::Letter,
+        ));
+    }
+    STANDARD_ID_LIST.iter().find(|x| x.value == value).copied()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CardEvent {
+    Placed { value: u32, angle: u16 },
+    Removed { value: u32 },
+}
+
+impl CardEvent {
+    pub fn get_value(&self) -> u32 {
+        match *self {
+            CardEvent::Placed { value, .. } => value,
+            CardEvent::Removed { value } => value,
+        }
+    }
+
+    pub fn get_item(&self) -> Option<StandardIdItem> {
+        find_standard_id(self.get_value())
+    }
+}
+
+/// Detect "card placed / card removed" from the ID information notifications
+#[derive(Debug, Default)]
+pub struct CardEventDetector {
+    current: Option<u32>,
+}
+
+impl CardEventDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Standard ID value of the card under the cube
+    pub fn get_current(&self) -> Option<u32> {
+        self.current
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Vec<CardEvent> {
+        let mut events: Vec<CardEvent> = Vec::new();
+        match get_id_info(data) {
+            IdInfo::StandardId(standard_id) => {
+                if self.current == Some(standard_id.value) {
+                    return events;
+                }
+                if let Some(value) = self.current {
+                    events.push(CardEvent::Removed { value });
+                }
+                events.push(CardEvent::Placed {
+                    value: standard_id.value,
+                    angle: standard_id.angle,
+                });
+                self.current = Some(standard_id.value);
+            }
+            // the cube left the card (or moved onto a mat)
+            IdInfo::StandardIdMissed | IdInfo::PositionId(_) => {
+                if let Some(value) = self.current.take() {
+                    events.push(CardEvent::Removed { value });
+                }
+            }
+            _ => (),
+        }
+
+        for event in &events {
+            match event.get_item() {
+                Some(x) => info!("{:?}: {} ({:?})", event, x.name, x.category),
+                None => debug!("{:?}: unknown card", event),
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn standard_id_data(value: u32) -> Vec<u8> {
+        let v = value.to_le_bytes();
+        vec![0x02, v[0], v[1], v[2], v[3], 0x00, 0x00]
+    }
+
+    fn find(value: u32) -> Option<(&'static str, StandardIdCategory)> {
+        find_standard_id(value).map(|x| (x.name, x.category))
+    }
+
+    #[test]
+    fn catalog_cards() {
+        let card = StandardIdCategory::ToioCollectionCard;
+        assert_eq!(find(3670016), Some(("Typhoon", card)));
+        assert_eq!(find(3670020), Some(("Push Power Up", card)));
+        assert_eq!(find(3670058), Some(("Strut Power Up", card)));
+        assert_eq!(find(3670022), Some(("Side Attack", card)));
+        assert_eq!(find(3670060), Some(("Easy Mode", card)));
+    }
+
+    #[test]
+    fn catalog_stickers() {
+        let sticker = StandardIdCategory::ToioCollectionSticker;
+        assert_eq!(find(3670024), Some(("Speed Up", sticker)));
+        assert_eq!(find(3670062), Some(("Speed Down", sticker)));
+        assert_eq!(find(3670026), Some(("Wobble", sticker)));
+        assert_eq!(find(3670064), Some(("Panic", sticker)));
+        assert_eq!(find(3670028), Some(("Spin", sticker)));
+        assert_eq!(find(3670066), Some(("Shock", sticker)));
+    }
+
+    #[test]
+    fn catalog_simple_cards() {
+        assert_eq!(find(3670320), Some(("0", StandardIdCategory::Number)));
+        assert_eq!(find(3670329), Some(("9", StandardIdCategory::Number)));
+        assert_eq!(find(3670337), Some(("A", StandardIdCategory::Letter)));
+        assert_eq!(find(3670362), Some(("Z", StandardIdCategory::Letter)));
+        assert_eq!(find(1), None);
+    }
+
+    #[test]
+    fn card_events() {
+        let mut detector = CardEventDetector::new();
+        assert_eq!(
+            detector.update(&standard_id_data(3670016)),
+            vec![CardEvent::Placed {
+                value: 3670016,
+                angle: 0
+            }]
+        );
+        assert_eq!(detector.update(&standard_id_data(3670016)), vec![]);
+        assert_eq!(
+            detector.update(&standard_id_data(3670054)),
+            vec![
+                CardEvent::Removed { value: 3670016 },
+                CardEvent::Placed {
+                    value: 3670054,
+                    angle: 0
+                }
+            ]
+        );
+        assert_eq!(
+            detector.update(&[0x04]),
+            vec![CardEvent::Removed { value: 3670054 }]
+        );
+        assert_eq!(detector.update(&[0x04]), vec![]);
+        assert_eq!(detector.get_current(), None);
+    }
+}
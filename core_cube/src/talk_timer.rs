@@ -0,0 +1,457 @@
+/* Talk timer: remaining time of a presentation shown with the cube light and sound */
+
+use std::fmt;
+use std::str::FromStr;
+use std::time;
+
+use crate::light::{get_light_bytes, get_light_scenario_bytes, LightColor};
+use crate::sound::{get_midi_bytes, Note};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TalkTimerConfig {
+    pub length: time::Duration,
+    /// Remaining times notified with a sound
+    pub warnings: Vec<time::Duration>,
+}
+
+impl Default for TalkTimerConfig {
+    fn default() -> Self {
+        Self {
+            length: time::Duration::from_secs(20 * 60),
+            warnings: vec![
+                time::Duration::from_secs(5 * 60),
+                time::Duration::from_secs(60),
+            ],
+        }
+    }
+}
+
+impl TalkTimerConfig {
+    pub fn new(
+        length: time::Duration,
+        warnings: &[time::Duration],
+    ) -> std::result::Result<Self, String> {
+        if length == time::Duration::ZERO {
+            return Err("the talk length must be longer than 0".to_string());
+        }
+        let mut warnings: Vec<time::Duration> = warnings.to_vec();
+        if let Some(x) = warnings
+            .iter()
+            .find(|x| **x == time::Duration::ZERO || **x >= length)
+        {
+            return Err(format!(
+                "warning {} must be shorter than the talk length {}",
+                format_duration(*x),
+                format_duration(length)
+            ));
+        }
+        // from the longest remaining time
+        warnings.sort_by(|a, b| b.cmp(a));
+        warnings.dedup();
+        Ok(Self { length, warnings })
+    }
+}
+
+/// Parse a time: "20m", "90s", "1m30s", "12:30" (minutes:seconds) or "15" (minutes)
+pub fn parse_duration(s: &str) -> std::result::Result<time::Duration, String> {
+    let error = || format!("invalid time \"{}\" (20m, 90s, 1m30s, 12:30)", s);
+    let s = s.trim();
+    if let Some((minutes, seconds)) = s.split_once(':') {
+        let minutes = minutes.parse::<u64>().map_err(|_| error())?;
+        let seconds = seconds.parse::<u64>().map_err(|_| error())?;
+        if seconds >= 60 {
+            return Err(error());
+        }
+        let seconds = minutes.checked_mul(60).and_then(|x| x.checked_add(seconds));
+        return seconds.map(time::Duration::from_secs).ok_or_else(error);
+    }
+    if let Ok(minutes) = s.parse::<u64>() {
+        let seconds = minutes.checked_mul(60);
+        return seconds.map(time::Duration::from_secs).ok_or_else(error);
+    }
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'h' | 'm' | 's' if !number.is_empty() => {
+                let unit = match c {
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                // too long: an error (not an overflow)
+                seconds = number
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|x| x.checked_mul(unit))
+                    .and_then(|x| x.checked_add(seconds))
+                    .ok_or_else(error)?;
+                number.clear();
+            }
+            _ => return Err(error()),
+        }
+    }
+    if s.is_empty() || !number.is_empty() {
+        return Err(error());
+    }
+    Ok(time::Duration::from_secs(seconds))
+}
+
+/// "12:05" (minutes:seconds)
+pub fn format_duration(duration: time::Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Operations of the timer from the key mapping
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TimerControl {
+    Start,
+    Pause,
+    /// Start or pause
+    Toggle,
+    Reset,
+}
+
+const TIMER_CONTROL_NAMES: [(TimerControl, &str); 4] = [
+    (TimerControl::Start, "start"),
+    (TimerControl::Pause, "pause"),
+    (TimerControl::Toggle, "toggle"),
+    (TimerControl::Reset, "reset"),
+];
+
+impl fmt::Display for TimerControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (_, name) = TIMER_CONTROL_NAMES.iter().find(|(x, _)| x == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for TimerControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match TIMER_CONTROL_NAMES.iter().find(|(_, name)| *name == s) {
+            Some((control, _)) => Ok(*control),
+            None => Err(format!(
+                "unknown timer operation \"{}\" ({})",
+                s,
+                TIMER_CONTROL_NAMES
+                    .iter()
+                    .map(|(_, name)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TalkTimerEvent {
+    Started,
+    Paused,
+    Resumed,
+    Reset,
+    /// A warning point is passed (with the remaining time of the warning)
+    Warning(time::Duration),
+    TimeUp,
+}
+
+impl fmt::Display for TalkTimerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TalkTimerEvent::Started => write!(f, "started"),
+            TalkTimerEvent::Paused => write!(f, "paused"),
+            TalkTimerEvent::Resumed => write!(f, "resumed"),
+            TalkTimerEvent::Reset => write!(f, "reset"),
+            TalkTimerEvent::Warning(x) => write!(f, "{} left", format_duration(*x)),
+            TalkTimerEvent::TimeUp => write!(f, "time up"),
+        }
+    }
+}
+
+/// Light of the timer
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TalkTimerPhase {
+    /// Not started
+    Ready,
+    Paused,
+    /// Before the first warning
+    Green,
+    Yellow,
+    /// After the last warning
+    Red,
+    OverTime,
+}
+
+pub struct TalkTimer {
+    config: TalkTimerConfig,
+    /// Elapsed time until the last pause
+    elapsed: time::Duration,
+    running_since: Option<time::Instant>,
+    /// Warnings notified
+    passed: usize,
+    time_up: bool,
+}
+
+impl TalkTimer {
+    pub fn new(config: TalkTimerConfig) -> Self {
+        Self {
+            config,
+            elapsed: time::Duration::ZERO,
+            running_since: None,
+            passed: 0,
+            time_up: false,
+        }
+    }
+
+    pub fn get_config(&self) -> &TalkTimerConfig {
+        &self.config
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running_since.is_some()
+    }
+
+    pub fn get_elapsed(&self, now: time::Instant) -> time::Duration {
+        match self.running_since {
+            Some(since) => self.elapsed + now.saturating_duration_since(since),
+            None => self.elapsed,
+        }
+    }
+
+    /// Remaining time, or the over time with `true`
+    pub fn get_remaining(&self, now: time::Instant) -> (time::Duration, bool) {
+        let elapsed = self.get_elapsed(now);
+        if elapsed < self.config.length {
+            (self.config.length - elapsed, false)
+        } else {
+            (elapsed - self.config.length, true)
+        }
+    }
+
+    pub fn control(&mut self, control: TimerControl, now: time::Instant) -> Option<TalkTimerEvent> {
+        match (control, self.running_since) {
+            (TimerControl::Start, None) | (TimerControl::Toggle, None) => {
+                self.running_since = Some(now);
+                if self.elapsed == time::Duration::ZERO {
+                    Some(TalkTimerEvent::Started)
+                } else {
+                    Some(TalkTimerEvent::Resumed)
+                }
+            }
+            (TimerControl::Pause, Some(since)) | (TimerControl::Toggle, Some(since)) => {
+                self.elapsed += now.saturating_duration_since(since);
+                self.running_since = None;
+                Some(TalkTimerEvent::Paused)
+            }
+            (TimerControl::Reset, _) => {
+                *self = Self::new(self.config.clone());
+                Some(TalkTimerEvent::Reset)
+            }
+            _ => None,
+        }
+    }
+
+    /// Warnings and the time up passed since the last update
+    pub fn update(&mut self, now: time::Instant) -> Vec<TalkTimerEvent> {
+        let mut events: Vec<TalkTimerEvent> = Vec::new();
+        let (remaining, over) = self.get_remaining(now);
+        while let Some(warning) = self.config.warnings.get(self.passed) {
+            if !over && remaining > *warning {
+                break;
+            }
+            // only the last one of the warnings passed at once
+            let next_passed =
+                matches!(self.config.warnings.get(self.passed + 1), Some(x) if remaining <= *x);
+            if !over && !next_passed {
+                events.push(TalkTimerEvent::Warning(*warning));
+            }
+            self.passed += 1;
+        }
+        if over && !self.time_up {
+            self.time_up = true;
+            events.push(TalkTimerEvent::TimeUp);
+        }
+        events
+    }
+
+    pub fn get_phase(&self, now: time::Instant) -> TalkTimerPhase {
+        let (remaining, over) = self.get_remaining(now);
+        if over {
+            return TalkTimerPhase::OverTime;
+        }
+        if !self.is_running() {
+            if self.elapsed == time::Duration::ZERO {
+                return TalkTimerPhase::Ready;
+            }
+            return TalkTimerPhase::Paused;
+        }
+        let warnings = &self.config.warnings;
+        match warnings.iter().filter(|x| remaining <= **x).count() {
+            0 => TalkTimerPhase::Green,
+            n if n == warnings.len() => TalkTimerPhase::Red,
+            _ => TalkTimerPhase::Yellow,
+        }
+    }
+}
+
+/// Light control bytes of the phase
+pub fn get_phase_light_bytes(phase: TalkTimerPhase) -> Vec<u8> {
+    let blink = time::Duration::from_millis(300);
+    match phase {
+        TalkTimerPhase::Ready => get_light_bytes(LightColor::new(0x10, 0x10, 0x10), None),
+        TalkTimerPhase::Paused => get_light_bytes(LightColor::new(0x00, 0x00, 0x20), None),
+        TalkTimerPhase::Green => get_light_bytes(LightColor::new(0x00, 0x20, 0x00), None),
+        TalkTimerPhase::Yellow => get_light_bytes(LightColor::new(0x30, 0x20, 0x00), None),
+        TalkTimerPhase::Red => get_light_bytes(LightColor::new(0x40, 0x00, 0x00), None),
+        TalkTimerPhase::OverTime => get_light_scenario_bytes(
+            0,
+            &[
+                (LightColor::new(0xff, 0x00, 0x00), blink),
+                (LightColor::off(), blink),
+            ],
+        ),
+    }
+}
+
+/// Sound control bytes of the event
+pub fn get_event_sound_bytes(event: TalkTimerEvent) -> Vec<u8> {
+    let note = |note: u8, ms: u64| Note::new(note, time::Duration::from_millis(ms));
+    let rest = |ms: u64| Note::rest(time::Duration::from_millis(ms));
+    match event {
+        TalkTimerEvent::Started | TalkTimerEvent::Resumed => {
+            get_midi_bytes(1, &[note(72, 100), note(79, 150)])
+        }
+        TalkTimerEvent::Paused => get_midi_bytes(1, &[note(79, 100), note(72, 150)]),
+        TalkTimerEvent::Reset => get_midi_bytes(1, &[note(72, 100)]),
+        TalkTimerEvent::Warning(_) => get_midi_bytes(1, &[note(81, 150), rest(100), note(81, 150)]),
+        TalkTimerEvent::TimeUp => get_midi_bytes(
+            1,
+            &[
+                note(84, 200),
+                rest(100),
+                note(84, 200),
+                rest(100),
+                note(84, 500),
+            ],
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(x: u64) -> time::Duration {
+        time::Duration::from_secs(x)
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("20m"), Ok(secs(1200)));
+        assert_eq!(parse_duration("90s"), Ok(secs(90)));
+        assert_eq!(parse_duration("1m30s"), Ok(secs(90)));
+        assert_eq!(parse_duration("1h"), Ok(secs(3600)));
+        assert_eq!(parse_duration("12:30"), Ok(secs(750)));
+        assert_eq!(parse_duration("15"), Ok(secs(900)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5x").is_err());
+        assert!(parse_duration("1:75").is_err());
+        assert!(parse_duration("999999999999999999h").is_err());
+        assert!(parse_duration("1s999999999999999999h").is_err());
+        assert!(parse_duration("999999999999999999:00").is_err());
+        assert!(parse_duration("999999999999999999").is_err());
+        assert_eq!(format_duration(secs(725)), "12:05");
+
+        let config = TalkTimerConfig::new(secs(600), &[secs(60), secs(300), secs(60)]).unwrap();
+        assert_eq!(config.warnings, vec![secs(300), secs(60)]);
+        assert!(TalkTimerConfig::new(secs(600), &[secs(600)]).is_err());
+        assert!(TalkTimerConfig::new(secs(0), &[]).is_err());
+    }
+
+    #[test]
+    fn talk_timer() {
+        let start = time::Instant::now();
+        let at = |x: u64| start + secs(x);
+        let config = TalkTimerConfig::new(secs(600), &[secs(300), secs(60)]).unwrap();
+        let mut timer = TalkTimer::new(config);
+        assert_eq!(timer.get_phase(at(0)), TalkTimerPhase::Ready);
+        assert!(timer.update(at(100)).is_empty());
+
+        assert_eq!(
+            timer.control(TimerControl::Toggle, at(0)),
+            Some(TalkTimerEvent::Started)
+        );
+        assert_eq!(timer.control(TimerControl::Start, at(10)), None);
+        assert_eq!(timer.get_phase(at(100)), TalkTimerPhase::Green);
+        assert!(timer.update(at(299)).is_empty());
+        assert_eq!(
+            timer.update(at(300)),
+            vec![TalkTimerEvent::Warning(secs(300))]
+        );
+        assert_eq!(timer.get_phase(at(300)), TalkTimerPhase::Yellow);
+        assert!(timer.update(at(310)).is_empty());
+
+        // the pause stops the time
+        assert_eq!(
+            timer.control(TimerControl::Toggle, at(400)),
+            Some(TalkTimerEvent::Paused)
+        );
+        assert_eq!(timer.get_phase(at(1000)), TalkTimerPhase::Paused);
+        assert_eq!(timer.get_remaining(at(1000)), (secs(200), false));
+        assert_eq!(
+            timer.control(TimerControl::Start, at(1000)),
+            Some(TalkTimerEvent::Resumed)
+        );
+        assert_eq!(
+            timer.update(at(1140)),
+            vec![TalkTimerEvent::Warning(secs(60))]
+        );
+        assert_eq!(timer.get_phase(at(1140)), TalkTimerPhase::Red);
+        assert_eq!(timer.update(at(1200)), vec![TalkTimerEvent::TimeUp]);
+        assert_eq!(timer.get_phase(at(1230)), TalkTimerPhase::OverTime);
+        assert_eq!(timer.get_remaining(at(1230)), (secs(30), true));
+        assert!(timer.update(at(1300)).is_empty());
+
+        assert_eq!(
+            timer.control(TimerControl::Reset, at(1300)),
+            Some(TalkTimerEvent::Reset)
+        );
+        assert_eq!(timer.get_phase(at(1300)), TalkTimerPhase::Ready);
+
+        // a single warning is the last warning
+        let config = TalkTimerConfig::new(secs(600), &[secs(60)]).unwrap();
+        let mut single = TalkTimer::new(config);
+        single.control(TimerControl::Start, at(0));
+        assert_eq!(single.get_phase(at(539)), TalkTimerPhase::Green);
+        assert_eq!(single.get_phase(at(540)), TalkTimerPhase::Red);
+
+        // warnings passed at once (e.g. no update while paused) are notified once
+        timer.control(TimerControl::Start, at(0));
+        assert_eq!(
+            timer.update(at(550)),
+            vec![TalkTimerEvent::Warning(secs(60))]
+        );
+        assert_eq!("toggle".parse::<TimerControl>(), Ok(TimerControl::Toggle));
+        assert!("stop".parse::<TimerControl>().is_err());
+    }
+
+    #[test]
+    fn phase_bytes() {
+        assert_eq!(
+            get_phase_light_bytes(TalkTimerPhase::Green),
+            vec![0x03, 0x00, 0x01, 0x01, 0x00, 0x20, 0x00]
+        );
+        assert_eq!(
+            get_phase_light_bytes(TalkTimerPhase::OverTime)[..3],
+            [0x04, 0, 2]
+        );
+        assert_eq!(
+            get_event_sound_bytes(TalkTimerEvent::Warning(secs(60)))[..3],
+            [0x03, 1, 3]
+        );
+    }
+}
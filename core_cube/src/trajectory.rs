@@ -0,0 +1,515 @@
+/* Trajectory recording and rendering to SVG */
+
+use std::fmt::Write;
+use std::time;
+
+use crate::id_info::{get_id_info, IdInfo};
+use crate::kinematics::Pose;
+use crate::mat::Mat;
+use crate::transport::CoreCubeUuidName;
+use crate::units::Angle;
+
+// colors of the cubes (in the order of the trajectories)
+const COLORS: [&str; 6] = [
+    "#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b",
+];
+// space around the drawing [Position ID units]
+const MARGIN: f32 = 20.0;
+const ARROW_LENGTH: f32 = 12.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TrajectorySample {
+    /// Time from the start of the recording
+    pub time: time::Duration,
+    pub pose: Pose,
+}
+
+/// Command written to the cube
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandMarker {
+    pub time: time::Duration,
+    pub label: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Trajectory {
+    pub name: String,
+    /// Samples split where the Position ID is missed
+    pub segments: Vec<Vec<TrajectorySample>>,
+    pub commands: Vec<CommandMarker>,
+    /// Intended path (e.g. targets of the target move commands)
+    pub intended: Vec<(f32, f32)>,
+}
+
+impl Trajectory {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: TrajectorySample) {
+        match self.segments.last_mut() {
+            Some(segment) => segment.push(sample),
+            None => self.segments.push(vec![sample]),
+        }
+    }
+
+    /// Start a new segment at the next sample
+    pub fn add_break(&mut self) {
+        if matches!(self.segments.last(), Some(segment) if !segment.is_empty()) {
+            self.segments.push(Vec::new());
+        }
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &TrajectorySample> {
+        self.segments.iter().flatten()
+    }
+
+    /// Sample nearest to `time`
+    pub fn get_sample_at(&self, time: time::Duration) -> Option<&TrajectorySample> {
+        self.samples()
+            .min_by_key(|x| time.checked_sub(x.time).unwrap_or_else(|| x.time - time))
+    }
+}
+
+/// Label of a command (None: not shown)
+pub fn get_command_label(characteristic: CoreCubeUuidName, bytes: &[u8]) -> Option<String> {
+    match (characteristic, bytes) {
+        (CoreCubeUuidName::MotorCtrl, [0x01, _, ld, l, _, rd, r, ..])
+        | (CoreCubeUuidName::MotorCtrl, [0x02, _, ld, l, _, rd, r, ..]) => {
+            let signed = |direction: u8, speed: u8| {
+                if direction == 0x02 {
+                    -(speed as i16)
+                } else {
+                    speed as i16
+                }
+            };
+            Some(format!("move {},{}", signed(*ld, *l), signed(*rd, *r)))
+        }
+        (CoreCubeUuidName::MotorCtrl, [0x03, id, ..]) => Some(format!("target #{}", id)),
+        (CoreCubeUuidName::MotorCtrl, [0x04, id, ..]) => Some(format!("multi-target #{}", id)),
+        _ => None,
+    }
+}
+
+// target positions of the target move commands (0xffff: unchanged, skipped)
+fn get_command_targets(bytes: &[u8]) -> Vec<(f32, f32)> {
+    let targets = match bytes {
+        [0x03, ..] if bytes.len() >= 13 => &bytes[7..13],
+        [0x04, ..] if bytes.len() >= 14 => &bytes[8..],
+        _ => return Vec::new(),
+    };
+    targets
+        .chunks_exact(6)
+        .map(|x| {
+            (
+                u16::from_le_bytes([x[0], x[1]]),
+                u16::from_le_bytes([x[2], x[3]]),
+            )
+        })
+        .filter(|(x, y)| *x != 0xffff && *y != 0xffff)
+        .map(|(x, y)| (x as f32, y as f32))
+        .collect()
+}
+
+/// Record the trajectories of the cubes from Position ID notifications
+pub struct TrajectoryRecorder {
+    start_time: time::Instant,
+    trajectories: Vec<Trajectory>,
+}
+
+impl TrajectoryRecorder {
+    pub fn new(start_time: time::Instant) -> Self {
+        Self {
+            start_time,
+            trajectories: Vec::new(),
+        }
+    }
+
+    /// Returns the index of the cube
+    pub fn add_cube(&mut self, name: &str) -> usize {
+        self.trajectories.push(Trajectory::new(name));
+        self.trajectories.len() - 1
+    }
+
+    fn get_time(&self, now: time::Instant) -> time::Duration {
+        now.saturating_duration_since(self.start_time)
+    }
+
+    pub fn add_pose(&mut self, cube: usize, pose: Pose, now: time::Instant) {
+        let time = self.get_time(now);
+        self.trajectories[cube].add_sample(TrajectorySample { time, pose });
+    }
+
+    /// Feed an ID information notification
+    pub fn update_id_info(&mut self, cube: usize, data: &[u8], now: time::Instant) {
+        match get_id_info(data) {
+            IdInfo::PositionId(position) => self.add_pose(cube, Pose::from(&position), now),
+            IdInfo::PositionIdMissed => self.trajectories[cube].add_break(),
+            _ => (),
+        }
+    }
+
+    /// Record a command written to the cube. Targets are added to the intended path.
+    pub fn add_command(
+        &mut self,
+        cube: usize,
+        characteristic: CoreCubeUuidName,
+        bytes: &[u8],
+        now: time::Instant,
+    ) {
+        let time = self.get_time(now);
+        let trajectory = &mut self.trajectories[cube];
+        if let Some(label) = get_command_label(characteristic, bytes) {
+            trajectory.commands.push(CommandMarker { time, label });
+        }
+        if characteristic == CoreCubeUuidName::MotorCtrl {
+            trajectory.intended.extend(get_command_targets(bytes));
+        }
+    }
+
+    pub fn set_intended_path(&mut self, cube: usize, path: Vec<(f32, f32)>) {
+        self.trajectories[cube].intended = path;
+    }
+
+    pub fn get_trajectories(&self) -> &[Trajectory] {
+        &self.trajectories
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SvgOptions {
+    /// Pixels per Position ID unit
+    pub scale: f32,
+    /// Interval of the heading arrows (None or zero: no arrows)
+    pub arrow_interval: Option<time::Duration>,
+    /// Interval of the timestamps (None or zero: no timestamps)
+    pub timestamp_interval: Option<time::Duration>,
+    pub show_commands: bool,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        Self {
+            scale: 2.0,
+            arrow_interval: Some(time::Duration::from_millis(500)),
+            timestamp_interval: Some(time::Duration::from_secs(2)),
+            show_commands: true,
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// (left, top, right, bottom) of the drawing
+fn get_bounds(mat: Option<&Mat>, trajectories: &[Trajectory]) -> (f32, f32, f32, f32) {
+    let mut points: Vec<(f32, f32)> = Vec::new();
+    if let Some(mat) = mat {
+        points.push((mat.rect.left as f32, mat.rect.top as f32));
+        points.push((mat.rect.right as f32, mat.rect.bottom as f32));
+    }
+    for trajectory in trajectories {
+        points.extend(trajectory.samples().map(|x| (x.pose.x, x.pose.y)));
+        points.extend(trajectory.intended.iter().copied());
+    }
+    if points.is_empty() {
+        return (0.0, 0.0, 100.0, 100.0);
+    }
+    let fold = |f: fn(f32, f32) -> f32, init: f32, get: fn(&(f32, f32)) -> f32| {
+        points.iter().map(get).fold(init, f)
+    };
+    (
+        fold(f32::min, f32::INFINITY, |p| p.0) - MARGIN,
+        fold(f32::min, f32::INFINITY, |p| p.1) - MARGIN,
+        fold(f32::max, f32::NEG_INFINITY, |p| p.0) + MARGIN,
+        fold(f32::max, f32::NEG_INFINITY, |p| p.1) + MARGIN,
+    )
+}
+
+// samples at every `interval` (zero: no samples)
+fn get_periodic_samples(
+    trajectory: &Trajectory,
+    interval: time::Duration,
+) -> Vec<&TrajectorySample> {
+    let mut samples: Vec<&TrajectorySample> = Vec::new();
+    if interval.is_zero() {
+        return samples;
+    }
+    let mut next = time::Duration::ZERO;
+    for sample in trajectory.samples() {
+        if sample.time >= next {
+            samples.push(sample);
+            while next <= sample.time {
+                next += interval;
+            }
+        }
+    }
+    samples
+}
+
+fn write_arrow(svg: &mut String, x: f32, y: f32, angle: Angle, color: &str) {
+    let point = |length: f32, offset: f32| {
+        let direction = (angle + Angle::from_degrees(offset)).radians();
+        (x + length * direction.cos(), y + length * direction.sin())
+    };
+    let (tip_x, tip_y) = point(ARROW_LENGTH, 0.0);
+    let (left_x, left_y) = point(ARROW_LENGTH * 0.6, 15.0);
+    let (right_x, right_y) = point(ARROW_LENGTH * 0.6, -15.0);
+    let _ = writeln!(
+        svg,
+        r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}" stroke="{}"/><polygon points="{:.1},{:.1} {:.1},{:.1} {:.1},{:.1}" fill="{}"/>"#,
+        x, y, tip_x, tip_y, color, tip_x, tip_y, left_x, left_y, right_x, right_y, color
+    );
+}
+
+/// Render the mat and the trajectories to SVG.
+/// Coordinates are Position ID units (the same orientation as the mat).
+pub fn render_svg(mat: Option<&Mat>, trajectories: &[Trajectory], options: &SvgOptions) -> String {
+    let (left, top, right, bottom) = get_bounds(mat, trajectories);
+    let (width, height) = (right - left, bottom - top);
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{:.0}" height="{:.0}" viewBox="{:.1} {:.1} {:.1} {:.1}" font-family="sans-serif" font-size="8">"#,
+        width * options.scale,
+        height * options.scale,
+        left,
+        top,
+        width,
+        height
+    );
+    let _ = writeln!(
+        svg,
+        r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="white"/>"#,
+        left, top, width, height
+    );
+
+    if let Some(mat) = mat {
+        let rect = mat.rect;
+        let _ = writeln!(svg, r##"<g class="mat" stroke="#bbbbbb" fill="none">"##);
+        for column in 1..mat.grid.0 {
+            let x = rect.left as f32 + rect.width() as f32 * column as f32 / mat.grid.0 as f32;
+            let _ = writeln!(
+                svg,
+                r#"<line x1="{:.1}" y1="{}" x2="{:.1}" y2="{}" stroke-dasharray="2,2"/>"#,
+                x, rect.top, x, rect.bottom
+            );
+        }
+        for row in 1..mat.grid.1 {
+            let y = rect.top as f32 + rect.height() as f32 * row as f32 / mat.grid.1 as f32;
+            let _ = writeln!(
+                svg,
+                r#"<line x1="{}" y1="{:.1}" x2="{}" y2="{:.1}" stroke-dasharray="2,2"/>"#,
+                rect.left, y, rect.right, y
+            );
+        }
+        let _ = writeln!(
+            svg,
+            r##"<rect x="{}" y="{}" width="{}" height="{}" stroke="#555555"/>"##,
+            rect.left,
+            rect.top,
+            rect.width(),
+            rect.height()
+        );
+        let _ = writeln!(
+            svg,
+            r##"<text x="{}" y="{}" fill="#555555" stroke="none">{}</text>"##,
+            rect.left,
+            rect.top as f32 - 4.0,
+            escape(&mat.mat_type.to_string())
+        );
+        let _ = writeln!(svg, "</g>");
+    }
+
+    for (i, trajectory) in trajectories.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let _ = writeln!(svg, r#"<g class="cube" id="{}">"#, escape(&trajectory.name));
+
+        // intended path
+        if !trajectory.intended.is_empty() {
+            let points: Vec<String> = trajectory
+                .intended
+                .iter()
+                .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+                .collect();
+            let _ = writeln!(
+                svg,
+                r#"<polyline class="intended" points="{}" fill="none" stroke="{}" stroke-opacity="0.5" stroke-dasharray="4,3"/>"#,
+                points.join(" "),
+                color
+            );
+            for (x, y) in &trajectory.intended {
+                let _ = writeln!(
+                    svg,
+                    r#"<path d="M{:.1},{:.1} l6,6 m0,-6 l-6,6" stroke="{}" stroke-opacity="0.5"/>"#,
+                    x - 3.0,
+                    y - 3.0,
+                    color
+                );
+            }
+        }
+
+        // actual path
+        for segment in trajectory.segments.iter().filter(|x| !x.is_empty()) {
+            let points: Vec<String> = segment
+                .iter()
+                .map(|x| format!("{:.1},{:.1}", x.pose.x, x.pose.y))
+                .collect();
+            let _ = writeln!(
+                svg,
+                r#"<polyline class="actual" points="{}" fill="none" stroke="{}" stroke-width="1.5"/>"#,
+                points.join(" "),
+                color
+            );
+        }
+        if let Some(first) = trajectory.samples().next() {
+            let _ = writeln!(
+                svg,
+                r#"<circle cx="{:.1}" cy="{:.1}" r="3" fill="{}"/><text x="{:.1}" y="{:.1}" fill="{}">{}</text>"#,
+                first.pose.x,
+                first.pose.y,
+                color,
+                first.pose.x + 5.0,
+                first.pose.y - 5.0,
+                color,
+                escape(&trajectory.name)
+            );
+        }
+
+        if let Some(interval) = options.arrow_interval {
+            for sample in get_periodic_samples(trajectory, interval) {
+                write_arrow(
+                    &mut svg,
+                    sample.pose.x,
+                    sample.pose.y,
+                    sample.pose.angle,
+                    color,
+                );
+            }
+        }
+        if let Some(interval) = options.timestamp_interval {
+            for sample in get_periodic_samples(trajectory, interval) {
+                let _ = writeln!(
+                    svg,
+                    r#"<text class="time" x="{:.1}" y="{:.1}" fill="{}">{:.1}s</text>"#,
+                    sample.pose.x + 4.0,
+                    sample.pose.y + 10.0,
+                    color,
+                    sample.time.as_secs_f32()
+                );
+            }
+        }
+        if options.show_commands {
+            for command in &trajectory.commands {
+                if let Some(sample) = trajectory.get_sample_at(command.time) {
+                    let _ = writeln!(
+                        svg,
+                        r#"<g class="command"><rect x="{:.1}" y="{:.1}" width="5" height="5" fill="{}"/><title>{:.2}s {}</title></g>"#,
+                        sample.pose.x - 2.5,
+                        sample.pose.y - 2.5,
+                        color,
+                        command.time.as_secs_f32(),
+                        escape(&command.label)
+                    );
+                }
+            }
+        }
+        let _ = writeln!(svg, "</g>");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id_info::get_position_id_bytes;
+    use crate::mat::{get_mat, MatType};
+    use crate::motor::*;
+
+    #[test]
+    fn recording() {
+        let start = time::Instant::now();
+        let ms = time::Duration::from_millis;
+        let mut recorder = TrajectoryRecorder::new(start);
+        let cube = recorder.add_cube("a<1>");
+
+        let bytes = get_target_bytes(
+            1,
+            &TargetMoveConfig::default(),
+            &Waypoint::new(300, 200, None),
+        );
+        recorder.add_command(cube, CoreCubeUuidName::MotorCtrl, &bytes, start);
+        recorder.add_command(cube, CoreCubeUuidName::LightCtrl, &[0x01], start);
+        for i in 0..10u16 {
+            recorder.update_id_info(
+                cube,
+                &get_position_id_bytes(200 + i * 10, 200, 0),
+                start + ms(i as u64 * 100),
+            );
+        }
+        recorder.update_id_info(cube, &[0x03], start + ms(1000));
+        recorder.update_id_info(cube, &[0x03], start + ms(1100));
+        recorder.update_id_info(cube, &get_position_id_bytes(350, 250, 90), start + ms(1500));
+        recorder.add_command(
+            cube,
+            CoreCubeUuidName::MotorCtrl,
+            &MotorControl::new(30, -30).get_bytes(),
+            start + ms(1500),
+        );
+
+        let trajectory = &recorder.get_trajectories()[cube];
+        assert_eq!(
+            trajectory
+                .segments
+                .iter()
+                .map(|x| x.len())
+                .collect::<Vec<usize>>(),
+            vec![10, 1]
+        );
+        assert_eq!(trajectory.intended, vec![(300.0, 200.0)]);
+        assert_eq!(
+            trajectory
+                .commands
+                .iter()
+                .map(|x| x.label.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["target #1", "move 30,-30"]
+        );
+        assert_eq!(trajectory.get_sample_at(ms(420)).unwrap().pose.x, 240.0);
+
+        let mat = get_mat(MatType::ToioCollectionRing);
+        let svg = render_svg(
+            Some(&mat),
+            recorder.get_trajectories(),
+            &SvgOptions::default(),
+        );
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches(r#"class="actual""#).count(), 2);
+        assert_eq!(svg.matches(r#"class="intended""#).count(), 1);
+        assert_eq!(svg.matches(r#"class="command""#).count(), 2);
+        // arrows every 500ms: 0s, 0.5s, 1.5s
+        assert_eq!(svg.matches("<polygon").count(), 3);
+        assert!(svg.contains(">0.0s</text>"));
+        assert!(svg.contains(">a&lt;1&gt;</text>"));
+        assert!(svg.contains(r#"points="200.0,200.0 210.0,200.0"#));
+
+        // zero intervals: no arrows and no timestamps
+        let options = SvgOptions {
+            arrow_interval: Some(ms(0)),
+            timestamp_interval: Some(ms(0)),
+            ..SvgOptions::default()
+        };
+        let svg = render_svg(Some(&mat), recorder.get_trajectories(), &options);
+        assert_eq!(svg.matches("<polygon").count(), 0);
+        assert_eq!(svg.matches(r#"class="time""#).count(), 0);
+    }
+}
@@ -0,0 +1,87 @@
+/* Transport: access to the characteristics of a core cube */
+
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CoreCubeUuidName {
+    Service,
+    IdInfo,
+    SensorInfo,
+    ButtonInfo,
+    BatteryInfo,
+    MotorCtrl,
+    LightCtrl,
+    SoundCtrl,
+    Configuration,
+}
+
+impl fmt::Display for CoreCubeUuidName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Handler of the notifications of a characteristic
+pub type CoreCubeNotifyHandlerFunction = Box<dyn Fn(Vec<u8>) + Send>;
+
+/// Platform independent access to a connected cube
+pub trait CoreCubeTransport {
+    fn read(&self, characteristic_name: CoreCubeUuidName) -> std::result::Result<Vec<u8>, String>;
+
+    fn write(
+        &self,
+        characteristic_name: CoreCubeUuidName,
+        bytes: &[u8],
+    ) -> std::result::Result<bool, String>;
+}
+
+/// Request of the BLE protocol version (the response is notified to Configuration)
+pub fn get_protocol_version_request_bytes() -> Vec<u8> {
+    vec![0x01, 0x00]
+}
+
+/// BLE protocol version response (0x81)
+pub fn get_protocol_version(data: &[u8]) -> Option<String> {
+    match data {
+        [0x81, _, version @ ..] => Some(String::from_utf8_lossy(version).to_string()),
+        _ => None,
+    }
+}
+
+/// Transport which records the written bytes (for tests)
+#[cfg(test)]
+pub(crate) mod recording {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    pub(crate) struct RecordingCube {
+        pub(crate) written: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl CoreCubeTransport for RecordingCube {
+        fn read(&self, _: CoreCubeUuidName) -> std::result::Result<Vec<u8>, String> {
+            Err("not supported".to_string())
+        }
+
+        fn write(&self, _: CoreCubeUuidName, bytes: &[u8]) -> std::result::Result<bool, String> {
+            self.written.borrow_mut().push(bytes.to_vec());
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_version() {
+        assert_eq!(get_protocol_version_request_bytes(), vec![0x01, 0x00]);
+        assert_eq!(
+            get_protocol_version(&[0x81, 0x00, b'2', b'.', b'4', b'.', b'0']),
+            Some("2.4.0".to_string())
+        );
+        assert_eq!(get_protocol_version(&[0x01, 0x00]), None);
+    }
+}
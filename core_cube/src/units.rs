@@ -0,0 +1,241 @@
+/* Physical units for the core cube: mm, mm/s, degrees, durations */
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::ops::{Add, Neg, Sub};
+use std::time;
+
+use crate::mat::MatType;
+
+/// Maximum motor speed value
+pub const MOTOR_SPEED_MAX: u8 = 115;
+/// Motors don't rotate below this speed value
+pub const MOTOR_SPEED_MIN: u8 = 10;
+
+/// Rotation speed of the motor per speed value [rpm]
+pub const MOTOR_RPM_PER_SPEED: f32 = 4.3;
+/// Diameter of the wheel [mm]
+pub const WHEEL_DIAMETER_MM: f32 = 12.5;
+/// Distance between left and right wheels [mm]
+pub const WHEEL_BASE_MM: f32 = 26.6;
+
+/// Length of a Position ID unit on the toio collection mat [mm]
+pub const MM_PER_UNIT: f32 = 560.0 / 411.0;
+
+/// Time unit of the motor duration [ms]
+pub const MOTOR_DURATION_UNIT_MS: u64 = 10;
+/// Longest duration of a motor control command
+pub const MOTOR_DURATION_MAX: time::Duration =
+    time::Duration::from_millis(u8::MAX as u64 * MOTOR_DURATION_UNIT_MS);
+
+/// Moving speed of the cube per speed value [mm/s]
+pub fn get_mm_per_sec_per_speed() -> f32 {
+    MOTOR_RPM_PER_SPEED * PI * WHEEL_DIAMETER_MM / 60.0
+}
+
+/// Motor speed value -> wheel speed [mm/s]
+/// Values in the dead zone (below MOTOR_SPEED_MIN) don't move the cube.
+pub fn speed_to_mm_per_sec(speed: u8) -> f32 {
+    if speed < MOTOR_SPEED_MIN {
+        return 0.0;
+    }
+    speed.min(MOTOR_SPEED_MAX) as f32 * get_mm_per_sec_per_speed()
+}
+
+/// Wheel speed [mm/s] -> motor speed value
+/// Speeds in the dead zone are rounded to 0 or MOTOR_SPEED_MIN, whichever is nearer.
+pub fn mm_per_sec_to_speed(mm_per_sec: f32) -> u8 {
+    let speed = (mm_per_sec.abs() / get_mm_per_sec_per_speed()).round();
+    if speed < (MOTOR_SPEED_MIN as f32 / 2.0) {
+        0
+    } else if speed < MOTOR_SPEED_MIN as f32 {
+        MOTOR_SPEED_MIN
+    } else {
+        speed.min(MOTOR_SPEED_MAX as f32) as u8
+    }
+}
+
+/// Signed motor speed (negative: backward) -> wheel speed [mm/s]
+pub fn signed_speed_to_mm_per_sec(speed: i16) -> f32 {
+    let mm_per_sec = speed_to_mm_per_sec(speed.unsigned_abs().min(u8::MAX as u16) as u8);
+    if speed < 0 {
+        -mm_per_sec
+    } else {
+        mm_per_sec
+    }
+}
+
+/// Wheel speed [mm/s] -> signed motor speed (negative: backward)
+pub fn mm_per_sec_to_signed_speed(mm_per_sec: f32) -> i16 {
+    let speed = mm_per_sec_to_speed(mm_per_sec) as i16;
+    if mm_per_sec < 0.0 {
+        -speed
+    } else {
+        speed
+    }
+}
+
+/// Length of a Position ID unit on the mat [mm]
+pub fn get_mm_per_unit(mat_type: MatType) -> f32 {
+    match mat_type {
+        MatType::ToioCollectionRing | MatType::ToioCollectionTiles | MatType::Gesundroid => {
+            MM_PER_UNIT
+        }
+        // A3 sized mats (420mm x 297mm)
+        MatType::SimpleMat | MatType::Developer(_) => 420.0 / 304.0,
+    }
+}
+
+pub fn units_to_mm(units: f32, mat_type: MatType) -> f32 {
+    units * get_mm_per_unit(mat_type)
+}
+
+pub fn mm_to_units(mm: f32, mat_type: MatType) -> f32 {
+    mm / get_mm_per_unit(mat_type)
+}
+
+/// Motor duration (10ms unit) -> Duration
+/// 0 means "no time limit" and returns None.
+pub fn motor_duration_to_duration(motor_duration: u8) -> Option<time::Duration> {
+    match motor_duration {
+        0 => None,
+        x => Some(time::Duration::from_millis(
+            x as u64 * MOTOR_DURATION_UNIT_MS,
+        )),
+    }
+}
+
+/// Duration -> motor duration (10ms unit, 10ms - 2550ms)
+pub fn duration_to_motor_duration(duration: time::Duration) -> u8 {
+    let motor_duration = duration.as_millis() / MOTOR_DURATION_UNIT_MS as u128;
+    motor_duration.clamp(1, u8::MAX as u128) as u8
+}
+
+/// Angle normalized into [0, 360) degrees
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default)]
+pub struct Angle(f32);
+
+impl Angle {
+    pub fn from_degrees(degrees: f32) -> Self {
+        let normalized = degrees.rem_euclid(360.0);
+        // rem_euclid() may return 360.0 for tiny negative values
+        if normalized >= 360.0 {
+            Angle(0.0)
+        } else {
+            Angle(normalized)
+        }
+    }
+
+    pub fn from_radians(radians: f32) -> Self {
+        Self::from_degrees(radians.to_degrees())
+    }
+
+    /// Angle of the Position ID / Standard ID notification
+    pub fn from_id_angle(angle: u16) -> Self {
+        Self::from_degrees(angle as f32)
+    }
+
+    pub fn degrees(&self) -> f32 {
+        self.0
+    }
+
+    pub fn radians(&self) -> f32 {
+        self.0.to_radians()
+    }
+
+    /// Signed shortest rotation from `self` to `other` [-180, 180) degrees
+    pub fn difference(&self, other: Angle) -> f32 {
+        (other.0 - self.0 + 180.0).rem_euclid(360.0) - 180.0
+    }
+
+    /// Angle for the target position command (0 - 360 degrees)
+    pub fn to_id_angle(&self) -> u16 {
+        (self.0.round() as u16) % 360
+    }
+}
+
+impl fmt::Display for Angle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1}deg", self.0)
+    }
+}
+
+impl Add for Angle {
+    type Output = Angle;
+
+    fn add(self, other: Angle) -> Angle {
+        Angle::from_degrees(self.0 + other.0)
+    }
+}
+
+impl Sub for Angle {
+    type Output = Angle;
+
+    fn sub(self, other: Angle) -> Angle {
+        Angle::from_degrees(self.0 - other.0)
+    }
+}
+
+impl Neg for Angle {
+    type Output = Angle;
+
+    fn neg(self) -> Angle {
+        Angle::from_degrees(-self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_conversion() {
+        assert_eq!(speed_to_mm_per_sec(9), 0.0);
+        assert!((speed_to_mm_per_sec(115) - 323.6).abs() < 0.1);
+        assert_eq!(mm_per_sec_to_speed(0.0), 0);
+        assert_eq!(mm_per_sec_to_speed(10.0), 0);
+        assert_eq!(mm_per_sec_to_speed(20.0), MOTOR_SPEED_MIN);
+        assert_eq!(mm_per_sec_to_speed(1000.0), MOTOR_SPEED_MAX);
+        assert_eq!(mm_per_sec_to_speed(speed_to_mm_per_sec(50)), 50);
+        assert_eq!(mm_per_sec_to_signed_speed(-speed_to_mm_per_sec(50)), -50);
+    }
+
+    #[test]
+    fn duration_conversion() {
+        assert_eq!(motor_duration_to_duration(0), None);
+        assert_eq!(
+            motor_duration_to_duration(50),
+            Some(time::Duration::from_millis(500))
+        );
+        assert_eq!(
+            duration_to_motor_duration(time::Duration::from_millis(500)),
+            50
+        );
+        assert_eq!(
+            duration_to_motor_duration(time::Duration::from_secs(10)),
+            255
+        );
+        assert_eq!(
+            duration_to_motor_duration(time::Duration::from_millis(1)),
+            1
+        );
+    }
+
+    #[test]
+    fn angle() {
+        assert_eq!(Angle::from_degrees(-90.0).degrees(), 270.0);
+        assert_eq!(Angle::from_degrees(720.0).degrees(), 0.0);
+        assert_eq!(
+            Angle::from_degrees(350.0).difference(Angle::from_degrees(10.0)),
+            20.0
+        );
+        assert_eq!(
+            Angle::from_degrees(10.0).difference(Angle::from_degrees(350.0)),
+            -20.0
+        );
+        assert_eq!(
+            (Angle::from_degrees(300.0) + Angle::from_degrees(90.0)).degrees(),
+            30.0
+        );
+    }
+}
@@ -1,7 +1,6 @@
 /* This is a test code */
 
 use log::{debug, error, info};
-use std::fmt::{self, Debug};
 use std::sync::mpsc;
 use std::time;
 
@@ -15,27 +14,7 @@ use windows::{
     Storage::Streams::*,
 };
 
-
-pub type CoreCubeNotifyHandlerFunction = Box<dyn Fn(Vec<u8>) + Send>;
-
-#[derive(Debug, Copy, Clone)]
-pub enum CoreCubeUuidName {
-    Service,
-    IdInfo,
-    SensorInfo,
-    ButtonInfo,
-    BatteryInfo,
-    MotorCtrl,
-    LightCtrl,
-    SoundCtrl,
-    Configuration,
-}
-
-impl fmt::Display for CoreCubeUuidName {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
-    }
-}
+pub use crate::transport::{CoreCubeNotifyHandlerFunction, CoreCubeUuidName};
 
 pub fn get_uuid(name: CoreCubeUuidName) -> Option<GUID> {
     match name {
@@ -129,6 +108,56 @@ pub fn get_ble_devices() -> std::result::Result<Vec<String>, String> {
     Ok(uuid_list)
 }
 
+/// Paired cubes: (device id, device name)
+pub fn get_ble_device_names() -> std::result::Result<Vec<(String, String)>, String> {
+    let mut device_list: Vec<(String, String)> = Vec::new();
+    for id in get_ble_devices()? {
+        let device_info =
+            match DeviceInformation::CreateFromIdAsync(&HSTRING::from(&id)).and_then(|x| x.get()) {
+                Ok(x) => x,
+                Err(e) => return Err(e.message().to_string()),
+            };
+        let name = device_info
+            .Name()
+            .map(|x| x.to_string())
+            .unwrap_or_default();
+        device_list.push((id, name));
+    }
+
+    Ok(device_list)
+}
+
+/// Connect to the cube of the address or a paired cube which isn't in `used_devices`
+/// (the device id of the connected paired cube is added to `used_devices`)
+pub fn connect_unused_cube(
+    address: Option<u64>,
+    used_devices: &mut Vec<String>,
+) -> std::result::Result<CoreCubeBLE, String> {
+    let mut cube = CoreCubeBLE::new("Cube".to_string());
+    if let Some(address) = address {
+        info!("connect to {:#08x}", address);
+        return match cube.connect(address) {
+            Ok(true) => Ok(cube),
+            _ => Err(format!("failed to connect to {:#08x}", address)),
+        };
+    }
+    for device_info in get_ble_devices()? {
+        if used_devices.contains(&device_info) {
+            continue;
+        }
+        info!("Searching cube: {:?}", device_info);
+        // a paired cube which is out of range may still connect without responding
+        if let Ok(true) = cube.connect_ref_id(&device_info) {
+            if let Ok(v) = cube.read(CoreCubeUuidName::BatteryInfo) {
+                info!("battery level {}%", v[0]);
+                used_devices.push(device_info);
+                return Ok(cube);
+            }
+        }
+    }
+    Err("failed to connect".to_string())
+}
+
 pub fn get_ble_device_from_address(address: u64) -> std::result::Result<Vec<u64>, String> {
     info!("search with address");
     let watcher = BluetoothLEAdvertisementWatcher::new().unwrap();
@@ -416,6 +445,20 @@ impl CoreCubeBLEAccess for CoreCubeBLE {
     }
 }
 
+impl crate::transport::CoreCubeTransport for CoreCubeBLE {
+    fn read(&self, characteristic_name: CoreCubeUuidName) -> std::result::Result<Vec<u8>, String> {
+        CoreCubeBLEAccess::read(self, characteristic_name)
+    }
+
+    fn write(
+        &self,
+        characteristic_name: CoreCubeUuidName,
+        bytes: &[u8],
+    ) -> std::result::Result<bool, String> {
+        CoreCubeBLEAccess::write(self, characteristic_name, &bytes.to_vec())
+    }
+}
+
 pub struct CoreCubeNotifyHandler {
     name: String,
     characteristic_name: String,
@@ -1,7 +1,9 @@
+// connects to the cubes by BLE: Windows only
+#![cfg_attr(not(windows), allow(dead_code, unused_imports))]
+
 use clap::{App, Arg};
+#[cfg(windows)]
 use core_cube::win10::*;
-use ctrlc;
-use env_logger;
 use lazy_static::lazy_static;
 use log::{debug, error, info};
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -154,6 +156,7 @@ fn id_information_notify(data: Vec<u8>) {
 }
 
 // Connect by ref_id (paired cube)
+#[cfg(windows)]
 fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -194,6 +197,7 @@ fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
 }
 
 // Connect by address
+#[cfg(windows)]
 fn connect(address: u64) -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -225,6 +229,7 @@ fn connect(address: u64) -> std::result::Result<CoreCubeBLE, String> {
     }
 }
 
+#[cfg(windows)]
 fn main() {
     env_logger::init();
 
@@ -404,3 +409,9 @@ fn main() {
     let result = id_handler.unregister();
     assert_eq!(result.unwrap(), true);
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("this example connects to the cubes and runs only on Windows");
+    std::process::exit(1);
+}
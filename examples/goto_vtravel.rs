@@ -1,16 +1,21 @@
+// connects to the cubes by BLE: Windows only
+#![cfg_attr(not(windows), allow(dead_code, unused_imports))]
+
 use clap::{App, Arg};
+use core_cube::motion::*;
+use core_cube::sensor::get_motion_detection;
+#[cfg(windows)]
 use core_cube::win10::*;
-use ctrlc;
 use enigo::*;
-use env_logger;
 use lazy_static::lazy_static;
 use log::{debug, error, info};
+use std::io::Write;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
 
-const KEYEVENT_THRESH_LR: usize = 15;
-const KEYEVENT_THRESH_UP: usize = 8;
+const KEYEVENT_THRESH_LR: u32 = 15;
+const KEYEVENT_THRESH_UP: u32 = 8;
 const KEYEVENT_EFFECTIVE_DURATION: u64 = 600;
 const TURNING_DURATION: usize = 3;
 
@@ -21,38 +26,6 @@ enum ButtonStatus {
     Release,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum SlopeStatus {
-    Unknown,
-    Aslant,
-    Horizontal,
-}
-
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum CollisionStatus {
-    Unknown,
-    NotDetect,
-    Detect,
-}
-
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum DoubleTapStatus {
-    Unknown,
-    NotDetect,
-    Detect,
-}
-
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum PostureStatus {
-    Unknown = 0,
-    Normal = 1,
-    Reverse = 2,
-    Downward = 3,
-    Upward = 4,
-    RightSideUp = 5,
-    LeftSideUp = 6,
-}
-
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum KeyTableName {
     Page = 0,
@@ -75,33 +48,9 @@ impl Default for ButtonInfo {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-struct SensorInfo {
-    time: time::Instant,
-    slope: SlopeStatus,
-    collision: CollisionStatus,
-    double_tap: DoubleTapStatus,
-    posture: PostureStatus,
-    shaking: usize,
-}
-
-impl Default for SensorInfo {
-    fn default() -> Self {
-        Self {
-            time: time::Instant::now(),
-            slope: SlopeStatus::Unknown,
-            collision: CollisionStatus::Unknown,
-            double_tap: DoubleTapStatus::Unknown,
-            posture: PostureStatus::Unknown,
-            shaking: 0,
-        }
-    }
-}
-
 lazy_static! {
     static ref BUTTON: Mutex<Vec<ButtonInfo>> = Mutex::new(Vec::new());
-    static ref SENSOR_1: Mutex<Vec<SensorInfo>> = Mutex::new(Vec::new());
-    static ref SENSOR_2: Mutex<Vec<SensorInfo>> = Mutex::new(Vec::new());
+    static ref SENSOR: Mutex<Vec<(usize, time::Instant, Vec<u8>)>> = Mutex::new(Vec::new());
 }
 
 // Button Notify Handler
@@ -121,43 +70,12 @@ fn button_notify(data: Vec<u8>) {
     }
 }
 
-fn get_sensor_info(data: Vec<u8>) -> SensorInfo {
-    SensorInfo {
-        time: time::Instant::now(),
-        slope: match data[1] {
-            0x00 => SlopeStatus::Aslant,
-            0x01 => SlopeStatus::Horizontal,
-            _ => SlopeStatus::Unknown,
-        },
-        collision: match data[2] {
-            0x00 => CollisionStatus::NotDetect,
-            0x01 => CollisionStatus::Detect,
-            _ => CollisionStatus::Unknown,
-        },
-        double_tap: match data[3] {
-            0x00 => DoubleTapStatus::NotDetect,
-            0x01 => DoubleTapStatus::Detect,
-            _ => DoubleTapStatus::Unknown,
-        },
-        posture: match data[4] {
-            0x01 => PostureStatus::Normal,
-            0x02 => PostureStatus::Reverse,
-            0x03 => PostureStatus::Downward,
-            0x04 => PostureStatus::Upward,
-            0x05 => PostureStatus::RightSideUp,
-            0x06 => PostureStatus::LeftSideUp,
-            _ => PostureStatus::Unknown,
-        },
-        shaking: data[5] as usize,
-    }
-}
-
 // cube1 Sensor Notify Handler
 fn sensor_information_notify_1(data: Vec<u8>) {
     debug!("sensor(cube1) information status changed {:?}", data);
     {
-        let mut sensor = SENSOR_1.lock().unwrap();
-        (*sensor).push(get_sensor_info(data));
+        let mut sensor = SENSOR.lock().unwrap();
+        (*sensor).push((0, time::Instant::now(), data));
     }
 }
 
@@ -165,8 +83,8 @@ fn sensor_information_notify_1(data: Vec<u8>) {
 fn sensor_information_notify_2(data: Vec<u8>) {
     debug!("sensor(cube2) information status changed {:?}", data);
     {
-        let mut sensor = SENSOR_2.lock().unwrap();
-        (*sensor).push(get_sensor_info(data));
+        let mut sensor = SENSOR.lock().unwrap();
+        (*sensor).push((1, time::Instant::now(), data));
     }
 }
 
@@ -176,6 +94,7 @@ fn id_information_notify(data: Vec<u8>) {
 }
 
 // Connect by ref_id (paired cube)
+#[cfg(windows)]
 fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -216,6 +135,7 @@ fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
 }
 
 // Connect by address
+#[cfg(windows)]
 fn connect(address: u64) -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -247,28 +167,14 @@ fn connect(address: u64) -> std::result::Result<CoreCubeBLE, String> {
     }
 }
 
-fn get_sensor_info_list_1() -> Vec<SensorInfo> {
-    let mut sensor = SENSOR_1.lock().unwrap();
-    let sensor_info_list = (*sensor).clone();
+fn get_sensor_list() -> Vec<(usize, time::Instant, Vec<u8>)> {
+    let mut sensor = SENSOR.lock().unwrap();
+    let sensor_list = (*sensor).clone();
     (*sensor).clear();
-    if !sensor_info_list.is_empty() {
-        debug!("cube1:sensor {:?}", sensor_info_list);
-    }
-
-    sensor_info_list
-}
-
-fn get_sensor_info_list_2() -> Vec<SensorInfo> {
-    let mut sensor = SENSOR_2.lock().unwrap();
-    let sensor_info_list = (*sensor).clone();
-    (*sensor).clear();
-    if !sensor_info_list.is_empty() {
-        debug!("cube2:sensor {:?}", sensor_info_list);
-    }
-
-    sensor_info_list
+    sensor_list
 }
 
+#[cfg(windows)]
 fn main() {
     env_logger::init();
 
@@ -279,6 +185,12 @@ fn main() {
         .version("0.0.1")
         .arg(Arg::with_name("lr").help("LR arrow key mode").long("lr"))
         .arg(Arg::with_name("ud").help("UD arrow key mode").long("ud"))
+        .arg(
+            Arg::with_name("record")
+                .help("record the sensor notifications to a motion trace file")
+                .long("record")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("address")
                 .help("BLE address")
@@ -300,6 +212,15 @@ fn main() {
     }
     debug!("key table {:?}", key_table);
 
+    let mut record = match matches.value_of("record").map(std::fs::File::create) {
+        Some(Ok(file)) => Some(file),
+        Some(Err(e)) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
     // connect
     let cube: CoreCubeBLE;
     if let Some(adrs_str) = matches.value_of("address") {
@@ -366,7 +287,10 @@ fn main() {
     let result = cube.register_notify(CoreCubeUuidName::ButtonInfo, Box::new(button_notify));
     let button_handler = result.unwrap();
 
-    let result = cube.register_notify(CoreCubeUuidName::SensorInfo, Box::new(sensor_information_notify_1));
+    let result = cube.register_notify(
+        CoreCubeUuidName::SensorInfo,
+        Box::new(sensor_information_notify_1),
+    );
     let sensor_handler = result.unwrap();
 
     let result = cube.register_notify(CoreCubeUuidName::IdInfo, Box::new(id_information_notify));
@@ -381,7 +305,10 @@ fn main() {
     assert_eq!(result.unwrap(), true);
 
     // cube2: Register cube notify handlers
-    let result = cube2.register_notify(CoreCubeUuidName::SensorInfo, Box::new(sensor_information_notify_2));
+    let result = cube2.register_notify(
+        CoreCubeUuidName::SensorInfo,
+        Box::new(sensor_information_notify_2),
+    );
     let sensor_handler2 = result.unwrap();
 
     // Register Ctrl-C handler
@@ -396,13 +323,19 @@ fn main() {
 
     let mut engio = Enigo::new();
     let tick = time::Duration::from_millis(100);
-    let mut last_sensor_info_cube1: SensorInfo = Default::default();
-    let mut last_sensor_info_cube2: SensorInfo = Default::default();
-    let mut integral_counter_cube1: usize = 0;
-    let mut integral_counter_cube2: usize = 0;
-    let mut integral_counter_inner_product: usize = 0;
+    let start = time::Instant::now();
+    let mut classifier = MotionClassifier::new(MotionConfig {
+        shake_hold_time: time::Duration::from_millis(KEYEVENT_EFFECTIVE_DURATION),
+        sample_interval: tick,
+        sustained_level: KEYEVENT_THRESH_LR,
+        sync_level: KEYEVENT_THRESH_UP,
+        // no samples while turning and on the tick of the key down
+        cooldown: tick * (TURNING_DURATION as u32 + 1),
+        ..MotionConfig::default()
+    });
     let mut key: Key = Key::Layout(' ');
     let mut turning: usize = 0;
+    let mut walking = false;
 
     while running.load(Ordering::SeqCst) {
         if turning > 0 {
@@ -417,72 +350,42 @@ fn main() {
             key = Key::Layout(' ');
         }
 
-        let shaking_cube1: usize;
-        let shaking_cube2: usize;
-
-        match get_sensor_info_list_1().pop() {
-            Some(last) => last_sensor_info_cube1 = last,
-            None => (),
-        };
-
-        match get_sensor_info_list_2().pop() {
-            Some(last) => last_sensor_info_cube2 = last,
-            None => (),
-        };
-
-        debug!(
-            "elapsed: 1:{:?} 2:{:?}",
-            last_sensor_info_cube1.time.elapsed(),
-            last_sensor_info_cube2.time.elapsed()
-        );
-
-        if last_sensor_info_cube1.time.elapsed()
-            < time::Duration::from_millis(KEYEVENT_EFFECTIVE_DURATION)
-        {
-            shaking_cube1 = last_sensor_info_cube1.shaking;
-        } else {
-            shaking_cube1 = 0;
-        }
-
-        if last_sensor_info_cube2.time.elapsed()
-            < time::Duration::from_millis(KEYEVENT_EFFECTIVE_DURATION)
-        {
-            shaking_cube2 = last_sensor_info_cube2.shaking;
-        } else {
-            shaking_cube2 = 0;
-        }
-
-        debug!("shaking 1:{} 2:{}", shaking_cube1, shaking_cube2);
-
-        let inner_product = shaking_cube1 * shaking_cube2;
-        if (inner_product) != 0 {
-            integral_counter_cube1 = 0;
-            integral_counter_cube2 = 0;
-            integral_counter_inner_product += inner_product;
-            if integral_counter_inner_product > KEYEVENT_THRESH_UP {
-                key = Key::Layout('w');
+        for (cube, time, data) in get_sensor_list() {
+            if let Some(file) = record.as_mut() {
+                let line = get_motion_trace_line(time.duration_since(start), cube, &data);
+                if let Err(e) = writeln!(file, "{}", line) {
+                    error!("{}", e);
+                }
             }
-        } else {
-            integral_counter_cube1 += shaking_cube1;
-            integral_counter_cube2 += shaking_cube2;
-            integral_counter_inner_product = 0;
-
-            if integral_counter_cube1 > KEYEVENT_THRESH_LR {
-                key = Key::Layout('a');
-                turning = TURNING_DURATION;
-                integral_counter_cube1 = 0;
-            } else {
-                integral_counter_cube1 += shaking_cube1;
+            if let Some(motion) = get_motion_detection(&data) {
+                for event in classifier.update(cube, &motion, time) {
+                    debug!("[MOTION] {}", event);
+                }
             }
+        }
 
-            if integral_counter_cube2 > KEYEVENT_THRESH_LR {
-                key = Key::Layout('d');
-                turning = TURNING_DURATION;
-                integral_counter_cube2 = 0;
-            } else {
-                integral_counter_cube2 += shaking_cube2;
+        // both cubes: forward, cube1: left, cube2: right
+        let now = time::Instant::now();
+        for event in classifier.poll(now) {
+            info!("[MOTION] {}", event);
+            match event {
+                MotionEvent::SyncShake(..) => walking = true,
+                MotionEvent::SustainedShake(0) => {
+                    key = Key::Layout('a');
+                    turning = TURNING_DURATION;
+                }
+                MotionEvent::SustainedShake(_) => {
+                    key = Key::Layout('d');
+                    turning = TURNING_DURATION;
+                }
+                _ => (),
             }
         }
+        // forward while both cubes are shaken (sync_shake comes once when they start)
+        walking = walking && classifier.get_shake(0, now) > 0 && classifier.get_shake(1, now) > 0;
+        if walking {
+            key = Key::Layout('w');
+        }
 
         if key != Key::Layout(' ') {
             info!("[KEYCODE(down)] {:?}", key);
@@ -524,3 +427,9 @@ fn main() {
     let result = sensor_handler2.unregister();
     assert_eq!(result.unwrap(), true);
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("this example connects to the cubes and runs only on Windows");
+    std::process::exit(1);
+}
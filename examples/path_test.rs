@@ -0,0 +1,217 @@
+// connects to the cubes by BLE: Windows only
+#![cfg_attr(not(windows), allow(dead_code, unused_imports))]
+
+use clap::{App, Arg};
+use core_cube::motor::{MotorControl, TargetMoveConfig};
+use core_cube::path::*;
+#[cfg(windows)]
+use core_cube::win10::*;
+use lazy_static::lazy_static;
+use log::{error, info};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+lazy_static! {
+    static ref MOTOR_RESPONSE: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+    static ref ID_INFO: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+// Motor Notify Handler (responses of the target move commands)
+fn motor_notify(data: Vec<u8>) {
+    info!("motor response {:?}", data);
+    let mut response = MOTOR_RESPONSE.lock().unwrap();
+    (*response).push(data);
+}
+
+// ID Information Notify Handler
+fn id_information_notify(data: Vec<u8>) {
+    let mut id_info = ID_INFO.lock().unwrap();
+    (*id_info).push(data);
+}
+
+// Connect by ref_id (paired cube)
+#[cfg(windows)]
+fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
+    let mut cube = CoreCubeBLE::new("Cube1".to_string());
+    println!("search registered cubes");
+    let dev_list = get_ble_devices().unwrap();
+    for device_info in &dev_list {
+        info!("Searching cube: {:?}", device_info);
+        if let Ok(true) = cube.connect_ref_id(device_info) {
+            if cube.read(CoreCubeUuidName::BatteryInfo).is_ok() {
+                println!("success to connect");
+                return Ok(cube);
+            }
+        }
+    }
+    Err("failed to connect".to_string())
+}
+
+// Connect by address
+#[cfg(windows)]
+fn connect(address: u64) -> std::result::Result<CoreCubeBLE, String> {
+    let mut cube = CoreCubeBLE::new("Cube1".to_string());
+    println!("connect to cube {:#08x}", address);
+    match cube.connect(address) {
+        Ok(true) => Ok(cube),
+        _ => Err("failed to connect".to_string()),
+    }
+}
+
+fn get_path(name: &str) -> Option<Path> {
+    let center = (250.0, 250.0);
+    match name {
+        "circle" => Some(Path::Circle {
+            center,
+            radius: 120.0,
+        }),
+        "eight" => Some(Path::FigureEight {
+            center,
+            width: 300.0,
+            height: 160.0,
+        }),
+        "lissajous" => Some(Path::Lissajous {
+            center,
+            amplitude: (150.0, 150.0),
+            frequency: (3, 2),
+            phase_degrees: 90.0,
+        }),
+        "square" => Some(Path::Polyline(vec![
+            (150.0, 150.0),
+            (350.0, 150.0),
+            (350.0, 350.0),
+            (150.0, 350.0),
+            (150.0, 150.0),
+        ])),
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+fn main() {
+    env_logger::init();
+
+    // Set command line options
+    let app = App::new("path_test")
+        .version("0.0.1")
+        .arg(
+            Arg::with_name("path")
+                .help("circle | eight | lissajous | square")
+                .long("path")
+                .takes_value(true)
+                .default_value("eight"),
+        )
+        .arg(
+            Arg::with_name("speed")
+                .help("max speed (10 - 115)")
+                .long("speed")
+                .takes_value(true)
+                .default_value("50"),
+        )
+        .arg(
+            Arg::with_name("address")
+                .help("BLE address")
+                .long("address")
+                .takes_value(true),
+        );
+
+    // Parse arguments
+    let matches = app.get_matches();
+    let path = match get_path(matches.value_of("path").unwrap()) {
+        Some(x) => x,
+        None => {
+            error!("unknown path");
+            std::process::exit(1);
+        }
+    };
+    let max_speed: u8 = matches.value_of("speed").unwrap().parse().unwrap_or(50);
+
+    // connect
+    let result = match matches.value_of("address") {
+        Some(adrs_str) => {
+            let mut adrs = adrs_str.to_string();
+            adrs.retain(|c| c != ':' && c != '-');
+            match u64::from_str_radix(&adrs, 16) {
+                Ok(ble_adrs) => connect(ble_adrs),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        None => connect_ref_id(),
+    };
+    let cube = match result {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let motor_handler = cube
+        .register_notify(CoreCubeUuidName::MotorCtrl, Box::new(motor_notify))
+        .unwrap();
+    let id_handler = cube
+        .register_notify(CoreCubeUuidName::IdInfo, Box::new(id_information_notify))
+        .unwrap();
+
+    // Register Ctrl-C handler
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    let config = TargetMoveConfig {
+        max_speed,
+        ..TargetMoveConfig::default()
+    };
+    let waypoints = path.sample(DEFAULT_WAYPOINT_STEP);
+    let mut follower = PathFollower::new(config, &waypoints);
+    println!(
+        "{} waypoints, {} commands",
+        waypoints.len(),
+        follower.get_chunk_count()
+    );
+
+    let tick = time::Duration::from_millis(50);
+    let mut last_passed = 0;
+    while running.load(Ordering::SeqCst) && !follower.is_done() {
+        if let Some(packet) = follower.next_packet() {
+            let result = cube.write(CoreCubeUuidName::MotorCtrl, &packet);
+            assert!(result.unwrap());
+        }
+        let responses: Vec<Vec<u8>> = MOTOR_RESPONSE.lock().unwrap().drain(..).collect();
+        for data in responses {
+            follower.update_response(&data);
+        }
+        let id_info: Vec<Vec<u8>> = ID_INFO.lock().unwrap().drain(..).collect();
+        for data in id_info {
+            let progress = follower.update_id_info(&data);
+            if progress.passed_waypoints != last_passed {
+                last_passed = progress.passed_waypoints;
+                println!("progress {:.0}%", progress.ratio() * 100.0);
+            }
+        }
+        thread::sleep(tick);
+    }
+    println!("{:?}", follower.get_status());
+
+    // stop
+    let result = cube.write(
+        CoreCubeUuidName::MotorCtrl,
+        &MotorControl::stop().get_bytes(),
+    );
+    assert!(result.unwrap());
+
+    let result = motor_handler.unregister();
+    assert!(result.unwrap());
+    let result = id_handler.unregister();
+    assert!(result.unwrap());
+}
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("this example connects to the cubes and runs only on Windows");
+    std::process::exit(1);
+}
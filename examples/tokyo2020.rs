@@ -1,25 +1,26 @@
+// connects to the cubes by BLE: Windows only
+#![cfg_attr(not(windows), allow(dead_code, unused_imports))]
+
 use clap::{App, Arg};
+use core_cube::mat::*;
+use core_cube::scheduler::BeatScheduler;
+use core_cube::sequence::*;
+use core_cube::show::TEMPO_MAX;
+#[cfg(windows)]
 use core_cube::win10::*;
-use ctrlc;
-use env_logger;
 use lazy_static::lazy_static;
 use log::{debug, error, info};
 use once_cell::sync::OnceCell;
 use rand::Rng;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::{cmp, thread, time};
-
-const MOTOR_FW: u8 = 0x01;
-const MOTOR_RV: u8 = 0x02;
+use std::{thread, time};
 
 const SUPPORTED_MAX_CUBES: usize = 4;
 
-const CIRCLE_TERM_MS: u64 = 7500;
+const MAT_DETECTION_TIMEOUT_SEC: u64 = 10;
 
-static MAT_ENABLE: OnceCell<bool> = OnceCell::new();
-static MAT_OFFSET_X: OnceCell<usize> = OnceCell::new();
-static MAT_OFFSET_Y: OnceCell<usize> = OnceCell::new();
+static MAT: OnceCell<Option<Mat>> = OnceCell::new();
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum ButtonStatus {
@@ -60,54 +61,11 @@ enum PostureStatus {
     LeftSideUp = 6,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum CubeAction {
-    SwingR,
-    SwingL,
-    Step2,
-    Step4,
-    Step8,
-    StepRL2,
-    RollingR,
-    RollingL,
-    GetReady,
-    HomePosition,
-    ByeBye,
-    Circle1,
-    Circle2,
-}
-
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum CubeCommand {
-    Move,
-    MoveTo,
-    Nothing,
-    End,
-}
-
-#[derive(Debug)]
-struct CubeControl {
-    command: CubeCommand,
-    term_ms: Option<u64>, // None: default time
-    data: Option<Vec<u8>>,
-}
-
-impl Default for CubeControl {
-    fn default() -> Self {
-        Self {
-            command: CubeCommand::Nothing,
-            term_ms: None,
-            data: None,
-        }
-    }
-}
-
+#[cfg(windows)]
 struct CubeInfo {
     id: usize,
     ble: CoreCubeBLE,
-    action: CubeAction,
-    step_count: usize,
-    action_term: time::Duration,
+    action: BuiltinMove,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -152,6 +110,7 @@ lazy_static! {
     static ref BUTTON: Mutex<Vec<ButtonInfo>> = Mutex::new(Vec::new());
     static ref SENSOR_1: Mutex<Vec<SensorInfo>> = Mutex::new(Vec::new());
     static ref SENSOR_2: Mutex<Vec<SensorInfo>> = Mutex::new(Vec::new());
+    static ref MAT_DETECTOR: Mutex<MatDetector> = Mutex::new(MatDetector::new());
 }
 
 // Button Notify Handler
@@ -214,9 +173,19 @@ fn sensor_information_notify_1(data: Vec<u8>) {
 // ID Information Notify Handler
 fn id_information_notify(data: Vec<u8>) {
     info!("id information status changed {:?}", data);
+    {
+        let mut detector = MAT_DETECTOR.lock().unwrap();
+        (*detector).update(&data);
+    }
+}
+
+// None: without mat mode
+fn get_selected_mat() -> Option<Mat> {
+    *MAT.get().unwrap()
 }
 
 // Connect by ref_id (paired cube)
+#[cfg(windows)]
 fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -257,6 +226,7 @@ fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
 }
 
 // Connect by address
+#[cfg(windows)]
 fn connect_ble_address(address: u64) -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -288,6 +258,7 @@ fn connect_ble_address(address: u64) -> std::result::Result<CoreCubeBLE, String>
     }
 }
 
+#[cfg(windows)]
 fn connect(param: Option<u64>) -> std::result::Result<CoreCubeBLE, String> {
     if let Some(address) = param {
         connect_ble_address(address)
@@ -297,16 +268,16 @@ fn connect(param: Option<u64>) -> std::result::Result<CoreCubeBLE, String> {
 }
 
 // choose next cube action
-fn get_next_cube_action() -> CubeAction {
+fn get_next_cube_action() -> BuiltinMove {
     let mut rng = rand::thread_rng();
     let actions = [
-        CubeAction::SwingR,
-        CubeAction::SwingL,
-        CubeAction::Step2,
-        CubeAction::Step4,
-        CubeAction::StepRL2,
-        CubeAction::RollingR,
-        CubeAction::RollingL,
+        BuiltinMove::SwingR,
+        BuiltinMove::SwingL,
+        BuiltinMove::Step2,
+        BuiltinMove::Step4,
+        BuiltinMove::StepRL2,
+        BuiltinMove::RollingR,
+        BuiltinMove::RollingL,
     ];
     let weight = [4, 4, 4, 3, 5, 5, 5];
 
@@ -330,446 +301,7 @@ fn get_next_cube_action() -> CubeAction {
     actions[next_action_number]
 }
 
-fn get_cube_control_data(cube: &CubeInfo, max_duration: u64) -> Option<CubeControl> {
-    let motor_duration = ((max_duration * 4) / 5) as u8;
-    let control = match cube.action {
-        CubeAction::GetReady | CubeAction::HomePosition => {
-            if !MAT_ENABLE.get().unwrap() {
-                if cube.action == CubeAction::HomePosition {
-                    return None;
-                }
-                let speed: u8 = 30;
-                match cube.step_count {
-                    0 => CubeControl {
-                        command: CubeCommand::Move,
-                        term_ms: Some(3000),
-                        data: Some(vec![MOTOR_FW, speed, MOTOR_FW, speed, 0]),
-                    },
-                    1 => CubeControl {
-                        command: CubeCommand::Move,
-                        // Turn Left
-                        data: Some(vec![MOTOR_RV, 15, MOTOR_FW, 15, 55]),
-                        ..CubeControl::default()
-                    },
-                    2 => CubeControl {
-                        command: CubeCommand::Nothing,
-                        term_ms: Some(2000),
-                        ..CubeControl::default()
-                    },
-                    _ => CubeControl {
-                        command: CubeCommand::End,
-                        ..CubeControl::default()
-                    },
-                }
-            } else {
-                let x = match cube.id % 4 {
-                    0 => MAT_OFFSET_X.get().unwrap() + 200,
-                    1 => MAT_OFFSET_X.get().unwrap() + 300,
-                    2 => MAT_OFFSET_X.get().unwrap() + 140,
-                    3 => MAT_OFFSET_X.get().unwrap() + 360,
-                    _ => MAT_OFFSET_X.get().unwrap() + 250,
-                };
-                let x_upper = (x / 256) as u8;
-                let x_lower = (x % 256) as u8;
-
-                let y_offset = (cube.id / 2) * 30;
-                let y = match cube.action {
-                    CubeAction::GetReady => MAT_OFFSET_Y.get().unwrap() + 190 + y_offset,
-                    CubeAction::HomePosition => MAT_OFFSET_Y.get().unwrap() + 210 + y_offset,
-                    _ => MAT_OFFSET_Y.get().unwrap() + 230 + y_offset,
-                };
-                let y_upper = (y / 256) as u8;
-                let y_lower = (y % 256) as u8;
-
-                let speed = match cube.action {
-                    CubeAction::GetReady => 80,
-                    CubeAction::HomePosition => 30,
-                    _ => 30,
-                };
-                let term_ms = match cube.action {
-                    CubeAction::GetReady => 4000,
-                    CubeAction::HomePosition => 400,
-                    _ => 1500,
-                };
-                let moving_type = match cube.action {
-                    CubeAction::GetReady => 3,
-                    CubeAction::HomePosition => 0,
-                    _ => 0,
-                };
-                match cube.step_count {
-                    0 => CubeControl {
-                        command: CubeCommand::MoveTo,
-                        term_ms: Some(term_ms),
-                        data: Some(vec![
-                            4,
-                            0,
-                            speed,
-                            moving_type,
-                            x_lower,
-                            x_upper,
-                            y_lower,
-                            y_upper,
-                            90,
-                            0,
-                        ]),
-                    },
-                    _ => CubeControl {
-                        command: CubeCommand::End,
-                        ..CubeControl::default()
-                    },
-                }
-            }
-        }
-        CubeAction::Circle1 => {
-            let speed: u8 = 50;
-            let mut speed_r = speed;
-            let mut speed_l = speed;
-            if cube.id == 0 {
-                speed_l -= 8;
-            } else {
-                speed_l = 0;
-                speed_r = 0;
-            }
-
-            match cube.step_count {
-                0 => CubeControl {
-                    command: CubeCommand::Move,
-                    term_ms: Some(CIRCLE_TERM_MS),
-                    data: Some(vec![MOTOR_FW, speed_l, MOTOR_FW, speed_r, 0]),
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::Circle2 => {
-            let speed: u8 = 50;
-            let mut speed_r = speed;
-            let mut speed_l = speed;
-            if cube.id == 1 {
-                speed_r -= 8;
-            } else {
-                speed_l = 0;
-                speed_r = 0;
-            }
-            match cube.step_count {
-                0 => CubeControl {
-                    command: CubeCommand::Move,
-                    term_ms: Some(CIRCLE_TERM_MS - 100),
-                    data: Some(vec![MOTOR_FW, speed_l, MOTOR_FW, speed_r, 0]),
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::ByeBye => {
-            if !MAT_ENABLE.get().unwrap() {
-                return None;
-            }
-            let speed: u8 = 30;
-            let state = match cube.step_count {
-                0..=19 => cube.step_count % 2,
-                _ => cube.step_count - 19,
-            };
-
-            let x = match cube.id % 2 {
-                0 => MAT_OFFSET_X.get().unwrap() + 80,
-                1 => MAT_OFFSET_X.get().unwrap() + 420,
-                _ => MAT_OFFSET_X.get().unwrap() + 250,
-            };
-            let x_upper = (x / 256) as u8;
-            let x_lower = (x % 256) as u8;
-
-            let y = match cube.id % 4 {
-                0 | 1 => MAT_OFFSET_Y.get().unwrap() + 70,
-                2 | 3 => MAT_OFFSET_Y.get().unwrap() + 430,
-                _ => MAT_OFFSET_Y.get().unwrap() + 200,
-            };
-            let y_upper = (y / 256) as u8;
-            let y_lower = (y % 256) as u8;
-
-            let d = 270;
-            let d_upper = (d / 256) as u8;
-            let d_lower = (d % 256) as u8;
-
-            match state {
-                0 => CubeControl {
-                    command: CubeCommand::Move,
-                    term_ms: Some(100),
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_FW, speed, 0]),
-                },
-                1 => CubeControl {
-                    command: CubeCommand::Move,
-                    term_ms: Some(100),
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_RV, speed, 0]),
-                },
-                2 => CubeControl {
-                    command: CubeCommand::Move,
-                    term_ms: Some(30),
-                    data: Some(vec![MOTOR_RV, 0, MOTOR_RV, 0, 0]),
-                },
-                3 => CubeControl {
-                    command: CubeCommand::MoveTo,
-                    term_ms: Some(5000),
-                    data: Some(vec![
-                        4, 0, 80, 3, x_lower, x_upper, y_lower, y_upper, d_lower, d_upper,
-                    ]),
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::SwingR => {
-            let speed: u8 = 20;
-            match cube.step_count {
-                0 | 3 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_RV, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                1 | 2 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_FW, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::SwingL => {
-            let speed: u8 = 20;
-            match cube.step_count {
-                0 | 3 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_FW, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                1 | 2 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_RV, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::Step2 => {
-            let speed: u8 = 20;
-            match cube.step_count {
-                0 | 3 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_FW, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                1 | 2 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_RV, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::Step4 => {
-            let speed: u8 = 20;
-            match cube.step_count {
-                0 | 1 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_FW, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                2 | 3 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_RV, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::Step8 => {
-            let speed: u8 = 20;
-            match cube.step_count {
-                0 | 1 | 2 | 3 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_FW, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                4 | 5 | 6 | 7 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_RV, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::StepRL2 => {
-            let speed: u8 = 20;
-            match cube.step_count {
-                0 | 3 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_FW, speed / 2, motor_duration]),
-                    ..CubeControl::default()
-                },
-                1 | 2 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed / 2, MOTOR_FW, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                4 | 7 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed / 2, MOTOR_RV, speed, motor_duration]),
-                    ..CubeControl::default()
-                },
-                5 | 6 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_RV, speed / 2, motor_duration]),
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::RollingR => {
-            let speed: u8 = 37;
-            let full_time = cmp::min(255, max_duration * 4) as u8;
-            match cube.step_count {
-                0 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_FW, speed, MOTOR_RV, speed, full_time]),
-                    ..CubeControl::default()
-                },
-                1 | 2 | 3 => CubeControl {
-                    command: CubeCommand::Nothing,
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-        CubeAction::RollingL => {
-            let speed: u8 = 37;
-            let full_time = cmp::min(255, max_duration * 4) as u8;
-            match cube.step_count {
-                0 => CubeControl {
-                    command: CubeCommand::Move,
-                    data: Some(vec![MOTOR_RV, speed, MOTOR_FW, speed, full_time]),
-                    ..CubeControl::default()
-                },
-                1 | 2 | 3 => CubeControl {
-                    command: CubeCommand::Nothing,
-                    ..CubeControl::default()
-                },
-                _ => CubeControl {
-                    command: CubeCommand::End,
-                    ..CubeControl::default()
-                },
-            }
-        }
-    };
-
-    match control.command {
-        CubeCommand::End => None,
-        _ => Some(control),
-    }
-}
-
-fn send_command_to_cube(cube: &mut CubeInfo, control: &CubeControl, default_action_term_ms: u64) {
-    info!("{:?}", control.command);
-    match control.command {
-        CubeCommand::Move => {
-            if let Some(data) = &control.data {
-                let ble_data: Vec<u8> = vec![
-                    0x02, 0x01, data[0], data[1], 0x02, data[2], data[3], data[4],
-                ];
-                let result = cube.ble.write(CoreCubeUuidName::MotorCtrl, &ble_data);
-                assert_eq!(result.unwrap(), true);
-            }
-            cube.step_count += 1;
-            if let Some(action_term_ms) = control.term_ms {
-                cube.action_term = time::Duration::from_millis(action_term_ms);
-                debug!("action_term_ms");
-            } else {
-                cube.action_term = time::Duration::from_millis(default_action_term_ms);
-                debug!("default_action_term_ms");
-            }
-        }
-        CubeCommand::MoveTo => {
-            if !MAT_ENABLE.get().unwrap() {
-                return;
-            }
-            if let Some(data) = &control.data {
-                let timeout = data[0];
-                let moving_type = data[1];
-                let max_speed = data[2];
-                let acceleration = data[3];
-                let x_l = data[4];
-                let x_u = data[5];
-                let y_l = data[6];
-                let y_u = data[7];
-                let degree_l = data[8];
-                let degree_u = data[9];
-                let ble_data: Vec<u8> = vec![
-                    0x03,
-                    0x00,
-                    timeout,
-                    moving_type,
-                    max_speed,
-                    acceleration,
-                    0x00,
-                    x_l,
-                    x_u,
-                    y_l,
-                    y_u,
-                    degree_l,
-                    degree_u,
-                ];
-                debug!("{:?}", ble_data);
-                let result = cube.ble.write(CoreCubeUuidName::MotorCtrl, &ble_data);
-                assert_eq!(result.unwrap(), true);
-            }
-            cube.step_count += 1;
-            if let Some(action_term_ms) = control.term_ms {
-                cube.action_term = time::Duration::from_millis(action_term_ms);
-            } else {
-                cube.action_term = time::Duration::from_millis(default_action_term_ms);
-            }
-        }
-        CubeCommand::End => {
-            cube.step_count = 0;
-            cube.action_term = time::Duration::from_millis(0);
-        }
-        CubeCommand::Nothing => {
-            cube.step_count += 1;
-            if let Some(action_term_ms) = control.term_ms {
-                cube.action_term = time::Duration::from_millis(action_term_ms);
-            } else {
-                cube.action_term = time::Duration::from_millis(default_action_term_ms);
-            }
-        }
-    }
-}
-
+#[cfg(windows)]
 fn main() {
     env_logger::init();
 
@@ -794,7 +326,7 @@ fn main() {
                 .help("mat type")
                 .long("mat")
                 .takes_value(true)
-                .possible_values(&["tc1", "tc2", "gesun", "none"])
+                .possible_values(&["tc1", "tc2", "gesun", "simple", "auto", "none"])
                 .default_value("none"),
         );
 
@@ -808,13 +340,18 @@ fn main() {
 
     let mut default_action_term_ms: u64 = 600;
     if let Some(tempo_str) = matches.value_of("tempo") {
-        default_action_term_ms = match u64::from_str_radix(&tempo_str, 10) {
-            Ok(tempo) => (1000 * 1000) / (tempo * 1000 / 60),
+        let tempo = match u64::from_str_radix(&tempo_str, 10) {
+            Ok(tempo) => tempo,
             Err(e) => {
                 error!("{}", e);
-                120
+                std::process::exit(1);
             }
         };
+        if tempo == 0 || tempo as f32 > TEMPO_MAX {
+            error!("ERROR: specify tempo between 1 to {}", TEMPO_MAX);
+            std::process::exit(1);
+        }
+        default_action_term_ms = (1000 * 1000) / (tempo * 1000 / 60);
         println!(
             "tempo {} (default_action_term_ms:{}[ms])",
             tempo_str, default_action_term_ms
@@ -840,30 +377,31 @@ fn main() {
         println!("using {} cubes", cube_max);
     }
 
+    let mat_auto_detection = matches.value_of("mat").unwrap() == "auto";
     match matches.value_of("mat").unwrap() {
         "tc1" => {
             println!("Use toio collection mat (circle side)");
-            MAT_ENABLE.set(true).unwrap();
-            MAT_OFFSET_X.set(0).unwrap();
-            MAT_OFFSET_Y.set(0).unwrap();
+            MAT.set(Some(get_mat(MatType::ToioCollectionRing))).unwrap();
         }
         "tc2" => {
             println!("Use toio collection mat (checker side)");
-            MAT_ENABLE.set(true).unwrap();
-            MAT_OFFSET_X.set(503).unwrap();
-            MAT_OFFSET_Y.set(0).unwrap();
+            MAT.set(Some(get_mat(MatType::ToioCollectionTiles)))
+                .unwrap();
         }
         "gesun" => {
             println!("Use gesundroiod mat");
-            MAT_ENABLE.set(true).unwrap();
-            MAT_OFFSET_X.set(1003).unwrap();
-            MAT_OFFSET_Y.set(0).unwrap();
+            MAT.set(Some(get_mat(MatType::Gesundroid))).unwrap();
+        }
+        "simple" => {
+            println!("Use simple mat");
+            MAT.set(Some(get_mat(MatType::SimpleMat))).unwrap();
+        }
+        "auto" => {
+            println!("Detect mat automatically");
         }
         _ => {
             println!("Without mat mode");
-            MAT_ENABLE.set(false).unwrap();
-            MAT_OFFSET_X.set(0).unwrap();
-            MAT_OFFSET_Y.set(0).unwrap();
+            MAT.set(None).unwrap();
         }
     };
 
@@ -878,9 +416,7 @@ fn main() {
                 let info = CubeInfo {
                     id: connected_cubes,
                     ble: c,
-                    action: CubeAction::GetReady,
-                    step_count: 0,
-                    action_term: time::Duration::from_millis(0),
+                    action: BuiltinMove::GetReady,
                 };
                 cube.push(info);
                 1
@@ -935,6 +471,24 @@ fn main() {
         .register_notify(CoreCubeUuidName::IdInfo, Box::new(id_information_notify));
     let id_handler = result.unwrap();
 
+    // Detect mat from the Position IDs of cube 1
+    if mat_auto_detection {
+        println!("put cube 1 on the mat and move it around");
+        let start_time = time::Instant::now();
+        let mut mat: Option<Mat> = None;
+        while mat.is_none()
+            && start_time.elapsed() < time::Duration::from_secs(MAT_DETECTION_TIMEOUT_SEC)
+        {
+            mat = MAT_DETECTOR.lock().unwrap().get_mat();
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        match mat {
+            Some(m) => println!("mat detected: {}", m.mat_type),
+            None => println!("mat is not detected: without mat mode (specify it with --mat)"),
+        }
+        MAT.set(mat).unwrap();
+    }
+
     // Register Ctrl-C handler
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -946,86 +500,110 @@ fn main() {
     // MAIN LOOP
     // --------------------------------------------------------------------------------
 
-    let cube_max_duration = cmp::min(255, (default_action_term_ms / 10) & 0xff);
-    let cube_motor_duration = ((cube_max_duration * 4) / 5) as u8;
-    println!(
-        "cube_max_duration:{}, cube_motor_duration:{}",
-        cube_max_duration, cube_motor_duration
-    );
+    let context = SequenceContext {
+        beat: time::Duration::from_millis(default_action_term_ms),
+        mat: get_selected_mat(),
+    };
+    let tick = time::Duration::from_millis(10);
 
     let action_list = [
-        [CubeAction::GetReady, CubeAction::GetReady],
-        [CubeAction::Circle1, CubeAction::Circle1],
-        [CubeAction::HomePosition, CubeAction::HomePosition],
-        [CubeAction::Circle2, CubeAction::Circle2],
-        [CubeAction::HomePosition, CubeAction::HomePosition],
-        [CubeAction::SwingR, CubeAction::SwingL],
-        [CubeAction::Step2, CubeAction::Step2],
-        [CubeAction::RollingR, CubeAction::RollingR],
-        [CubeAction::RollingL, CubeAction::RollingL],
-        [CubeAction::HomePosition, CubeAction::HomePosition],
-        [CubeAction::StepRL2, CubeAction::StepRL2],
-        [CubeAction::Step8, CubeAction::Step8],
-        [CubeAction::RollingL, CubeAction::RollingR],
-        [CubeAction::RollingR, CubeAction::RollingL],
-        [CubeAction::HomePosition, CubeAction::HomePosition],
-        [CubeAction::ByeBye, CubeAction::ByeBye],
+        [BuiltinMove::GetReady, BuiltinMove::GetReady],
+        [BuiltinMove::Circle1, BuiltinMove::Circle1],
+        [BuiltinMove::HomePosition, BuiltinMove::HomePosition],
+        [BuiltinMove::Circle2, BuiltinMove::Circle2],
+        [BuiltinMove::HomePosition, BuiltinMove::HomePosition],
+        [BuiltinMove::SwingR, BuiltinMove::SwingL],
+        [BuiltinMove::Step2, BuiltinMove::Step2],
+        [BuiltinMove::RollingR, BuiltinMove::RollingR],
+        [BuiltinMove::RollingL, BuiltinMove::RollingL],
+        [BuiltinMove::HomePosition, BuiltinMove::HomePosition],
+        [BuiltinMove::StepRL2, BuiltinMove::StepRL2],
+        [BuiltinMove::Step8, BuiltinMove::Step8],
+        [BuiltinMove::RollingL, BuiltinMove::RollingR],
+        [BuiltinMove::RollingR, BuiltinMove::RollingL],
+        [BuiltinMove::HomePosition, BuiltinMove::HomePosition],
+        [BuiltinMove::ByeBye, BuiltinMove::ByeBye],
     ];
 
-    let mut action_count = 0;
-    while running.load(Ordering::SeqCst) {
-        let mut action_end: bool = false;
-        for i in 0..cube_max {
-            loop {
-                let motor_control_data: Option<CubeControl> =
-                    get_cube_control_data(&cube[i], cube_max_duration);
-                if let Some(control) = motor_control_data {
-                    send_command_to_cube(&mut cube[i], &control, default_action_term_ms);
-                    break;
-                } else {
-                    action_end = true;
-                    break;
-                }
+    // actions are scheduled on the beats to keep the cubes in sync with the music
+    let mut scheduler =
+        match BeatScheduler::new(60_000.0 / default_action_term_ms as f32, 4, cube_max) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
             }
+        };
+    scheduler.add_beat_handler(Box::new(|event| {
+        if event.is_bar_start() {
+            debug!("bar {}", event.bar);
         }
+    }));
 
-        if action_end {
-            if random_mode {
-                let next_action = get_next_cube_action();
-                for i in 0..cube_max {
-                    cube[i].step_count = 0;
-                    cube[i].action = next_action;
-                }
-            } else {
-                action_count += 1;
-                if action_count >= action_list.len() {
-                    break;
-                }
-                for i in 0..cube_max {
-                    cube[i].step_count = 0;
-                    cube[i].action = action_list[action_count][i % 2];
-                }
-            }
+    let mut start_beat: f64 = 0.0;
+    let mut action_count = 0;
+    while running.load(Ordering::SeqCst) {
+        // play the action on all cubes from the same beat
+        let mut end_beat = start_beat;
+        for (i, c) in cube[0..cube_max].iter().enumerate() {
+            let sequence = get_builtin_sequence(c.action, c.id, context.mat.is_some());
+            let timeline = sequence.compile(&context);
+            end_beat =
+                end_beat.max(scheduler.schedule_timeline(i, start_beat, &timeline, context.beat));
+        }
+        // the next action starts on a beat
+        start_beat = (end_beat - 0.001).ceil();
+
+        // schedule the next action a beat ahead to write it before the beat
+        while running.load(Ordering::SeqCst) {
+            let now = time::Instant::now();
+            let ble: Vec<&CoreCubeBLE> = cube[0..cube_max].iter().map(|c| &c.ble).collect();
+            scheduler.poll(&ble, now).unwrap();
+            if scheduler.get_beat(now) + 1.0 >= start_beat {
+                break;
+            }
+            thread::sleep(tick);
+        }
 
+        if random_mode {
+            let next_action = get_next_cube_action();
             for i in 0..cube_max {
-                print!(" cube {} {:?},", i, cube[i].action);
+                cube[i].action = next_action;
             }
-            println!("");
         } else {
-            let mut max_action_term = time::Duration::from_millis(0);
-            for cube_info in &cube {
-                debug!(
-                    "  cube {} action_term {:?}",
-                    cube_info.id, cube_info.action_term
-                );
-                max_action_term = cmp::max(max_action_term, cube_info.action_term);
+            action_count += 1;
+            if action_count >= action_list.len() {
+                break;
             }
-            info!("max_action_term {:?}", max_action_term);
-
-            if max_action_term > time::Duration::from_millis(0) {
-                thread::sleep(max_action_term);
+            for i in 0..cube_max {
+                cube[i].action = action_list[action_count][i % 2];
             }
         }
+
+        for i in 0..cube_max {
+            print!(" cube {} {:?},", i, cube[i].action);
+        }
+        println!("");
+    }
+
+    // play the rest of the last action
+    while running.load(Ordering::SeqCst) {
+        let ble: Vec<&CoreCubeBLE> = cube[0..cube_max].iter().map(|c| &c.ble).collect();
+        if scheduler.poll(&ble, time::Instant::now()).unwrap() {
+            break;
+        }
+        thread::sleep(tick);
+    }
+
+    for i in 0..cube_max {
+        let drift = scheduler.get_drift(i);
+        println!(
+            "cube {}: latency {:?}, drift mean {}us max {}us",
+            i,
+            scheduler.get_latency(i),
+            drift.get_mean_us(),
+            drift.max_us
+        );
     }
     // --------------------------------------------------------------------------------
 
@@ -1054,3 +632,9 @@ fn main() {
     let result = id_handler.unregister();
     assert_eq!(result.unwrap(), true);
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("this example connects to the cubes and runs only on Windows");
+    std::process::exit(1);
+}
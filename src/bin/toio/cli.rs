@@ -0,0 +1,619 @@
+/* Argument parsers and output records of the toio command line tool */
+
+use core_cube::id_info::{get_id_info, IdInfo};
+use core_cube::light::LightColor;
+use core_cube::motor::{
+    get_motor_speed_config_bytes, get_motor_speed_info, get_target_move_response,
+};
+use core_cube::sensor::{get_motion_detection, get_posture_angle, get_posture_angle_config_bytes};
+use core_cube::sound::*;
+use core_cube::standard_id::find_standard_id;
+use core_cube::transport::{get_protocol_version, CoreCubeUuidName};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OutputFormat {
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format \"{}\" (human, json)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(x) => write!(f, "{}", x),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Bool(x) => write!(f, "{}", x),
+            Value::Text(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+// NaN and infinity are null in JSON
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(x) => serializer.serialize_i64(*x),
+            Value::Float(x) => serializer.serialize_f64(*x),
+            Value::Bool(x) => serializer.serialize_bool(*x),
+            Value::Text(x) => serializer.serialize_str(x),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+/// An output line: a kind (e.g. "battery") and its fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub kind: String,
+    pub fields: Vec<(String, Value)>,
+}
+
+impl Record {
+    pub fn new(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn with<T: Into<Value>>(mut self, name: &str, value: T) -> Self {
+        self.fields.push((name.to_string(), value.into()));
+        self
+    }
+
+    /// "kind name=value ..."
+    pub fn to_human(&self) -> String {
+        let mut line = self.kind.clone();
+        for (name, value) in &self.fields {
+            line.push_str(&format!(" {}={}", name, value));
+        }
+        line
+    }
+
+    /// {"type":"kind","name":value,...}
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn format(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Human => self.to_human(),
+            OutputFormat::Json => self.to_json(),
+        }
+    }
+}
+
+// the fields in their order after "type"
+impl Serialize for Record {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len() + 1))?;
+        map.serialize_entry("type", &self.kind)?;
+        for (name, value) in &self.fields {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Notification decoded into a record (unknown data: "raw" with the bytes)
+pub fn decode_notification(characteristic: CoreCubeUuidName, data: &[u8]) -> Record {
+    let record = match characteristic {
+        CoreCubeUuidName::IdInfo => match get_id_info(data) {
+            IdInfo::PositionId(x) => Some(
+                Record::new("position")
+                    .with("x", x.cube_x as i64)
+                    .with("y", x.cube_y as i64)
+                    .with("angle", x.cube_angle as i64),
+            ),
+            IdInfo::StandardId(x) => {
+                let record = Record::new("standard_id")
+                    .with("value", x.value as i64)
+                    .with("angle", x.angle as i64);
+                Some(match find_standard_id(x.value) {
+                    Some(item) => record.with("name", item.name),
+                    None => record,
+                })
+            }
+            IdInfo::PositionIdMissed => Some(Record::new("position_missed")),
+            IdInfo::StandardIdMissed => Some(Record::new("standard_id_missed")),
+            IdInfo::Unknown => None,
+        },
+        CoreCubeUuidName::SensorInfo => {
+            if let Some(x) = get_motion_detection(data) {
+                Some(
+                    Record::new("motion")
+                        .with("horizontal", x.horizontal)
+                        .with("collision", x.collision)
+                        .with("double_tap", x.double_tap)
+                        .with("posture", x.posture.to_string())
+                        .with("shake", x.shake as i64),
+                )
+            } else {
+                get_posture_angle(data).map(|x| {
+                    Record::new("posture_angle")
+                        .with("roll", x.roll as i64)
+                        .with("pitch", x.pitch as i64)
+                        .with("yaw", x.yaw as i64)
+                })
+            }
+        }
+        CoreCubeUuidName::ButtonInfo => match data {
+            [0x01, state, ..] => Some(Record::new("button").with("pressed", *state == 0x80)),
+            _ => None,
+        },
+        CoreCubeUuidName::BatteryInfo => data
+            .first()
+            .map(|x| Record::new("battery").with("level", *x as i64)),
+        CoreCubeUuidName::MotorCtrl => {
+            if let Some(x) = get_target_move_response(data) {
+                Some(
+                    Record::new("target")
+                        .with("id", x.id as i64)
+                        .with("multi", x.multi_target)
+                        .with("result", format!("{:?}", x.result)),
+                )
+            } else {
+                get_motor_speed_info(data).map(|(left, right)| {
+                    Record::new("motor_speed")
+                        .with("left", left as i64)
+                        .with("right", right as i64)
+                })
+            }
+        }
+        CoreCubeUuidName::Configuration => {
+            get_protocol_version(data).map(|x| Record::new("protocol_version").with("version", x))
+        }
+        _ => None,
+    };
+    record.unwrap_or_else(|| {
+        Record::new("raw")
+            .with("characteristic", characteristic.to_string())
+            .with("bytes", format_bytes(data))
+    })
+}
+
+/// "02 01 01 64"
+pub fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// "e0:12:34:56:78:9a"
+pub fn format_ble_address(address: u64) -> String {
+    (0..6)
+        .rev()
+        .map(|i| format!("{:02x}", (address >> (i * 8)) & 0xff))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// BLE address at the end of a device id ("BluetoothLE#BluetoothLE...-e0:12:34:56:78:9a")
+pub fn get_device_id_address(device_id: &str) -> Option<u64> {
+    let address = device_id.rsplit('-').next()?;
+    if address.len() != 17 || address.split(':').count() != 6 {
+        return None;
+    }
+    let mut hex = address.to_string();
+    hex.retain(|c| c != ':');
+    u64::from_str_radix(&hex, 16).ok()
+}
+
+/// Colors of `parse_color` by name
+pub const COLOR_NAMES: [(&str, LightColor); 8] = [
+    ("off", LightColor::new(0, 0, 0)),
+    ("white", LightColor::new(255, 255, 255)),
+    ("red", LightColor::new(255, 0, 0)),
+    ("green", LightColor::new(0, 255, 0)),
+    ("blue", LightColor::new(0, 0, 255)),
+    ("yellow", LightColor::new(255, 255, 0)),
+    ("cyan", LightColor::new(0, 255, 255)),
+    ("magenta", LightColor::new(255, 0, 255)),
+];
+
+/// "#ff0000", "ff0000", "255,0,0" or a color name ("red", "off", ...)
+pub fn parse_color(s: &str) -> std::result::Result<LightColor, String> {
+    let error = || format!("invalid color \"{}\" (#ff0000, 255,0,0, red)", s);
+    let s = s.trim();
+    if let Some((_, color)) = COLOR_NAMES.iter().find(|(name, _)| *name == s) {
+        return Ok(*color);
+    }
+    if s.contains(',') {
+        let values = s
+            .split(',')
+            .map(|x| x.trim().parse::<u8>())
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| error())?;
+        return match values.as_slice() {
+            [r, g, b] => Ok(LightColor::new(*r, *g, *b)),
+            _ => Err(error()),
+        };
+    }
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(error());
+    }
+    let value = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| error());
+    Ok(LightColor::new(value(0)?, value(2)?, value(4)?))
+}
+
+/// Sound effects of the cube in the order of their ids
+pub const SOUND_EFFECTS: [&str; 11] = [
+    "enter", "selected", "cancel", "cursor", "mat_in", "mat_out", "get1", "get2", "get3",
+    "effect1", "effect2",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SoundCommand {
+    Effect(u8),
+    Notes(Vec<Note>),
+    Stop,
+}
+
+impl SoundCommand {
+    pub fn get_bytes(&self, volume: u8) -> Vec<u8> {
+        match self {
+            SoundCommand::Effect(id) => get_sound_effect_bytes(*id, volume),
+            SoundCommand::Notes(notes) => {
+                let notes: Vec<Note> = notes.iter().map(|x| Note { volume, ..*x }).collect();
+                get_midi_bytes(1, &notes)
+            }
+            SoundCommand::Stop => get_sound_stop_bytes(),
+        }
+    }
+}
+
+/// "stop", a sound effect (name or id) or MML
+pub fn parse_sound(s: &str) -> std::result::Result<SoundCommand, String> {
+    let s = s.trim();
+    if s == "stop" {
+        return Ok(SoundCommand::Stop);
+    }
+    if let Some(id) = SOUND_EFFECTS.iter().position(|x| *x == s) {
+        return Ok(SoundCommand::Effect(id as u8));
+    }
+    if let Ok(id) = s.parse::<u8>() {
+        if (id as usize) < SOUND_EFFECTS.len() {
+            return Ok(SoundCommand::Effect(id));
+        }
+        return Err(format!(
+            "sound effect {} must be 0 - {}",
+            id,
+            SOUND_EFFECTS.len() - 1
+        ));
+    }
+    parse_mml(s).map(SoundCommand::Notes)
+}
+
+// number following the current character of the MML
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u32> {
+    let mut number = String::new();
+    while let Some(c) = chars.peek().filter(|x| x.is_ascii_digit()) {
+        number.push(*c);
+        chars.next();
+    }
+    number.parse::<u32>().ok()
+}
+
+/// MML: notes `c` - `b` (`+`/`#`: sharp, `-`: flat, length, `.`: dotted), `r` (rest),
+/// `o` (octave, default 4), `<` / `>` (octave down / up), `l` (default length, default 4)
+/// and `t` (tempo, default 120). `o4a` is MIDI note 69.
+pub fn parse_mml(s: &str) -> std::result::Result<Vec<Note>, String> {
+    let mut notes: Vec<Note> = Vec::new();
+    let mut octave: i32 = 4;
+    let mut length: u32 = 4;
+    let mut tempo: u32 = 120;
+    let mut chars = s.to_ascii_lowercase().chars().collect::<String>();
+    chars.retain(|c| !c.is_whitespace());
+    let mut chars = chars.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            'a'..='g' | 'r' => {
+                // None: rest
+                let mut note: Option<i32> = match c {
+                    'c' => Some(0),
+                    'd' => Some(2),
+                    'e' => Some(4),
+                    'f' => Some(5),
+                    'g' => Some(7),
+                    'a' => Some(9),
+                    'b' => Some(11),
+                    _ => None,
+                };
+                if let Some(x) = note.as_mut() {
+                    match chars.peek() {
+                        Some('+') | Some('#') => *x += 1,
+                        Some('-') => *x -= 1,
+                        _ => (),
+                    }
+                    if matches!(chars.peek(), Some('+') | Some('#') | Some('-')) {
+                        chars.next();
+                    }
+                }
+                let note_length = take_number(&mut chars).unwrap_or(length);
+                if !(1..=64).contains(&note_length) {
+                    return Err(format!("MML: invalid length {}", note_length));
+                }
+                let mut seconds = 240.0 / (tempo * note_length) as f32;
+                if chars.peek() == Some(&'.') {
+                    chars.next();
+                    seconds *= 1.5;
+                }
+                let duration = time::Duration::from_secs_f32(seconds);
+                match note {
+                    None => notes.push(Note::rest(duration)),
+                    Some(note) => {
+                        let number = (octave + 1) * 12 + note;
+                        if !(0..NOTE_REST as i32).contains(&number) {
+                            return Err(format!("MML: note out of range (octave {})", octave));
+                        }
+                        notes.push(Note::new(number as u8, duration));
+                    }
+                }
+            }
+            'o' | 'l' | 't' => {
+                let value = take_number(&mut chars)
+                    .ok_or_else(|| format!("MML: \"{}\" needs a number", c))?;
+                match c {
+                    'o' if value <= 9 => octave = value as i32,
+                    'l' if (1..=64).contains(&value) => length = value,
+                    't' if (20..=600).contains(&value) => tempo = value,
+                    _ => return Err(format!("MML: invalid value {}{}", c, value)),
+                }
+            }
+            '<' => octave -= 1,
+            '>' => octave += 1,
+            _ => return Err(format!("MML: unknown command \"{}\"", c)),
+        }
+    }
+    if notes.is_empty() {
+        return Err(format!(
+            "unknown sound \"{}\" (sound effect, stop or MML)",
+            s
+        ));
+    }
+    if notes.len() > MIDI_NOTES_MAX {
+        return Err(format!("MML: more than {} notes", MIDI_NOTES_MAX));
+    }
+    Ok(notes)
+}
+
+/// "500ms", "1.5s" or milliseconds ("500")
+pub fn parse_millis(s: &str) -> std::result::Result<time::Duration, String> {
+    let error = || format!("invalid duration \"{}\" (500ms, 1.5s)", s);
+    let s = s.trim();
+    let (number, scale) = if let Some(x) = s.strip_suffix("ms") {
+        (x, 1.0)
+    } else if let Some(x) = s.strip_suffix('s') {
+        (x, 1000.0)
+    } else {
+        (s, 1.0)
+    };
+    match number.parse::<f64>() {
+        Ok(x) if x >= 0.0 && x.is_finite() => {
+            Ok(time::Duration::from_millis((x * scale).round() as u64))
+        }
+        _ => Err(error()),
+    }
+}
+
+/// Duration of a timed move: like `parse_millis`, but 0 is rejected
+/// (the cube would otherwise drive without a time limit)
+pub fn parse_move_duration(s: &str) -> std::result::Result<time::Duration, String> {
+    match parse_millis(s)? {
+        x if x.is_zero() => Err(format!("duration must be more than 0 (got \"{}\")", s)),
+        x => Ok(x),
+    }
+}
+
+/// Names of the settings of `parse_config`
+pub const CONFIG_NAMES: [&str; 6] = [
+    "horizontal",
+    "collision",
+    "double_tap",
+    "posture_angle",
+    "motor_speed",
+    "id_missed",
+];
+
+/// Configuration bytes of "name=value"
+///
+/// horizontal: threshold angle 1 - 45 [deg], collision: threshold 1 - 10,
+/// double_tap: interval 0 - 7, posture_angle: notification interval [ms] (0: off),
+/// motor_speed: on / off, id_missed: sensitivity 0 - 255 [10ms]
+pub fn parse_config(s: &str) -> std::result::Result<Vec<u8>, String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid setting \"{}\" (name=value)", s))?;
+    let (name, value) = (name.trim(), value.trim());
+    let number = |min: u16, max: u16| match value.parse::<u16>() {
+        Ok(x) if x >= min && x <= max => Ok(x),
+        _ => Err(format!(
+            "{}: value must be {} - {} (got \"{}\")",
+            name, min, max, value
+        )),
+    };
+    match name {
+        "horizontal" => Ok(vec![0x05, 0x00, number(1, 45)? as u8]),
+        "collision" => Ok(vec![0x06, 0x00, number(1, 10)? as u8]),
+        "double_tap" => Ok(vec![0x17, 0x00, number(0, 7)? as u8]),
+        "posture_angle" => Ok(get_posture_angle_config_bytes(number(0, 2550)?)),
+        "motor_speed" => match value {
+            "on" | "true" => Ok(get_motor_speed_config_bytes(true)),
+            "off" | "false" => Ok(get_motor_speed_config_bytes(false)),
+            _ => Err(format!(
+                "{}: value must be on or off (got \"{}\")",
+                name, value
+            )),
+        },
+        "id_missed" => Ok(vec![0x19, 0x00, number(0, 255)? as u8]),
+        _ => Err(format!(
+            "unknown setting \"{}\" ({})",
+            name,
+            CONFIG_NAMES.join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsers() {
+        assert_eq!(parse_color("#ff8000"), Ok(LightColor::new(255, 128, 0)));
+        assert_eq!(parse_color("0, 16, 0"), Ok(LightColor::new(0, 16, 0)));
+        assert_eq!(parse_color("blue"), Ok(LightColor::new(0, 0, 255)));
+        assert!(parse_color("#ff00").is_err());
+        assert!(parse_color("256,0,0").is_err());
+
+        assert_eq!(parse_sound("mat_in"), Ok(SoundCommand::Effect(4)));
+        assert_eq!(parse_sound("10"), Ok(SoundCommand::Effect(10)));
+        assert!(parse_sound("11").is_err());
+        let notes = match parse_sound("t60 o4 a8. >c+ r2").unwrap() {
+            SoundCommand::Notes(x) => x,
+            x => panic!("{:?}", x),
+        };
+        let millis: Vec<(u8, u128)> = notes
+            .iter()
+            .map(|x| (x.note, x.duration.as_millis()))
+            .collect();
+        assert_eq!(millis, vec![(69, 750), (73, 1000), (NOTE_REST, 2000)]);
+        assert_eq!(
+            SoundCommand::Notes(notes[..1].to_vec()).get_bytes(0x40),
+            vec![0x03, 0x01, 0x01, 75, 69, 0x40]
+        );
+        assert_eq!(
+            parse_mml("x"),
+            Err("MML: unknown command \"x\"".to_string())
+        );
+        assert!(parse_mml("o9b+").is_err());
+        // a flat c is the b of the octave below, not a rest
+        assert_eq!(parse_mml("o4c-").unwrap()[0].note, 59);
+        assert_eq!(parse_mml(">c-").unwrap()[0].note, 71);
+        assert!(parse_mml("o0<c-").is_err());
+
+        assert_eq!(parse_millis("500ms"), Ok(time::Duration::from_millis(500)));
+        assert_eq!(parse_millis("1.5s"), Ok(time::Duration::from_millis(1500)));
+        assert_eq!(parse_millis("20"), Ok(time::Duration::from_millis(20)));
+        assert!(parse_millis("-1s").is_err());
+        assert_eq!(
+            parse_move_duration("3s"),
+            Ok(time::Duration::from_millis(3000))
+        );
+        assert!(parse_move_duration("0").is_err());
+        assert!(parse_move_duration("0.0001s").is_err());
+
+        assert_eq!(parse_config("collision=5"), Ok(vec![0x06, 0x00, 0x05]));
+        assert_eq!(
+            parse_config("posture_angle=100"),
+            Ok(vec![0x1d, 0x00, 0x01, 0x0a, 0x01])
+        );
+        assert_eq!(
+            parse_config("collision=11"),
+            Err("collision: value must be 1 - 10 (got \"11\")".to_string())
+        );
+        assert!(parse_config("volume=1").is_err());
+    }
+
+    #[test]
+    fn records() {
+        let record = decode_notification(CoreCubeUuidName::BatteryInfo, &[80]);
+        assert_eq!(record.to_human(), "battery level=80");
+        assert_eq!(record.fields, vec![("level".to_string(), Value::Int(80))]);
+
+        let record = decode_notification(
+            CoreCubeUuidName::SensorInfo,
+            &[0x01, 0x01, 0x00, 0x01, 0x02, 0x00],
+        );
+        assert_eq!(
+            record.to_json(),
+            "{\"type\":\"motion\",\"horizontal\":true,\"collision\":false,\
+             \"double_tap\":true,\"posture\":\"Reverse\",\"shake\":0}"
+        );
+        let record = decode_notification(
+            CoreCubeUuidName::Configuration,
+            &[0x81, 0x00, b'2', b'.', b'1', b'.', b'0'],
+        );
+        assert_eq!(record.to_human(), "protocol_version version=2.1.0");
+        let record = decode_notification(CoreCubeUuidName::LightCtrl, &[0x01, 0xff]);
+        assert_eq!(
+            record.to_human(),
+            "raw characteristic=LightCtrl bytes=01 ff"
+        );
+        assert_eq!(
+            Record::new("scan").with("name", "a\"b").to_json(),
+            "{\"type\":\"scan\",\"name\":\"a\\\"b\"}"
+        );
+        assert_eq!(
+            Record::new("odometry")
+                .with("x", 1.5)
+                .with("y", f64::NAN)
+                .with("angle", f64::INFINITY)
+                .to_json(),
+            "{\"type\":\"odometry\",\"x\":1.5,\"y\":null,\"angle\":null}"
+        );
+
+        assert_eq!(format_ble_address(0xe0123456789a), "e0:12:34:56:78:9a");
+        assert_eq!(
+            get_device_id_address("BluetoothLE#BluetoothLEb8:31:b5:00:11:22-e0:12:34:56:78:9a"),
+            Some(0xe0123456789a)
+        );
+        assert_eq!(get_device_id_address("cube"), None);
+    }
+}
@@ -0,0 +1,1011 @@
+/* Commands of the toio tool on the cubes connected by BLE (Windows) */
+
+use crate::cli::*;
+use crate::repl::*;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use core_cube::light::get_light_bytes;
+use core_cube::motor::*;
+use core_cube::show::parse_ble_address;
+use core_cube::transport::{get_protocol_version, get_protocol_version_request_bytes};
+use core_cube::win10::*;
+use lazy_static::lazy_static;
+use log::{error, info};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::{cursor, queue, style, terminal};
+use std::io::{BufRead, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+const RESPONSE_TIMEOUT_MS: u64 = 2000;
+// the cube gives up a target move in 10 seconds
+const TARGET_TIMEOUT_MS: u64 = 12000;
+const POLL_INTERVAL_MS: u64 = 10;
+const SHELL_POLL_INTERVAL_MS: u64 = 50;
+const SHELL_PROMPT: &str = "toio> ";
+
+const SHELL_HELP: &str = "\
+[CUBE|all] COMMAND ARGS...  (without CUBE: the cubes selected by use)
+  move LEFT RIGHT [DURATION]    drive the motors (-115 - 115, up to 2.55s, none: until stop)
+  stop                          stop the motors
+  goto X Y [ANGLE]              move to the Position ID
+  led COLOR [DURATION]          #ff0000, 255,0,0, red, off
+  sound EFFECT|stop|MML         mat_in, get1, \"t120 o4 l8 cdefg\"
+  raw CHARACTERISTIC BYTES...   write bytes (motor, light, sound, config, ...)
+  read CHARACTERISTIC           read and decode (id, sensor, button, battery, ...)
+  config NAME=VALUE...          collision=5, posture_angle=100, ...
+  watch [CHARACTERISTIC...]     print the notifications (id, sensor, button, battery, motor, config, all)
+  unwatch [CHARACTERISTIC...]   stop printing the notifications
+use CUBE|all                    select the cubes of the commands without CUBE
+cubes, history, help, quit      (Tab: completion, Up / Down: history)";
+
+/// (index of the cube, characteristic, time, data)
+type Notification = (usize, CoreCubeUuidName, time::Instant, Vec<u8>);
+
+lazy_static! {
+    static ref NOTIFICATION: Mutex<Vec<Notification>> = Mutex::new(Vec::new());
+}
+
+fn notify(index: usize, characteristic: CoreCubeUuidName) -> CoreCubeNotifyHandlerFunction {
+    Box::new(move |data: Vec<u8>| {
+        NOTIFICATION
+            .lock()
+            .unwrap()
+            .push((index, characteristic, time::Instant::now(), data));
+    })
+}
+
+/// Cubes to connect
+enum Selection {
+    Address(Vec<u64>),
+    /// Paired cubes whose names contain one of the names
+    Name(Vec<String>),
+    /// All paired cubes
+    All,
+    /// The first paired cube which can be connected
+    First,
+}
+
+struct Cube {
+    label: String,
+    name: Option<String>,
+    address: Option<u64>,
+    ble: CoreCubeBLE,
+    handlers: Vec<CoreCubeNotifyHandler>,
+}
+
+impl Cube {
+    fn write(&self, characteristic: CoreCubeUuidName, bytes: &[u8]) -> bool {
+        match self.ble.write(characteristic, &bytes.to_vec()) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("{}", e);
+                eprintln!("{}: {}", self.label, e);
+                false
+            }
+        }
+    }
+}
+
+fn get_selection(matches: &ArgMatches) -> std::result::Result<Selection, String> {
+    if let Some(values) = matches.values_of("address") {
+        let addresses = values
+            .map(parse_ble_address)
+            .collect::<std::result::Result<Vec<u64>, String>>()?;
+        return Ok(Selection::Address(addresses));
+    }
+    if let Some(values) = matches.values_of("name") {
+        return Ok(Selection::Name(values.map(|x| x.to_string()).collect()));
+    }
+    if matches.is_present("all") {
+        return Ok(Selection::All);
+    }
+    Ok(Selection::First)
+}
+
+fn connect(selection: &Selection) -> std::result::Result<Vec<Cube>, String> {
+    let mut cubes: Vec<Cube> = Vec::new();
+    if let Selection::Address(addresses) = selection {
+        for address in addresses {
+            let label = format!("cube{}", cubes.len() + 1);
+            let mut ble = CoreCubeBLE::new(label.clone());
+            info!("connect to {}", format_ble_address(*address));
+            match ble.connect(*address) {
+                Ok(true) => (),
+                _ => {
+                    return Err(format!(
+                        "failed to connect to {}",
+                        format_ble_address(*address)
+                    ))
+                }
+            }
+            cubes.push(Cube {
+                label,
+                name: None,
+                address: Some(*address),
+                ble,
+                handlers: Vec::new(),
+            });
+        }
+    } else {
+        for (id, name) in get_ble_device_names()? {
+            let selected = match selection {
+                Selection::Name(names) => names.iter().any(|x| name.contains(x.as_str())),
+                _ => true,
+            };
+            if !selected {
+                continue;
+            }
+            let label = format!("cube{}", cubes.len() + 1);
+            let mut ble = CoreCubeBLE::new(label.clone());
+            info!("Searching cube: {} {}", name, id);
+            if let Ok(true) = ble.connect_ref_id(&id) {
+                cubes.push(Cube {
+                    label,
+                    name: Some(name),
+                    address: get_device_id_address(&id),
+                    ble,
+                    handlers: Vec::new(),
+                });
+                if let Selection::First = selection {
+                    break;
+                }
+            }
+        }
+    }
+    if cubes.is_empty() {
+        return Err("failed to connect".to_string());
+    }
+    for (index, cube) in cubes.iter_mut().enumerate() {
+        for characteristic in &NOTIFY_CHARACTERISTICS {
+            match cube
+                .ble
+                .register_notify(*characteristic, notify(index, *characteristic))
+            {
+                Ok(handler) => cube.handlers.push(handler),
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+    Ok(cubes)
+}
+
+fn disconnect(cubes: Vec<Cube>) {
+    for cube in cubes {
+        for handler in cube.handlers {
+            if let Err(e) = handler.unregister() {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+fn format_record(format: OutputFormat, label: Option<&str>, record: Record) -> String {
+    match (format, label) {
+        (OutputFormat::Human, Some(label)) => format!("{} {}", label, record.to_human()),
+        (OutputFormat::Json, Some(label)) => {
+            let mut record = record;
+            record
+                .fields
+                .insert(0, ("cube".to_string(), Value::from(label)));
+            record.to_json()
+        }
+        (format, None) => record.format(format),
+    }
+}
+
+fn print_record(format: OutputFormat, label: Option<&str>, record: Record) {
+    println!("{}", format_record(format, label, record));
+}
+
+// Decoded notification with the time from `start_time`
+fn format_notification(
+    cubes: &[Cube],
+    format: OutputFormat,
+    start_time: time::Instant,
+    notification: Notification,
+) -> String {
+    let (index, characteristic, time, data) = notification;
+    let seconds = time.saturating_duration_since(start_time).as_secs_f64();
+    let mut record = decode_notification(characteristic, &data);
+    let label = &cubes[index].label;
+    match format {
+        OutputFormat::Human => format!("{:>8.3} {} {}", seconds, label, record.to_human()),
+        OutputFormat::Json => {
+            let seconds = (seconds * 1000.0).round() / 1000.0;
+            record
+                .fields
+                .insert(0, ("time".to_string(), Value::from(seconds)));
+            format_record(format, Some(label), record)
+        }
+    }
+}
+
+// Wait for a notification of the cube which matches `condition` (None: timeout or Ctrl-C)
+fn wait_notification<F>(
+    index: usize,
+    timeout: time::Duration,
+    running: &AtomicBool,
+    condition: F,
+) -> Option<Vec<u8>>
+where
+    F: Fn(CoreCubeUuidName, &[u8]) -> bool,
+{
+    let start_time = time::Instant::now();
+    while start_time.elapsed() < timeout && running.load(Ordering::SeqCst) {
+        {
+            let mut notification = NOTIFICATION.lock().unwrap();
+            let found = notification
+                .iter()
+                .position(|(i, characteristic, _, data)| {
+                    *i == index && condition(*characteristic, data)
+                });
+            if let Some(position) = found {
+                return Some(notification.remove(position).3);
+            }
+        }
+        thread::sleep(time::Duration::from_millis(POLL_INTERVAL_MS));
+    }
+    None
+}
+
+// Sleep for `duration` in short slices: false when Ctrl-C is pressed
+fn sleep_while_running(duration: time::Duration, running: &AtomicBool) -> bool {
+    let start_time = time::Instant::now();
+    while running.load(Ordering::SeqCst) {
+        let remaining = duration.saturating_sub(start_time.elapsed());
+        if remaining.is_zero() {
+            return true;
+        }
+        thread::sleep(remaining.min(time::Duration::from_millis(POLL_INTERVAL_MS)));
+    }
+    false
+}
+
+fn stop_cubes(cubes: &[Cube]) {
+    for cube in cubes {
+        cube.write(
+            CoreCubeUuidName::MotorCtrl,
+            &MotorControl::stop().get_bytes(),
+        );
+    }
+}
+
+fn exit_with_error(message: &str) -> ! {
+    error!("{}", message);
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn parse_or_exit<T>(name: &str, result: std::result::Result<T, String>) -> T {
+    match result {
+        Ok(x) => x,
+        Err(e) => exit_with_error(&format!("{}: {}", name, e)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr + PartialOrd + std::fmt::Display>(
+    value: &str,
+    min: T,
+    max: T,
+) -> std::result::Result<T, String> {
+    match value.parse::<T>() {
+        Ok(x) if x >= min && x <= max => Ok(x),
+        _ => Err(format!("must be {} - {} (got \"{}\")", min, max, value)),
+    }
+}
+
+fn scan(format: OutputFormat) {
+    let devices = parse_or_exit("scan", get_ble_device_names());
+    if devices.is_empty() {
+        eprintln!("no paired cube");
+    }
+    for (id, name) in devices {
+        let address = get_device_id_address(&id).map_or("-".to_string(), format_ble_address);
+        print_record(
+            format,
+            None,
+            Record::new("cube")
+                .with("name", name)
+                .with("address", address)
+                .with("id", id),
+        );
+    }
+}
+
+fn show_info(cubes: &[Cube], format: OutputFormat, running: &AtomicBool) {
+    for (index, cube) in cubes.iter().enumerate() {
+        let mut record = Record::new("info")
+            .with("name", cube.name.clone().unwrap_or_else(|| "-".to_string()))
+            .with(
+                "address",
+                cube.address.map_or("-".to_string(), format_ble_address),
+            );
+        if let Ok(data) = cube.ble.read(CoreCubeUuidName::BatteryInfo) {
+            if let Some(level) = data.first() {
+                record = record.with("battery", *level as i64);
+            }
+        }
+        if cube.write(
+            CoreCubeUuidName::Configuration,
+            &get_protocol_version_request_bytes(),
+        ) {
+            let timeout = time::Duration::from_millis(RESPONSE_TIMEOUT_MS);
+            let response = wait_notification(index, timeout, running, |characteristic, data| {
+                characteristic == CoreCubeUuidName::Configuration
+                    && get_protocol_version(data).is_some()
+            });
+            if let Some(version) = response.as_deref().and_then(get_protocol_version) {
+                record = record.with("version", version);
+            }
+        }
+        print_record(format, Some(&cube.label), record);
+    }
+}
+
+fn move_cubes(
+    cubes: &[Cube],
+    format: OutputFormat,
+    left: i16,
+    right: i16,
+    duration: time::Duration,
+    running: &AtomicBool,
+) {
+    // the motor duration of the protocol is up to 2.55 seconds
+    let limited = !duration.is_zero() && duration.as_millis() <= 2550;
+    let mut control = MotorControl::new(left, right);
+    if limited {
+        control = control.with_duration(duration);
+    }
+    for cube in cubes {
+        if cube.write(CoreCubeUuidName::MotorCtrl, &control.get_bytes()) {
+            print_record(
+                format,
+                Some(&cube.label),
+                Record::new("move")
+                    .with("left", left as i64)
+                    .with("right", right as i64)
+                    .with("duration_ms", duration.as_millis() as i64),
+            );
+        }
+    }
+    let finished = sleep_while_running(duration, running);
+    if !limited || !finished {
+        stop_cubes(cubes);
+    }
+}
+
+fn goto(
+    cubes: &[Cube],
+    format: OutputFormat,
+    target: Waypoint,
+    max_speed: u8,
+    running: &AtomicBool,
+) {
+    let config = TargetMoveConfig {
+        max_speed,
+        ..TargetMoveConfig::default()
+    };
+    let bytes = get_target_bytes(1, &config, &target);
+    let written: Vec<bool> = cubes
+        .iter()
+        .map(|cube| cube.write(CoreCubeUuidName::MotorCtrl, &bytes))
+        .collect();
+    for (index, cube) in cubes.iter().enumerate() {
+        if !written[index] {
+            continue;
+        }
+        let timeout = time::Duration::from_millis(TARGET_TIMEOUT_MS);
+        let response = wait_notification(index, timeout, running, |characteristic, data| {
+            characteristic == CoreCubeUuidName::MotorCtrl
+                && get_target_move_response(data).is_some()
+        });
+        if !running.load(Ordering::SeqCst) {
+            stop_cubes(cubes);
+            return;
+        }
+        let record = match response {
+            Some(data) => decode_notification(CoreCubeUuidName::MotorCtrl, &data),
+            None => Record::new("target").with("result", "NoResponse"),
+        };
+        print_record(format, Some(&cube.label), record);
+    }
+}
+
+fn write_config(cubes: &[Cube], format: OutputFormat, settings: &[(String, Vec<u8>)]) {
+    for cube in cubes {
+        for (setting, bytes) in settings {
+            if cube.write(CoreCubeUuidName::Configuration, bytes) {
+                print_record(
+                    format,
+                    Some(&cube.label),
+                    Record::new("config").with("setting", setting.as_str()),
+                );
+            }
+        }
+    }
+}
+
+fn monitor(cubes: &[Cube], format: OutputFormat, running: &AtomicBool) {
+    let start_time = time::Instant::now();
+    while running.load(Ordering::SeqCst) {
+        let notification: Vec<Notification> = NOTIFICATION.lock().unwrap().drain(..).collect();
+        for x in notification {
+            println!("{}", format_notification(cubes, format, start_time, x));
+        }
+        thread::sleep(time::Duration::from_millis(POLL_INTERVAL_MS));
+    }
+}
+
+/// State of the interactive shell
+struct Shell<'a> {
+    cubes: &'a [Cube],
+    cube_names: Vec<String>,
+    format: OutputFormat,
+    /// Target of the commands without a cube name
+    target: ShellTarget,
+    watching: Vec<CoreCubeUuidName>,
+    start_time: time::Instant,
+}
+
+impl<'a> Shell<'a> {
+    fn new(cubes: &'a [Cube], format: OutputFormat) -> Self {
+        Self {
+            cubes,
+            cube_names: cubes.iter().map(|x| x.label.clone()).collect(),
+            format,
+            target: ShellTarget::All,
+            watching: Vec::new(),
+            start_time: time::Instant::now(),
+        }
+    }
+
+    fn get_indexes(&self, target: ShellTarget) -> Vec<usize> {
+        match target {
+            ShellTarget::Default => self.get_indexes(self.target),
+            ShellTarget::All => (0..self.cubes.len()).collect(),
+            ShellTarget::Cube(index) => vec![index],
+        }
+    }
+
+    /// Output lines of the watched notifications (the others are dropped)
+    fn get_notification_lines(&self) -> Vec<String> {
+        let notification: Vec<Notification> = NOTIFICATION.lock().unwrap().drain(..).collect();
+        notification
+            .into_iter()
+            .filter(|(_, characteristic, _, _)| self.watching.contains(characteristic))
+            .map(|x| format_notification(self.cubes, self.format, self.start_time, x))
+            .collect()
+    }
+
+    /// Run a command line: the output lines and whether to quit
+    fn run_line(&mut self, line: &str, history: &[String]) -> (Vec<String>, bool) {
+        let shell_line = match parse_shell_line(line, &self.cube_names) {
+            Ok(Some(x)) => x,
+            Ok(None) => return (Vec::new(), false),
+            Err(e) => return (vec![e], false),
+        };
+        let mut output: Vec<String> = Vec::new();
+        match shell_line.command {
+            ShellCommand::Write(commands) => {
+                for index in self.get_indexes(shell_line.target) {
+                    let cube = &self.cubes[index];
+                    for (characteristic, bytes) in &commands {
+                        let record = match cube.ble.write(*characteristic, bytes) {
+                            Ok(_) => Record::new("write")
+                                .with("characteristic", get_characteristic_name(*characteristic))
+                                .with("bytes", format_bytes(bytes)),
+                            Err(e) => Record::new("error").with("message", e),
+                        };
+                        output.push(format_record(self.format, Some(&cube.label), record));
+                    }
+                }
+            }
+            ShellCommand::Read(characteristic) => {
+                for index in self.get_indexes(shell_line.target) {
+                    let cube = &self.cubes[index];
+                    let record = match cube.ble.read(characteristic) {
+                        Ok(data) => decode_notification(characteristic, &data),
+                        Err(e) => Record::new("error").with("message", e),
+                    };
+                    output.push(format_record(self.format, Some(&cube.label), record));
+                }
+            }
+            ShellCommand::Watch(characteristics) => {
+                for characteristic in characteristics {
+                    if !self.watching.contains(&characteristic) {
+                        self.watching.push(characteristic);
+                    }
+                }
+                output.push(self.get_watching());
+            }
+            ShellCommand::Unwatch(characteristics) => {
+                self.watching.retain(|x| !characteristics.contains(x));
+                output.push(self.get_watching());
+            }
+            ShellCommand::Use => {
+                self.target = shell_line.target;
+                output.push(self.get_target());
+            }
+            ShellCommand::Cubes => {
+                for cube in self.cubes {
+                    let address = cube.address.map_or("-".to_string(), format_ble_address);
+                    let record = Record::new("cube")
+                        .with("name", cube.name.clone().unwrap_or_else(|| "-".to_string()))
+                        .with("address", address);
+                    output.push(format_record(self.format, Some(&cube.label), record));
+                }
+                output.push(self.get_target());
+            }
+            ShellCommand::History => {
+                for (i, x) in history.iter().enumerate() {
+                    output.push(format!("{:>4} {}", i + 1, x));
+                }
+            }
+            ShellCommand::Help => output.extend(SHELL_HELP.lines().map(|x| x.to_string())),
+            ShellCommand::Quit => return (output, true),
+        }
+        (output, false)
+    }
+
+    fn get_watching(&self) -> String {
+        let names: Vec<&str> = self
+            .watching
+            .iter()
+            .map(|x| get_characteristic_name(*x))
+            .collect();
+        format!("watching: {}", names.join(" "))
+    }
+
+    fn get_target(&self) -> String {
+        let names: Vec<&str> = self
+            .get_indexes(self.target)
+            .into_iter()
+            .map(|x| self.cubes[x].label.as_str())
+            .collect();
+        format!("using: {}", names.join(" "))
+    }
+}
+
+// Prompt and the line being edited
+fn draw_prompt(stdout: &mut std::io::Stdout, editor: &LineEditor) -> std::io::Result<()> {
+    let column = (SHELL_PROMPT.chars().count() + editor.get_cursor()) as u16;
+    queue!(
+        stdout,
+        cursor::MoveToColumn(0),
+        terminal::Clear(terminal::ClearType::CurrentLine),
+        style::Print(SHELL_PROMPT),
+        style::Print(editor.get_line()),
+        cursor::MoveToColumn(column)
+    )?;
+    stdout.flush()
+}
+
+// Print the lines above the prompt (raw mode needs CR LF)
+fn print_above(
+    stdout: &mut std::io::Stdout,
+    editor: &LineEditor,
+    lines: &[String],
+) -> std::io::Result<()> {
+    queue!(
+        stdout,
+        cursor::MoveToColumn(0),
+        terminal::Clear(terminal::ClearType::CurrentLine)
+    )?;
+    for line in lines {
+        queue!(stdout, style::Print(line), style::Print("\r\n"))?;
+    }
+    draw_prompt(stdout, editor)
+}
+
+// Complete the word before the cursor: the candidates when it is ambiguous
+fn complete_line(editor: &mut LineEditor, cube_names: &[String]) -> Vec<String> {
+    let head = editor.get_head();
+    let candidates = complete(&head, cube_names);
+    match candidates.as_slice() {
+        [] => Vec::new(),
+        [word] => {
+            editor.complete_word(word);
+            if !word.ends_with('=') {
+                editor.insert(' ');
+            }
+            Vec::new()
+        }
+        _ => {
+            let partial = head.rsplit(char::is_whitespace).next().unwrap_or("");
+            let prefix = get_common_prefix(&candidates);
+            if prefix.len() > partial.len() {
+                editor.complete_word(&prefix);
+                Vec::new()
+            } else {
+                vec![candidates.join("  ")]
+            }
+        }
+    }
+}
+
+fn run_shell_script(shell: &mut Shell) {
+    for line in std::io::stdin().lock().lines() {
+        let line = match line {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{}", e);
+                break;
+            }
+        };
+        let (output, quit) = shell.run_line(&line, &[]);
+        for x in output.iter().chain(shell.get_notification_lines().iter()) {
+            println!("{}", x);
+        }
+        if quit {
+            break;
+        }
+    }
+}
+
+/// Raw mode of the terminal until dropped (also on the errors of the shell)
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> std::io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Err(e) = terminal::disable_raw_mode() {
+            error!("{}", e);
+        }
+    }
+}
+
+fn run_shell(cubes: &[Cube], format: OutputFormat, running: &AtomicBool) -> std::io::Result<()> {
+    let mut shell = Shell::new(cubes, format);
+    if !std::io::stdin().is_terminal() {
+        run_shell_script(&mut shell);
+        return Ok(());
+    }
+    let mut stdout = std::io::stdout();
+    let mut editor = LineEditor::new();
+    println!("{} (help: list of commands)", shell.get_target());
+    let raw_mode = RawMode::enable()?;
+    draw_prompt(&mut stdout, &editor)?;
+    while running.load(Ordering::SeqCst) {
+        let lines = shell.get_notification_lines();
+        if !lines.is_empty() {
+            print_above(&mut stdout, &editor, &lines)?;
+        }
+        if !event::poll(time::Duration::from_millis(SHELL_POLL_INTERVAL_MS))? {
+            continue;
+        }
+        let key = match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let mut output: Vec<String> = Vec::new();
+        match key.code {
+            KeyCode::Char('c') if control => editor.set_line(""),
+            KeyCode::Char('d') if control && editor.get_line().is_empty() => break,
+            KeyCode::Char('a') if control => editor.move_home(),
+            KeyCode::Char('e') if control => editor.move_end(),
+            KeyCode::Char('u') if control => editor.set_line(""),
+            KeyCode::Char('w') if control => editor.delete_word(),
+            KeyCode::Char(c) if !control => editor.insert(c),
+            KeyCode::Enter => {
+                let line = editor.submit();
+                queue!(
+                    stdout,
+                    cursor::MoveToColumn(0),
+                    terminal::Clear(terminal::ClearType::CurrentLine),
+                    style::Print(SHELL_PROMPT),
+                    style::Print(&line),
+                    style::Print("\r\n")
+                )?;
+                let (lines, quit) = shell.run_line(&line, editor.get_history());
+                if quit {
+                    break;
+                }
+                output = lines;
+            }
+            KeyCode::Tab => output = complete_line(&mut editor, &shell.cube_names),
+            KeyCode::Backspace => editor.backspace(),
+            KeyCode::Delete => editor.delete(),
+            KeyCode::Left => editor.move_left(),
+            KeyCode::Right => editor.move_right(),
+            KeyCode::Home => editor.move_home(),
+            KeyCode::End => editor.move_end(),
+            KeyCode::Up => editor.previous(),
+            KeyCode::Down => editor.next(),
+            _ => (),
+        }
+        if output.is_empty() {
+            draw_prompt(&mut stdout, &editor)?;
+        } else {
+            print_above(&mut stdout, &editor, &output)?;
+        }
+    }
+    drop(raw_mode);
+    println!();
+    Ok(())
+}
+
+fn get_settings(matches: &ArgMatches, name: &str) -> Vec<(String, Vec<u8>)> {
+    matches
+        .values_of(name)
+        .map(|values| {
+            values
+                .map(|x| (x.to_string(), parse_or_exit(name, parse_config(x))))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn main() {
+    env_logger::init();
+
+    // options shared by the subcommands
+    let selection_args = [
+        Arg::with_name("address")
+            .help("BLE address of the cube (e0:12:34:56:78:9a, repeat for more cubes)")
+            .long("address")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .global(true),
+        Arg::with_name("name")
+            .help("paired cubes whose names contain NAME (repeat for more names)")
+            .long("name")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .global(true),
+        Arg::with_name("all")
+            .help("all paired cubes")
+            .long("all")
+            .global(true),
+        Arg::with_name("format")
+            .help("output format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["human", "json"])
+            .default_value("human")
+            .global(true),
+    ];
+
+    // Set command line options
+    let app = App::new("toio")
+        .version("0.0.1")
+        .about("Control toio core cubes from the command line")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .args(&selection_args)
+        .subcommand(SubCommand::with_name("scan").about("list the paired cubes"))
+        .subcommand(
+            SubCommand::with_name("info").about("show the battery level, BLE protocol version and address"),
+        )
+        .subcommand(
+            SubCommand::with_name("led")
+                .about("turn on the light")
+                .arg(
+                    Arg::with_name("color")
+                        .help("#ff0000, 255,0,0, red, off")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("duration")
+                        .help("light duration (500ms, 1.5s; default: until the next command)")
+                        .long("duration")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sound")
+                .about("play a sound effect or MML")
+                .arg(
+                    Arg::with_name("sound")
+                        .help("sound effect (enter, selected, cancel, cursor, mat_in, mat_out, get1 - get3, effect1, effect2 or 0 - 10), stop or MML (\"t120 o4 l8 cdefg\")")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("volume")
+                        .help("0 - 255")
+                        .long("volume")
+                        .takes_value(true)
+                        .default_value("255"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("move")
+                .about("drive the motors")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(
+                    Arg::with_name("left")
+                        .help("left motor speed -115 - 115")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("right")
+                        .help("right motor speed -115 - 115")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("duration")
+                        .help("duration (500ms, 1.5s)")
+                        .long("duration")
+                        .takes_value(true)
+                        .default_value("1s"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("goto")
+                .about("move to a position of the mat")
+                .arg(Arg::with_name("x").help("Position ID x").required(true))
+                .arg(Arg::with_name("y").help("Position ID y").required(true))
+                .arg(Arg::with_name("angle").help("angle at the target [deg]"))
+                .arg(
+                    Arg::with_name("speed")
+                        .help("maximum speed 10 - 115")
+                        .long("speed")
+                        .takes_value(true)
+                        .default_value("50"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("change the settings of the cube")
+                .arg(
+                    Arg::with_name("setting")
+                        .help("name=value (horizontal, collision, double_tap, posture_angle, motor_speed, id_missed)")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("monitor")
+                .about("print the notifications until Ctrl-C")
+                .arg(
+                    Arg::with_name("config")
+                        .help("setting written before monitoring (e.g. posture_angle=100)")
+                        .long("config")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("shell")
+                .about("interactive shell: type commands to the cubes and watch the notifications"),
+        );
+
+    // Parse arguments
+    let matches = app.get_matches();
+    let (command, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.unwrap();
+    let format = parse_or_exit(
+        "--format",
+        sub_matches
+            .value_of("format")
+            .unwrap()
+            .parse::<OutputFormat>(),
+    );
+    if command == "scan" {
+        scan(format);
+        return;
+    }
+
+    // check the arguments before connecting
+    let selection = parse_or_exit("--address", get_selection(sub_matches));
+    let mut settings: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut light: Option<Vec<u8>> = None;
+    let mut sound: Option<Vec<u8>> = None;
+    let mut motor: Option<(i16, i16, time::Duration)> = None;
+    let mut target: Option<(Waypoint, u8)> = None;
+    match command {
+        "led" => {
+            let color = parse_or_exit("color", parse_color(sub_matches.value_of("color").unwrap()));
+            let duration = sub_matches
+                .value_of("duration")
+                .map(|x| parse_or_exit("--duration", parse_millis(x)));
+            light = Some(get_light_bytes(color, duration));
+        }
+        "sound" => {
+            let command =
+                parse_or_exit("sound", parse_sound(sub_matches.value_of("sound").unwrap()));
+            let volume = parse_or_exit(
+                "--volume",
+                parse_number::<u8>(sub_matches.value_of("volume").unwrap(), 0, 255),
+            );
+            sound = Some(command.get_bytes(volume));
+        }
+        "move" => {
+            let speed = |name: &str| {
+                parse_or_exit(
+                    name,
+                    parse_number::<i16>(sub_matches.value_of(name).unwrap(), -115, 115),
+                )
+            };
+            let duration = parse_or_exit(
+                "--duration",
+                parse_move_duration(sub_matches.value_of("duration").unwrap()),
+            );
+            motor = Some((speed("left"), speed("right"), duration));
+        }
+        "goto" => {
+            let position = |name: &str| {
+                parse_or_exit(
+                    name,
+                    parse_number::<u16>(sub_matches.value_of(name).unwrap(), 0, 65534),
+                )
+            };
+            let angle = sub_matches
+                .value_of("angle")
+                .map(|x| parse_or_exit("angle", parse_number::<f32>(x, -360.0, 360.0)));
+            let speed = parse_or_exit(
+                "--speed",
+                parse_number::<u8>(sub_matches.value_of("speed").unwrap(), 10, 115),
+            );
+            target = Some((Waypoint::new(position("x"), position("y"), angle), speed));
+        }
+        "config" => settings = get_settings(sub_matches, "setting"),
+        "monitor" => settings = get_settings(sub_matches, "config"),
+        _ => (),
+    }
+
+    // connect
+    let cubes = match connect(&selection) {
+        Ok(x) => x,
+        Err(e) => exit_with_error(&e),
+    };
+
+    // Register Ctrl-C handler
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    match command {
+        "info" => show_info(&cubes, format, &running),
+        "led" | "sound" => {
+            let (characteristic, bytes) = match (light, sound) {
+                (Some(bytes), _) => (CoreCubeUuidName::LightCtrl, bytes),
+                (_, Some(bytes)) => (CoreCubeUuidName::SoundCtrl, bytes),
+                _ => unreachable!(),
+            };
+            for cube in &cubes {
+                if cube.write(characteristic, &bytes) {
+                    print_record(
+                        format,
+                        Some(&cube.label),
+                        Record::new(command).with("bytes", format_bytes(&bytes)),
+                    );
+                }
+            }
+        }
+        "move" => {
+            let (left, right, duration) = motor.unwrap();
+            move_cubes(&cubes, format, left, right, duration, &running);
+        }
+        "goto" => {
+            let (waypoint, speed) = target.unwrap();
+            goto(&cubes, format, waypoint, speed, &running);
+        }
+        "config" => write_config(&cubes, format, &settings),
+        "monitor" => {
+            write_config(&cubes, format, &settings);
+            monitor(&cubes, format, &running);
+        }
+        "shell" => {
+            if let Err(e) = run_shell(&cubes, format, &running) {
+                error!("{}", e);
+                eprintln!("{}", e);
+            }
+        }
+        _ => (),
+    }
+
+    disconnect(cubes);
+}
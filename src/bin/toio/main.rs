@@ -0,0 +1,19 @@
+/* toio: command line tool for toio core cubes */
+
+#[cfg_attr(not(windows), allow(dead_code))]
+mod cli;
+#[cfg(windows)]
+mod commands;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod repl;
+
+#[cfg(windows)]
+fn main() {
+    commands::main();
+}
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("toio: connecting to the cubes is supported only on Windows");
+    std::process::exit(1);
+}
@@ -0,0 +1,609 @@
+/* Interactive shell: command lines, completion and line editing */
+
+use std::time;
+
+use crate::cli::*;
+use core_cube::light::get_light_bytes;
+use core_cube::motor::*;
+use core_cube::transport::CoreCubeUuidName;
+
+pub const SHELL_COMMANDS: [&str; 15] = [
+    "move", "stop", "goto", "led", "sound", "raw", "read", "config", "watch", "unwatch", "use",
+    "cubes", "history", "help", "quit",
+];
+
+/// Name of the target of all the cubes
+pub const ALL_CUBES: &str = "all";
+
+/// Characteristics by their names in the shell
+pub const CHARACTERISTIC_NAMES: [(&str, CoreCubeUuidName); 8] = [
+    ("id", CoreCubeUuidName::IdInfo),
+    ("sensor", CoreCubeUuidName::SensorInfo),
+    ("button", CoreCubeUuidName::ButtonInfo),
+    ("battery", CoreCubeUuidName::BatteryInfo),
+    ("motor", CoreCubeUuidName::MotorCtrl),
+    ("light", CoreCubeUuidName::LightCtrl),
+    ("sound", CoreCubeUuidName::SoundCtrl),
+    ("config", CoreCubeUuidName::Configuration),
+];
+
+/// Characteristics which have notifications
+pub const NOTIFY_CHARACTERISTICS: [CoreCubeUuidName; 6] = [
+    CoreCubeUuidName::IdInfo,
+    CoreCubeUuidName::SensorInfo,
+    CoreCubeUuidName::ButtonInfo,
+    CoreCubeUuidName::BatteryInfo,
+    CoreCubeUuidName::MotorCtrl,
+    CoreCubeUuidName::Configuration,
+];
+
+pub fn parse_characteristic(name: &str) -> std::result::Result<CoreCubeUuidName, String> {
+    CHARACTERISTIC_NAMES
+        .iter()
+        .find(|(x, _)| *x == name)
+        .map(|(_, x)| *x)
+        .ok_or_else(|| {
+            let names: Vec<&str> = CHARACTERISTIC_NAMES.iter().map(|(x, _)| *x).collect();
+            format!("unknown characteristic \"{}\" ({})", name, names.join(", "))
+        })
+}
+
+pub fn get_characteristic_name(characteristic: CoreCubeUuidName) -> &'static str {
+    CHARACTERISTIC_NAMES
+        .iter()
+        .find(|(_, x)| *x == characteristic)
+        .map_or("service", |(x, _)| *x)
+}
+
+/// "02 01 ff" or "0x02 0x01 0xff"
+pub fn parse_hex_bytes(words: &[&str]) -> std::result::Result<Vec<u8>, String> {
+    if words.is_empty() {
+        return Err("no bytes".to_string());
+    }
+    words
+        .iter()
+        .map(|x| {
+            let hex = x.strip_prefix("0x").unwrap_or(x);
+            match hex.len() {
+                1 | 2 => u8::from_str_radix(hex, 16).map_err(|_| format!("invalid byte \"{}\"", x)),
+                _ => Err(format!("invalid byte \"{}\"", x)),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShellTarget {
+    /// The cubes selected by `use`
+    Default,
+    All,
+    Cube(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShellCommand {
+    Write(Vec<(CoreCubeUuidName, Vec<u8>)>),
+    Read(CoreCubeUuidName),
+    Watch(Vec<CoreCubeUuidName>),
+    Unwatch(Vec<CoreCubeUuidName>),
+    /// Select the target of the commands without a cube name
+    Use,
+    Cubes,
+    History,
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellLine {
+    pub target: ShellTarget,
+    pub command: ShellCommand,
+}
+
+fn find_target(word: &str, cube_names: &[String]) -> Option<ShellTarget> {
+    if word == ALL_CUBES {
+        return Some(ShellTarget::All);
+    }
+    cube_names
+        .iter()
+        .position(|x| x == word)
+        .map(ShellTarget::Cube)
+}
+
+fn parse_value<T: std::str::FromStr>(command: &str, word: &str) -> std::result::Result<T, String> {
+    word.parse::<T>()
+        .map_err(|_| format!("{}: invalid value \"{}\"", command, word))
+}
+
+// "all" or characteristic names which have notifications
+fn parse_watch(words: &[&str]) -> std::result::Result<Vec<CoreCubeUuidName>, String> {
+    if words.is_empty() || words == [ALL_CUBES] {
+        return Ok(NOTIFY_CHARACTERISTICS.to_vec());
+    }
+    words
+        .iter()
+        .map(|x| {
+            let characteristic = parse_characteristic(x)?;
+            if NOTIFY_CHARACTERISTICS.contains(&characteristic) {
+                Ok(characteristic)
+            } else {
+                Err(format!("\"{}\" has no notification", x))
+            }
+        })
+        .collect()
+}
+
+fn parse_command(words: &[&str]) -> std::result::Result<ShellCommand, String> {
+    let (command, args) = (words[0], &words[1..]);
+    let write = |characteristic: CoreCubeUuidName, bytes: Vec<u8>| {
+        Ok(ShellCommand::Write(vec![(characteristic, bytes)]))
+    };
+    match command {
+        "move" => {
+            if args.len() < 2 || args.len() > 3 {
+                return Err("usage: move LEFT RIGHT [DURATION]".to_string());
+            }
+            let speed = |i: usize| match args[i].parse::<i16>() {
+                Ok(x) if (-115..=115).contains(&x) => Ok(x),
+                _ => Err(format!(
+                    "move: speed must be -115 - 115 (got \"{}\")",
+                    args[i]
+                )),
+            };
+            let mut control = MotorControl::new(speed(0)?, speed(1)?);
+            if let Some(duration) = args.get(2) {
+                let duration = parse_millis(duration).map_err(|e| format!("move: {}", e))?;
+                if duration > time::Duration::from_millis(2550) {
+                    return Err("move: duration must be up to 2.55s (none: until stop)".to_string());
+                }
+                control = control.with_duration(duration);
+            }
+            write(CoreCubeUuidName::MotorCtrl, control.get_bytes())
+        }
+        "stop" => write(
+            CoreCubeUuidName::MotorCtrl,
+            MotorControl::stop().get_bytes(),
+        ),
+        "goto" => {
+            if args.len() < 2 || args.len() > 3 {
+                return Err("usage: goto X Y [ANGLE]".to_string());
+            }
+            let x = parse_value::<u16>("goto", args[0])?;
+            let y = parse_value::<u16>("goto", args[1])?;
+            let angle = match args.get(2) {
+                Some(x) => Some(parse_value::<f32>("goto", x)?),
+                None => None,
+            };
+            let target = Waypoint::new(x, y, angle);
+            write(
+                CoreCubeUuidName::MotorCtrl,
+                get_target_bytes(1, &TargetMoveConfig::default(), &target),
+            )
+        }
+        "led" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err("usage: led COLOR [DURATION]".to_string());
+            }
+            let color = parse_color(args[0])?;
+            let duration = match args.get(1) {
+                Some(x) => Some(parse_millis(x)?),
+                None => None,
+            };
+            write(
+                CoreCubeUuidName::LightCtrl,
+                get_light_bytes(color, duration),
+            )
+        }
+        "sound" => {
+            if args.is_empty() {
+                return Err("usage: sound EFFECT|stop|MML".to_string());
+            }
+            let sound = parse_sound(&args.join(" "))?;
+            write(CoreCubeUuidName::SoundCtrl, sound.get_bytes(0xff))
+        }
+        "raw" => {
+            if args.is_empty() {
+                return Err("usage: raw CHARACTERISTIC BYTES...".to_string());
+            }
+            write(parse_characteristic(args[0])?, parse_hex_bytes(&args[1..])?)
+        }
+        "read" => match args {
+            [name] => Ok(ShellCommand::Read(parse_characteristic(name)?)),
+            _ => Err("usage: read CHARACTERISTIC".to_string()),
+        },
+        "config" => {
+            if args.is_empty() {
+                return Err("usage: config NAME=VALUE...".to_string());
+            }
+            let settings = args
+                .iter()
+                .map(|x| parse_config(x).map(|bytes| (CoreCubeUuidName::Configuration, bytes)))
+                .collect::<std::result::Result<Vec<(CoreCubeUuidName, Vec<u8>)>, String>>()?;
+            Ok(ShellCommand::Write(settings))
+        }
+        "watch" => Ok(ShellCommand::Watch(parse_watch(args)?)),
+        "unwatch" => Ok(ShellCommand::Unwatch(parse_watch(args)?)),
+        "cubes" => Ok(ShellCommand::Cubes),
+        "history" => Ok(ShellCommand::History),
+        "help" => Ok(ShellCommand::Help),
+        "quit" | "exit" => Ok(ShellCommand::Quit),
+        _ => Err(format!(
+            "unknown command \"{}\" (help: list of commands)",
+            command
+        )),
+    }
+}
+
+/// Command line: `[CUBE|all] COMMAND ARGS...` (None: empty line or comment)
+pub fn parse_shell_line(
+    line: &str,
+    cube_names: &[String],
+) -> std::result::Result<Option<ShellLine>, String> {
+    // "#" starts a comment only at the beginning ("led #ff0000")
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() || words[0].starts_with('#') {
+        return Ok(None);
+    }
+    parse_words(&words, cube_names)
+}
+
+fn parse_words(
+    words: &[&str],
+    cube_names: &[String],
+) -> std::result::Result<Option<ShellLine>, String> {
+    if words[0] == "use" {
+        let target = match words {
+            [_, name] => {
+                find_target(name, cube_names).ok_or_else(|| format!("unknown cube \"{}\"", name))?
+            }
+            _ => return Err("usage: use CUBE|all".to_string()),
+        };
+        return Ok(Some(ShellLine {
+            target,
+            command: ShellCommand::Use,
+        }));
+    }
+    let (target, words) = match find_target(words[0], cube_names) {
+        Some(target) => (target, &words[1..]),
+        None => (ShellTarget::Default, words),
+    };
+    if words.is_empty() {
+        return Err("no command".to_string());
+    }
+    Ok(Some(ShellLine {
+        target,
+        command: parse_command(words)?,
+    }))
+}
+
+/// Candidates of the word before the cursor (`line` is the text before the cursor)
+pub fn complete(line: &str, cube_names: &[String]) -> Vec<String> {
+    let mut words: Vec<&str> = line.split_whitespace().collect();
+    let partial = if line.ends_with(char::is_whitespace) || line.is_empty() {
+        ""
+    } else {
+        words.pop().unwrap_or("")
+    };
+    let targets = || {
+        let mut names: Vec<String> = cube_names.to_vec();
+        names.push(ALL_CUBES.to_string());
+        names
+    };
+    if matches!(words.first(), Some(x) if find_target(x, cube_names).is_some()) {
+        words.remove(0);
+    } else if words.is_empty() {
+        let mut candidates = targets();
+        candidates.extend(SHELL_COMMANDS.iter().map(|x| x.to_string()));
+        return filter_candidates(candidates, partial);
+    }
+    let candidates: Vec<String> = match (words.first(), words.len()) {
+        (None, _) => SHELL_COMMANDS
+            .iter()
+            .filter(|x| **x != "use")
+            .map(|x| x.to_string())
+            .collect(),
+        (Some(&"raw"), 1) | (Some(&"read"), 1) => CHARACTERISTIC_NAMES
+            .iter()
+            .map(|(x, _)| x.to_string())
+            .collect(),
+        (Some(&"watch"), _) | (Some(&"unwatch"), _) => {
+            let mut names: Vec<String> = NOTIFY_CHARACTERISTICS
+                .iter()
+                .map(|x| get_characteristic_name(*x).to_string())
+                .collect();
+            names.push(ALL_CUBES.to_string());
+            names
+        }
+        (Some(&"led"), 1) => COLOR_NAMES.iter().map(|(x, _)| x.to_string()).collect(),
+        (Some(&"sound"), 1) => {
+            let mut names: Vec<String> = SOUND_EFFECTS.iter().map(|x| x.to_string()).collect();
+            names.push("stop".to_string());
+            names
+        }
+        (Some(&"config"), _) => CONFIG_NAMES.iter().map(|x| format!("{}=", x)).collect(),
+        (Some(&"use"), 1) => targets(),
+        _ => Vec::new(),
+    };
+    filter_candidates(candidates, partial)
+}
+
+fn filter_candidates(candidates: Vec<String>, partial: &str) -> Vec<String> {
+    candidates
+        .into_iter()
+        .filter(|x| x.starts_with(partial))
+        .collect()
+}
+
+/// Longest common prefix of the candidates
+pub fn get_common_prefix(candidates: &[String]) -> String {
+    let mut prefix: Vec<char> = match candidates.first() {
+        Some(x) => x.chars().collect(),
+        None => return String::new(),
+    };
+    for candidate in &candidates[1..] {
+        let length = prefix
+            .iter()
+            .zip(candidate.chars())
+            .take_while(|(a, b)| *a == b)
+            .count();
+        prefix.truncate(length);
+    }
+    prefix.into_iter().collect()
+}
+
+/// Line buffer with the cursor and the history of the entered lines
+#[derive(Debug, Clone, Default)]
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    /// Position in the history while browsing it (None: the line being edited)
+    history_index: Option<usize>,
+    /// The line being edited while browsing the history
+    editing: String,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    /// Text before the cursor
+    pub fn get_head(&self) -> String {
+        self.buffer[..self.cursor].iter().collect()
+    }
+
+    /// Cursor position in characters
+    pub fn get_cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn get_history(&self) -> &[String] {
+        &self.history
+    }
+
+    pub fn set_line(&mut self, line: &str) {
+        self.buffer = line.chars().collect();
+        self.cursor = self.buffer.len();
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.buffer.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn insert_str(&mut self, text: &str) {
+        for c in text.chars() {
+            self.insert(c);
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.buffer.len() {
+            self.buffer.remove(self.cursor);
+        }
+    }
+
+    /// Delete the word before the cursor
+    pub fn delete_word(&mut self) {
+        while self.cursor > 0 && self.buffer[self.cursor - 1].is_whitespace() {
+            self.backspace();
+        }
+        while self.cursor > 0 && !self.buffer[self.cursor - 1].is_whitespace() {
+            self.backspace();
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.buffer.len());
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.buffer.len();
+    }
+
+    /// Replace the word before the cursor with the completed word
+    pub fn complete_word(&mut self, word: &str) {
+        while self.cursor > 0 && !self.buffer[self.cursor - 1].is_whitespace() {
+            self.backspace();
+        }
+        self.insert_str(word);
+    }
+
+    /// Previous line of the history
+    pub fn previous(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(x) => x - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.editing = self.get_line();
+                self.history.len() - 1
+            }
+        };
+        self.history_index = Some(index);
+        let line = self.history[index].clone();
+        self.set_line(&line);
+    }
+
+    /// Next line of the history (the line being edited after the last)
+    pub fn next(&mut self) {
+        match self.history_index {
+            Some(x) if x + 1 < self.history.len() => {
+                self.history_index = Some(x + 1);
+                let line = self.history[x + 1].clone();
+                self.set_line(&line);
+            }
+            Some(_) => {
+                self.history_index = None;
+                let line = std::mem::take(&mut self.editing);
+                self.set_line(&line);
+            }
+            None => (),
+        }
+    }
+
+    /// Take the line and add it to the history
+    pub fn submit(&mut self) -> String {
+        let line = self.get_line();
+        let trimmed = line.trim();
+        if !trimmed.is_empty() && self.history.last().map(|x| x.as_str()) != Some(trimmed) {
+            self.history.push(trimmed.to_string());
+        }
+        self.buffer.clear();
+        self.cursor = 0;
+        self.history_index = None;
+        self.editing.clear();
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        vec!["cube1".to_string(), "cube2".to_string()]
+    }
+
+    #[test]
+    fn shell_lines() {
+        let names = names();
+        assert_eq!(
+            parse_shell_line("cube1 move 50 -50 500ms", &names),
+            Ok(Some(ShellLine {
+                target: ShellTarget::Cube(0),
+                command: ShellCommand::Write(vec![(
+                    CoreCubeUuidName::MotorCtrl,
+                    vec![0x02, 0x01, 0x01, 50, 0x02, 0x02, 50, 50]
+                )]),
+            }))
+        );
+        assert_eq!(
+            parse_shell_line("all led #ff0000", &names),
+            Ok(Some(ShellLine {
+                target: ShellTarget::All,
+                command: ShellCommand::Write(vec![(
+                    CoreCubeUuidName::LightCtrl,
+                    vec![0x03, 0x00, 0x01, 0x01, 0xff, 0x00, 0x00]
+                )]),
+            }))
+        );
+        assert_eq!(
+            parse_shell_line("raw motor 02 01 01 64 02 02 64 ff", &names),
+            Ok(Some(ShellLine {
+                target: ShellTarget::Default,
+                command: ShellCommand::Write(vec![(
+                    CoreCubeUuidName::MotorCtrl,
+                    vec![0x02, 0x01, 0x01, 0x64, 0x02, 0x02, 0x64, 0xff]
+                )]),
+            }))
+        );
+        assert_eq!(
+            parse_shell_line("watch sensor", &names)
+                .unwrap()
+                .unwrap()
+                .command,
+            ShellCommand::Watch(vec![CoreCubeUuidName::SensorInfo])
+        );
+        assert_eq!(
+            parse_shell_line("use cube2", &names)
+                .unwrap()
+                .unwrap()
+                .target,
+            ShellTarget::Cube(1)
+        );
+        assert_eq!(parse_shell_line("  ", &names), Ok(None));
+        assert_eq!(parse_shell_line("# comment", &names), Ok(None));
+        assert_eq!(
+            parse_shell_line("cube3 stop", &names),
+            Err("unknown command \"cube3\" (help: list of commands)".to_string())
+        );
+        assert!(parse_shell_line("move 50 50 3s", &names).is_err());
+        assert!(parse_shell_line("watch light", &names).is_err());
+        assert!(parse_shell_line("raw motor 123", &names).is_err());
+        assert!(parse_shell_line("cube1", &names).is_err());
+    }
+
+    #[test]
+    fn completion() {
+        let names = names();
+        assert_eq!(complete("cu", &names), vec!["cube1", "cube2", "cubes"]);
+        assert_eq!(get_common_prefix(&complete("cu", &names)), "cube");
+        assert_eq!(complete("cube1 mo", &names), vec!["move"]);
+        assert_eq!(complete("raw m", &names), vec!["motor"]);
+        assert_eq!(complete("all watch s", &names), vec!["sensor"]);
+        assert_eq!(complete("config col", &names), vec!["collision="]);
+        assert_eq!(complete("use ", &names), vec!["cube1", "cube2", "all"]);
+        assert!(complete("move 5", &names).is_empty());
+    }
+
+    #[test]
+    fn line_editor() {
+        let mut editor = LineEditor::new();
+        editor.insert_str("led red");
+        editor.move_home();
+        editor.delete();
+        editor.insert('L');
+        assert_eq!(editor.get_line(), "Led red");
+        editor.move_end();
+        editor.delete_word();
+        editor.complete_word("blue");
+        assert_eq!(editor.submit(), "Led blue");
+        editor.insert_str("stop");
+        editor.submit();
+        editor.insert_str("stop");
+        editor.submit();
+        assert_eq!(editor.get_history(), &["Led blue", "stop"]);
+
+        editor.insert_str("mo");
+        editor.previous();
+        assert_eq!(editor.get_line(), "stop");
+        editor.previous();
+        editor.previous();
+        assert_eq!(editor.get_line(), "Led blue");
+        editor.next();
+        editor.next();
+        assert_eq!(editor.get_line(), "mo");
+        assert_eq!(editor.get_head(), "mo");
+    }
+}
@@ -0,0 +1,396 @@
+use clap::{App, Arg};
+use core_cube::mat::*;
+use core_cube::monitor::*;
+use core_cube::motor::{get_motor_speed_config_bytes, MotorControl};
+use core_cube::sensor::get_posture_angle_config_bytes;
+use core_cube::show::parse_ble_address;
+use core_cube::simulator::*;
+use core_cube::transport::{self, CoreCubeNotifyHandlerFunction, CoreCubeUuidName};
+#[cfg(windows)]
+use core_cube::win10::*;
+use lazy_static::lazy_static;
+use log::error;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table};
+use ratatui::Frame;
+use std::sync::Mutex;
+use std::time;
+
+// characteristics shown on the dashboard
+const NOTIFY_CHARACTERISTICS: [CoreCubeUuidName; 5] = [
+    CoreCubeUuidName::IdInfo,
+    CoreCubeUuidName::SensorInfo,
+    CoreCubeUuidName::ButtonInfo,
+    CoreCubeUuidName::BatteryInfo,
+    CoreCubeUuidName::MotorCtrl,
+];
+
+const DRAW_INTERVAL_MS: u64 = 100;
+// motor command of the arrow keys (repeated while the key is held)
+const DRIVE_SPEED: i16 = 40;
+const DRIVE_DURATION_MS: u64 = 200;
+
+lazy_static! {
+    static ref STATUS: Mutex<Vec<CubeStatus>> = Mutex::new(Vec::new());
+}
+
+fn notify(index: usize, characteristic: CoreCubeUuidName) -> CoreCubeNotifyHandlerFunction {
+    Box::new(move |data: Vec<u8>| {
+        STATUS.lock().unwrap()[index].update(characteristic, &data, time::Instant::now());
+    })
+}
+
+// Initial status and the notifications which are disabled by default
+fn setup(index: usize, cube: &dyn transport::CoreCubeTransport) {
+    let now = time::Instant::now();
+    for characteristic in &[CoreCubeUuidName::BatteryInfo, CoreCubeUuidName::SensorInfo] {
+        if let Ok(data) = cube.read(*characteristic) {
+            STATUS.lock().unwrap()[index].update(*characteristic, &data, now);
+        }
+    }
+    for bytes in &[
+        get_posture_angle_config_bytes(100),
+        get_motor_speed_config_bytes(true),
+    ] {
+        if let Err(e) = cube.write(CoreCubeUuidName::Configuration, bytes) {
+            error!("{}", e);
+        }
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "-"
+    }
+}
+
+fn draw(frame: &mut Frame, selected: usize) {
+    let now = time::Instant::now();
+    let status = STATUS.lock().unwrap();
+    let [table_area, main_area, help_area] = Layout::vertical([
+        Constraint::Length(status.len() as u16 + 3),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    // cube list
+    let header = Row::new(vec![
+        "#", "name", "state", "battery", "button", "posture", "position", "motor", "command",
+    ])
+    .style(Style::default().add_modifier(Modifier::BOLD));
+    let rows = status.iter().enumerate().map(|(i, cube)| {
+        let position = match (cube.position, cube.on_mat) {
+            (Some(p), true) => format!("({}, {}) {}", p.cube_x, p.cube_y, p.cube_angle),
+            (Some(p), false) => format!("({}, {}) off", p.cube_x, p.cube_y),
+            (None, _) => "-".to_string(),
+        };
+        let row = Row::new(vec![
+            Cell::from((i + 1).to_string()),
+            Cell::from(cube.name.clone()),
+            Cell::from(cube.get_connection_state(now).to_string()),
+            Cell::from(cube.battery.map_or("-".to_string(), |x| format!("{}%", x))),
+            Cell::from(match cube.button_pressed {
+                Some(true) => "pressed",
+                Some(false) => "released",
+                None => "-",
+            }),
+            Cell::from(
+                cube.motion
+                    .map_or("-".to_string(), |x| x.posture.to_string()),
+            ),
+            Cell::from(position),
+            Cell::from(
+                cube.motor_speed
+                    .map_or("-".to_string(), |(l, r)| format!("{} {}", l, r)),
+            ),
+            Cell::from(
+                cube.last_command
+                    .as_ref()
+                    .map_or("-".to_string(), |(time, label)| {
+                        format!("{} ({:.1}s ago)", label, (now - *time).as_secs_f32())
+                    }),
+            ),
+        ]);
+        if i == selected {
+            row.style(Style::default().add_modifier(Modifier::REVERSED))
+        } else {
+            row
+        }
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(2),
+            Constraint::Length(10),
+            Constraint::Length(11),
+            Constraint::Length(7),
+            Constraint::Length(8),
+            Constraint::Length(11),
+            Constraint::Length(18),
+            Constraint::Length(7),
+            Constraint::Min(10),
+        ],
+    )
+    .header(header)
+    .block(Block::bordered().title(" cubes "));
+    frame.render_widget(table, table_area);
+
+    // map of the mat of the selected cube and the details
+    let [map_area, detail_area] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .areas(main_area);
+    let cube = &status[selected];
+    let (map, title) = match cube.mat {
+        Some(mat) => {
+            let marks: Vec<(char, &core_cube::id_info::PositionId)> = status
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| x.on_mat && x.mat.map(|m| m.mat_type) == Some(mat.mat_type))
+                .filter_map(|(i, x)| {
+                    let mark = std::char::from_digit((i + 1) as u32 % 36, 36).unwrap_or('*');
+                    x.position.as_ref().map(|p| (mark, p))
+                })
+                .collect();
+            let columns = map_area.width.saturating_sub(4) as usize;
+            let rows = map_area.height.saturating_sub(4) as usize;
+            (
+                render_mat_map(&mat, &marks, columns, rows).join("\n"),
+                format!(" {} ", mat.mat_type),
+            )
+        }
+        None => ("no mat".to_string(), " mat ".to_string()),
+    };
+    frame.render_widget(
+        Paragraph::new(map).block(Block::bordered().title(title)),
+        map_area,
+    );
+
+    let mut details = vec![format!("name       {}", cube.name)];
+    if let Some(angle) = cube.posture_angle {
+        details.push(format!(
+            "roll/pitch/yaw {} / {} / {}",
+            angle.roll, angle.pitch, angle.yaw
+        ));
+    }
+    if let Some(motion) = cube.motion {
+        details.push(format!("horizontal {}", on_off(motion.horizontal)));
+        details.push(format!("collision  {}", on_off(motion.collision)));
+        details.push(format!("double tap {}", on_off(motion.double_tap)));
+        details.push(format!("shake      {}", motion.shake));
+    }
+    if let Some(result) = cube.target_result {
+        details.push(format!("target     {:?}", result));
+    }
+    if let Some(last) = cube.last_update {
+        details.push(format!("updated    {:.1}s ago", (now - last).as_secs_f32()));
+    }
+    frame.render_widget(
+        Paragraph::new(details.join("\n")).block(Block::bordered().title(" details ")),
+        detail_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new("Tab: select cube  arrows: drive  Space: stop  q: quit"),
+        help_area,
+    );
+}
+
+fn drive(index: usize, cube: &dyn transport::CoreCubeTransport, left: i16, right: i16) {
+    let mut control = MotorControl::new(left, right);
+    if left != 0 || right != 0 {
+        control = control.with_duration(time::Duration::from_millis(DRIVE_DURATION_MS));
+    }
+    let bytes = control.get_bytes();
+    let now = time::Instant::now();
+    // write without holding STATUS: the notification handlers lock it too
+    let result = cube.write(CoreCubeUuidName::MotorCtrl, &bytes);
+    let mut status = STATUS.lock().unwrap();
+    match result {
+        Ok(_) => status[index].set_command(CoreCubeUuidName::MotorCtrl, &bytes, now),
+        Err(e) => {
+            error!("{}", e);
+            status[index].set_connected(false, now);
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    // Set command line options
+    let app = App::new("toio_dashboard")
+        .version("0.0.1")
+        .about("Show the status of toio core cubes")
+        .arg(
+            Arg::with_name("address")
+                .help("BLE address of the cube (e0:12:34:56:78:9a)")
+                .long("address")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("count")
+                .help("number of paired cubes to connect")
+                .long("count")
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .help("show simulated cubes")
+                .long("simulate")
+                .value_name("COUNT")
+                .takes_value(true)
+                .conflicts_with_all(&["address", "count"]),
+        );
+
+    // Parse arguments
+    let matches = app.get_matches();
+    let parse_count = |name: &str| -> usize {
+        let value = matches.value_of(name).unwrap();
+        match value.parse::<usize>() {
+            Ok(x) if x > 0 => x,
+            _ => {
+                eprintln!("--{}: invalid count \"{}\"", name, value);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    if matches.is_present("simulate") {
+        let count = parse_count("simulate");
+        let mat = get_mat(MatType::ToioCollectionRing);
+        let mut cubes: Vec<Box<dyn transport::CoreCubeTransport>> = Vec::new();
+        let mut runners: Vec<SimulatorRunner> = Vec::new();
+        for i in 0..count {
+            let mut config = SimulatorConfig::on_mat(mat);
+            config.pose.x += (i as f32 - (count - 1) as f32 / 2.0) * 60.0;
+            let cube = SimulatedCube::new(config);
+            STATUS
+                .lock()
+                .unwrap()
+                .push(CubeStatus::new(&format!("sim{}", i + 1)));
+            for characteristic in &NOTIFY_CHARACTERISTICS {
+                cube.register_notify(*characteristic, notify(i, *characteristic));
+            }
+            STATUS.lock().unwrap()[i].set_connected(true, time::Instant::now());
+            runners.push(cube.spawn());
+            cubes.push(Box::new(cube));
+        }
+        run_dashboard(&cubes);
+        for mut runner in runners {
+            runner.stop();
+        }
+    } else {
+        let addresses: Vec<Option<u64>> = match matches.values_of("address") {
+            Some(values) => values
+                .map(|x| match parse_ble_address(x) {
+                    Ok(address) => Some(address),
+                    Err(e) => {
+                        eprintln!("--address: {}", e);
+                        std::process::exit(1);
+                    }
+                })
+                .collect(),
+            None => vec![None; parse_count("count")],
+        };
+        connect_and_run(addresses);
+    }
+}
+
+#[cfg(not(windows))]
+fn connect_and_run(_addresses: Vec<Option<u64>>) {
+    eprintln!(
+        "toio_dashboard: connecting to the cubes is supported only on Windows (use --simulate)"
+    );
+    std::process::exit(1);
+}
+
+#[cfg(windows)]
+fn connect_and_run(addresses: Vec<Option<u64>>) {
+    let mut cubes: Vec<Box<dyn transport::CoreCubeTransport>> = Vec::new();
+    let mut handlers: Vec<CoreCubeNotifyHandler> = Vec::new();
+    let mut used_devices: Vec<String> = Vec::new();
+    for (i, address) in addresses.into_iter().enumerate() {
+        let cube = match connect_unused_cube(address, &mut used_devices) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{}", e);
+                eprintln!("cube{}: {}", i + 1, e);
+                std::process::exit(1);
+            }
+        };
+        STATUS
+            .lock()
+            .unwrap()
+            .push(CubeStatus::new(&format!("cube{}", i + 1)));
+        for characteristic in &NOTIFY_CHARACTERISTICS {
+            match cube.register_notify(*characteristic, notify(i, *characteristic)) {
+                Ok(handler) => handlers.push(handler),
+                Err(e) => error!("{}", e),
+            }
+        }
+        STATUS.lock().unwrap()[i].set_connected(true, time::Instant::now());
+        cubes.push(Box::new(cube));
+    }
+    run_dashboard(&cubes);
+    for handler in handlers {
+        let result = handler.unregister();
+        assert!(result.unwrap());
+    }
+}
+
+fn run_dashboard(cubes: &[Box<dyn transport::CoreCubeTransport>]) {
+    for (i, cube) in cubes.iter().enumerate() {
+        setup(i, cube.as_ref());
+    }
+
+    // MAIN LOOP
+    // --------------------------------------------------------------------------------
+
+    let mut terminal = ratatui::init();
+    let mut selected: usize = 0;
+    loop {
+        if let Err(e) = terminal.draw(|frame| draw(frame, selected)) {
+            error!("{}", e);
+            break;
+        }
+        match event::poll(time::Duration::from_millis(DRAW_INTERVAL_MS)) {
+            Ok(true) => (),
+            Ok(false) => continue,
+            Err(e) => {
+                error!("{}", e);
+                break;
+            }
+        }
+        let key = match event::read() {
+            Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        let cube = cubes[selected].as_ref();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Tab => selected = (selected + 1) % cubes.len(),
+            KeyCode::BackTab => selected = (selected + cubes.len() - 1) % cubes.len(),
+            KeyCode::Up => drive(selected, cube, DRIVE_SPEED, DRIVE_SPEED),
+            KeyCode::Down => drive(selected, cube, -DRIVE_SPEED, -DRIVE_SPEED),
+            KeyCode::Left => drive(selected, cube, -DRIVE_SPEED / 2, DRIVE_SPEED / 2),
+            KeyCode::Right => drive(selected, cube, DRIVE_SPEED / 2, -DRIVE_SPEED / 2),
+            KeyCode::Char(' ') => drive(selected, cube, 0, 0),
+            _ => (),
+        }
+    }
+    ratatui::restore();
+    // --------------------------------------------------------------------------------
+
+    for (i, cube) in cubes.iter().enumerate() {
+        drive(i, cube.as_ref(), 0, 0);
+    }
+}
@@ -0,0 +1,443 @@
+use clap::{App, Arg};
+use core_cube::clock::*;
+use core_cube::light::{get_light_bytes, get_light_off_bytes};
+use core_cube::mat::*;
+use core_cube::motor::MotorControl;
+use core_cube::scheduler::BeatScheduler;
+use core_cube::show::*;
+use core_cube::simulator::*;
+use core_cube::trajectory::*;
+use core_cube::transport::{self, CoreCubeUuidName};
+#[cfg(windows)]
+use core_cube::win10::*;
+use lazy_static::lazy_static;
+use log::{error, info};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+#[cfg(windows)]
+const MAT_DETECTION_TIMEOUT_SEC: u64 = 10;
+
+// distance between the simulated cubes at the start [Position ID units]
+const SIMULATED_CUBE_SPACING: f32 = 60.0;
+
+lazy_static! {
+    static ref MAT_DETECTOR: Mutex<Vec<MatDetector>> = Mutex::new(Vec::new());
+    static ref RECORDER: Mutex<Option<TrajectoryRecorder>> = Mutex::new(None);
+}
+
+// Position ID notify of the cube: detect the mat and record the trajectory
+fn id_information_notify(index: usize, data: &[u8], clock: &Arc<dyn Clock>) {
+    MAT_DETECTOR.lock().unwrap()[index].update(data);
+    if let Some(recorder) = RECORDER.lock().unwrap().as_mut() {
+        recorder.update_id_info(index, data, clock.now());
+    }
+}
+
+/// Record the commands written to the cube
+struct RecordingCube {
+    cube: Box<dyn transport::CoreCubeTransport>,
+    index: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl transport::CoreCubeTransport for RecordingCube {
+    fn read(&self, characteristic_name: CoreCubeUuidName) -> std::result::Result<Vec<u8>, String> {
+        self.cube.read(characteristic_name)
+    }
+
+    fn write(
+        &self,
+        characteristic_name: CoreCubeUuidName,
+        bytes: &[u8],
+    ) -> std::result::Result<bool, String> {
+        if let Some(recorder) = RECORDER.lock().unwrap().as_mut() {
+            recorder.add_command(self.index, characteristic_name, bytes, self.clock.now());
+        }
+        self.cube.write(characteristic_name, bytes)
+    }
+}
+
+/// Print the commands instead of writing them to a cube
+struct DryRunCube {
+    role: String,
+    clock: Arc<dyn Clock>,
+    start_time: time::Instant,
+}
+
+impl transport::CoreCubeTransport for DryRunCube {
+    fn read(&self, characteristic_name: CoreCubeUuidName) -> std::result::Result<Vec<u8>, String> {
+        Err(format!(
+            "{}: can't read {} in dry run",
+            self.role, characteristic_name
+        ))
+    }
+
+    fn write(
+        &self,
+        characteristic_name: CoreCubeUuidName,
+        bytes: &[u8],
+    ) -> std::result::Result<bool, String> {
+        println!(
+            "{:>8.3} {:<10} {:<12} {:02x?}",
+            (self.clock.now() - self.start_time).as_secs_f32(),
+            self.role,
+            characteristic_name.to_string(),
+            bytes
+        );
+        Ok(true)
+    }
+}
+
+fn get_fixed_mat(show_mat: ShowMat) -> Option<Mat> {
+    match show_mat {
+        ShowMat::Mat(mat_type) => Some(get_mat(mat_type)),
+        _ => None,
+    }
+}
+
+// Wait for the mat detection of the cube
+#[cfg(windows)]
+fn detect_mat(index: usize) -> Option<Mat> {
+    let start_time = time::Instant::now();
+    while start_time.elapsed() < time::Duration::from_secs(MAT_DETECTION_TIMEOUT_SEC) {
+        if let Some(mat) = MAT_DETECTOR.lock().unwrap()[index].get_mat() {
+            return Some(mat);
+        }
+        std::thread::sleep(time::Duration::from_millis(100));
+    }
+    None
+}
+
+// Connect the cubes of the show and detect the mats
+#[cfg(windows)]
+fn connect_cubes(
+    show: &Show,
+    clock: &Arc<dyn Clock>,
+    record: bool,
+    cubes: &mut Vec<Box<dyn transport::CoreCubeTransport>>,
+    mats: &mut Vec<Option<Mat>>,
+) -> Vec<CoreCubeNotifyHandler> {
+    let mut handlers: Vec<CoreCubeNotifyHandler> = Vec::new();
+    let mut used_devices: Vec<String> = Vec::new();
+    for (i, show_cube) in show.cubes.iter().enumerate() {
+        println!("connect cube \"{}\"", show_cube.role);
+        let cube = match connect_unused_cube(show_cube.address, &mut used_devices) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("{}", e);
+                eprintln!("{}: {}", show_cube.role, e);
+                std::process::exit(1);
+            }
+        };
+        if let Ok(v) = cube.read(CoreCubeUuidName::BatteryInfo) {
+            println!("battery level {}%", v[0]);
+        }
+        MAT_DETECTOR.lock().unwrap().push(MatDetector::new());
+        if show_cube.mat == ShowMat::Auto || record {
+            let notify_clock = clock.clone();
+            let result = cube.register_notify(
+                CoreCubeUuidName::IdInfo,
+                Box::new(move |data: Vec<u8>| {
+                    id_information_notify(i, &data, &notify_clock);
+                }),
+            );
+            handlers.push(result.unwrap());
+        }
+        let mat = match show_cube.mat {
+            ShowMat::Auto => {
+                println!("put \"{}\" on the mat", show_cube.role);
+                let mat = detect_mat(i);
+                match mat {
+                    Some(m) => println!("mat detected: {}", m.mat_type),
+                    None => println!(
+                        "mat is not detected: without mat mode (move the cube or specify the mat)"
+                    ),
+                }
+                mat
+            }
+            show_mat => get_fixed_mat(show_mat),
+        };
+        mats.push(mat);
+        cubes.push(Box::new(cube));
+    }
+    handlers
+}
+
+fn main() {
+    env_logger::init();
+
+    // Set command line options
+    let app = App::new("toio_show")
+        .version("0.0.1")
+        .about("Play a show file with toio core cubes")
+        .arg(
+            Arg::with_name("file")
+                .help("show file (TOML)")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("tempo")
+                .help("override the tempo of the show")
+                .long("tempo")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .help("print the commands without cubes")
+                .long("dry-run"),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .help("play the show with simulated cubes")
+                .long("simulate")
+                .conflicts_with("dry-run"),
+        )
+        .arg(
+            Arg::with_name("fast")
+                .help("play without waiting (with --dry-run or --simulate)")
+                .long("fast"),
+        )
+        .arg(
+            Arg::with_name("svg")
+                .help("write the trajectories of the cubes to SVG file")
+                .long("svg")
+                .value_name("FILE")
+                .takes_value(true),
+        );
+
+    // Parse arguments
+    let matches = app.get_matches();
+    let path = std::path::Path::new(matches.value_of("file").unwrap());
+    let mut show = match load_show_file(path) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(tempo_str) = matches.value_of("tempo") {
+        let result = match tempo_str.parse::<f32>() {
+            Ok(tempo) => show.set_tempo(tempo),
+            Err(e) => Err(format!("--tempo: {}", e)),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    println!(
+        "show \"{}\": tempo {}, {} cubes, {} entries",
+        show.title,
+        show.tempo,
+        show.cubes.len(),
+        show.timeline.len()
+    );
+
+    // connect
+    let mut cubes: Vec<Box<dyn transport::CoreCubeTransport>> = Vec::new();
+    let mut mats: Vec<Option<Mat>> = Vec::new();
+    #[cfg(windows)]
+    let mut handlers: Vec<CoreCubeNotifyHandler> = Vec::new();
+    let mut simulated: Vec<SimulatedCube> = Vec::new();
+    let without_cubes = matches.is_present("dry-run") || matches.is_present("simulate");
+    let clock: Arc<dyn Clock> = if without_cubes && matches.is_present("fast") {
+        Arc::new(ManualClock::new())
+    } else {
+        get_system_clock()
+    };
+    let svg_path = matches.value_of("svg");
+    if svg_path.is_some() {
+        let mut recorder = TrajectoryRecorder::new(clock.now());
+        for show_cube in &show.cubes {
+            recorder.add_cube(&show_cube.role);
+        }
+        *RECORDER.lock().unwrap() = Some(recorder);
+    }
+    if matches.is_present("dry-run") {
+        let start_time = clock.now();
+        for show_cube in &show.cubes {
+            if show_cube.mat == ShowMat::Auto {
+                println!("{}: mat can't be detected in dry run", show_cube.role);
+            }
+            mats.push(get_fixed_mat(show_cube.mat));
+            cubes.push(Box::new(DryRunCube {
+                role: show_cube.role.clone(),
+                clock: clock.clone(),
+                start_time,
+            }));
+        }
+    } else if matches.is_present("simulate") {
+        let offset = (show.cubes.len() - 1) as f32 / 2.0;
+        for (i, show_cube) in show.cubes.iter().enumerate() {
+            // cubes of the show without mat are also put on a mat
+            let mat = get_fixed_mat(show_cube.mat)
+                .unwrap_or_else(|| get_mat(MatType::ToioCollectionRing));
+            let mut config = SimulatorConfig::on_mat(mat);
+            config.pose.x += (i as f32 - offset) * SIMULATED_CUBE_SPACING;
+            let cube = SimulatedCube::with_clock(config, clock.clone());
+            MAT_DETECTOR.lock().unwrap().push(MatDetector::new());
+            let notify_clock = clock.clone();
+            cube.register_notify(
+                CoreCubeUuidName::IdInfo,
+                Box::new(move |data: Vec<u8>| {
+                    id_information_notify(i, &data, &notify_clock);
+                }),
+            );
+            // the first Position ID
+            cube.update_now();
+            // a resting cube can't tell overlapped mats apart: use the simulated mat
+            mats.push(match show_cube.mat {
+                ShowMat::Auto => Some(mat),
+                show_mat => get_fixed_mat(show_mat),
+            });
+            println!("simulate cube \"{}\" at {:?}", show_cube.role, config.pose);
+            cubes.push(Box::new(cube.clone()));
+            simulated.push(cube);
+        }
+    } else {
+        #[cfg(windows)]
+        handlers.extend(connect_cubes(
+            &show,
+            &clock,
+            svg_path.is_some(),
+            &mut cubes,
+            &mut mats,
+        ));
+        #[cfg(not(windows))]
+        {
+            eprintln!("toio_show: connecting to the cubes is supported only on Windows (use --simulate or --dry-run)");
+            std::process::exit(1);
+        }
+    }
+
+    if svg_path.is_some() {
+        cubes = cubes
+            .into_iter()
+            .enumerate()
+            .map(|(index, cube)| -> Box<dyn transport::CoreCubeTransport> {
+                Box::new(RecordingCube {
+                    cube,
+                    index,
+                    clock: clock.clone(),
+                })
+            })
+            .collect();
+    }
+
+    let timelines = match show.compile(&mats) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    // Register Ctrl-C handler
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    for (cube, show_cube) in cubes.iter().zip(&show.cubes) {
+        if let Some(color) = show_cube.light {
+            let result = cube.write(CoreCubeUuidName::LightCtrl, &get_light_bytes(color, None));
+            assert!(result.unwrap());
+        }
+    }
+
+    // MAIN LOOP
+    // --------------------------------------------------------------------------------
+
+    let tick = time::Duration::from_millis(10);
+    let mut scheduler = match BeatScheduler::new(show.tempo, 4, cubes.len()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    scheduler.set_clock(clock.clone());
+    scheduler.add_beat_handler(Box::new(|event| {
+        if event.is_bar_start() {
+            info!("bar {}", event.bar);
+        }
+    }));
+    for (i, timeline) in timelines.iter().enumerate() {
+        scheduler.schedule_timeline(i, 0.0, timeline, show.get_beat());
+    }
+    let transports: Vec<&dyn transport::CoreCubeTransport> =
+        cubes.iter().map(|x| x.as_ref()).collect();
+    // a failed write stops the show (the step would be retried every tick)
+    let mut failed = false;
+    while running.load(Ordering::SeqCst) {
+        let now = clock.now();
+        for cube in &simulated {
+            cube.update(now);
+        }
+        match scheduler.poll(&transports, now) {
+            Ok(true) => break,
+            Ok(false) => (),
+            Err(e) => {
+                error!("{}", e);
+                eprintln!("show stopped: {}", e);
+                failed = true;
+                break;
+            }
+        }
+        clock.sleep(tick);
+    }
+    for (i, show_cube) in show.cubes.iter().enumerate() {
+        let drift = scheduler.get_drift(i);
+        println!(
+            "{}: latency {:?}, drift mean {}us max {}us",
+            show_cube.role,
+            scheduler.get_latency(i),
+            drift.get_mean_us(),
+            drift.max_us
+        );
+    }
+    // --------------------------------------------------------------------------------
+
+    // stop and LED off (a cube which failed may not respond)
+    for cube in &cubes {
+        let result = cube.write(
+            CoreCubeUuidName::MotorCtrl,
+            &MotorControl::stop().get_bytes(),
+        );
+        if let Err(e) = result {
+            error!("{}", e);
+        }
+        let result = cube.write(CoreCubeUuidName::LightCtrl, &get_light_off_bytes());
+        if let Err(e) = result {
+            error!("{}", e);
+        }
+    }
+
+    for (cube, show_cube) in simulated.iter().zip(&show.cubes) {
+        println!("{}: {:?}", show_cube.role, cube.get_pose());
+    }
+
+    if let (Some(svg_path), Some(recorder)) = (svg_path, RECORDER.lock().unwrap().as_ref()) {
+        let mat = mats.iter().flatten().next();
+        let svg = render_svg(mat, recorder.get_trajectories(), &SvgOptions::default());
+        match std::fs::write(svg_path, svg) {
+            Ok(()) => println!("trajectories: {}", svg_path),
+            Err(e) => eprintln!("{}: {}", svg_path, e),
+        }
+    }
+
+    #[cfg(windows)]
+    for handler in handlers {
+        let result = handler.unregister();
+        assert!(result.unwrap());
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
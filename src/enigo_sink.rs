@@ -0,0 +1,108 @@
+/* Key and mouse output with enigo */
+
+use crate::keysink::{self, KeySink};
+use enigo::*;
+
+pub struct EnigoSink {
+    enigo: Enigo,
+}
+
+impl EnigoSink {
+    pub fn new() -> Self {
+        Self {
+            enigo: Enigo::new(),
+        }
+    }
+}
+
+fn get_key(name: &str) -> std::result::Result<Key, String> {
+    let key = match name {
+        "Alt" => Key::Alt,
+        "Backspace" => Key::Backspace,
+        "CapsLock" => Key::CapsLock,
+        "Control" => Key::Control,
+        "Delete" => Key::Delete,
+        "DownArrow" => Key::DownArrow,
+        "End" => Key::End,
+        "Escape" => Key::Escape,
+        "F1" => Key::F1,
+        "F2" => Key::F2,
+        "F3" => Key::F3,
+        "F4" => Key::F4,
+        "F5" => Key::F5,
+        "F6" => Key::F6,
+        "F7" => Key::F7,
+        "F8" => Key::F8,
+        "F9" => Key::F9,
+        "F10" => Key::F10,
+        "F11" => Key::F11,
+        "F12" => Key::F12,
+        "Home" => Key::Home,
+        "LeftArrow" => Key::LeftArrow,
+        "Meta" | "Command" => Key::Meta,
+        "Option" => Key::Option,
+        "PageDown" => Key::PageDown,
+        "PageUp" => Key::PageUp,
+        "Return" => Key::Return,
+        "RightArrow" => Key::RightArrow,
+        "Shift" => Key::Shift,
+        "Space" => Key::Space,
+        "Tab" => Key::Tab,
+        "UpArrow" => Key::UpArrow,
+        // virtual-key codes
+        #[cfg(windows)]
+        "VolumeMute" => Key::Raw(0xad),
+        #[cfg(windows)]
+        "VolumeDown" => Key::Raw(0xae),
+        #[cfg(windows)]
+        "VolumeUp" => Key::Raw(0xaf),
+        x if x.chars().count() == 1 => Key::Layout(x.chars().next().unwrap()),
+        x => return Err(format!("enigo: unsupported key \"{}\"", x)),
+    };
+    Ok(key)
+}
+
+impl KeySink for EnigoSink {
+    fn key_down(&mut self, key: &str) -> std::result::Result<(), String> {
+        self.enigo.key_down(get_key(key)?);
+        Ok(())
+    }
+
+    fn key_up(&mut self, key: &str) -> std::result::Result<(), String> {
+        self.enigo.key_up(get_key(key)?);
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> std::result::Result<(), String> {
+        self.enigo.key_sequence(text);
+        Ok(())
+    }
+
+    fn scroll(&mut self, lines: i32) -> std::result::Result<(), String> {
+        self.enigo.mouse_scroll_y(lines);
+        Ok(())
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) -> std::result::Result<(), String> {
+        self.enigo.mouse_move_to(x, y);
+        Ok(())
+    }
+
+    fn mouse_button(
+        &mut self,
+        button: keysink::MouseButton,
+        pressed: bool,
+    ) -> std::result::Result<(), String> {
+        let button = match button {
+            keysink::MouseButton::Left => MouseButton::Left,
+            keysink::MouseButton::Right => MouseButton::Right,
+            keysink::MouseButton::Middle => MouseButton::Middle,
+        };
+        if pressed {
+            self.enigo.mouse_down(button);
+        } else {
+            self.enigo.mouse_up(button);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,907 @@
+/* Key mapping files: bind cube gestures to key presses, text and cube feedback */
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use core_cube::dial::DialConfig;
+use core_cube::joystick::{JoystickConfig, StickDirection};
+use core_cube::light::LightColor;
+use core_cube::sensor::Posture;
+use core_cube::talk_timer::TimerControl;
+
+/// Profile used when the file has no `default`
+pub const DEFAULT_PROFILE: &str = "default";
+
+// key names (the names of enigo::Key)
+const KEY_NAMES: [&str; 36] = [
+    "Alt",
+    "Backspace",
+    "CapsLock",
+    "Control",
+    "Delete",
+    "DownArrow",
+    "End",
+    "Escape",
+    "F1",
+    "F2",
+    "F3",
+    "F4",
+    "F5",
+    "F6",
+    "F7",
+    "F8",
+    "F9",
+    "F10",
+    "F11",
+    "F12",
+    "Home",
+    "LeftArrow",
+    "Meta",
+    "PageDown",
+    "PageUp",
+    "Return",
+    "RightArrow",
+    "Shift",
+    "Space",
+    "Tab",
+    "UpArrow",
+    "Option",
+    "Command",
+    "VolumeUp",
+    "VolumeDown",
+    "VolumeMute",
+];
+
+const KEY_ALIASES: [(&str, &str); 6] = [
+    ("Ctrl", "Control"),
+    ("Enter", "Return"),
+    ("Esc", "Escape"),
+    ("Up", "UpArrow"),
+    ("Down", "DownArrow"),
+    ("Left", "LeftArrow"),
+];
+
+const MODIFIER_NAMES: [&str; 5] = ["Alt", "Control", "Meta", "Shift", "Command"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawKeyMap {
+    default: Option<String>,
+    #[serde(default)]
+    profile: BTreeMap<String, RawProfile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProfile {
+    description: Option<String>,
+    dial: Option<DialConfig>,
+    joystick: Option<JoystickConfig>,
+    #[serde(default)]
+    binding: Vec<RawBinding>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(x) => vec![x],
+            OneOrMany::Many(x) => x,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBinding {
+    when: OneOrMany,
+    posture: Option<OneOrMany>,
+    card: Option<String>,
+    template: Option<String>,
+    #[serde(default)]
+    keys: Vec<String>,
+    text: Option<String>,
+    #[serde(default)]
+    feedback: Vec<String>,
+    led: Option<[u8; 3]>,
+    timer: Option<String>,
+    scroll: Option<i32>,
+    #[serde(default)]
+    hold: bool,
+    #[serde(default)]
+    calibrate: bool,
+}
+
+/// Gestures of the cube
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    Click,
+    DoubleClick,
+    TripleClick,
+    LongPress,
+    DoubleTap,
+    Shake,
+    Tilt,
+    /// A card (Standard ID) is scanned
+    Card,
+    /// The posture is changed (matched with the new posture)
+    Posture,
+    /// A step of the rotation dial (yaw increasing / decreasing)
+    DialCw,
+    DialCcw,
+    /// A direction of the tilt joystick is pressed
+    StickLeft,
+    StickRight,
+    StickUp,
+    StickDown,
+    /// A recorded gesture template is matched
+    Template,
+}
+
+impl Trigger {
+    pub fn is_stick(&self) -> bool {
+        matches!(
+            self,
+            Trigger::StickLeft | Trigger::StickRight | Trigger::StickUp | Trigger::StickDown
+        )
+    }
+}
+
+impl From<StickDirection> for Trigger {
+    fn from(direction: StickDirection) -> Self {
+        match direction {
+            StickDirection::Left => Trigger::StickLeft,
+            StickDirection::Right => Trigger::StickRight,
+            StickDirection::Up => Trigger::StickUp,
+            StickDirection::Down => Trigger::StickDown,
+        }
+    }
+}
+
+const TRIGGER_NAMES: [(Trigger, &str); 16] = [
+    (Trigger::Click, "click"),
+    (Trigger::DoubleClick, "double_click"),
+    (Trigger::TripleClick, "triple_click"),
+    (Trigger::LongPress, "long_press"),
+    (Trigger::DoubleTap, "double_tap"),
+    (Trigger::Shake, "shake"),
+    (Trigger::Tilt, "tilt"),
+    (Trigger::Card, "card"),
+    (Trigger::Posture, "posture"),
+    (Trigger::DialCw, "dial_cw"),
+    (Trigger::DialCcw, "dial_ccw"),
+    (Trigger::StickLeft, "stick_left"),
+    (Trigger::StickRight, "stick_right"),
+    (Trigger::StickUp, "stick_up"),
+    (Trigger::StickDown, "stick_down"),
+    (Trigger::Template, "template"),
+];
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (_, name) = TRIGGER_NAMES.iter().find(|(x, _)| x == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match TRIGGER_NAMES.iter().find(|(_, name)| *name == s) {
+            Some((trigger, _)) => Ok(*trigger),
+            None => Err(format!(
+                "unknown gesture \"{}\" ({})",
+                s,
+                TRIGGER_NAMES
+                    .iter()
+                    .map(|(_, name)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+const POSTURE_NAMES: [(Posture, &str); 6] = [
+    (Posture::Normal, "normal"),
+    (Posture::Reverse, "reverse"),
+    (Posture::Downward, "downward"),
+    (Posture::Upward, "upward"),
+    (Posture::RightSideUp, "right_side_up"),
+    (Posture::LeftSideUp, "left_side_up"),
+];
+
+fn parse_posture(s: &str) -> std::result::Result<Posture, String> {
+    match POSTURE_NAMES.iter().find(|(_, name)| *name == s) {
+        Some((posture, _)) => Ok(*posture),
+        None => Err(format!(
+            "unknown posture \"{}\" ({})",
+            s,
+            POSTURE_NAMES
+                .iter()
+                .map(|(_, name)| *name)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Key name (the name of enigo::Key or a character)
+fn parse_key_name(s: &str) -> std::result::Result<String, String> {
+    if s.chars().count() == 1 {
+        return Ok(s.to_string());
+    }
+    if let Some(name) = KEY_NAMES.iter().find(|x| x.eq_ignore_ascii_case(s)) {
+        return Ok(name.to_string());
+    }
+    match KEY_ALIASES.iter().find(|(x, _)| x.eq_ignore_ascii_case(s)) {
+        Some((_, name)) => Ok(name.to_string()),
+        None => Err(format!("unknown key \"{}\"", s)),
+    }
+}
+
+/// Key press with modifiers ("Control+Shift+S")
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyStroke {
+    pub modifiers: Vec<String>,
+    pub key: String,
+}
+
+impl FromStr for KeyStroke {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        // "+" alone is the key itself
+        let mut names: Vec<&str> = if s == "+" {
+            vec![s]
+        } else {
+            s.split('+').map(|x| x.trim()).collect()
+        };
+        let key = parse_key_name(names.pop().unwrap())?;
+        let mut modifiers: Vec<String> = Vec::with_capacity(names.len());
+        for name in names {
+            let modifier = parse_key_name(name)?;
+            if !MODIFIER_NAMES.contains(&modifier.as_str()) {
+                return Err(format!("\"{}\" is not a modifier key", name));
+            }
+            modifiers.push(modifier);
+        }
+        Ok(KeyStroke { modifiers, key })
+    }
+}
+
+/// Feedback from the cube
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Feedback {
+    Beep,
+    Fanfare,
+    /// Spin the cube
+    Spin,
+    Light(LightColor),
+}
+
+impl FromStr for Feedback {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "beep" => Ok(Feedback::Beep),
+            "fanfare" => Ok(Feedback::Fanfare),
+            "spin" => Ok(Feedback::Spin),
+            _ => Err(format!("unknown feedback \"{}\" (beep, fanfare, spin)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KeyAction {
+    /// Pressed in order
+    pub keys: Vec<KeyStroke>,
+    /// Typed after the keys
+    pub text: Option<String>,
+    pub feedback: Vec<Feedback>,
+    /// Operation of the talk timer
+    pub timer: Option<TimerControl>,
+    /// Mouse wheel lines (positive: down)
+    pub scroll: Option<i32>,
+    /// Keep the keys pressed until the button / the joystick direction is released
+    /// (long_press and stick_* only)
+    pub hold: bool,
+    /// Use the current pose as the neutral pose of the dial and the joystick
+    pub calibrate: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub triggers: Vec<Trigger>,
+    /// Postures of the cube (empty: any posture)
+    pub postures: Vec<Posture>,
+    /// Name or value of the card (None: any card)
+    pub card: Option<String>,
+    /// Name of the gesture template (None: any template)
+    pub template: Option<String>,
+    pub action: KeyAction,
+}
+
+impl Binding {
+    fn is_matched(&self, trigger: Trigger, names: &[&str]) -> bool {
+        if !self.triggers.contains(&trigger) {
+            return false;
+        }
+        let name = if trigger == Trigger::Template {
+            &self.template
+        } else {
+            &self.card
+        };
+        match name {
+            Some(x) => names.iter().any(|name| x.eq_ignore_ascii_case(name)),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub description: String,
+    /// Settings of the rotation dial (Some: the profile uses the dial)
+    pub dial: Option<DialConfig>,
+    /// Settings of the tilt joystick (Some: the profile uses the joystick)
+    pub joystick: Option<JoystickConfig>,
+    pub bindings: Vec<Binding>,
+}
+
+impl Profile {
+    /// Action of the gesture. Bindings for the posture come before bindings for any posture.
+    /// `names` is the names of the scanned card (e.g. the name and the value)
+    /// or the name of the matched template.
+    pub fn find_action(
+        &self,
+        trigger: Trigger,
+        names: &[&str],
+        posture: Posture,
+    ) -> Option<&KeyAction> {
+        let matched = || {
+            self.bindings
+                .iter()
+                .filter(|x| x.is_matched(trigger, names))
+        };
+        matched()
+            .find(|x| x.postures.contains(&posture))
+            .or_else(|| matched().find(|x| x.postures.is_empty()))
+            .map(|x| &x.action)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    pub default_profile: String,
+    /// Sorted by the name
+    pub profiles: Vec<Profile>,
+}
+
+impl KeyMap {
+    /// Profile of the name (None: the default profile)
+    pub fn get_profile(&self, name: Option<&str>) -> std::result::Result<&Profile, String> {
+        let name = name.unwrap_or(&self.default_profile);
+        self.profiles
+            .iter()
+            .find(|x| x.name == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown profile \"{}\" (profiles: {})",
+                    name,
+                    self.profiles
+                        .iter()
+                        .map(|x| x.name.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+fn get_binding(path: &str, raw: RawBinding) -> std::result::Result<Binding, String> {
+    let mut triggers: Vec<Trigger> = Vec::new();
+    for (i, name) in raw.when.into_vec().iter().enumerate() {
+        triggers.push(
+            name.parse::<Trigger>()
+                .map_err(|e| format!("{}.when[{}]: {}", path, i, e))?,
+        );
+    }
+    if triggers.is_empty() {
+        return Err(format!("{}.when: no gesture", path));
+    }
+    if raw.card.is_some() && !triggers.contains(&Trigger::Card) {
+        return Err(format!("{}.card: needs the \"card\" gesture", path));
+    }
+    if raw.template.is_some() && !triggers.contains(&Trigger::Template) {
+        return Err(format!("{}.template: needs the \"template\" gesture", path));
+    }
+    let mut postures: Vec<Posture> = Vec::new();
+    for (i, name) in raw
+        .posture
+        .map(|x| x.into_vec())
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+    {
+        postures.push(parse_posture(name).map_err(|e| format!("{}.posture[{}]: {}", path, i, e))?);
+    }
+
+    let mut action = KeyAction::default();
+    for (i, stroke) in raw.keys.iter().enumerate() {
+        action.keys.push(
+            stroke
+                .parse::<KeyStroke>()
+                .map_err(|e| format!("{}.keys[{}]: {}", path, i, e))?,
+        );
+    }
+    action.text = raw.text;
+    if let Some(color) = raw.led {
+        action.feedback.push(Feedback::Light(LightColor::new(
+            color[0], color[1], color[2],
+        )));
+    }
+    for (i, name) in raw.feedback.iter().enumerate() {
+        action.feedback.push(
+            name.parse::<Feedback>()
+                .map_err(|e| format!("{}.feedback[{}]: {}", path, i, e))?,
+        );
+    }
+    if let Some(name) = raw.timer {
+        action.timer = Some(
+            name.parse::<TimerControl>()
+                .map_err(|e| format!("{}.timer: {}", path, e))?,
+        );
+    }
+    action.scroll = raw.scroll;
+    action.calibrate = raw.calibrate;
+    if action == KeyAction::default() {
+        return Err(format!(
+            "{}: no keys, text, scroll, feedback, led, timer or calibrate",
+            path
+        ));
+    }
+    if raw.hold {
+        if !triggers
+            .iter()
+            .all(|x| *x == Trigger::LongPress || x.is_stick())
+        {
+            return Err(format!(
+                "{}.hold: only for the \"long_press\" and \"stick_*\" gestures",
+                path
+            ));
+        }
+        if action.keys.is_empty() || action.text.is_some() {
+            return Err(format!("{}.hold: needs keys and no text", path));
+        }
+        action.hold = true;
+    }
+    Ok(Binding {
+        triggers,
+        postures,
+        card: raw.card,
+        template: raw.template,
+        action,
+    })
+}
+
+/// Load a key map from TOML text
+pub fn load_keymap(text: &str) -> std::result::Result<KeyMap, String> {
+    let raw: RawKeyMap = toml::from_str(text).map_err(|e| e.to_string())?;
+    if raw.profile.is_empty() {
+        return Err("profile: at least one profile is required".to_string());
+    }
+
+    let mut profiles: Vec<Profile> = Vec::with_capacity(raw.profile.len());
+    for (name, profile) in raw.profile {
+        let mut bindings: Vec<Binding> = Vec::with_capacity(profile.binding.len());
+        for (i, binding) in profile.binding.into_iter().enumerate() {
+            let path = format!("profile.{}.binding[{}]", name, i);
+            bindings.push(get_binding(&path, binding)?);
+        }
+        let uses_dial = bindings.iter().any(|x| {
+            x.triggers
+                .iter()
+                .any(|x| *x == Trigger::DialCw || *x == Trigger::DialCcw)
+        });
+        let dial = match profile.dial {
+            Some(dial) => {
+                dial.check()
+                    .map_err(|e| format!("profile.{}.dial: {}", name, e))?;
+                Some(dial)
+            }
+            None if uses_dial => Some(DialConfig::default()),
+            None => None,
+        };
+        let uses_joystick = bindings
+            .iter()
+            .any(|x| x.triggers.iter().any(|x| x.is_stick()));
+        let joystick = match profile.joystick {
+            Some(joystick) => {
+                joystick
+                    .check()
+                    .map_err(|e| format!("profile.{}.joystick: {}", name, e))?;
+                Some(joystick)
+            }
+            None if uses_joystick => Some(JoystickConfig::default()),
+            None => None,
+        };
+        profiles.push(Profile {
+            name,
+            description: profile.description.unwrap_or_default(),
+            dial,
+            joystick,
+            bindings,
+        });
+    }
+    let keymap = KeyMap {
+        default_profile: raw.default.unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+        profiles,
+    };
+    keymap
+        .get_profile(None)
+        .map_err(|e| format!("default: {}", e))?;
+    Ok(keymap)
+}
+
+/// Load a key map file. Errors start with the file name.
+pub fn load_keymap_file(path: &std::path::Path) -> std::result::Result<KeyMap, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    load_keymap(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Reload a key map file when it is changed
+pub struct KeyMapWatcher {
+    path: std::path::PathBuf,
+    // (modified time, size) of the last loaded file
+    last: Option<(Option<std::time::SystemTime>, u64)>,
+    last_error: Option<String>,
+}
+
+impl KeyMapWatcher {
+    pub fn new(path: &std::path::Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            last: None,
+            last_error: None,
+        }
+    }
+
+    /// Load the file if it is changed from the last check (None: not changed).
+    /// The modified time and the size of the file are compared,
+    /// an error (e.g. no file) is reported once until the file is changed.
+    pub fn check(&mut self) -> Option<std::result::Result<KeyMap, String>> {
+        let current = match std::fs::metadata(&self.path) {
+            Ok(metadata) => Some((metadata.modified().ok(), metadata.len())),
+            Err(_) => None,
+        };
+        if current == self.last && (current.is_some() || self.last_error.is_some()) {
+            return None;
+        }
+        self.last = current;
+        let result = load_keymap_file(&self.path);
+        self.last_error = result.as_ref().err().cloned();
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYMAP: &str = r#"
+default = "page"
+
+[profile.page]
+description = "slides"
+
+[[profile.page.binding]]
+when = "click"
+posture = "reverse"
+keys = ["PageDown"]
+
+[[profile.page.binding]]
+when = "click"
+keys = ["pageup"]
+
+[[profile.page.binding]]
+when = ["double_click", "triple_click"]
+keys = ["Ctrl+Shift+F5", "a"]
+text = "hello"
+
+[[profile.page.binding]]
+when = "card"
+card = "A"
+feedback = ["beep"]
+led = [0, 16, 0]
+
+[profile.other]
+[[profile.other.binding]]
+when = "shake"
+posture = ["left_side_up", "right_side_up"]
+feedback = ["spin"]
+
+[[profile.other.binding]]
+when = "posture"
+posture = "downward"
+timer = "pause"
+
+[profile.dial]
+dial = { detent = 30, rest_time = 500 }
+
+[[profile.dial.binding]]
+when = "dial_cw"
+scroll = 3
+
+[profile.stick]
+joystick = { max_angle = 20, curve = 2.0 }
+
+[[profile.stick.binding]]
+when = ["stick_left", "stick_right"]
+keys = ["Space"]
+hold = true
+
+[[profile.stick.binding]]
+when = "double_tap"
+calibrate = true
+
+[[profile.stick.binding]]
+when = "template"
+template = "circle"
+keys = ["F5"]
+"#;
+
+    #[test]
+    fn load() {
+        let keymap = load_keymap(KEYMAP).unwrap();
+        assert_eq!(
+            keymap
+                .profiles
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["dial", "other", "page", "stick"]
+        );
+        let page = keymap.get_profile(None).unwrap();
+        assert_eq!(page.description, "slides");
+
+        let find = |trigger, card, posture| page.find_action(trigger, card, posture).cloned();
+        let keys = |names: &[&str]| KeyAction {
+            keys: names
+                .iter()
+                .map(|x| x.parse::<KeyStroke>().unwrap())
+                .collect(),
+            ..KeyAction::default()
+        };
+        assert_eq!(
+            find(Trigger::Click, &[], Posture::Reverse),
+            Some(keys(&["PageDown"]))
+        );
+        assert_eq!(
+            find(Trigger::Click, &[], Posture::Normal),
+            Some(keys(&["PageUp"]))
+        );
+        let action = find(Trigger::TripleClick, &[], Posture::Normal).unwrap();
+        assert_eq!(
+            action.keys[0],
+            KeyStroke {
+                modifiers: vec!["Control".to_string(), "Shift".to_string()],
+                key: "F5".to_string()
+            }
+        );
+        assert_eq!(action.keys[1].key, "a");
+        assert_eq!(action.text.as_deref(), Some("hello"));
+        assert_eq!(
+            find(Trigger::Card, &["a", "1"], Posture::Normal).map(|x| x.feedback),
+            Some(vec![
+                Feedback::Light(LightColor::new(0, 16, 0)),
+                Feedback::Beep
+            ])
+        );
+        assert_eq!(find(Trigger::Card, &["B", "2"], Posture::Normal), None);
+        assert_eq!(find(Trigger::LongPress, &[], Posture::Normal), None);
+
+        let other = keymap.get_profile(Some("other")).unwrap();
+        assert!(other
+            .find_action(Trigger::Shake, &[], Posture::LeftSideUp)
+            .is_some());
+        assert!(other
+            .find_action(Trigger::Shake, &[], Posture::Normal)
+            .is_none());
+        assert_eq!(
+            other
+                .find_action(Trigger::Posture, &[], Posture::Downward)
+                .and_then(|x| x.timer),
+            Some(TimerControl::Pause)
+        );
+        assert!(keymap.get_profile(Some("none")).is_err());
+
+        let dial = keymap.get_profile(Some("dial")).unwrap();
+        assert_eq!(
+            dial.dial.map(|x| (x.detent, x.dead_zone, x.rest_time)),
+            Some((30.0, 5.0, std::time::Duration::from_millis(500)))
+        );
+        assert_eq!(
+            dial.find_action(Trigger::DialCw, &[], Posture::Normal)
+                .and_then(|x| x.scroll),
+            Some(3)
+        );
+        assert_eq!(other.dial, None);
+        assert_eq!(other.joystick, None);
+
+        let stick = keymap.get_profile(Some("stick")).unwrap();
+        assert_eq!(
+            stick.joystick.map(|x| (x.max_angle, x.curve, x.dead_zone)),
+            Some((20.0, 2.0, 5.0))
+        );
+        assert_eq!(
+            stick
+                .find_action(Trigger::from(StickDirection::Right), &[], Posture::Normal)
+                .map(|x| x.hold),
+            Some(true)
+        );
+        assert!(
+            stick
+                .find_action(Trigger::DoubleTap, &[], Posture::Normal)
+                .unwrap()
+                .calibrate
+        );
+        assert!(stick
+            .find_action(Trigger::Template, &["Circle"], Posture::Normal)
+            .is_some());
+        assert!(stick
+            .find_action(Trigger::Template, &["square"], Posture::Normal)
+            .is_none());
+    }
+
+    #[test]
+    fn builtin_keymap() {
+        let keymap = load_keymap(include_str!("../keymaps/cubekey.toml")).unwrap();
+        let postures = [
+            Posture::Unknown,
+            Posture::Normal,
+            Posture::Reverse,
+            Posture::Downward,
+            Posture::Upward,
+            Posture::RightSideUp,
+            Posture::LeftSideUp,
+        ];
+        // click keys of the postures (KEY_TABLE of the former cubekey)
+        let tables = [
+            ("page", "PageUp", "PageDown"),
+            ("lr", "LeftArrow", "RightArrow"),
+            ("ud", "UpArrow", "DownArrow"),
+        ];
+        for (name, key, reverse_key) in tables.iter() {
+            let profile = keymap.get_profile(Some(name)).unwrap();
+            let get_keys = |trigger, posture| {
+                profile.find_action(trigger, &[], posture).map(|x| {
+                    x.keys
+                        .iter()
+                        .map(|x| x.key.clone())
+                        .collect::<Vec<String>>()
+                })
+            };
+            for posture in postures.iter() {
+                let click = match posture {
+                    Posture::Unknown => None,
+                    Posture::Reverse => Some(vec![reverse_key.to_string()]),
+                    _ => Some(vec![key.to_string()]),
+                };
+                assert_eq!(get_keys(Trigger::Click, *posture), click, "{}", name);
+                for trigger in [Trigger::DoubleClick, Trigger::TripleClick].iter() {
+                    assert_eq!(get_keys(*trigger, *posture), Some(vec!["F5".to_string()]));
+                }
+                let long_press = profile
+                    .find_action(Trigger::LongPress, &[], *posture)
+                    .unwrap();
+                assert_eq!(long_press.keys[0].key, "Home", "{} {:?}", name, posture);
+                assert_eq!(long_press.feedback, vec![Feedback::Beep]);
+                let double_tap = profile
+                    .find_action(Trigger::DoubleTap, &[], *posture)
+                    .unwrap();
+                assert_eq!(double_tap.keys[0].key, "F5");
+                assert_eq!(double_tap.feedback, vec![Feedback::Fanfare, Feedback::Spin]);
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| load_keymap(text).unwrap_err();
+        let binding =
+            |body: &str| format!("[profile.default]\n[[profile.default.binding]]\n{}", body);
+        assert_eq!(
+            error(&binding("when = \"click\"\nkeys = [\"PgDn\"]")),
+            "profile.default.binding[0].keys[0]: unknown key \"PgDn\""
+        );
+        assert_eq!(
+            error(&binding("when = \"click\"\nkeys = [\"A+B\"]")),
+            "profile.default.binding[0].keys[0]: \"A\" is not a modifier key"
+        );
+        assert!(
+            error(&binding("when = [\"click\", \"swipe\"]\nkeys = [\"A\"]"))
+                .starts_with("profile.default.binding[0].when[1]: unknown gesture \"swipe\"")
+        );
+        assert!(error(&binding(
+            "when = \"click\"\nposture = \"flat\"\nkeys = [\"A\"]"
+        ))
+        .starts_with("profile.default.binding[0].posture[0]: unknown posture \"flat\""));
+        assert_eq!(
+            error(&binding("when = \"click\"\ncard = \"A\"\nkeys = [\"A\"]")),
+            "profile.default.binding[0].card: needs the \"card\" gesture"
+        );
+        assert_eq!(
+            error(&binding(
+                "when = \"click\"\ntemplate = \"x\"\nkeys = [\"A\"]"
+            )),
+            "profile.default.binding[0].template: needs the \"template\" gesture"
+        );
+        assert_eq!(
+            error(&binding("when = \"click\"")),
+            "profile.default.binding[0]: no keys, text, scroll, feedback, led, timer or calibrate"
+        );
+        assert!(error(&binding("when = \"shake\"\ntimer = \"stop\""))
+            .starts_with("profile.default.binding[0].timer: unknown timer operation \"stop\""));
+        assert_eq!(
+            error(&binding("when = \"click\"\nkeys = [\"A\"]\nhold = true")),
+            "profile.default.binding[0].hold: only for the \"long_press\" and \"stick_*\" gestures"
+        );
+        assert_eq!(
+            error(&binding("when = \"long_press\"\ntext = \"A\"\nhold = true")),
+            "profile.default.binding[0].hold: needs keys and no text"
+        );
+        assert_eq!(
+            error("default = \"x\"\n[profile.y]\n"),
+            "default: unknown profile \"x\" (profiles: y)"
+        );
+        assert_eq!(error(""), "profile: at least one profile is required");
+        assert_eq!(
+            error("[profile.x]\ndial = { detent = 0 }\n"),
+            "profile.x.dial: detent 0 must be in (0, 180]"
+        );
+        assert_eq!(
+            error("[profile.x]\njoystick = { threshold = 2.0 }\n"),
+            "profile.x.joystick: threshold 2 must be in (0, 1]"
+        );
+        assert_eq!("+".parse::<KeyStroke>().unwrap().key, "+");
+    }
+
+    #[test]
+    fn watcher() {
+        let path = std::env::temp_dir().join(format!("keymap_watcher_{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut watcher = KeyMapWatcher::new(&path);
+        assert!(matches!(watcher.check(), Some(Err(_))));
+        assert!(watcher.check().is_none());
+
+        std::fs::write(&path, KEYMAP).unwrap();
+        assert!(matches!(watcher.check(), Some(Ok(_))));
+        assert!(watcher.check().is_none());
+
+        std::fs::write(&path, "[profile.default]\n").unwrap();
+        match watcher.check() {
+            Some(Ok(keymap)) => assert_eq!(keymap.default_profile, DEFAULT_PROFILE),
+            _ => panic!("not reloaded"),
+        }
+        assert!(watcher.check().is_none());
+
+        std::fs::write(&path, "default = 1\n").unwrap();
+        assert!(matches!(watcher.check(), Some(Err(_))));
+        assert!(watcher.check().is_none());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(watcher.check(), Some(Err(_))));
+        assert!(watcher.check().is_none());
+    }
+}
@@ -0,0 +1,490 @@
+/* Key output sinks: where the key presses (and the mouse) of the key mapping go */
+
+use crate::keymap::{KeyAction, KeyStroke};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::fmt;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    // mapped by the enigo and uinput sinks; no gesture clicks it yet
+    #[allow(dead_code)]
+    Middle,
+}
+
+impl fmt::Display for MouseButton {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MouseButton::Left => write!(f, "left"),
+            MouseButton::Right => write!(f, "right"),
+            MouseButton::Middle => write!(f, "middle"),
+        }
+    }
+}
+
+/// Key output. Keys are the names of the key mapping (enigo::Key names or a character).
+pub trait KeySink {
+    fn key_down(&mut self, key: &str) -> std::result::Result<(), String>;
+    fn key_up(&mut self, key: &str) -> std::result::Result<(), String>;
+    fn type_text(&mut self, text: &str) -> std::result::Result<(), String>;
+    /// Mouse wheel (positive: down)
+    fn scroll(&mut self, lines: i32) -> std::result::Result<(), String>;
+    /// Move the mouse pointer to the screen position
+    fn mouse_move_to(&mut self, x: i32, y: i32) -> std::result::Result<(), String>;
+    fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        pressed: bool,
+    ) -> std::result::Result<(), String>;
+}
+
+/// Press the modifiers and the key
+pub fn press_stroke(sink: &mut dyn KeySink, stroke: &KeyStroke) -> std::result::Result<(), String> {
+    for modifier in &stroke.modifiers {
+        sink.key_down(modifier)?;
+    }
+    sink.key_down(&stroke.key)
+}
+
+/// Release the key and the modifiers (in reverse order)
+pub fn release_stroke(
+    sink: &mut dyn KeySink,
+    stroke: &KeyStroke,
+) -> std::result::Result<(), String> {
+    sink.key_up(&stroke.key)?;
+    for modifier in stroke.modifiers.iter().rev() {
+        sink.key_up(modifier)?;
+    }
+    Ok(())
+}
+
+pub fn click_stroke(sink: &mut dyn KeySink, stroke: &KeyStroke) -> std::result::Result<(), String> {
+    press_stroke(sink, stroke)?;
+    release_stroke(sink, stroke)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySinkEvent {
+    Down(String),
+    Up(String),
+    Text(String),
+    Scroll(i32),
+    MouseMove(i32, i32),
+    MouseDown(MouseButton),
+    MouseUp(MouseButton),
+}
+
+impl KeySinkEvent {
+    fn get_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// {"event":"down","key":"PageDown"}: the event name first
+impl Serialize for KeySinkEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            KeySinkEvent::Down(key) => {
+                map.serialize_entry("event", "down")?;
+                map.serialize_entry("key", key)?;
+            }
+            KeySinkEvent::Up(key) => {
+                map.serialize_entry("event", "up")?;
+                map.serialize_entry("key", key)?;
+            }
+            KeySinkEvent::Text(text) => {
+                map.serialize_entry("event", "text")?;
+                map.serialize_entry("text", text)?;
+            }
+            KeySinkEvent::Scroll(lines) => {
+                map.serialize_entry("event", "scroll")?;
+                map.serialize_entry("lines", lines)?;
+            }
+            KeySinkEvent::MouseMove(x, y) => {
+                map.serialize_entry("event", "move")?;
+                map.serialize_entry("x", x)?;
+                map.serialize_entry("y", y)?;
+            }
+            KeySinkEvent::MouseDown(button) => {
+                map.serialize_entry("event", "mouse_down")?;
+                map.serialize_entry("button", &button.to_string())?;
+            }
+            KeySinkEvent::MouseUp(button) => {
+                map.serialize_entry("event", "mouse_up")?;
+                map.serialize_entry("button", &button.to_string())?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl std::fmt::Display for KeySinkEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeySinkEvent::Down(key) => write!(f, "down {}", key),
+            KeySinkEvent::Up(key) => write!(f, "up   {}", key),
+            KeySinkEvent::Text(text) => write!(f, "text {:?}", text),
+            KeySinkEvent::Scroll(lines) => write!(f, "scroll {}", lines),
+            KeySinkEvent::MouseMove(x, y) => write!(f, "move ({}, {})", x, y),
+            KeySinkEvent::MouseDown(button) => write!(f, "down {} button", button),
+            KeySinkEvent::MouseUp(button) => write!(f, "up   {} button", button),
+        }
+    }
+}
+
+/// Print the key events instead of pressing keys (one JSON object per line with `json`)
+pub struct StdoutSink {
+    json: bool,
+}
+
+impl StdoutSink {
+    pub fn new(json: bool) -> Self {
+        Self { json }
+    }
+
+    fn print(&self, event: KeySinkEvent) {
+        if self.json {
+            println!("{}", event.get_json());
+        } else {
+            println!("{}", event);
+        }
+    }
+}
+
+impl KeySink for StdoutSink {
+    fn key_down(&mut self, key: &str) -> std::result::Result<(), String> {
+        self.print(KeySinkEvent::Down(key.to_string()));
+        Ok(())
+    }
+
+    fn key_up(&mut self, key: &str) -> std::result::Result<(), String> {
+        self.print(KeySinkEvent::Up(key.to_string()));
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> std::result::Result<(), String> {
+        self.print(KeySinkEvent::Text(text.to_string()));
+        Ok(())
+    }
+
+    fn scroll(&mut self, lines: i32) -> std::result::Result<(), String> {
+        self.print(KeySinkEvent::Scroll(lines));
+        Ok(())
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) -> std::result::Result<(), String> {
+        self.print(KeySinkEvent::MouseMove(x, y));
+        Ok(())
+    }
+
+    fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        pressed: bool,
+    ) -> std::result::Result<(), String> {
+        if pressed {
+            self.print(KeySinkEvent::MouseDown(button));
+        } else {
+            self.print(KeySinkEvent::MouseUp(button));
+        }
+        Ok(())
+    }
+}
+
+/// Record the key events (for tests). Clones share the same record.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    events: Arc<Mutex<Vec<KeySinkEvent>>>,
+}
+
+#[cfg(test)]
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_events(&self) -> Vec<KeySinkEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    /// Keys pressed and not released yet
+    pub fn get_pressed_keys(&self) -> Vec<String> {
+        let mut pressed: Vec<String> = Vec::new();
+        for event in self.events.lock().unwrap().iter() {
+            match event {
+                KeySinkEvent::Down(key) if !pressed.contains(key) => pressed.push(key.clone()),
+                KeySinkEvent::Up(key) => pressed.retain(|x| x != key),
+                _ => (),
+            }
+        }
+        pressed
+    }
+}
+
+#[cfg(test)]
+impl KeySink for RecordingSink {
+    fn key_down(&mut self, key: &str) -> std::result::Result<(), String> {
+        let event = KeySinkEvent::Down(key.to_string());
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+
+    fn key_up(&mut self, key: &str) -> std::result::Result<(), String> {
+        let event = KeySinkEvent::Up(key.to_string());
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> std::result::Result<(), String> {
+        let event = KeySinkEvent::Text(text.to_string());
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+
+    fn scroll(&mut self, lines: i32) -> std::result::Result<(), String> {
+        self.events
+            .lock()
+            .unwrap()
+            .push(KeySinkEvent::Scroll(lines));
+        Ok(())
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) -> std::result::Result<(), String> {
+        let event = KeySinkEvent::MouseMove(x, y);
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+
+    fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        pressed: bool,
+    ) -> std::result::Result<(), String> {
+        let event = if pressed {
+            KeySinkEvent::MouseDown(button)
+        } else {
+            KeySinkEvent::MouseUp(button)
+        };
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+}
+
+/// Output the keys of the actions to a sink and keep the held keys and mouse buttons
+pub struct KeyOutput {
+    sink: Box<dyn KeySink>,
+    held: Vec<KeyStroke>,
+    buttons: Vec<MouseButton>,
+}
+
+impl KeyOutput {
+    pub fn new(sink: Box<dyn KeySink>) -> Self {
+        Self {
+            sink,
+            held: Vec::new(),
+            buttons: Vec::new(),
+        }
+    }
+
+    pub fn mouse_move_to(&mut self, x: i32, y: i32) -> std::result::Result<(), String> {
+        self.sink.mouse_move_to(x, y)
+    }
+
+    /// Press / release a mouse button (repeated presses / releases are ignored)
+    pub fn mouse_button(
+        &mut self,
+        button: MouseButton,
+        pressed: bool,
+    ) -> std::result::Result<(), String> {
+        if pressed == self.buttons.contains(&button) {
+            return Ok(());
+        }
+        self.sink.mouse_button(button, pressed)?;
+        if pressed {
+            self.buttons.push(button);
+        } else {
+            self.buttons.retain(|x| *x != button);
+        }
+        Ok(())
+    }
+
+    pub fn mouse_click(&mut self, button: MouseButton) -> std::result::Result<(), String> {
+        self.mouse_button(button, true)?;
+        self.mouse_button(button, false)
+    }
+
+    /// Click the keys, type the text and scroll. Keys of a `hold` action are kept pressed
+    /// until release_held().
+    pub fn run(&mut self, action: &KeyAction) -> std::result::Result<(), String> {
+        for stroke in &action.keys {
+            if action.hold {
+                if !self.held.contains(stroke) {
+                    press_stroke(self.sink.as_mut(), stroke)?;
+                    self.held.push(stroke.clone());
+                }
+            } else {
+                click_stroke(self.sink.as_mut(), stroke)?;
+            }
+        }
+        if let Some(text) = &action.text {
+            self.sink.type_text(text)?;
+        }
+        if let Some(lines) = action.scroll {
+            self.sink.scroll(lines)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn is_holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// Release the held keys (in reverse order)
+    pub fn release_held(&mut self) -> std::result::Result<(), String> {
+        while let Some(stroke) = self.held.pop() {
+            release_stroke(self.sink.as_mut(), &stroke)?;
+        }
+        Ok(())
+    }
+
+    /// Release the held keys of the action (the other held keys are kept)
+    pub fn release(&mut self, action: &KeyAction) -> std::result::Result<(), String> {
+        for stroke in action.keys.iter().rev() {
+            if let Some(i) = self.held.iter().position(|x| x == stroke) {
+                self.held.remove(i);
+                release_stroke(self.sink.as_mut(), stroke)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for KeyOutput {
+    // no key or mouse button is left pressed
+    fn drop(&mut self) {
+        let _ = self.release_held();
+        while let Some(button) = self.buttons.pop() {
+            let _ = self.sink.mouse_button(button, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(keys: &[&str], text: Option<&str>, hold: bool) -> KeyAction {
+        KeyAction {
+            keys: keys
+                .iter()
+                .map(|x| x.parse::<KeyStroke>().unwrap())
+                .collect(),
+            text: text.map(|x| x.to_string()),
+            hold,
+            ..KeyAction::default()
+        }
+    }
+
+    #[test]
+    fn key_output() {
+        use KeySinkEvent::*;
+        let sink = RecordingSink::new();
+        let mut output = KeyOutput::new(Box::new(sink.clone()));
+
+        output
+            .run(&action(&["Control+Shift+F5", "a"], Some("hi"), false))
+            .unwrap();
+        assert_eq!(
+            sink.get_events(),
+            vec![
+                Down("Control".to_string()),
+                Down("Shift".to_string()),
+                Down("F5".to_string()),
+                Up("F5".to_string()),
+                Up("Shift".to_string()),
+                Up("Control".to_string()),
+                Down("a".to_string()),
+                Up("a".to_string()),
+                Text("hi".to_string()),
+            ]
+        );
+        assert!(sink.get_pressed_keys().is_empty());
+
+        // held keys are pressed once and released at release_held()
+        sink.clear();
+        let hold = action(&["Shift+Space"], None, true);
+        output.run(&hold).unwrap();
+        output.run(&hold).unwrap();
+        assert!(output.is_holding());
+        assert_eq!(sink.get_pressed_keys(), vec!["Shift", "Space"]);
+        output.release_held().unwrap();
+        assert!(!output.is_holding());
+        assert!(sink.get_pressed_keys().is_empty());
+        assert_eq!(sink.get_events().len(), 4);
+
+        // release() keeps the held keys of the other actions
+        let left = action(&["LeftArrow"], None, true);
+        output.run(&hold).unwrap();
+        output.run(&left).unwrap();
+        output.release(&hold).unwrap();
+        assert_eq!(sink.get_pressed_keys(), vec!["LeftArrow"]);
+        output.release(&left).unwrap();
+        assert!(!output.is_holding());
+
+        // mouse buttons
+        sink.clear();
+        output.mouse_button(MouseButton::Left, true).unwrap();
+        output.mouse_button(MouseButton::Left, true).unwrap();
+        output.mouse_click(MouseButton::Right).unwrap();
+        assert_eq!(
+            sink.get_events(),
+            vec![
+                MouseDown(MouseButton::Left),
+                MouseDown(MouseButton::Right),
+                MouseUp(MouseButton::Right)
+            ]
+        );
+
+        // released when the output is dropped
+        sink.clear();
+        output.run(&hold).unwrap();
+        drop(output);
+        assert!(sink.get_pressed_keys().is_empty());
+        assert_eq!(sink.get_events().last(), Some(&MouseUp(MouseButton::Left)));
+    }
+
+    #[test]
+    fn stdout_format() {
+        assert_eq!(
+            KeySinkEvent::Down("PageDown".to_string()).get_json(),
+            r#"{"event":"down","key":"PageDown"}"#
+        );
+        assert_eq!(
+            KeySinkEvent::Text("say \"hi\"\n".to_string()).get_json(),
+            r#"{"event":"text","text":"say \"hi\"\n"}"#
+        );
+        assert_eq!(
+            KeySinkEvent::Scroll(-3).get_json(),
+            r#"{"event":"scroll","lines":-3}"#
+        );
+        assert_eq!(
+            KeySinkEvent::MouseMove(10, -2).get_json(),
+            r#"{"event":"move","x":10,"y":-2}"#
+        );
+        assert_eq!(
+            KeySinkEvent::MouseDown(MouseButton::Right).get_json(),
+            r#"{"event":"mouse_down","button":"right"}"#
+        );
+        assert_eq!(KeySinkEvent::Up("a".to_string()).to_string(), "up   a");
+    }
+}
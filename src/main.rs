@@ -1,13 +1,36 @@
 use clap::{App, Arg};
+use core_cube::clock::*;
+use core_cube::dial::*;
+use core_cube::gesture::*;
+use core_cube::gesture_template::*;
+use core_cube::id_info::*;
+use core_cube::joystick::*;
+use core_cube::light::get_light_bytes;
+use core_cube::motor::MotorControl;
+use core_cube::pointer::*;
+use core_cube::sensor::{get_posture_angle, get_posture_angle_config_bytes, Posture, PostureAngle};
+use core_cube::simulator::*;
+use core_cube::standard_id::*;
+use core_cube::talk_timer::*;
+use core_cube::transport::{self, CoreCubeUuidName};
+#[cfg(windows)]
 use core_cube::win10::*;
-use ctrlc;
-use enigo::*;
-use env_logger;
 use lazy_static::lazy_static;
 use log::{debug, error, info};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::{thread, time};
+use std::time;
+
+mod enigo_sink;
+use enigo_sink::EnigoSink;
+mod keymap;
+use keymap::*;
+mod keysink;
+use keysink::*;
+#[cfg(target_os = "linux")]
+mod uinput_sink;
+#[cfg(target_os = "linux")]
+use uinput_sink::UinputSink;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum ButtonStatus {
@@ -23,13 +46,6 @@ enum SlopeStatus {
     Horizontal,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum CollisionStatus {
-    Unknown,
-    NotDetect,
-    Detect,
-}
-
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum DoubleTapStatus {
     Unknown,
@@ -48,12 +64,27 @@ enum PostureStatus {
     LeftSideUp = 6,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum KeyTableName {
-    Page = 0,
-    LR = 1,
-    UD = 2,
-}
+// key mapping built into cubekey
+const BUILTIN_KEYMAP: &str = include_str!("../keymaps/cubekey.toml");
+
+// interval to check the key mapping file
+const KEYMAP_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// interval of the posture angle notifications for the dial and the joystick [ms]
+const ANGLE_INTERVAL_MS: u16 = 50;
+
+// main loop interval (the pointer mode follows the cube faster)
+const TICK: time::Duration = time::Duration::from_millis(100);
+const POINTER_TICK: time::Duration = time::Duration::from_millis(20);
+
+// key outputs of --output
+#[cfg(target_os = "linux")]
+const OUTPUT_NAMES: [&str; 4] = ["enigo", "stdout", "json", "uinput"];
+#[cfg(not(target_os = "linux"))]
+const OUTPUT_NAMES: [&str; 3] = ["enigo", "stdout", "json"];
+
+// screen of the mouse positions when the pointer mode is not used
+const DEFAULT_SCREEN: (i32, i32) = (1920, 1080);
 
 #[derive(Debug, Copy, Clone)]
 struct ButtonInfo {
@@ -61,32 +92,21 @@ struct ButtonInfo {
     button: ButtonStatus,
 }
 
-impl Default for ButtonInfo {
-    fn default() -> Self {
-        Self {
-            time: time::Instant::now(),
-            button: ButtonStatus::Unknown,
-        }
-    }
-}
-
 #[derive(Debug, Copy, Clone)]
 struct SensorInfo {
-    time: time::Instant,
     slope: SlopeStatus,
-    collision: CollisionStatus,
     double_tap: DoubleTapStatus,
     posture: PostureStatus,
+    shake: u8,
 }
 
 impl Default for SensorInfo {
     fn default() -> Self {
         Self {
-            time: time::Instant::now(),
             slope: SlopeStatus::Unknown,
-            collision: CollisionStatus::Unknown,
             double_tap: DoubleTapStatus::Unknown,
             posture: PostureStatus::Unknown,
+            shake: 0,
         }
     }
 }
@@ -94,13 +114,17 @@ impl Default for SensorInfo {
 lazy_static! {
     static ref BUTTON: Mutex<Vec<ButtonInfo>> = Mutex::new(Vec::new());
     static ref SENSOR: Mutex<Vec<SensorInfo>> = Mutex::new(Vec::new());
+    static ref CARD: Mutex<CardEventDetector> = Mutex::new(CardEventDetector::new());
+    static ref CARD_PLACED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    static ref POSTURE_ANGLE: Mutex<Vec<(time::Instant, PostureAngle)>> = Mutex::new(Vec::new());
+    static ref POSITION: Mutex<Vec<(time::Instant, IdInfo)>> = Mutex::new(Vec::new());
 }
 
 // Button Notify Handler
-fn button_notify(data: Vec<u8>) {
+fn button_notify(clock: &dyn Clock, data: Vec<u8>) {
     debug!("button status changed {:?}", data);
     let button_info = ButtonInfo {
-        time: time::Instant::now(),
+        time: clock.now(),
         button: match data[1] {
             0x00 => ButtonStatus::Release,
             0x80 => ButtonStatus::Press,
@@ -114,24 +138,25 @@ fn button_notify(data: Vec<u8>) {
 }
 
 // Sensor Notify Handler
-fn sensor_information_notify(data: Vec<u8>) {
+fn sensor_information_notify(clock: &dyn Clock, data: Vec<u8>) {
     debug!("sensor information status changed {:?}", data);
 
-    let now = time::Instant::now();
+    let now = clock.now();
+    if let Some(angle) = get_posture_angle(&data) {
+        POSTURE_ANGLE.lock().unwrap().push((now, angle));
+        return;
+    }
+    if data[0] != 0x01 {
+        return;
+    }
     {
         let mut sensor = SENSOR.lock().unwrap();
         (*sensor).push(SensorInfo {
-            time: now,
             slope: match data[1] {
                 0x00 => SlopeStatus::Aslant,
                 0x01 => SlopeStatus::Horizontal,
                 _ => SlopeStatus::Unknown,
             },
-            collision: match data[2] {
-                0x00 => CollisionStatus::NotDetect,
-                0x01 => CollisionStatus::Detect,
-                _ => CollisionStatus::Unknown,
-            },
             double_tap: match data[3] {
                 0x00 => DoubleTapStatus::NotDetect,
                 0x01 => DoubleTapStatus::Detect,
@@ -146,22 +171,43 @@ fn sensor_information_notify(data: Vec<u8>) {
                 0x06 => PostureStatus::LeftSideUp,
                 _ => PostureStatus::Unknown,
             },
+            shake: data.get(5).copied().unwrap_or(0),
         });
     }
 }
 
 // ID Information Notify Handler
-fn id_information_notify(data: Vec<u8>) {
+fn id_information_notify(clock: &dyn Clock, data: Vec<u8>) {
     info!("id information status changed {:?}", data);
-    if data[0] == 0x01 {
-        let x: u16 = (data[2] as u16) << 8 | data[1] as u16;
-        let y: u16 = (data[4] as u16) << 8 | data[3] as u16;
-        let d: u16 = (data[6] as u16) << 8 | data[5] as u16;
-        println!("({}, {}) {}", x, y, d);
+    let id_info = get_id_info(&data);
+    if let IdInfo::PositionId(position) = id_info {
+        println!(
+            "({}, {}) {}",
+            position.cube_x, position.cube_y, position.cube_angle
+        );
+    }
+    if let IdInfo::PositionId(_) | IdInfo::PositionIdMissed = id_info {
+        POSITION.lock().unwrap().push((clock.now(), id_info));
+    }
+
+    let mut card = CARD.lock().unwrap();
+    for event in (*card).update(&data) {
+        let name = match event.get_item() {
+            Some(item) => item.name,
+            None => "unknown",
+        };
+        match event {
+            CardEvent::Placed { value, .. } => {
+                println!("card placed: {} ({})", name, value);
+                CARD_PLACED.lock().unwrap().push(value);
+            }
+            CardEvent::Removed { value } => println!("card removed: {} ({})", name, value),
+        }
     }
 }
 
 // Connect by ref_id (paired cube)
+#[cfg(windows)]
 fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -202,6 +248,7 @@ fn connect_ref_id() -> std::result::Result<CoreCubeBLE, String> {
 }
 
 // Connect by address
+#[cfg(windows)]
 fn connect(address: u64) -> std::result::Result<CoreCubeBLE, String> {
     loop {
         let mut cube = CoreCubeBLE::new("Cube1".to_string());
@@ -244,204 +291,926 @@ fn get_sensor_info_list() -> Vec<SensorInfo> {
     sensor_info_list
 }
 
-fn get_button_info_list(duration: time::Duration) -> Vec<ButtonInfo> {
+fn get_button_info_list() -> Vec<ButtonInfo> {
     let mut button = BUTTON.lock().unwrap();
-    (*button).retain(|event| event.time.elapsed() <= duration);
     let button_info_list = (*button).clone();
+    (*button).clear();
+    if !button_info_list.is_empty() {
+        debug!("button {:?}", button_info_list);
+    }
 
-    //debug!("list len (duration): {}", button_info_list.len());
+    button_info_list
+}
 
-    //if !button_info_list.is_empty() {
-    //    for info in button_info_list.clone() {
-    //        debug!("button {:?}", info.time.elapsed());
-    //    }
-    //}
+fn get_card_list() -> Vec<u32> {
+    let mut card = CARD_PLACED.lock().unwrap();
+    let card_list = (*card).clone();
+    (*card).clear();
+    card_list
+}
 
-    button_info_list
+fn get_posture_angle_list() -> Vec<(time::Instant, PostureAngle)> {
+    let mut angle = POSTURE_ANGLE.lock().unwrap();
+    let angle_list = (*angle).clone();
+    (*angle).clear();
+    angle_list
 }
 
-const CUBE_POSTURES: usize = 7;
-const TABLE_TYPES: usize = 3;
-
-static KEY_TABLE: [[Key; CUBE_POSTURES]; TABLE_TYPES] = [
-    [
-        Key::Escape,
-        Key::PageUp,
-        Key::PageDown,
-        Key::PageUp,
-        Key::PageUp,
-        Key::PageUp,
-        Key::PageUp,
-    ],
-    [
-        Key::Escape,
-        Key::LeftArrow,
-        Key::RightArrow,
-        Key::LeftArrow,
-        Key::LeftArrow,
-        Key::LeftArrow,
-        Key::LeftArrow,
-    ],
-    [
-        Key::Escape,
-        Key::UpArrow,
-        Key::DownArrow,
-        Key::UpArrow,
-        Key::UpArrow,
-        Key::UpArrow,
-        Key::UpArrow,
-    ],
-];
+fn get_position_list() -> Vec<(time::Instant, IdInfo)> {
+    let mut position = POSITION.lock().unwrap();
+    let position_list = (*position).clone();
+    (*position).clear();
+    position_list
+}
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum KeyAction {
-    Beep,
-    Rolling,
+// Enable / disable the posture angle notifications for the dial and the joystick
+fn set_angle_notify(cube: &dyn transport::CoreCubeTransport, enable: bool) {
+    let interval = if enable { ANGLE_INTERVAL_MS } else { 0 };
+    let result = cube.write(
+        CoreCubeUuidName::Configuration,
+        &get_posture_angle_config_bytes(interval),
+    );
+    if let Err(e) = result {
+        error!("{}", e);
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum ButtonEvent {
-    Nothing,
-    Single,
-    Double,
-    LongPress,
-}
-
-struct KeyEvent {
-    last_key_event_time: time::Instant,
-    last_double_tap_time: time::Instant,
-    double_click_detection_time: time::Duration,
-    long_press_detection_time: time::Duration,
-    next_ignore_button_event: ButtonStatus,
-}
-
-impl KeyEvent {
-    fn detect_click(&mut self, mut button_info_list: Vec<ButtonInfo>) -> ButtonEvent {
-        button_info_list.retain(|e| e.time >= self.last_key_event_time);
-
-        let now = time::Instant::now();
-        if button_info_list.is_empty() {
-            self.last_key_event_time = now;
-            return ButtonEvent::Nothing;
-        }
-
-        // Ensure that the first event of button_info_list is 'Press'
-        let first_event = button_info_list[0];
-        if first_event.button == ButtonStatus::Release {
-            self.last_key_event_time = first_event.time + time::Duration::from_millis(1);
-            button_info_list.remove(0);
-            if button_info_list.is_empty() {
-                return ButtonEvent::Nothing;
-            }
+fn detect_gesture(
+    gesture: &mut GestureRecognizer,
+    button_info_list: Vec<ButtonInfo>,
+    now: time::Instant,
+) -> Vec<ButtonGesture> {
+    let mut gestures: Vec<ButtonGesture> = Vec::new();
+    for event in button_info_list {
+        match event.button {
+            ButtonStatus::Press => gestures.extend(gesture.update(true, event.time)),
+            ButtonStatus::Release => gestures.extend(gesture.update(false, event.time)),
+            ButtonStatus::Unknown => (),
         }
+    }
+    gestures.extend(gesture.poll(now));
+    for x in &gestures {
+        info!("[GESTURE] {:?}", x);
+    }
+    gestures
+}
 
-        match button_info_list.len() {
-            1 => {
-                if now.duration_since(self.last_key_event_time) > self.long_press_detection_time {
-                    let last_event = button_info_list.last().unwrap();
-                    self.last_key_event_time = last_event.time + time::Duration::from_millis(1);
-                    info!(
-                        "[LONG-PRESS] next ignore:{:?} buflen:{} buf:{:?}",
-                        self.next_ignore_button_event,
-                        button_info_list.len(),
-                        now.duration_since(self.last_key_event_time)
-                    );
-                    return ButtonEvent::LongPress;
-                } else {
-                    return ButtonEvent::Nothing;
+fn get_button_trigger(gesture: ButtonGesture) -> Option<Trigger> {
+    match gesture {
+        ButtonGesture::Click => Some(Trigger::Click),
+        ButtonGesture::DoubleClick => Some(Trigger::DoubleClick),
+        ButtonGesture::TripleClick => Some(Trigger::TripleClick),
+        ButtonGesture::LongPress => Some(Trigger::LongPress),
+        _ => None,
+    }
+}
+
+// Gestures from the change of the sensor information
+fn detect_motion(last: &SensorInfo, sensor_info: &SensorInfo) -> Vec<Trigger> {
+    let mut triggers: Vec<Trigger> = Vec::new();
+    if sensor_info.double_tap == DoubleTapStatus::Detect
+        && last.double_tap != DoubleTapStatus::Detect
+    {
+        triggers.push(Trigger::DoubleTap);
+    }
+    if sensor_info.shake > 0 && last.shake == 0 {
+        triggers.push(Trigger::Shake);
+    }
+    if sensor_info.slope == SlopeStatus::Aslant && last.slope == SlopeStatus::Horizontal {
+        triggers.push(Trigger::Tilt);
+    }
+    if sensor_info.posture != last.posture && last.posture != PostureStatus::Unknown {
+        triggers.push(Trigger::Posture);
+    }
+    triggers
+}
+
+// `screen`: size of the screen for the mouse positions (uinput)
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn get_key_sink(name: &str, screen: (i32, i32)) -> std::result::Result<Box<dyn KeySink>, String> {
+    match name {
+        "enigo" => Ok(Box::new(EnigoSink::new())),
+        "stdout" => Ok(Box::new(StdoutSink::new(false))),
+        "json" => Ok(Box::new(StdoutSink::new(true))),
+        #[cfg(target_os = "linux")]
+        "uinput" => Ok(Box::new(UinputSink::new(screen)?)),
+        x => Err(format!("output \"{}\" is not available", x)),
+    }
+}
+
+fn get_pointer_config(
+    area: Option<&str>,
+    screen: Option<&str>,
+    smoothing: Option<&str>,
+) -> std::result::Result<PointerConfig, String> {
+    let mut config = PointerConfig::default();
+    if let Some(area) = area {
+        config.area = Some(parse_mat_area(area)?);
+    }
+    if let Some(screen) = screen {
+        config.screen = screen.parse()?;
+    }
+    if let Some(smoothing) = smoothing {
+        config.smoothing = smoothing
+            .parse::<f32>()
+            .map_err(|_| format!("invalid smoothing \"{}\"", smoothing))?;
+    }
+    Ok(config)
+}
+
+// Templates of the file (an empty list when the file does not exist and `may_be_new`)
+fn read_templates(
+    path: &str,
+    may_be_new: bool,
+) -> std::result::Result<Vec<GestureTemplate>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => load_templates(&text).map_err(|e| format!("{}: {}", path, e)),
+        Err(e) if may_be_new && e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
+}
+
+fn run_timer_event(
+    cube: &dyn transport::CoreCubeTransport,
+    clock: &dyn Clock,
+    timer: &TalkTimer,
+    event: TalkTimerEvent,
+) {
+    let (remaining, over) = timer.get_remaining(clock.now());
+    let sign = if over { "-" } else { "" };
+    println!("timer: {} ({}{})", event, sign, format_duration(remaining));
+    let result = cube.write(CoreCubeUuidName::SoundCtrl, &get_event_sound_bytes(event));
+    if let Err(e) = result {
+        error!("{}", e);
+    }
+}
+
+fn run_action(
+    cube: &dyn transport::CoreCubeTransport,
+    clock: &dyn Clock,
+    output: &mut KeyOutput,
+    timer: Option<&mut TalkTimer>,
+    action: &KeyAction,
+) {
+    for stroke in &action.keys {
+        info!("[KEYCODE] {:?}", stroke);
+    }
+    if let Err(e) = output.run(action) {
+        error!("{}", e);
+    }
+
+    if let Some(control) = action.timer {
+        match timer {
+            Some(timer) => {
+                if let Some(event) = timer.control(control, clock.now()) {
+                    run_timer_event(cube, clock, timer, event);
                 }
             }
-            2 => {
-                if now.duration_since(self.last_key_event_time) > self.double_click_detection_time {
-                    let last_event = button_info_list.last().unwrap();
-                    self.last_key_event_time = last_event.time + time::Duration::from_millis(1);
-                    info!(
-                        "[SINGLE] next ignore:{:?} buflen:{} buf:{:?}",
-                        self.next_ignore_button_event,
-                        button_info_list.len(),
-                        now.duration_since(self.last_key_event_time)
-                    );
-                    return ButtonEvent::Single;
-                } else {
-                    return ButtonEvent::Nothing;
-                }
+            None => info!("timer {}: the timer is not enabled (--timer)", control),
+        }
+    }
+
+    for feedback in &action.feedback {
+        let result = match feedback {
+            Feedback::Beep => {
+                debug!("beep");
+                cube.write(
+                    CoreCubeUuidName::SoundCtrl,
+                    &[0x03, 0x01, 0x01, 0x05, 87, 0xff],
+                )
             }
-            _ => {
-                // Multiple click is detected
-                let last_event = button_info_list.last().unwrap();
-                self.last_key_event_time = last_event.time + time::Duration::from_millis(1);
-                info!(
-                    "[DOUBLE] next ignore:{:?} buflen:{} buf:{:?}",
-                    self.next_ignore_button_event,
-                    button_info_list.len(),
-                    button_info_list,
+            Feedback::Fanfare => {
+                debug!("fanfare");
+                let result = cube.write(
+                    CoreCubeUuidName::SoundCtrl,
+                    &vec![
+                        0x03, 0x01, 13, 15, 69, 0xff, 1, 128, 0xff, 15, 69, 0xff, 1, 128, 0xff, 15,
+                        69, 0xff, 1, 128, 0xff, 50, 69, 0xff, 50, 65, 0xff, 50, 67, 0xff, 15, 69,
+                        0xff, 18, 128, 0xff, 15, 67, 0xff, 70, 69, 0xff,
+                    ],
                 );
-                return ButtonEvent::Double;
+                clock.sleep(time::Duration::from_millis(3200));
+                result
+            }
+            Feedback::Spin => {
+                debug!("spin");
+                cube.write(
+                    CoreCubeUuidName::MotorCtrl,
+                    &MotorControl::new(115, -115)
+                        .with_duration(time::Duration::from_millis(1200))
+                        .get_bytes(),
+                )
+            }
+            Feedback::Light(color) => {
+                cube.write(CoreCubeUuidName::LightCtrl, &get_light_bytes(*color, None))
             }
+        };
+        if let Err(e) = result {
+            error!("{}", e);
         }
     }
+}
 
-    fn get_key_code(
+// Key mapping and modes of cubekey, and the state of the main loop
+struct Cubekey {
+    profile_name: Option<String>,
+    keymap: KeyMap,
+    keymap_watcher: Option<KeyMapWatcher>,
+    timer: Option<TalkTimer>,
+    pointer: Option<MatPointer>,
+    recorder: Option<TemplateRecorder>,
+    recognizer: Option<TemplateRecognizer>,
+    templates_path: Option<String>,
+    gesture: GestureRecognizer,
+    last_sensor_info: SensorInfo,
+    last_keymap_check: time::Instant,
+    last_timer_phase: Option<TalkTimerPhase>,
+    dial: Option<Dial>,
+    joystick: Option<Joystick>,
+    // actions pressed by the stick directions (released as pressed)
+    stick_actions: Vec<(StickDirection, KeyAction)>,
+    angle_notify: bool,
+}
+
+impl Cubekey {
+    fn new(keymap: KeyMap, profile_name: Option<String>, now: time::Instant) -> Self {
+        Self {
+            profile_name,
+            keymap,
+            keymap_watcher: None,
+            timer: None,
+            pointer: None,
+            recorder: None,
+            recognizer: None,
+            templates_path: None,
+            gesture: GestureRecognizer::new(GestureConfig {
+                multi_click_time: time::Duration::from_millis(500),
+                long_press_time: time::Duration::from_millis(1500),
+                repeat_interval: None,
+                max_clicks: 2,
+            }),
+            last_sensor_info: Default::default(),
+            last_keymap_check: now,
+            last_timer_phase: None,
+            dial: None,
+            joystick: None,
+            stick_actions: Vec::new(),
+            angle_notify: false,
+        }
+    }
+
+    fn get_tick(&self) -> time::Duration {
+        if self.pointer.is_some() {
+            POINTER_TICK
+        } else {
+            TICK
+        }
+    }
+
+    /// One tick of the main loop: the notifications since the last tick -> key output.
+    /// Returns false when cubekey ends (the gesture template is recorded).
+    fn update(
         &mut self,
-        key_table: KeyTableName,
-        sensor_info: SensorInfo,
-        click_type: ButtonEvent,
-    ) -> (Option<Key>, Option<KeyAction>) {
-        match click_type {
-            ButtonEvent::Nothing => {
-                if (self.last_double_tap_time != sensor_info.time)
-                    && (sensor_info.double_tap == DoubleTapStatus::Detect)
-                {
-                    self.last_double_tap_time = sensor_info.time;
-                    return (Some(Key::F5), Some(KeyAction::Rolling));
+        cube: &dyn transport::CoreCubeTransport,
+        clock: &dyn Clock,
+        output: &mut KeyOutput,
+    ) -> bool {
+        let now = clock.now();
+
+        // hot reload of the key mapping file
+        if let Some(watcher) = self.keymap_watcher.as_mut() {
+            if now.saturating_duration_since(self.last_keymap_check) >= KEYMAP_CHECK_INTERVAL {
+                self.last_keymap_check = now;
+                match watcher.check() {
+                    Some(Ok(new_keymap)) => {
+                        match new_keymap.get_profile(self.profile_name.as_deref()) {
+                            Ok(_) => {
+                                println!("key mapping reloaded");
+                                self.keymap = new_keymap;
+                            }
+                            Err(e) => eprintln!("{} (not reloaded)", e),
+                        }
+                    }
+                    Some(Err(e)) => eprintln!("{} (not reloaded)", e),
+                    None => (),
+                }
+            }
+        }
+        let profile = self
+            .keymap
+            .get_profile(self.profile_name.as_deref())
+            .unwrap();
+
+        // triggers with the names of the card or the template
+        let mut triggers: Vec<(Trigger, Vec<String>)> = Vec::new();
+        for sensor_info in get_sensor_info_list() {
+            for trigger in detect_motion(&self.last_sensor_info, &sensor_info) {
+                triggers.push((trigger, Vec::new()));
+            }
+            self.last_sensor_info = sensor_info;
+        }
+        if let Some(pointer) = self.pointer.as_mut() {
+            // the pointer follows the cube, the button is the left button
+            for (time, id_info) in get_position_list() {
+                match id_info {
+                    IdInfo::PositionId(position) => {
+                        if let Some((x, y)) = pointer.update_position(&position, time) {
+                            if let Err(e) = output.mouse_move_to(x, y) {
+                                error!("{}", e);
+                            }
+                        }
+                    }
+                    _ => pointer.lift(time),
+                }
+            }
+            for event in get_button_info_list() {
+                let result = match event.button {
+                    ButtonStatus::Press => output.mouse_button(MouseButton::Left, true),
+                    ButtonStatus::Release => output.mouse_button(MouseButton::Left, false),
+                    ButtonStatus::Unknown => Ok(()),
+                };
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+            }
+        } else {
+            get_position_list();
+        }
+        for x in detect_gesture(&mut self.gesture, get_button_info_list(), now) {
+            if let ButtonGesture::HoldRelease(_) = x {
+                // keys held by the long press
+                if let Err(e) = output.release_held() {
+                    error!("{}", e);
+                }
+            }
+            if let Some(trigger) = get_button_trigger(x) {
+                triggers.push((trigger, Vec::new()));
+            }
+        }
+        for value in get_card_list() {
+            // a card is matched with its name or its value
+            let mut card_names: Vec<String> = Vec::new();
+            if let Some(item) = find_standard_id(value) {
+                card_names.push(item.name.to_string());
+            }
+            card_names.push(value.to_string());
+            triggers.push((Trigger::Card, card_names));
+        }
+
+        // rotation dial and tilt joystick of the profile (changed by the reload)
+        if profile.dial != self.dial.as_ref().map(|x| *x.get_config()) {
+            self.dial = profile.dial.map(Dial::new);
+        }
+        if profile.joystick != self.joystick.as_ref().map(|x| *x.get_config()) {
+            // keys held by the old joystick
+            if let Err(e) = output.release_held() {
+                error!("{}", e);
+            }
+            self.stick_actions.clear();
+            self.joystick = profile.joystick.map(Joystick::new);
+        }
+        let uses_angle = self.dial.is_some()
+            || self.joystick.is_some()
+            || self.recognizer.is_some()
+            || self.recorder.is_some();
+        if self.angle_notify != uses_angle {
+            self.angle_notify = !self.angle_notify;
+            set_angle_notify(cube, self.angle_notify);
+            get_posture_angle_list();
+        }
+        let angle_list = get_posture_angle_list();
+        if let Some(dial) = self.dial.as_mut() {
+            for (time, angle) in &angle_list {
+                let steps = dial.update(angle.yaw as f32, *time);
+                if steps == 0 {
+                    continue;
+                }
+                debug!("[DIAL] {} {}", angle.yaw, steps);
+                if dial.get_config().feedback {
+                    let result =
+                        cube.write(CoreCubeUuidName::MotorCtrl, &get_dial_pulse_bytes(steps));
+                    if let Err(e) = result {
+                        error!("{}", e);
+                    }
+                }
+                let trigger = if steps > 0 {
+                    Trigger::DialCw
                 } else {
-                    return (None, None);
+                    Trigger::DialCcw
+                };
+                for _ in 0..steps.abs() {
+                    triggers.push((trigger, Vec::new()));
+                }
+            }
+        }
+
+        // gesture templates: recorded or matched
+        if let Some(recorder) = self.recorder.as_mut() {
+            let shake = self.last_sensor_info.shake;
+            let mut recorded: Vec<bool> = angle_list
+                .iter()
+                .map(|(time, angle)| recorder.update(angle, shake, *time))
+                .collect();
+            // the gesture ends while the resting cube sends no posture angles
+            recorded.push(recorder.poll(now));
+            for _ in recorded.into_iter().filter(|x| *x) {
+                let (recorded, count) = recorder.get_count();
+                println!("example {}/{} recorded", recorded, count);
+                let result = cube.write(
+                    CoreCubeUuidName::SoundCtrl,
+                    &[0x03, 0x01, 0x01, 0x05, 87, 0xff],
+                );
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+            }
+            if let Some(template) = recorder.get_template() {
+                let path = self.templates_path.as_deref().unwrap();
+                let result = read_templates(path, true).and_then(|mut templates| {
+                    add_template(&mut templates, template);
+                    let text = save_templates(&templates)?;
+                    std::fs::write(path, text).map_err(|e| format!("{}: {}", path, e))
+                });
+                match result {
+                    Ok(()) => println!("template saved: {}", path),
+                    Err(e) => {
+                        error!("{}", e);
+                        eprintln!("{}", e);
+                    }
+                }
+                return false;
+            }
+            // no key output while recording
+            triggers.clear();
+        }
+        if let Some(recognizer) = self.recognizer.as_mut() {
+            let shake = self.last_sensor_info.shake;
+            let mut matches: Vec<TemplateMatch> = angle_list
+                .iter()
+                .filter_map(|(time, angle)| recognizer.update(angle, shake, *time))
+                .collect();
+            // the gesture ends while the resting cube sends no posture angles
+            matches.extend(recognizer.poll(now));
+            for matched in matches {
+                info!("[TEMPLATE] {} ({:.2})", matched.name, matched.confidence);
+                triggers.push((Trigger::Template, vec![matched.name]));
+            }
+        }
+
+        let posture = Posture::from(self.last_sensor_info.posture as u8);
+        if let Some(joystick) = self.joystick.as_mut() {
+            let axis = joystick.get_axis();
+            for (_, angle) in &angle_list {
+                for event in joystick.update(angle) {
+                    match event {
+                        JoystickEvent::Pressed(direction) => {
+                            let trigger = Trigger::from(direction);
+                            if let Some(action) = profile.find_action(trigger, &[], posture) {
+                                self.stick_actions.push((direction, action.clone()));
+                            }
+                            triggers.push((trigger, Vec::new()))
+                        }
+                        JoystickEvent::Released(direction) => {
+                            // keys held while the direction is pressed (not looked up again:
+                            // the posture or a reload may change the binding)
+                            let i = self.stick_actions.iter().position(|x| x.0 == direction);
+                            if let Some(i) = i {
+                                let (_, action) = self.stick_actions.remove(i);
+                                if let Err(e) = output.release(&action) {
+                                    error!("{}", e);
+                                }
+                            }
+                        }
+                    }
                 }
             }
-            ButtonEvent::Single => {
-                let key = KEY_TABLE[key_table as usize][sensor_info.posture as usize];
-                match key {
-                    Key::Escape => (None, None),
-                    x => (Some(x), None),
+            if joystick.get_axis() != axis {
+                let (x, y) = joystick.get_axis();
+                debug!("[STICK] x: {:.2} y: {:.2}", x, y);
+            }
+        }
+
+        for (trigger, names) in triggers {
+            if trigger == Trigger::Tilt && self.pointer.is_some() {
+                debug!("[{}] right click", trigger);
+                if let Err(e) = output.mouse_click(MouseButton::Right) {
+                    error!("{}", e);
                 }
+                continue;
+            }
+            let names: Vec<&str> = names.iter().map(|x| x.as_str()).collect();
+            match profile.find_action(trigger, &names, posture) {
+                Some(action) => {
+                    info!("[{}] {:?} {:?}", trigger, posture, action);
+                    run_action(cube, clock, output, self.timer.as_mut(), action);
+                    if action.calibrate {
+                        println!("calibrate: the current pose is the neutral pose");
+                        if let Some(dial) = self.dial.as_mut() {
+                            dial.reset();
+                        }
+                        if let Some(joystick) = self.joystick.as_mut() {
+                            joystick.calibrate();
+                        }
+                    }
+                }
+                None => debug!("[{}] {:?}: no binding", trigger, posture),
             }
-            ButtonEvent::Double => return (Some(Key::F5), None),
-            ButtonEvent::LongPress => return (Some(Key::Home), Some(KeyAction::Beep)),
+        }
+
+        // talk timer: sounds at the warnings and the light of the phase
+        if let Some(timer) = self.timer.as_mut() {
+            for event in timer.update(now) {
+                run_timer_event(cube, clock, timer, event);
+            }
+            let phase = timer.get_phase(now);
+            if self.last_timer_phase != Some(phase) {
+                self.last_timer_phase = Some(phase);
+                debug!("[TIMER] {:?}", phase);
+                let result = cube.write(CoreCubeUuidName::LightCtrl, &get_phase_light_bytes(phase));
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+            }
+        }
+        true
+    }
+
+    /// Disable the notifications enabled by the main loop
+    fn stop(&mut self, cube: &dyn transport::CoreCubeTransport) {
+        if self.angle_notify {
+            self.angle_notify = false;
+            set_angle_notify(cube, false);
         }
     }
 }
 
+// MAIN LOOP (until Ctrl-C or the end of the recording)
+fn run_main_loop(
+    cube: &dyn transport::CoreCubeTransport,
+    clock: &dyn Clock,
+    cubekey: &mut Cubekey,
+    output: &mut KeyOutput,
+    running: &AtomicBool,
+) {
+    while running.load(Ordering::SeqCst) && cubekey.update(cube, clock, output) {
+        clock.sleep(cubekey.get_tick());
+    }
+    cubekey.stop(cube);
+}
+
 fn main() {
     env_logger::init();
 
-    let key_table: KeyTableName;
-
     // Set command line options
     let app = App::new("cubekey")
         .version("0.0.1")
-        .arg(Arg::with_name("lr").help("LR arrow key mode").long("lr"))
-        .arg(Arg::with_name("ud").help("UD arrow key mode").long("ud"))
+        .arg(
+            Arg::with_name("lr")
+                .help("LR arrow key mode (--profile lr)")
+                .long("lr"),
+        )
+        .arg(
+            Arg::with_name("ud")
+                .help("UD arrow key mode (--profile ud)")
+                .long("ud"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .help("profile of the key mapping")
+                .long("profile")
+                .takes_value(true)
+                .conflicts_with_all(&["lr", "ud"]),
+        )
+        .arg(
+            Arg::with_name("keymap")
+                .help("key mapping file (TOML, reloaded when changed)")
+                .long("keymap")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("key output")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&OUTPUT_NAMES)
+                .default_value("enigo"),
+        )
+        .arg(
+            Arg::with_name("timer")
+                .help("talk timer: length of the talk (20m, 90s, 1m30s, 12:30)")
+                .long("timer")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timer-warning")
+                .help("remaining times of the timer warnings (comma separated, default: 5m,1m)")
+                .long("timer-warning")
+                .takes_value(true)
+                .requires("timer"),
+        )
+        .arg(
+            Arg::with_name("pointer")
+                .help("pointer mode: the mouse pointer follows the cube on the mat")
+                .long("pointer"),
+        )
+        .arg(
+            Arg::with_name("mat-area")
+                .help("mat area of the pointer: mat name or left,top,right,bottom (default: detected)")
+                .long("mat-area")
+                .takes_value(true)
+                .requires("pointer"),
+        )
+        .arg(
+            Arg::with_name("screen")
+                .help("screen of the pointer: WxH or WxH+X+Y (default: 1920x1080)")
+                .long("screen")
+                .takes_value(true)
+                .requires("pointer"),
+        )
+        .arg(
+            Arg::with_name("smoothing")
+                .help("smoothing of the pointer: 0.0 (none) - 0.95 (default: 0.5)")
+                .long("smoothing")
+                .takes_value(true)
+                .requires("pointer"),
+        )
+        .arg(
+            Arg::with_name("templates")
+                .help("gesture template file (TOML) for the \"template\" gesture")
+                .long("templates")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record-template")
+                .help("record a gesture template of the name to the template file and exit")
+                .long("record-template")
+                .takes_value(true)
+                .requires("templates"),
+        )
+        .arg(
+            Arg::with_name("record-count")
+                .help("examples of the recorded gesture (default: 3)")
+                .long("record-count")
+                .takes_value(true)
+                .requires("record-template"),
+        )
         .arg(
             Arg::with_name("address")
                 .help("BLE address")
                 .long("address")
                 .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .help("use a simulated cube instead of a cube (no gesture without the input)")
+                .long("simulate")
+                .conflicts_with("address"),
         );
 
     // Parse arguments
     let matches = app.get_matches();
-    if matches.is_present("lr") {
-        println!("LR arrow key mode");
-        key_table = KeyTableName::LR;
+    let profile_name: Option<String> = if matches.is_present("lr") {
+        Some("lr".to_string())
     } else if matches.is_present("ud") {
-        println!("UD arrow key mode");
-        key_table = KeyTableName::UD;
+        Some("ud".to_string())
+    } else {
+        matches.value_of("profile").map(|x| x.to_string())
+    };
+    let mut keymap_watcher = matches
+        .value_of("keymap")
+        .map(|x| KeyMapWatcher::new(std::path::Path::new(x)));
+    let keymap_result = match keymap_watcher.as_mut() {
+        Some(watcher) => watcher.check().unwrap(),
+        None => load_keymap(BUILTIN_KEYMAP),
+    };
+    let keymap = match keymap_result.and_then(|keymap| {
+        keymap.get_profile(profile_name.as_deref())?;
+        Ok(keymap)
+    }) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let profile = keymap.get_profile(profile_name.as_deref()).unwrap();
+    println!("profile \"{}\": {}", profile.name, profile.description);
+
+    let timer_result = match matches.value_of("timer") {
+        Some(length) => parse_duration(length).and_then(|length| {
+            let warnings = matches
+                .value_of("timer-warning")
+                .unwrap_or("5m,1m")
+                .split(',')
+                .filter(|x| !x.trim().is_empty())
+                .map(parse_duration)
+                .collect::<std::result::Result<Vec<time::Duration>, String>>()?;
+            let config = TalkTimerConfig::new(length, &warnings)?;
+            Ok(Some(TalkTimer::new(config)))
+        }),
+        None => Ok(None),
+    };
+    let timer = match timer_result {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(timer) = &timer {
+        let config = timer.get_config();
+        println!(
+            "timer: {} (warnings: {})",
+            format_duration(config.length),
+            config
+                .warnings
+                .iter()
+                .map(|x| format_duration(*x))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+
+    let pointer_result = if matches.is_present("pointer") {
+        get_pointer_config(
+            matches.value_of("mat-area"),
+            matches.value_of("screen"),
+            matches.value_of("smoothing"),
+        )
+        .map(|config| Some(MatPointer::new(config)))
+    } else {
+        Ok(None)
+    };
+    let pointer = match pointer_result {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(pointer) = &pointer {
+        let config = pointer.get_config();
+        let area = match config.area {
+            Some(x) => format!("({}, {}) - ({}, {})", x.left, x.top, x.right, x.bottom),
+            None => "detected".to_string(),
+        };
+        println!(
+            "pointer: mat {} -> screen {}x{}+{}+{} (smoothing {})",
+            area,
+            config.screen.width,
+            config.screen.height,
+            config.screen.left,
+            config.screen.top,
+            config.smoothing
+        );
+    }
+
+    let templates_path = matches.value_of("templates");
+    let recording = matches.value_of("record-template");
+    let templates_result = templates_path
+        .map(|path| read_templates(path, recording.is_some()))
+        .transpose();
+    let templates = match templates_result {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut recorder: Option<TemplateRecorder> = None;
+    let mut recognizer: Option<TemplateRecognizer> = None;
+    if let Some(name) = recording {
+        let count = match matches
+            .value_of("record-count")
+            .unwrap_or("3")
+            .parse::<usize>()
+        {
+            Ok(x) if x > 0 => x,
+            _ => {
+                eprintln!("invalid record count");
+                std::process::exit(1);
+            }
+        };
+        println!(
+            "record \"{}\": perform the gesture {} times (rest the cube between them)",
+            name, count
+        );
+        recorder = Some(TemplateRecorder::new(
+            name,
+            count,
+            TemplateConfig::default(),
+        ));
+    } else if let Some(templates) = templates {
+        println!(
+            "templates: {}",
+            templates
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+        recognizer = Some(TemplateRecognizer::new(
+            TemplateConfig::default(),
+            templates,
+        ));
+    }
+
+    let screen = match &pointer {
+        Some(pointer) => {
+            let screen = &pointer.get_config().screen;
+            (
+                screen.left + screen.width as i32,
+                screen.top + screen.height as i32,
+            )
+        }
+        None => DEFAULT_SCREEN,
+    };
+    let output = match get_key_sink(matches.value_of("output").unwrap(), screen) {
+        Ok(sink) => KeyOutput::new(sink),
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let clock = get_system_clock();
+    let mut cubekey = Cubekey::new(keymap, profile_name, clock.now());
+    cubekey.keymap_watcher = keymap_watcher;
+    cubekey.timer = timer;
+    cubekey.pointer = pointer;
+    cubekey.recorder = recorder;
+    cubekey.recognizer = recognizer;
+    cubekey.templates_path = templates_path.map(|x| x.to_string());
+    if matches.is_present("simulate") {
+        run_simulated_cube(clock, cubekey, output);
     } else {
-        println!("Page key mode");
-        key_table = KeyTableName::Page;
+        connect_and_run(&matches, clock, cubekey, output);
     }
-    debug!("key table {:?}", key_table);
+}
 
+// Cleared by Ctrl-C
+fn get_running_flag() -> Arc<AtomicBool> {
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("Error setting Ctrl-C handler");
+    running
+}
+
+fn register_simulator_handlers(cube: &SimulatedCube, clock: &Arc<dyn Clock>) {
+    let notify_clock = clock.clone();
+    cube.register_notify(
+        CoreCubeUuidName::ButtonInfo,
+        Box::new(move |data: Vec<u8>| button_notify(&*notify_clock, data)),
+    );
+    let notify_clock = clock.clone();
+    cube.register_notify(
+        CoreCubeUuidName::SensorInfo,
+        Box::new(move |data: Vec<u8>| sensor_information_notify(&*notify_clock, data)),
+    );
+    let notify_clock = clock.clone();
+    cube.register_notify(
+        CoreCubeUuidName::IdInfo,
+        Box::new(move |data: Vec<u8>| id_information_notify(&*notify_clock, data)),
+    );
+}
+
+fn run_simulated_cube(clock: Arc<dyn Clock>, mut cubekey: Cubekey, mut output: KeyOutput) {
+    let cube = SimulatedCube::with_clock(SimulatorConfig::default(), clock.clone());
+    register_simulator_handlers(&cube, &clock);
+    let mut runner = cube.spawn();
+    println!("simulated cube at {:?}", cube.get_pose());
+
+    let running = get_running_flag();
+    run_main_loop(&cube, &*clock, &mut cubekey, &mut output, &running);
+    runner.stop();
+}
+
+#[cfg(not(windows))]
+fn connect_and_run(
+    _matches: &clap::ArgMatches,
+    _clock: Arc<dyn Clock>,
+    _cubekey: Cubekey,
+    _output: KeyOutput,
+) {
+    eprintln!("cubekey: connecting to the cube is supported only on Windows (use --simulate)");
+    std::process::exit(1);
+}
+
+#[cfg(windows)]
+fn connect_and_run(
+    matches: &clap::ArgMatches,
+    clock: Arc<dyn Clock>,
+    mut cubekey: Cubekey,
+    mut output: KeyOutput,
+) {
     // connect
     let cube: CoreCubeBLE;
     if let Some(adrs_str) = matches.value_of("address") {
@@ -488,89 +1257,34 @@ fn main() {
     let result = cube.write(CoreCubeUuidName::Configuration, &vec![0x17, 0x00, 0x04]);
     assert_eq!(result.unwrap(), true);
 
-    // Register cube notify handlers
-    let result = cube.register_notify(CoreCubeUuidName::ButtonInfo, Box::new(button_notify));
+    // Register cube notify handlers (the notifications are stamped with the clock)
+    let notify_clock = clock.clone();
+    let result = cube.register_notify(
+        CoreCubeUuidName::ButtonInfo,
+        Box::new(move |data: Vec<u8>| button_notify(&*notify_clock, data)),
+    );
     let button_handler = result.unwrap();
 
-    let result = cube.register_notify(CoreCubeUuidName::SensorInfo, Box::new(sensor_information_notify));
+    let notify_clock = clock.clone();
+    let result = cube.register_notify(
+        CoreCubeUuidName::SensorInfo,
+        Box::new(move |data: Vec<u8>| sensor_information_notify(&*notify_clock, data)),
+    );
     let sensor_handler = result.unwrap();
 
-    let result = cube.register_notify(CoreCubeUuidName::IdInfo, Box::new(id_information_notify));
+    let notify_clock = clock.clone();
+    let result = cube.register_notify(
+        CoreCubeUuidName::IdInfo,
+        Box::new(move |data: Vec<u8>| id_information_notify(&*notify_clock, data)),
+    );
     let id_handler = result.unwrap();
 
     // Register Ctrl-C handler
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
+    let running = get_running_flag();
+    run_main_loop(&cube, &*clock, &mut cubekey, &mut output, &running);
 
-    // MAIN LOOP
-    let mut key = KeyEvent {
-        last_key_event_time: time::Instant::now(),
-        last_double_tap_time: time::Instant::now(),
-        double_click_detection_time: time::Duration::from_millis(500),
-        long_press_detection_time: time::Duration::from_millis(1500),
-        next_ignore_button_event: ButtonStatus::Press,
-    };
-
-    let tick = time::Duration::from_millis(100);
-    let mut last_sensor_info: SensorInfo = Default::default();
-    while running.load(Ordering::SeqCst) {
-        match get_sensor_info_list().pop() {
-            Some(last) => last_sensor_info = last,
-            None => (),
-        };
-        let duration = std::cmp::max(
-            key.last_key_event_time.elapsed(),
-            key.double_click_detection_time,
-        );
-        let button_info_list = get_button_info_list(duration);
-        let double_click_num = key.detect_click(button_info_list);
-        let (key_code, key_action) =
-            key.get_key_code(key_table, last_sensor_info, double_click_num);
-        match key_code {
-            Some(key) => {
-                info!("[KEYCODE] {:?}", key);
-                let mut engio = Enigo::new();
-                engio.key_down(key);
-            }
-            None => (),
-        };
-        match key_action {
-            Some(action) => match action {
-                KeyAction::Beep => {
-                    debug!("beep");
-                    let result = cube.write(
-                        CoreCubeUuidName::SoundCtrl,
-                        &vec![0x03, 0x01, 0x01, 0x05, 87, 0xff],
-                    );
-                    assert_eq!(result.unwrap(), true);
-                }
-                KeyAction::Rolling => {
-                    debug!("rolling");
-                    let result = cube.write(
-                        CoreCubeUuidName::SoundCtrl,
-                        &vec![
-                            0x03, 0x01, 13, 15, 69, 0xff, 1, 128, 0xff, 15, 69, 0xff, 1, 128, 0xff,
-                            15, 69, 0xff, 1, 128, 0xff, 50, 69, 0xff, 50, 65, 0xff, 50, 67, 0xff,
-                            15, 69, 0xff, 18, 128, 0xff, 15, 67, 0xff, 70, 69, 0xff,
-                        ],
-                    );
-                    assert_eq!(result.unwrap(), true);
-                    thread::sleep(time::Duration::from_millis(3200));
-                    let result = cube.write(
-                        CoreCubeUuidName::MotorCtrl,
-                        &vec![0x02, 0x01, 0x01, 115, 0x02, 0x02, 115, 120],
-                    );
-                    assert_eq!(result.unwrap(), true);
-                }
-            },
-            None => (),
-        }
-        thread::sleep(tick);
-    }
+    // release the held keys and mouse buttons
+    drop(output);
 
     // LED off
     let result = cube.write(
@@ -594,3 +1308,123 @@ fn main() {
     let result = id_handler.unregister();
     assert_eq!(result.unwrap(), true);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tests share the notification lists
+    static NOTIFY_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn simulated_cube_to_keys() {
+        let _lock = NOTIFY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let manual = ManualClock::new();
+        let clock: Arc<dyn Clock> = Arc::new(manual.clone());
+        let cube = SimulatedCube::with_clock(SimulatorConfig::default(), clock.clone());
+        register_simulator_handlers(&cube, &clock);
+        let sink = RecordingSink::new();
+        let mut output = KeyOutput::new(Box::new(sink.clone()));
+        let mut cubekey = Cubekey::new(load_keymap(BUILTIN_KEYMAP).unwrap(), None, clock.now());
+        let mut run = |ms: u64| {
+            let end = manual.get_elapsed() + time::Duration::from_millis(ms);
+            while manual.get_elapsed() < end {
+                assert!(cubekey.update(&cube, &*clock, &mut output));
+                clock.sleep(cubekey.get_tick());
+            }
+        };
+
+        // click upside down: PageDown of the "page" profile
+        cube.set_motion(MotionState {
+            posture: 2,
+            ..MotionState::default()
+        });
+        cube.set_button(true);
+        run(100);
+        cube.set_button(false);
+        run(300);
+        assert!(sink.get_events().is_empty());
+        run(400);
+        assert_eq!(
+            sink.get_events(),
+            vec![
+                KeySinkEvent::Down("PageDown".to_string()),
+                KeySinkEvent::Up("PageDown".to_string())
+            ]
+        );
+
+        // long press: Home and a beep
+        sink.clear();
+        cube.set_button(true);
+        run(1600);
+        cube.set_button(false);
+        run(200);
+        assert_eq!(
+            sink.get_events(),
+            vec![
+                KeySinkEvent::Down("Home".to_string()),
+                KeySinkEvent::Up("Home".to_string())
+            ]
+        );
+        assert!(cube.is_playing_sound());
+    }
+    #[test]
+    fn stick_keys_released_as_pressed() {
+        const KEYMAP: &str = r#"
+[profile.default]
+joystick = { dead_zone = 5, max_angle = 30, threshold = 0.5 }
+
+[[profile.default.binding]]
+when = "stick_right"
+posture = "reverse"
+keys = ["End"]
+hold = true
+
+[[profile.default.binding]]
+when = "stick_right"
+keys = ["RightArrow"]
+hold = true
+"#;
+        let _lock = NOTIFY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let manual = ManualClock::new();
+        let clock: Arc<dyn Clock> = Arc::new(manual.clone());
+        let cube = SimulatedCube::with_clock(SimulatorConfig::default(), clock.clone());
+        register_simulator_handlers(&cube, &clock);
+        let sink = RecordingSink::new();
+        let mut output = KeyOutput::new(Box::new(sink.clone()));
+        let mut cubekey = Cubekey::new(load_keymap(KEYMAP).unwrap(), None, clock.now());
+        // the simulator does not tilt: roll angles of the notifications
+        let mut tilt = |roll: i16| {
+            POSTURE_ANGLE.lock().unwrap().push((
+                clock.now(),
+                PostureAngle {
+                    roll,
+                    pitch: 0,
+                    yaw: 0,
+                },
+            ));
+            assert!(cubekey.update(&cube, &*clock, &mut output));
+            clock.sleep(cubekey.get_tick());
+        };
+
+        // dropped when the angle notifications are enabled, then the neutral pose
+        tilt(0);
+        tilt(0);
+        tilt(20);
+        assert_eq!(sink.get_pressed_keys(), vec!["RightArrow".to_string()]);
+        // turned over while the stick is pressed: the binding is changed
+        cube.set_motion(MotionState {
+            posture: 2,
+            ..MotionState::default()
+        });
+        tilt(20);
+        tilt(0);
+        assert_eq!(
+            sink.get_events(),
+            vec![
+                KeySinkEvent::Down("RightArrow".to_string()),
+                KeySinkEvent::Up("RightArrow".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+/* Key and mouse output with uinput virtual devices (Linux, needs write access to /dev/uinput) */
+
+use crate::keysink::{self, KeySink};
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{
+    AbsInfo, AbsoluteAxisType, AttributeSet, EventType, InputEvent, Key, RelativeAxisType,
+    UinputAbsSetup,
+};
+use std::str::FromStr;
+
+// key names of the key mapping and the characters typed with the US layout
+const NAMED_KEYS: [(&str, Key); 36] = [
+    ("Alt", Key::KEY_LEFTALT),
+    ("Backspace", Key::KEY_BACKSPACE),
+    ("CapsLock", Key::KEY_CAPSLOCK),
+    ("Control", Key::KEY_LEFTCTRL),
+    ("Delete", Key::KEY_DELETE),
+    ("DownArrow", Key::KEY_DOWN),
+    ("End", Key::KEY_END),
+    ("Escape", Key::KEY_ESC),
+    ("F1", Key::KEY_F1),
+    ("F2", Key::KEY_F2),
+    ("F3", Key::KEY_F3),
+    ("F4", Key::KEY_F4),
+    ("F5", Key::KEY_F5),
+    ("F6", Key::KEY_F6),
+    ("F7", Key::KEY_F7),
+    ("F8", Key::KEY_F8),
+    ("F9", Key::KEY_F9),
+    ("F10", Key::KEY_F10),
+    ("F11", Key::KEY_F11),
+    ("F12", Key::KEY_F12),
+    ("Home", Key::KEY_HOME),
+    ("LeftArrow", Key::KEY_LEFT),
+    ("Meta", Key::KEY_LEFTMETA),
+    ("Command", Key::KEY_LEFTMETA),
+    ("Option", Key::KEY_LEFTALT),
+    ("PageDown", Key::KEY_PAGEDOWN),
+    ("PageUp", Key::KEY_PAGEUP),
+    ("Return", Key::KEY_ENTER),
+    ("RightArrow", Key::KEY_RIGHT),
+    ("Shift", Key::KEY_LEFTSHIFT),
+    ("Space", Key::KEY_SPACE),
+    ("Tab", Key::KEY_TAB),
+    ("UpArrow", Key::KEY_UP),
+    ("VolumeMute", Key::KEY_MUTE),
+    ("VolumeDown", Key::KEY_VOLUMEDOWN),
+    ("VolumeUp", Key::KEY_VOLUMEUP),
+];
+
+// (character, shifted character, key)
+const SYMBOL_KEYS: [(char, char, Key); 21] = [
+    ('1', '!', Key::KEY_1),
+    ('2', '@', Key::KEY_2),
+    ('3', '#', Key::KEY_3),
+    ('4', '$', Key::KEY_4),
+    ('5', '%', Key::KEY_5),
+    ('6', '^', Key::KEY_6),
+    ('7', '&', Key::KEY_7),
+    ('8', '*', Key::KEY_8),
+    ('9', '(', Key::KEY_9),
+    ('0', ')', Key::KEY_0),
+    ('-', '_', Key::KEY_MINUS),
+    ('=', '+', Key::KEY_EQUAL),
+    ('[', '{', Key::KEY_LEFTBRACE),
+    (']', '}', Key::KEY_RIGHTBRACE),
+    ('\\', '|', Key::KEY_BACKSLASH),
+    (';', ':', Key::KEY_SEMICOLON),
+    ('\'', '"', Key::KEY_APOSTROPHE),
+    ('`', '~', Key::KEY_GRAVE),
+    (',', '<', Key::KEY_COMMA),
+    ('.', '>', Key::KEY_DOT),
+    ('/', '?', Key::KEY_SLASH),
+];
+
+// range of the absolute mouse axes (mapped to the whole screen by the desktop)
+const ABS_MAX: i32 = 32767;
+
+/// Key of the character and whether it needs Shift (US layout)
+fn get_char_key(c: char) -> Option<(Key, bool)> {
+    match c {
+        ' ' => Some((Key::KEY_SPACE, false)),
+        '\n' => Some((Key::KEY_ENTER, false)),
+        '\t' => Some((Key::KEY_TAB, false)),
+        c if c.is_ascii_alphabetic() => {
+            let key = Key::from_str(&format!("KEY_{}", c.to_ascii_uppercase())).ok()?;
+            Some((key, c.is_ascii_uppercase()))
+        }
+        c => SYMBOL_KEYS.iter().find_map(|(normal, shifted, key)| {
+            if c == *normal {
+                Some((*key, false))
+            } else if c == *shifted {
+                Some((*key, true))
+            } else {
+                None
+            }
+        }),
+    }
+}
+
+fn get_key(name: &str) -> std::result::Result<(Key, bool), String> {
+    if let Some((_, key)) = NAMED_KEYS.iter().find(|(x, _)| *x == name) {
+        return Ok((*key, false));
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => get_char_key(c),
+        _ => None,
+    }
+    .ok_or_else(|| format!("uinput: unsupported key \"{}\"", name))
+}
+
+fn key_event(key: Key, pressed: bool) -> InputEvent {
+    InputEvent::new(EventType::KEY, key.code(), pressed as i32)
+}
+
+/// Virtual keyboard and mouse
+pub struct UinputSink {
+    keyboard: VirtualDevice,
+    mouse: VirtualDevice,
+    screen: (i32, i32),
+}
+
+impl UinputSink {
+    /// `screen`: size of the screen for the mouse positions
+    pub fn new(screen: (i32, i32)) -> std::result::Result<Self, String> {
+        let error =
+            |e: std::io::Error| format!("uinput: {} (needs write access to /dev/uinput)", e);
+
+        let mut keys = AttributeSet::<Key>::new();
+        for (_, key) in NAMED_KEYS.iter() {
+            keys.insert(*key);
+        }
+        for c in ('a'..='z').chain(SYMBOL_KEYS.iter().map(|x| x.0)) {
+            if let Some((key, _)) = get_char_key(c) {
+                keys.insert(key);
+            }
+        }
+        let keyboard = VirtualDeviceBuilder::new()
+            .map_err(error)?
+            .name("cubekey keyboard")
+            .with_keys(&keys)
+            .map_err(error)?
+            .build()
+            .map_err(error)?;
+
+        let mut buttons = AttributeSet::<Key>::new();
+        buttons.insert(Key::BTN_LEFT);
+        buttons.insert(Key::BTN_RIGHT);
+        buttons.insert(Key::BTN_MIDDLE);
+        let mut wheel = AttributeSet::<RelativeAxisType>::new();
+        wheel.insert(RelativeAxisType::REL_WHEEL);
+        let abs_info = AbsInfo::new(0, 0, ABS_MAX, 0, 0, 0);
+        let mouse = VirtualDeviceBuilder::new()
+            .map_err(error)?
+            .name("cubekey mouse")
+            .with_keys(&buttons)
+            .map_err(error)?
+            .with_relative_axes(&wheel)
+            .map_err(error)?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_X, abs_info))
+            .map_err(error)?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, abs_info))
+            .map_err(error)?
+            .build()
+            .map_err(error)?;
+
+        Ok(Self {
+            keyboard,
+            mouse,
+            screen: (screen.0.max(1), screen.1.max(1)),
+        })
+    }
+
+    fn emit_keys(&mut self, events: &[InputEvent]) -> std::result::Result<(), String> {
+        self.keyboard
+            .emit(events)
+            .map_err(|e| format!("uinput: {}", e))
+    }
+
+    fn emit_mouse(&mut self, events: &[InputEvent]) -> std::result::Result<(), String> {
+        self.mouse
+            .emit(events)
+            .map_err(|e| format!("uinput: {}", e))
+    }
+}
+
+impl KeySink for UinputSink {
+    fn key_down(&mut self, key: &str) -> std::result::Result<(), String> {
+        let (key, shift) = get_key(key)?;
+        if shift {
+            self.emit_keys(&[key_event(Key::KEY_LEFTSHIFT, true)])?;
+        }
+        self.emit_keys(&[key_event(key, true)])
+    }
+
+    fn key_up(&mut self, key: &str) -> std::result::Result<(), String> {
+        let (key, shift) = get_key(key)?;
+        self.emit_keys(&[key_event(key, false)])?;
+        if shift {
+            self.emit_keys(&[key_event(Key::KEY_LEFTSHIFT, false)])?;
+        }
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> std::result::Result<(), String> {
+        // a report for each press and release (a press and a release in one report may be lost)
+        for c in text.chars() {
+            let key = c.to_string();
+            self.key_down(&key)?;
+            self.key_up(&key)?;
+        }
+        Ok(())
+    }
+
+    fn scroll(&mut self, lines: i32) -> std::result::Result<(), String> {
+        // positive REL_WHEEL is up
+        let event = InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_WHEEL.0, -lines);
+        self.emit_mouse(&[event])
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) -> std::result::Result<(), String> {
+        let scale = |value: i32, size: i32| {
+            (value.clamp(0, size - 1) as i64 * ABS_MAX as i64 / (size - 1).max(1) as i64) as i32
+        };
+        let events = [
+            InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_X.0,
+                scale(x, self.screen.0),
+            ),
+            InputEvent::new(
+                EventType::ABSOLUTE,
+                AbsoluteAxisType::ABS_Y.0,
+                scale(y, self.screen.1),
+            ),
+        ];
+        self.emit_mouse(&events)
+    }
+
+    fn mouse_button(
+        &mut self,
+        button: keysink::MouseButton,
+        pressed: bool,
+    ) -> std::result::Result<(), String> {
+        let button = match button {
+            keysink::MouseButton::Left => Key::BTN_LEFT,
+            keysink::MouseButton::Right => Key::BTN_RIGHT,
+            keysink::MouseButton::Middle => Key::BTN_MIDDLE,
+        };
+        self.emit_mouse(&[key_event(button, pressed)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        assert_eq!(get_key("PageDown"), Ok((Key::KEY_PAGEDOWN, false)));
+        assert_eq!(get_key("a"), Ok((Key::KEY_A, false)));
+        assert_eq!(get_key("A"), Ok((Key::KEY_A, true)));
+        assert_eq!(get_key("?"), Ok((Key::KEY_SLASH, true)));
+        assert_eq!(get_key("0"), Ok((Key::KEY_0, false)));
+        assert!(get_key("Hyper").is_err());
+        assert_eq!(get_char_key('\n'), Some((Key::KEY_ENTER, false)));
+        assert_eq!(get_char_key('あ'), None);
+    }
+}